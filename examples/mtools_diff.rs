@@ -0,0 +1,328 @@
+//! Runs mtools and dosfstools over the same images as this crate and reports
+//! where they disagree, to catch misreadings before a user does.
+//!
+//!     cargo run --example mtools_diff [IMAGE...]
+//!
+//! Every `gen-fixture` profile is checked, along with any images given. For
+//! each, `mdir -/ -a` is compared with `walk` (names, sizes, modification
+//! times and long names) and `minfo` with the BPB as `read_disk_info` reads
+//! it. `fsck.fat -n` is run over the fixtures and over damaged copies made
+//! with `corrupt`, and should find problems exactly where `check` does. A tool
+//! that isn't installed is skipped. The exit status is 1 if anything
+//! disagreed.
+
+extern crate chrono;
+extern crate fat12;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Output};
+use chrono::{NaiveDate, NaiveDateTime, Timelike};
+use fat12::*;
+
+/// An entry as both sides describe it: mdir shows times to the minute only.
+#[derive(Debug, PartialEq, Eq)]
+struct Item {
+    dir: bool,
+    size: u32,
+    modified: Option<NaiveDateTime>,
+    long_name: Option<String>,
+}
+
+/// Entries by their short-name path, as `walk` gives it.
+type Listing = BTreeMap<String, Item>;
+
+fn our_listing(image: &[u8]) -> Result<Listing> {
+    let mut volume = Fat12Volume::open(Cursor::new(image))?;
+    let mut listing = Listing::new();
+    for found in volume.walk(false)? {
+        let entry = found.entry;
+        let dir = (entry.attributes & DirEntryAttributes::SubDir as u8) != 0;
+        let size = if dir { 0 } else { entry.file_size };
+        let modified = dos_datetime(entry.last_write_date, entry.last_write_time);
+        let modified = modified.and_then(|t| t.with_second(0));
+        listing.insert(found.path, Item { dir, size, modified, long_name: entry.long_name });
+    }
+    Ok(listing)
+}
+
+/// Runs `program`, or returns `None` if it isn't installed. mtools is told not
+/// to refuse odd geometries and to print dates one way whatever its config.
+fn run(program: &str, args: &[&str]) -> Option<io::Result<Output>> {
+    let output = Command::new(program)
+        .args(args)
+        .env("MTOOLS_SKIP_CHECK", "1")
+        .env("MTOOLS_DATE_STRING", "yyyy-mm-dd")
+        .env("LC_ALL", "C.UTF-8")
+        .output();
+    match output {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+        output => Some(output),
+    }
+}
+
+/// Splits off the first word of `text`, and what follows it.
+fn take_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    let end = text.find(char::is_whitespace).unwrap_or(text.len());
+    (&text[..end], &text[end..])
+}
+
+/// Reads mdir's `12:05` or `12:05p` times.
+fn parse_time(text: &str) -> Option<(u32, u32)> {
+    let (clock, suffix) = match text.chars().last() {
+        Some(c @ 'a') | Some(c @ 'p') => (&text[..text.len() - 1], Some(c)),
+        _ => (text, None),
+    };
+    let (hour, minute) = clock.split_once(':')?;
+    let (mut hour, minute): (u32, u32) = (hour.parse().ok()?, minute.parse().ok()?);
+    match suffix {
+        Some('p') if hour < 12 => hour += 12,
+        Some('a') if hour == 12 => hour = 0,
+        _ => (),
+    }
+    Some((hour, minute))
+}
+
+/// Parses one entry line of `mdir`: an 8.3 name in fixed columns, then the
+/// size or `<DIR>`, the date and time, and the long name if there is one.
+fn parse_mdir_entry(line: &str) -> Option<(String, Item)> {
+    let name = line.get(..8)?.trim_end();
+    let ext = line.get(9..12).unwrap_or("").trim_end();
+    let (size, rest) = take_word(line.get(12..)?);
+    let (date, rest) = take_word(rest);
+    let (time, rest) = take_word(rest);
+    let dir = size == "<DIR>";
+    let size = if dir { 0 } else { size.parse().ok()? };
+    let modified = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| parse_time(time).and_then(|(h, m)| date.and_hms_opt(h, m, 0)));
+    let long_name = Some(rest.trim()).filter(|name| !name.is_empty()).map(str::to_string);
+    let short = if ext.is_empty() { name.to_string() } else { format!("{}.{}", name, ext) };
+    Some((short, Item { dir, size, modified, long_name }))
+}
+
+/// Parses `mdir -/ -a` output. Directory headers may name directories by their
+/// long names, so they are mapped back to short ones through `ours`.
+fn parse_mdir(output: &str, ours: &Listing) -> std::result::Result<Listing, String> {
+    let mut short_paths: BTreeMap<String, String> = BTreeMap::new();
+    for (path, item) in ours.iter().filter(|(_, item)| item.dir) {
+        let (parent, short) = path.rsplit_once('/').unwrap();
+        let parent = short_paths.get(&parent.to_uppercase()).cloned().unwrap_or_else(|| parent.to_string());
+        let long = item.long_name.as_ref().map_or(short, |name| &name[..]);
+        short_paths.insert(format!("{}/{}", parent, long).to_uppercase(), path.clone());
+        short_paths.insert(path.to_uppercase(), path.clone());
+    }
+    let mut listing = Listing::new();
+    let mut dir = String::new();
+    for line in output.lines() {
+        if let Some(header) = line.strip_prefix("Directory for ::") {
+            let header = header.trim_end_matches('/');
+            dir = short_paths.get(&header.to_uppercase()).cloned().unwrap_or_else(|| header.to_string());
+            continue;
+        }
+        // Volume lines and totals are indented; entries aren't.
+        if line.is_empty() || line.starts_with(char::is_whitespace) || line.starts_with("Total files") {
+            continue;
+        }
+        let (name, item) = parse_mdir_entry(line).ok_or_else(|| format!("unexpected mdir line: {:?}", line))?;
+        if name != "." && name != ".." {
+            listing.insert(format!("{}/{}", dir, name), item);
+        }
+    }
+    Ok(listing)
+}
+
+fn diff_listings(ours: &Listing, theirs: &Listing) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (path, item) in ours {
+        match theirs.get(path) {
+            None => problems.push(format!("{}: not listed by mdir", path)),
+            Some(other) if other != item => {
+                problems.push(format!("{}: read as {:?} here, {:?} by mdir", path, item, other))
+            }
+            Some(_) => (),
+        }
+    }
+    for path in theirs.keys().filter(|path| !ours.contains_key(*path)) {
+        problems.push(format!("{}: listed by mdir but not found here", path));
+    }
+    problems
+}
+
+/// The number `minfo` gives for a field, in hex if it says so.
+fn parse_minfo_number(key: &str, value: &str) -> Option<u64> {
+    let value = value.trim().trim_matches('"');
+    if let Some(hex) = value.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()
+    } else if key == "serial number" {
+        u64::from_str_radix(&value.replace('-', ""), 16).ok()
+    } else {
+        value.split_whitespace().next()?.parse().ok()
+    }
+}
+
+fn diff_minfo(info: &DiskInfo, output: &str) -> Vec<String> {
+    let ours: &[(&str, u64)] = &[("sector size", info.bytes_per_sector as u64),
+                                 ("cluster size", info.sectors_per_cluster as u64),
+                                 ("reserved (boot) sectors", info.reserved_sectors as u64),
+                                 ("fats", info.fats as u64),
+                                 ("max available root directory slots", info.root_dir_entries as u64),
+                                 ("small size", info.total_sectors as u64),
+                                 ("big size", info.large_sectors as u64),
+                                 ("sectors per fat", info.sectors_per_fat as u64),
+                                 ("sectors per track", info.sectors_per_track as u64),
+                                 ("heads", info.heads as u64),
+                                 ("serial number", info.volume_id as u64)];
+    // The device section repeats some keys with mtools' own guesses.
+    let boot_section = output.split("bootsector information").nth(1).unwrap_or("");
+    let mut problems = Vec::new();
+    for line in boot_section.lines() {
+        let (key, value) = match line.split_once(':') {
+            Some(pair) => pair,
+            None => continue,
+        };
+        let key = key.trim();
+        if let Some(&(_, expected)) = ours.iter().find(|&&(name, _)| name == key) {
+            match parse_minfo_number(key, value) {
+                Some(theirs) if theirs != expected => {
+                    problems.push(format!("{}: {} here, {} by minfo", key, expected, theirs))
+                }
+                Some(_) => (),
+                None => problems.push(format!("{}: minfo gave {:?}", key, value.trim())),
+            }
+        }
+    }
+    problems
+}
+
+/// Whether `check` finds the volume clean: one it can't check isn't.
+fn our_verdict(image: &[u8]) -> bool {
+    let mut image = Cursor::new(image);
+    read_disk_info(&mut image)
+        .and_then(|info| check::check(&info, &mut image))
+        .is_ok_and(|check| check.is_clean())
+}
+
+/// Compares what this crate and the installed tools make of `image`, saved at
+/// `path` for them, and returns the disagreements.
+fn compare(image: &[u8], path: &Path, listing: bool) -> Vec<String> {
+    let file = path.to_string_lossy();
+    let mut problems = Vec::new();
+    if let Some(output) = run("fsck.fat", &["-n", &file]) {
+        match output {
+            Ok(output) => {
+                // fsck.fat exits 0 only when it found nothing to fix.
+                let (ours, theirs) = (our_verdict(image), output.status.success());
+                if ours != theirs {
+                    let verdict = |clean| if clean { "clean" } else { "damaged" };
+                    problems.push(format!("fsck.fat: {} here, {} by fsck.fat",
+                                          verdict(ours),
+                                          verdict(theirs)));
+                }
+            }
+            Err(e) => problems.push(format!("fsck.fat: {}", e)),
+        }
+    }
+    if !listing {
+        return problems;
+    }
+    let mut cursor = Cursor::new(image);
+    let info = match read_disk_info(&mut cursor) {
+        Ok(info) => info,
+        Err(e) => {
+            problems.push(format!("can't read the BPB: {}", e));
+            return problems;
+        }
+    };
+    if let Some(output) = run("minfo", &["-i", &file, "::"]) {
+        match output {
+            Ok(output) => {
+                let text = String::from_utf8_lossy(&output.stdout);
+                problems.extend(diff_minfo(&info, &text).into_iter().map(|p| format!("minfo: {}", p)));
+            }
+            Err(e) => problems.push(format!("minfo: {}", e)),
+        }
+    }
+    if let Some(output) = run("mdir", &["-/", "-a", "-i", &file, "::/"]) {
+        let ours = match our_listing(image) {
+            Ok(ours) => ours,
+            Err(e) => {
+                problems.push(format!("can't walk the tree: {}", e));
+                return problems;
+            }
+        };
+        let theirs = output.map_err(|e| e.to_string()).and_then(|output| {
+            parse_mdir(&String::from_utf8_lossy(&output.stdout), &ours)
+        });
+        match theirs {
+            Ok(theirs) => {
+                problems.extend(diff_listings(&ours, &theirs).into_iter().map(|p| format!("mdir: {}", p)))
+            }
+            Err(e) => problems.push(format!("mdir: {}", e)),
+        }
+    }
+    problems
+}
+
+fn main() {
+    let tools = ["mdir", "minfo", "fsck.fat"];
+    let missing: Vec<&str> = tools.iter().cloned().filter(|tool| run(tool, &["-V"]).is_none()).collect();
+    if missing.len() == tools.len() {
+        eprintln!("none of {} is installed; nothing to compare against", tools.join(", "));
+        return;
+    }
+    for tool in &missing {
+        eprintln!("{} is not installed; skipping it", tool);
+    }
+
+    // (label, image, whether to compare listings too)
+    let mut images: Vec<(String, Vec<u8>, bool)> = Vec::new();
+    for name in &["lfn-heavy", "deep-tree", "fragmented", "dos1x"] {
+        let profile = fixture::Profile::parse(name).unwrap();
+        let image = fixture::build(profile).unwrap_or_else(|e| panic!("{}: {}", name, e));
+        images.push((name.to_string(), image.clone(), true));
+        for (kind_name, seed) in &[("crosslink", 1), ("loop", 2), ("orphan-chain", 3), ("bad-bpb", 4)] {
+            let mut damaged = Cursor::new(image.clone());
+            let info = read_disk_info(&mut damaged).unwrap();
+            let kind = corrupt::Kind::parse(kind_name).unwrap();
+            if corrupt::corrupt(&info, &mut damaged, kind, *seed).unwrap().is_some() {
+                images.push((format!("{} ({})", name, kind_name), damaged.into_inner(), false));
+            }
+        }
+    }
+    for path in env::args().skip(1) {
+        match fs::read(&path) {
+            Ok(image) => images.push((path, image, true)),
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let scratch: PathBuf = env::temp_dir().join(format!("fat12-mtools-diff-{}.img", process::id()));
+    let mut disagreements = 0;
+    for (label, image, listing) in &images {
+        if let Err(e) = fs::write(&scratch, image) {
+            eprintln!("{}: {}", scratch.display(), e);
+            process::exit(1);
+        }
+        let problems = compare(image, &scratch, *listing);
+        if problems.is_empty() {
+            println!("{}: ok", label);
+        }
+        for problem in &problems {
+            println!("{}: {}", label, problem);
+        }
+        disagreements += problems.len();
+    }
+    let _ = fs::remove_file(&scratch);
+    println!("{} images, {} disagreements", images.len(), disagreements);
+    if disagreements > 0 {
+        process::exit(1);
+    }
+}
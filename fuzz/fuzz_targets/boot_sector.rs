@@ -0,0 +1,28 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate fat12;
+
+use std::io::Cursor;
+use fat12::*;
+
+// The BPB parser, and the layout worked out from whatever passes for a boot
+// sector.
+fuzz_target!(|data: &[u8]| {
+    let _ = read_disk_info(&mut Cursor::new(data));
+    let info = match DiskInfo::parse(data) {
+        Ok(info) => info,
+        Err(_) => return,
+    };
+    let _ = bpb_looks_valid(&info);
+    let _ = info.fat_type();
+    let _ = info.fat_sectors();
+    let _ = root_dir_start(&info);
+    let limit = cluster_limit(&info);
+    let _ = cluster_start(&info, limit.saturating_sub(1).max(2));
+    for &lba in &[0, info.sector_count().saturating_sub(1)] {
+        if let Some(chs) = lba_to_chs(&info, lba) {
+            assert_eq!(chs_to_lba(&info, chs), Some(lba));
+        }
+    }
+});
@@ -0,0 +1,17 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate fat12;
+
+use fat12::*;
+
+// The directory iterator, on slots as they would be read from the image.
+fuzz_target!(|data: &[u8]| {
+    for (slot, entry) in decode_dir(data, FatType::Fat12, &Warnings::default()) {
+        assert!(slot < data.len() / 32);
+        let _ = entry.name();
+        let _ = entry.created();
+        let bytes = entry.to_bytes();
+        assert_eq!(DirEntry::read(&bytes, FatType::Fat12).to_bytes(), bytes);
+    }
+});
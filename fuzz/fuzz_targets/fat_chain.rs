@@ -0,0 +1,32 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate fat12;
+
+use fat12::*;
+
+// The FAT chain walker. The first byte picks the FAT type and the next two
+// the number of clusters; the rest is the FAT.
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 3 {
+        return;
+    }
+    let fat_type = match data[0] % 3 {
+        0 => FatType::Fat12,
+        1 => FatType::Fat16,
+        _ => FatType::Fat32,
+    };
+    let limit = u16::from_le_bytes([data[1], data[2]]) as u32;
+    let fat = Fat::of_type(fat_type, limit, data[3..].to_vec());
+    for first in 0..limit.min(64) {
+        let chain: Vec<_> = fat.chain(first).collect();
+        // Each cluster is visited once, so no chain outgrows the volume.
+        assert!(chain.len() <= limit as usize);
+        assert!(chain.iter().take(chain.len().saturating_sub(1)).all(|link| link.is_ok()));
+    }
+    if fat_type == FatType::Fat12 {
+        for cluster in 0..limit.min(64) as u16 {
+            let _ = fat12_entry(fat.as_bytes(), cluster);
+        }
+    }
+});
@@ -0,0 +1,14 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate fat12;
+
+use fat12::*;
+
+// The LFN reconstructor, on a run of slots ending with the short entry.
+fuzz_target!(|data: &[u8]| {
+    if let Some(name) = long_name(data) {
+        // Order numbers are five bits, and each slot holds 13 UCS-2 units.
+        assert!(name.encode_utf16().count() <= 0x1F * 13);
+    }
+});
@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use serde_json::{self, Map, Value};
+
+/// Notes about one file in one image. The database lives outside the images,
+/// keyed by the SHA-256 of the whole image, so annotating never changes an
+/// image and the notes follow its contents rather than its file name.
+#[derive(Default)]
+pub struct Annotation {
+    pub note: Option<String>,
+    /// What the file is known to be, e.g. "WordPerfect 5.1 installer".
+    pub software: Option<String>,
+    pub license: Option<String>,
+}
+impl Annotation {
+    /// The fields that are set, as one line for a listing.
+    pub fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(ref note) = self.note {
+            parts.push(note.clone());
+        }
+        if let Some(ref software) = self.software {
+            parts.push(format!("software: {}", software));
+        }
+        if let Some(ref license) = self.license {
+            parts.push(format!("license: {}", license));
+        }
+        parts.join("; ")
+    }
+
+    fn from_json(value: &Value) -> Self {
+        let field = |name: &str| value[name].as_str().map(|s| s.to_string());
+        Annotation { note: field("note"), software: field("software"), license: field("license") }
+    }
+
+    fn to_json(&self) -> Value {
+        let mut object = Map::new();
+        for (name, field) in [("note", &self.note), ("software", &self.software), ("license", &self.license)] {
+            if let Some(ref text) = *field {
+                object.insert(name.to_string(), Value::String(text.clone()));
+            }
+        }
+        Value::Object(object)
+    }
+}
+
+/// Paths are kept the way `tree` spells them: from the root, upper case.
+pub fn normalize_path(path: &str) -> String {
+    let path = path.trim_matches('/').to_uppercase();
+    format!("/{}", path)
+}
+
+/// An annotation database: `{"images": {image hash: {path: annotation}}}`.
+pub struct Annotations {
+    db: Value,
+}
+impl Annotations {
+    /// Loads the database at `path`, or starts an empty one if there is none.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut db = match File::open(path) {
+            Ok(mut file) => {
+                let mut text = String::new();
+                file.read_to_string(&mut text)?;
+                serde_json::from_str(&text).map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+                })?
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Value::Object(Map::new()),
+            Err(e) => return Err(e),
+        };
+        if !db["images"].is_object() {
+            db["images"] = Value::Object(Map::new());
+        }
+        Ok(Annotations { db })
+    }
+
+    /// Every annotation recorded for the image with hash `image`, by path.
+    pub fn for_image(&self, image: &str) -> BTreeMap<String, Annotation> {
+        match self.db["images"][image].as_object() {
+            Some(files) => files.iter().map(|(path, value)| (path.clone(), Annotation::from_json(value))).collect(),
+            None => BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&self, image: &str, path: &str) -> Option<Annotation> {
+        let value = &self.db["images"][image][&normalize_path(path)];
+        if value.is_object() {
+            Some(Annotation::from_json(value))
+        } else {
+            None
+        }
+    }
+
+    /// Records the fields set in `annotation` for a file, keeping the ones it
+    /// leaves unset.
+    pub fn set(&mut self, image: &str, path: &str, annotation: &Annotation) {
+        let path = normalize_path(path);
+        let mut merged = self.get(image, &path).unwrap_or_default();
+        if annotation.note.is_some() {
+            merged.note = annotation.note.clone();
+        }
+        if annotation.software.is_some() {
+            merged.software = annotation.software.clone();
+        }
+        if annotation.license.is_some() {
+            merged.license = annotation.license.clone();
+        }
+        if !self.db["images"][image].is_object() {
+            self.db["images"][image] = Value::Object(Map::new());
+        }
+        self.db["images"][image][&path] = merged.to_json();
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let partial = path.with_extension("partial");
+        let mut file = File::create(&partial)?;
+        serde_json::to_writer_pretty(&mut file, &self.db)?;
+        writeln!(file)?;
+        fs::rename(&partial, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn notes_merge_and_are_kept_by_image_and_path() {
+        let path = env::temp_dir().join(format!("fat12-{}-annotations.json", std::process::id()));
+        let mut db = Annotations::load(&path).unwrap();
+        assert!(db.for_image("aa").is_empty());
+        let note = |note: Option<&str>, software: Option<&str>| Annotation {
+            note: note.map(|s| s.to_string()),
+            software: software.map(|s| s.to_string()),
+            license: None,
+        };
+        db.set("aa", "disk1/install.exe", &note(Some("run first"), None));
+        db.set("aa", "/DISK1/INSTALL.EXE", &note(None, Some("WordPerfect 5.1 installer")));
+        db.save(&path).unwrap();
+
+        let db = Annotations::load(&path).unwrap();
+        let notes = db.for_image("aa");
+        assert_eq!(notes.keys().collect::<Vec<_>>(), ["/DISK1/INSTALL.EXE"]);
+        assert_eq!(notes["/DISK1/INSTALL.EXE"].summary(), "run first; software: WordPerfect 5.1 installer");
+        assert!(db.get("aa", "/Disk1/Install.exe").is_some());
+        assert!(db.get("bb", "/DISK1/INSTALL.EXE").is_none());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use chrono::Local;
+use backup::{sha256_hex, SECTOR_SIZE};
+use sectors::dirty_sectors;
+
+/// Hash that stands in for the previous entry at the start of a log.
+const GENESIS: &str = "-";
+
+pub fn log_path(image_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.f12log", image_path))
+}
+
+/// Mutations are logged when asked to with `--audit`, and always once an image
+/// has a log, so a history can't be interrupted by forgetting the flag.
+pub fn enabled(image_path: &str, args: &[String]) -> bool {
+    args.iter().any(|a| a == "--audit") || log_path(image_path).exists()
+}
+
+/// Formats the sectors that differ between two images as ranges, e.g. `0,19-20`.
+fn changed_sectors(before: &[u8], after: &[u8]) -> String {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for i in dirty_sectors(before, after, SECTOR_SIZE) {
+        match ranges.last_mut() {
+            Some(range) if range.1 + 1 == i => range.1 = i,
+            _ => ranges.push((i, i)),
+        }
+    }
+    if ranges.is_empty() {
+        return "none".to_string();
+    }
+    ranges.iter()
+        .map(|&(a, b)| if a == b { a.to_string() } else { format!("{}-{}", a, b) })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn read_log(path: &Path) -> io::Result<Vec<String>> {
+    let mut text = String::new();
+    match File::open(path) {
+        Ok(mut file) => file.read_to_string(&mut text)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    Ok(text.lines().map(|l| l.to_string()).collect())
+}
+
+fn field<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    line.split('\t').filter_map(|f| f.split_once('=')).find(|&(k, _)| k == key).map(|(_, v)| v)
+}
+
+/// Splits an entry into its body and the hash recorded for it.
+fn split_hash(line: &str) -> Option<(&str, &str)> {
+    let (body, hash) = line.rsplit_once("\thash=")?;
+    Some((body, hash))
+}
+
+/// Appends an entry describing a mutation of `image_path`. Each entry records
+/// the hash of the one before it and a hash of itself, so editing or removing
+/// an earlier entry breaks the chain.
+pub fn record(image_path: &str, command_line: &[String], before: &[u8], after: &[u8]) -> io::Result<()> {
+    let path = log_path(image_path);
+    let prev = read_log(&path)?
+        .last()
+        .and_then(|line| split_hash(line).map(|(_, hash)| hash.to_string()))
+        .unwrap_or_else(|| GENESIS.to_string());
+    let command = command_line.iter()
+        .map(|a| a.replace(['\t', '\n'], " "))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let body = format!("time={}\tcommand={}\tsectors={}\tbefore={}\tafter={}\tprev={}",
+                       Local::now().format("%Y-%m-%dT%H:%M:%S"),
+                       command,
+                       changed_sectors(before, after),
+                       sha256_hex(before),
+                       sha256_hex(after),
+                       prev);
+    let mut log = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(log, "{}\thash={}", body, sha256_hex(body.as_bytes()))
+}
+
+/// Prints the log for `image_path` and checks it: every entry must hash
+/// correctly and chain to the one before it, and the last recorded state must
+/// match `current`. Returns whether everything checked out.
+pub fn verify(image_path: &str, current: &[u8]) -> io::Result<bool> {
+    let lines = read_log(&log_path(image_path))?;
+    let mut ok = true;
+    let mut prev = GENESIS.to_string();
+    let mut last_after = None;
+    for (n, line) in lines.iter().enumerate() {
+        let (body, hash) = match split_hash(line) {
+            Some(parts) => parts,
+            None => {
+                println!("entry {}: malformed", n + 1);
+                ok = false;
+                continue;
+            }
+        };
+        println!("{} {} [sectors {}]",
+                 field(body, "time").unwrap_or("?"),
+                 field(body, "command").unwrap_or("?"),
+                 field(body, "sectors").unwrap_or("?"));
+        if sha256_hex(body.as_bytes()) != hash {
+            println!("  entry {} has been modified", n + 1);
+            ok = false;
+        }
+        if field(body, "prev") != Some(prev.as_str()) {
+            println!("  entry {} does not follow the previous entry", n + 1);
+            ok = false;
+        }
+        prev = hash.to_string();
+        last_after = field(body, "after");
+    }
+    if let Some(after) = last_after {
+        if after != sha256_hex(current) {
+            println!("image has changed since the last logged modification");
+            ok = false;
+        }
+    }
+    Ok(ok)
+}
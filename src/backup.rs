@@ -0,0 +1,235 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use byteorder::{LittleEndian, ByteOrder};
+use chrono::{Local, NaiveDateTime};
+use sha2::{Digest, Sha256};
+
+/// Backups track changes in 512-byte sectors regardless of the BPB, so they
+/// also work on images whose boot sector is damaged.
+pub const SECTOR_SIZE: usize = 512;
+const MANIFEST: &str = "manifest";
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+/// One line of the backup manifest.
+///
+/// A full backup stores the image as-is. An incremental one stores records of
+/// a little-endian `u32` sector number followed by the sector's new contents,
+/// relative to the state reconstructed from all earlier backups.
+pub struct Backup {
+    pub seq: u32,
+    pub time: NaiveDateTime,
+    pub full: bool,
+    pub length: u64,
+    pub image_hash: String,
+    pub data_hash: String,
+    pub file: String,
+    pub changed_sectors: usize,
+}
+impl Backup {
+    fn parse(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 8 {
+            return None;
+        }
+        Some(Backup {
+            seq: fields[0].parse().ok()?,
+            time: NaiveDateTime::parse_from_str(fields[1], TIMESTAMP_FORMAT).ok()?,
+            full: match fields[2] {
+                "full" => true,
+                "incr" => false,
+                _ => return None,
+            },
+            length: fields[3].parse().ok()?,
+            changed_sectors: fields[4].parse().ok()?,
+            image_hash: fields[5].to_string(),
+            data_hash: fields[6].to_string(),
+            file: fields[7].to_string(),
+        })
+    }
+
+    fn to_line(&self) -> String {
+        format!("{} {} {} {} {} {} {} {}",
+                self.seq,
+                self.time.format(TIMESTAMP_FORMAT),
+                if self.full { "full" } else { "incr" },
+                self.length,
+                self.changed_sectors,
+                self.image_hash,
+                self.data_hash,
+                self.file)
+    }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Hashes whatever is written to it, so a file can be hashed as it is copied
+/// out instead of being held whole.
+#[derive(Default)]
+pub struct Sha256Writer(Sha256);
+impl Sha256Writer {
+    pub fn new() -> Self {
+        Sha256Writer::default()
+    }
+
+    /// The hash, as `sha256_hex` gives it.
+    pub fn finish(self) -> String {
+        self.0.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+impl Write for Sha256Writer {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.0.update(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The hash of everything `input` reads from its current position on, read
+/// 64K at a time, which is within any memory limit worth setting.
+pub fn sha256_reader<R: Read>(input: &mut R) -> io::Result<String> {
+    let mut hasher = Sha256Writer::new();
+    let mut buf = vec![0; 64 << 10];
+    loop {
+        match input.read(&mut buf) {
+            Ok(0) => return Ok(hasher.finish()),
+            Ok(n) => hasher.write_all(&buf[..n])?,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads the manifest in `dir`, oldest backup first.
+pub fn read_manifest(dir: &Path) -> io::Result<Vec<Backup>> {
+    let mut text = String::new();
+    match File::open(dir.join(MANIFEST)) {
+        Ok(mut file) => file.read_to_string(&mut text)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| Backup::parse(line).ok_or_else(|| invalid(format!("bad manifest line: {}", line))))
+        .collect()
+}
+
+fn read_data(dir: &Path, backup: &Backup) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    File::open(dir.join(&backup.file))?.read_to_end(&mut data)?;
+    if sha256_hex(&data) != backup.data_hash {
+        return Err(invalid(format!("{} does not match its recorded hash", backup.file)));
+    }
+    Ok(data)
+}
+
+/// Rebuilds the image as it was when `backups[last]` was taken.
+fn reconstruct(dir: &Path, backups: &[Backup], last: usize) -> io::Result<Vec<u8>> {
+    let base = backups[..last + 1]
+        .iter()
+        .rposition(|b| b.full)
+        .ok_or_else(|| invalid("no full backup to start from".to_string()))?;
+    let mut image = read_data(dir, &backups[base])?;
+    for backup in &backups[base + 1..last + 1] {
+        let data = read_data(dir, backup)?;
+        image.resize(backup.length as usize, 0);
+        for record in data.chunks(4 + SECTOR_SIZE) {
+            if record.len() < 4 {
+                return Err(invalid(format!("{} is truncated", backup.file)));
+            }
+            let start = LittleEndian::read_u32(record) as usize * SECTOR_SIZE;
+            let sector = &record[4..];
+            let end = image.len().min(start + sector.len());
+            if start >= end {
+                return Err(invalid(format!("{} writes past the end of the image", backup.file)));
+            }
+            image[start..end].copy_from_slice(&sector[..end - start]);
+        }
+    }
+    if sha256_hex(&image) != backups[last].image_hash {
+        return Err(invalid(format!("reconstructed image for backup {} fails its hash check",
+                                   backups[last].seq)));
+    }
+    Ok(image)
+}
+
+/// Backs up `image` into `dir`. With `incremental`, only the sectors that
+/// changed since the previous backup are stored, if there is one.
+pub fn backup(image: &[u8], dir: &Path, incremental: bool) -> io::Result<Backup> {
+    backup_with_progress(image, dir, incremental, |_, _| ())
+}
+
+/// Backs up as `backup` does, calling `progress` with the sectors compared or
+/// stored so far and the sectors in all.
+pub fn backup_with_progress<F>(image: &[u8],
+                               dir: &Path,
+                               incremental: bool,
+                               mut progress: F)
+                               -> io::Result<Backup>
+    where F: FnMut(u64, u64)
+{
+    fs::create_dir_all(dir)?;
+    let sectors = image.len().div_ceil(SECTOR_SIZE) as u64;
+    progress(0, sectors);
+    let backups = read_manifest(dir)?;
+    let seq = backups.last().map_or(1, |b| b.seq + 1);
+    let file = format!("{:06}.f12b", seq);
+
+    let (full, data, changed_sectors) = if incremental && !backups.is_empty() {
+        let previous = reconstruct(dir, &backups, backups.len() - 1)?;
+        let mut data = Vec::new();
+        let mut changed = 0;
+        for (i, sector) in image.chunks(SECTOR_SIZE).enumerate() {
+            let start = i * SECTOR_SIZE;
+            if previous.get(start..start + sector.len()) != Some(sector) {
+                let mut index = [0; 4];
+                LittleEndian::write_u32(&mut index, i as u32);
+                data.extend_from_slice(&index);
+                data.extend_from_slice(sector);
+                changed += 1;
+            }
+            progress(i as u64 + 1, sectors);
+        }
+        (false, data, changed)
+    } else {
+        (true, image.to_vec(), image.len().div_ceil(SECTOR_SIZE))
+    };
+
+    File::create(dir.join(&file))?.write_all(&data)?;
+    if full {
+        progress(sectors, sectors);
+    }
+    let backup = Backup {
+        seq,
+        time: Local::now().naive_local(),
+        full,
+        length: image.len() as u64,
+        image_hash: sha256_hex(image),
+        data_hash: sha256_hex(&data),
+        file,
+        changed_sectors,
+    };
+    let mut manifest = OpenOptions::new().create(true).append(true).open(dir.join(MANIFEST))?;
+    writeln!(manifest, "{}", backup.to_line())?;
+    Ok(backup)
+}
+
+/// Reconstructs the image from the latest backup taken at or before `at`, or
+/// the latest backup overall. Returns the backup used and the image contents.
+pub fn restore(dir: &Path, at: Option<NaiveDateTime>) -> io::Result<(Backup, Vec<u8>)> {
+    let mut backups = read_manifest(dir)?;
+    let last = backups.iter()
+        .rposition(|b| at.is_none_or(|at| b.time <= at))
+        .ok_or_else(|| invalid("no backup at or before that time".to_string()))?;
+    let image = reconstruct(dir, &backups, last)?;
+    Ok((backups.swap_remove(last), image))
+}
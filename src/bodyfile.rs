@@ -0,0 +1,93 @@
+use std::io::{self, Read, Seek, Write};
+use chrono::{Local, NaiveDateTime, TimeZone};
+use {dos_date, dos_datetime, for_each_entry, DirEntryAttributes, DiskInfo, FatTable, DIR_ENTRY_SIZE};
+
+/// Seconds since the Unix epoch for a DOS timestamp, which is in local time.
+/// Missing or invalid timestamps are 0, as mactime expects.
+fn epoch(datetime: Option<NaiveDateTime>) -> i64 {
+    datetime.and_then(|datetime| Local.from_local_datetime(&datetime).earliest())
+        .map_or(0, |datetime| datetime.timestamp())
+}
+
+/// Writes a Sleuth Kit body file, the input `mactime` builds timelines from:
+///
+/// `MD5|name|inode|mode_as_string|UID|GID|size|atime|mtime|ctime|crtime`
+///
+/// one line per entry, deleted ones included and marked as `fls -m` marks
+/// them. Names are prefixed with `mount`. The inode is the slot number of the
+/// entry in the image, its byte offset over 32, which is unique per entry.
+/// FAT has no change time and only a date for the last access, so ctime is 0
+/// and atime is midnight.
+pub fn write<R: Read + Seek, F: FatTable + ?Sized, W: Write>(info: &DiskInfo,
+                                                             disk_file: &mut R,
+                                                             fat: &F,
+                                                             mount: &str,
+                                                             out: &mut W)
+                                                             -> io::Result<()> {
+    for_each_entry(info, disk_file, fat, true, |_, found| {
+        let entry = &found.entry;
+        let is_dir = (entry.attributes & DirEntryAttributes::SubDir as u8) != 0;
+        let read_only = (entry.attributes & DirEntryAttributes::ReadOnly as u8) != 0;
+        let mode = format!("{}/{}{}",
+                           if is_dir { 'd' } else { 'r' },
+                           if is_dir { 'd' } else { 'r' },
+                           if read_only { "r-xr-xr-x" } else { "rwxrwxrwx" });
+        // The path separator leads, so trim a trailing one from the mount point.
+        let name = format!("{}{}{}",
+                           mount.trim_end_matches('/'),
+                           found.path,
+                           if found.deleted { " (deleted)" } else { "" });
+        writeln!(out,
+                 "0|{}|{}|{}|0|0|{}|{}|{}|0|{}",
+                 name.replace('|', "\\|"),
+                 found.offset / DIR_ENTRY_SIZE as u64,
+                 mode,
+                 entry.file_size,
+                 epoch(dos_date(entry.last_access_date).and_then(|date| date.and_hms_opt(0, 0, 0))),
+                 epoch(dos_datetime(entry.last_write_date, entry.last_write_time)),
+                 epoch(dos_datetime(entry.create_date, entry.create_time)))?;
+        Ok(())
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use tests::{blank, host_file};
+    use {find_slot, mkdir, put, read_fat, rm, set_attributes, touch, Times};
+
+    #[test]
+    fn lines_have_the_slot_mode_and_times_of_each_entry() {
+        let (info, mut image) = blank();
+        mkdir(&info, &mut image, "/DIR").unwrap();
+        put(&info, &mut image, &host_file("bodyfile", "KEEP.TXT", b"kept"), "/DIR").unwrap();
+        put(&info, &mut image, &host_file("bodyfile", "GONE.TXT", b"gone"), "/").unwrap();
+        rm(&info, &mut image, "/GONE.TXT").unwrap();
+        set_attributes(&info, &mut image, "/DIR/KEEP.TXT", DirEntryAttributes::ReadOnly as u8, 0).unwrap();
+        let day = NaiveDate::from_ymd_opt(1995, 8, 24).unwrap();
+        let times = Times {
+            created: day.and_hms_opt(9, 30, 0),
+            modified: day.and_hms_opt(17, 45, 10),
+            accessed: day.and_hms_opt(12, 0, 0),
+        };
+        touch(&info, &mut image, "/DIR/KEEP.TXT", &times).unwrap();
+
+        let fat = read_fat(&info, &mut image).unwrap();
+        let mut out = Vec::new();
+        write(&info, &mut image, &fat[..], "/mnt/floppy/", &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let (directory, slot, _) = find_slot(&info, &mut image, "/DIR/KEEP.TXT").unwrap().unwrap();
+        let inode = directory.slot_offset(slot).unwrap() / DIR_ENTRY_SIZE as u64;
+        let line = format!("0|/mnt/floppy/DIR/KEEP.TXT|{}|r/rr-xr-xr-x|0|0|4|{}|{}|0|{}",
+                           inode,
+                           epoch(day.and_hms_opt(0, 0, 0)),
+                           epoch(times.modified),
+                           epoch(times.created));
+        assert!(text.lines().any(|l| l == line), "{}", text);
+        assert!(text.lines().any(|l| l.starts_with("0|/mnt/floppy/DIR|") && l.contains("|d/drwxrwxrwx|")));
+        assert!(text.lines().any(|l| l.starts_with("0|/mnt/floppy/_ONE.TXT (deleted)|")), "{}", text);
+        assert_eq!(epoch(None), 0);
+    }
+}
@@ -0,0 +1,211 @@
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime};
+use geometry;
+use serde_json::Value;
+use std::fs;
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+use {cluster_chain, find_path, format, mkdir, new_entry, read_disk_info, read_fat, set_attributes, store_file,
+     touch, DirEntryAttributes, DiskInfo, Error, Result, Times, CHANGEABLE_ATTRIBUTES};
+
+/// What `build` made.
+#[derive(Debug, PartialEq)]
+pub struct Build {
+    pub files: usize,
+    pub directories: usize,
+    pub bytes: u64,
+}
+
+fn invalid(why: String) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidData, why))
+}
+
+/// A timestamp as the manifest gives it: `2016-03-14 13:37:42`, with a `T`
+/// for the space if need be, or just the date.
+fn parse_time(text: &str) -> Option<NaiveDateTime> {
+    ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M"].iter()
+        .find_map(|form| NaiveDateTime::parse_from_str(text, form).ok())
+        .or_else(|| NaiveDate::parse_from_str(text, "%Y-%m-%d").ok()?.and_hms_opt(0, 0, 0))
+}
+
+/// Attributes as the manifest gives them: the letters of `RHSA`, in any
+/// order and either case.
+fn parse_attributes(text: &str) -> Option<u8> {
+    text.chars().try_fold(0, |bits, letter| {
+        let bit = match letter.to_ascii_uppercase() {
+            'R' => DirEntryAttributes::ReadOnly,
+            'H' => DirEntryAttributes::Hidden,
+            'S' => DirEntryAttributes::System,
+            'A' => DirEntryAttributes::Archive,
+            _ => return None,
+        };
+        Some(bits | bit as u8)
+    })
+}
+
+/// Whether the chain starting at `first` is one run of clusters.
+fn contiguous(info: &DiskInfo, fat: &[u8], first: u32) -> bool {
+    cluster_chain(info, fat, first).windows(2).all(|pair| pair[1] == pair[0] + 1)
+}
+
+/// Makes a new image in `out` from `manifest`, a JSON object such as
+///
+/// ```json
+/// {
+///     "geometry": "1.44M",
+///     "entries": [
+///         {"path": "/IO.SYS", "source": "dos/IO.SYS", "attributes": "RHS", "contiguous": true},
+///         {"path": "/DOS", "directory": true, "modified": "1994-05-31 06:22:00"},
+///         {"path": "/AUTOEXEC.BAT", "text": "@ECHO OFF\r\n", "modified": "1994-05-31"}
+///     ]
+/// }
+/// ```
+///
+/// The geometry is one of `geometry::STANDARD` by name. Each file's contents
+/// come from `source`, a host file relative to `base`, or from `text`, and
+/// it is empty with neither. Directories a path goes through are made if
+/// the manifest hasn't made them already.
+///
+/// `attributes` are the entry's exactly, where a file otherwise gets the
+/// archive bit; `created`, `modified` and `accessed` stamp it, and the host
+/// file's time is the write time otherwise. Entries are made in the order
+/// listed, which on the new volume is also the order of the entries in each
+/// directory and of the clusters, and `contiguous` fails the build if the
+/// file's clusters end up split anyway.
+pub fn build<W: Read + Write + Seek>(manifest: &Value, base: &Path, out: &mut W) -> Result<Build> {
+    let name = manifest.get("geometry").and_then(Value::as_str).unwrap_or("1.44M");
+    let geometry = geometry::by_name(name).ok_or_else(|| invalid(format!("unknown geometry: {}", name)))?;
+    let entries = match manifest.get("entries") {
+        Some(Value::Array(entries)) => &entries[..],
+        None => &[],
+        Some(_) => return Err(invalid("\"entries\" is not a list".to_string())),
+    };
+    format(out, geometry)?;
+    let info = read_disk_info(out)?;
+    let mut build = Build { files: 0, directories: 0, bytes: 0 };
+    for (i, entry) in entries.iter().enumerate() {
+        let field = |name: &str| entry.get(name).filter(|value| !value.is_null());
+        let text = |name: &str| -> Result<Option<&str>> {
+            match field(name) {
+                Some(value) => value.as_str()
+                    .map(Some)
+                    .ok_or_else(|| invalid(format!("entries[{}]: \"{}\" is not a string", i, name))),
+                None => Ok(None),
+            }
+        };
+        let path = text("path")?.ok_or_else(|| invalid(format!("entries[{}]: no \"path\"", i)))?;
+        let path = format!("/{}", path.trim_matches('/'));
+        let what = |why: &str| invalid(format!("{}: {}", path, why));
+        let mut times = Times::default();
+        for (name, time) in [("created", &mut times.created),
+                             ("modified", &mut times.modified),
+                             ("accessed", &mut times.accessed)] {
+            if let Some(value) = text(name)? {
+                let why = || what(&format!("invalid {} time: {}", name, value));
+                *time = Some(parse_time(value).ok_or_else(why)?);
+            }
+        }
+
+        // The directories on the way, then the entry itself.
+        let parts: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+        if parts.is_empty() {
+            return Err(what("the root is there already"));
+        }
+        for depth in 1..parts.len() {
+            let parent = format!("/{}", parts[..depth].join("/"));
+            if find_path(&info, out, &parent)?.is_none() {
+                mkdir(&info, out, &parent)?;
+                build.directories += 1;
+            }
+        }
+        let directory = field("directory").and_then(Value::as_bool).unwrap_or(false);
+        if directory {
+            mkdir(&info, out, &path)?;
+            build.directories += 1;
+        } else {
+            let data = match (text("source")?, text("text")?) {
+                (Some(_), Some(_)) => return Err(what("give \"source\" or \"text\", not both")),
+                (Some(source), None) => {
+                    let source = base.join(source);
+                    if times.modified.is_none() {
+                        let modified: DateTime<Local> = fs::metadata(&source)?.modified()?.into();
+                        times.modified = Some(modified.naive_local());
+                    }
+                    fs::read(&source).map_err(|e| what(&format!("{}: {}", source.display(), e)))?
+                }
+                (None, Some(text)) => text.as_bytes().to_vec(),
+                (None, None) => Vec::new(),
+            };
+            let size = data.len() as u32;
+            let now = Local::now().naive_local();
+            let archive = DirEntryAttributes::Archive as u8;
+            store_file(&info, out, &path, "", archive, &data, |short_name, attributes, first| {
+                new_entry(short_name, attributes, first, size, now)
+            })?;
+            build.files += 1;
+            build.bytes += data.len() as u64;
+        }
+        if times != Times::default() {
+            touch(&info, out, &path, &times)?;
+        }
+        if let Some(value) = text("attributes")? {
+            let bits = parse_attributes(value)
+                .ok_or_else(|| what(&format!("invalid attributes: {}", value)))?;
+            set_attributes(&info, out, &path, bits, CHANGEABLE_ATTRIBUTES & !bits)?;
+        }
+        if field("contiguous").and_then(Value::as_bool).unwrap_or(false) {
+            let found = find_path(&info, out, &path)?.ok_or_else(|| Error::NotFound(path.clone()))?;
+            if !contiguous(&info, &read_fat(&info, out)?, found.flc) {
+                return Err(what("its clusters aren't contiguous"));
+            }
+        }
+    }
+    Ok(build)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tests::{host_file, names};
+    use {attribute_string, dos_datetime};
+
+    #[test]
+    fn builds_images_from_manifests() {
+        let source = host_file("build", "IO.SYS", &[0x90; 3000]);
+        let manifest = json!({
+            "geometry": "1.44M",
+            "entries": [
+                {"path": "/IO.SYS", "source": "IO.SYS", "attributes": "rhs", "contiguous": true},
+                {"path": "/DOS/UTIL", "directory": true, "modified": "1994-05-31 06:22:00"},
+                {"path": "/dos/Readme.txt", "text": "hello", "modified": "1994-05-31"},
+            ],
+        });
+        let mut image = Cursor::new(Vec::new());
+        let build = build(&manifest, source.parent().unwrap(), &mut image).unwrap();
+        assert_eq!(build, Build { files: 2, directories: 2, bytes: 3005 });
+
+        let info = read_disk_info(&mut image).unwrap();
+        assert_eq!(names(&mut image, "/").into_iter().map(|(name, _)| name).collect::<Vec<_>>(),
+                   vec!["IO.SYS", "DOS"]);
+        let io = find_path(&info, &mut image, "/IO.SYS").unwrap().unwrap();
+        assert_eq!((io.flc, attribute_string(io.attributes).as_str()), (2, "RHS---"));
+        let readme = find_path(&info, &mut image, "/DOS/README.TXT").unwrap().unwrap();
+        assert_eq!(readme.long_name.as_deref(), Some("Readme.txt"));
+        assert_eq!(dos_datetime(readme.last_write_date, readme.last_write_time), parse_time("1994-05-31"));
+        let util = find_path(&info, &mut image, "/DOS/UTIL").unwrap().unwrap();
+        assert_eq!(dos_datetime(util.last_write_date, util.last_write_time),
+                   parse_time("1994-05-31T06:22:00"));
+
+        let bad = json!({"entries": [{"path": "/A.TXT", "attributes": "RX"}]});
+        assert!(build_fails(&bad, "/A.TXT: invalid attributes: RX"));
+        let bad = json!({"entries": [{"path": "/A.TXT", "modified": "yesterday"}]});
+        assert!(build_fails(&bad, "/A.TXT: invalid modified time: yesterday"));
+    }
+
+    fn build_fails(manifest: &Value, why: &str) -> bool {
+        match build(manifest, Path::new("."), &mut Cursor::new(Vec::new())) {
+            Err(e) => e.to_string() == why,
+            Ok(_) => false,
+        }
+    }
+}
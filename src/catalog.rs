@@ -0,0 +1,298 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use serde_json::{self, Value};
+use backup::sha256_reader;
+use grep::{self, wildcard_match, GrepOptions, Match};
+use pool;
+use scrub::find_files;
+use {bpb_looks_valid, dos_datetime, for_each_entry, load_fat, lock, read_disk_info, DirEntryAttributes,
+     DiskInfo, Error};
+
+fn invalid(path: &Path, why: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), why))
+}
+
+/// What a catalog run did: the images recorded, and a line for each file that
+/// looked like an image and couldn't be read.
+pub struct Report {
+    pub cataloged: usize,
+    pub files: usize,
+    pub problems: Vec<String>,
+}
+
+/// An entry the catalog holds that a search found.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hit {
+    pub image: String,
+    pub path: String,
+    pub long_name: Option<String>,
+    pub size: u64,
+    pub modified: Option<String>,
+    pub directory: bool,
+}
+
+/// Opens the image at `path` with its boot sector read, or gives `None` if
+/// it doesn't start with a plausible BPB and so isn't an image.
+fn open_volume(path: &Path) -> io::Result<Option<(File, DiskInfo)>> {
+    let mut disk_file = File::open(path)?;
+    if disk_file.metadata()?.len() < 512 {
+        return Ok(None);
+    }
+    match read_disk_info(&mut disk_file) {
+        Ok(ref info) if !bpb_looks_valid(info) => Ok(None),
+        Ok(info) => Ok(Some((disk_file, info))),
+        Err(Error::InvalidBootSector(_)) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The catalog's record of the image at `path`: its hash and every entry.
+fn record(path: &Path) -> io::Result<Option<Value>> {
+    let (mut disk_file, info) = match open_volume(path)? {
+        Some(opened) => opened,
+        None => return Ok(None),
+    };
+    let _lock = lock::shared(&disk_file, &path.to_string_lossy())?;
+    let sha256 = sha256_reader(&mut disk_file)?;
+    let fat = load_fat(&info, disk_file.try_clone()?)?;
+    let mut entries = Vec::new();
+    for_each_entry(&info, &mut disk_file, &*fat, false, |_, found| {
+        let entry = &found.entry;
+        let modified = dos_datetime(entry.last_write_date, entry.last_write_time)
+            .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string());
+        entries.push(json!({
+            "path": found.path,
+            "long_name": entry.long_name,
+            "size": entry.file_size,
+            "modified": modified,
+            "directory": entry.attributes & DirEntryAttributes::SubDir as u8 != 0,
+        }));
+        Ok(())
+    })?;
+    Ok(Some(json!({"sha256": sha256, "entries": entries})))
+}
+
+/// How `catalog_with` goes about it.
+pub struct Options {
+    /// Carry on from the partial catalog an interrupted run left, rather
+    /// than starting again. The images it has already are left as it has
+    /// them, not read again.
+    pub resume: bool,
+    /// How many images are read between checkpoints, where the catalog so
+    /// far is written as the partial catalog.
+    pub checkpoint_every: usize,
+    /// How many images are read at once, each on a thread of its own. The
+    /// catalog comes out the same however many there are.
+    pub jobs: usize,
+}
+impl Default for Options {
+    fn default() -> Self {
+        Options { resume: false, checkpoint_every: 100, jobs: pool::default_jobs() }
+    }
+}
+
+/// Where the catalog at `catalog_path` is written until it is finished.
+fn partial_path(catalog_path: &Path) -> PathBuf {
+    catalog_path.with_extension("partial")
+}
+
+fn save(catalog: &Value, path: &Path) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    serde_json::to_writer(&mut file, catalog)?;
+    Ok(())
+}
+
+/// Catalogs every image under `dir` into a new catalog at `catalog_path`.
+/// Files that aren't images are left out, and so is the catalog itself.
+///
+/// A catalog records what is in every image under a directory, so a file can
+/// be looked for across hundreds of images without opening each: a JSON
+/// object giving the directory as `root` and, under `images`, each image by
+/// its path from there with its hash and its entries.
+pub fn catalog(dir: &Path, catalog_path: &Path) -> io::Result<Report> {
+    catalog_with(dir, catalog_path, &Options::default())
+}
+
+/// `catalog`, checkpointing as `options` says. The catalog is only written
+/// at `catalog_path` once every image is in it.
+pub fn catalog_with(dir: &Path, catalog_path: &Path, options: &Options) -> io::Result<Report> {
+    let dir = fs::canonicalize(dir)?;
+    let root = dir.to_string_lossy().into_owned();
+    let partial = partial_path(catalog_path);
+    let mut catalog = match load(&partial) {
+        Ok(catalog) if options.resume && catalog["root"] != root.as_str() => {
+            return Err(invalid(&partial, &format!("a catalog of {}, not {}", catalog["root"], root)));
+        }
+        Ok(catalog) if options.resume => catalog,
+        Err(e) if options.resume && e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => json!({"root": root, "images": {}}),
+    };
+    let mut report = Report { cataloged: 0, files: 0, problems: Vec::new() };
+    let mut paths = Vec::new();
+    find_files(&dir, &fs::canonicalize(catalog_path).unwrap_or_default(), &mut paths)?;
+    let name = |path: &Path| path.strip_prefix(&dir).unwrap().to_string_lossy().replace('\\', "/");
+    paths.retain(|path| catalog["images"].get(name(path)).is_none());
+    let mut since_checkpoint = 0;
+    pool::map_ordered(&paths, options.jobs, |path| record(path), |path, record| -> io::Result<()> {
+        match record {
+            Ok(Some(record)) => {
+                catalog["images"][name(path)] = record;
+            }
+            Ok(None) => (),
+            Err(e) => report.problems.push(format!("{}: {}", name(path), e)),
+        }
+        since_checkpoint += 1;
+        if since_checkpoint == options.checkpoint_every {
+            save(&catalog, &partial)?;
+            since_checkpoint = 0;
+        }
+        Ok(())
+    })?;
+    for record in catalog["images"].as_object().into_iter().flat_map(|images| images.values()) {
+        report.cataloged += 1;
+        report.files += record["entries"].as_array().map_or(0, |entries| entries.len());
+    }
+    save(&catalog, &partial)?;
+    fs::rename(&partial, catalog_path)?;
+    Ok(report)
+}
+
+/// Reads the catalog at `path`, refusing anything not of its shape.
+pub fn load(path: &Path) -> io::Result<Value> {
+    let mut text = String::new();
+    File::open(path)?.read_to_string(&mut text)?;
+    let catalog: Value = serde_json::from_str(&text).map_err(|e| invalid(path, &e.to_string()))?;
+    if !catalog["root"].is_string() || !catalog["images"].is_object() {
+        return Err(invalid(path, "not a catalog"));
+    }
+    Ok(catalog)
+}
+
+/// The entries in the catalog whose short or long name matches the DOS
+/// wildcard `pattern`, as `CONFIG.SYS` or `*.BAT`, image by image.
+pub fn search(catalog: &Value, pattern: &str) -> Vec<Hit> {
+    let mut hits = Vec::new();
+    for (image, record) in catalog["images"].as_object().into_iter().flatten() {
+        for entry in record["entries"].as_array().into_iter().flatten() {
+            let path = entry["path"].as_str().unwrap_or("");
+            let long_name = entry["long_name"].as_str();
+            let name = path.rsplit('/').next().unwrap_or(path);
+            let matches = wildcard_match(pattern, name) ||
+                          long_name.is_some_and(|long| wildcard_match(pattern, long));
+            if !matches {
+                continue;
+            }
+            hits.push(Hit {
+                image: image.clone(),
+                path: path.to_string(),
+                long_name: long_name.map(String::from),
+                size: entry["size"].as_u64().unwrap_or(0),
+                modified: entry["modified"].as_str().map(String::from),
+                directory: entry["directory"].as_bool().unwrap_or(false),
+            });
+        }
+    }
+    hits
+}
+
+/// Searches the contents of the cataloged images for `text` as `grep::grep`
+/// does, reading them as code page 437, and passes each line holding it to
+/// `found` with the image's name. With `include`, only the images the
+/// catalog has a file matching it in are opened, and only those files are
+/// searched. Images that can't be read any more are reported to `problem`.
+/// Returns how many files were searched.
+pub fn grep_images(catalog: &Value,
+                   text: &str,
+                   include: Option<&str>,
+                   ignore_case: bool,
+                   found: &mut dyn FnMut(&str, Match),
+                   problem: &mut dyn FnMut(String))
+                   -> io::Result<usize> {
+    let root = PathBuf::from(catalog["root"].as_str().unwrap_or("."));
+    // Hits come image by image, so each image is named once after `dedup`.
+    let mut names: Vec<String> = match include {
+        Some(pattern) => search(catalog, pattern).into_iter().map(|hit| hit.image).collect(),
+        None => catalog["images"].as_object().into_iter().flat_map(|images| images.keys()).cloned().collect(),
+    };
+    names.dedup();
+    let options = GrepOptions { ignore_case, include: include.into_iter().map(String::from).collect() };
+    let mut searched = 0;
+    for name in &names {
+        let path = root.join(name);
+        let result = open_volume(&path).and_then(|opened| {
+            let (mut disk_file, info) = opened.ok_or_else(|| invalid(&path, "no longer an image"))?;
+            let fat = load_fat(&info, disk_file.try_clone()?)?;
+            Ok(grep::grep(&info, &mut disk_file, &*fat, text, &options, &mut |line| found(name, line))?)
+        });
+        match result {
+            Ok(files) => searched += files,
+            Err(e) => problem(format!("{}: {}", name, e)),
+        }
+    }
+    Ok(searched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Cursor;
+    use tests::host_file;
+    use {format, geometry, put};
+
+    #[test]
+    fn catalogs_are_searched_by_name_and_contents() {
+        let dir = env::temp_dir().join(format!("fat12-catalog-{}", std::process::id()));
+        fs::create_dir_all(dir.join("set")).unwrap();
+        for (image, name, text) in [("set/a.img", "CONFIG.SYS", "DEVICE=HIMEM.SYS\r\n"),
+                                    ("b.img", "Autoexec.bat", "@ECHO OFF\r\nPATH C:\\DOS\r\n")] {
+            let mut disk = Cursor::new(Vec::new());
+            format(&mut disk, geometry::by_name("1.44M").unwrap()).unwrap();
+            let info = read_disk_info(&mut disk).unwrap();
+            put(&info, &mut disk, &host_file("catalog", name, text.as_bytes()), "/").unwrap();
+            fs::write(dir.join(image), disk.into_inner()).unwrap();
+        }
+        fs::write(dir.join("notes.txt"), "not an image").unwrap();
+
+        let catalog_path = dir.join("catalog.json");
+        let report = catalog(&dir, &catalog_path).unwrap();
+        assert_eq!((report.cataloged, report.files, report.problems.len()), (2, 2, 0));
+        let catalog = load(&catalog_path).unwrap();
+        let hits = search(&catalog, "config.sys");
+        assert_eq!(hits.len(), 1);
+        assert_eq!((hits[0].image.as_str(), hits[0].path.as_str(), hits[0].size),
+                   ("set/a.img", "/CONFIG.SYS", 18));
+        assert_eq!(search(&catalog, "*.BAT")[0].long_name.as_deref(), Some("Autoexec.bat"));
+        assert!(search(&catalog, "*.EXE").is_empty());
+
+        let mut lines = Vec::new();
+        let searched = grep_images(&catalog,
+                                   "himem",
+                                   None,
+                                   true,
+                                   &mut |image, found| lines.push(format!("{}:{}", image, found.text)),
+                                   &mut |problem| panic!("{}", problem))
+            .unwrap();
+        assert_eq!(searched, 2);
+        assert_eq!(lines, vec!["set/a.img:DEVICE=HIMEM.SYS"]);
+        // Only b.img has a batch file, and it doesn't mention HIMEM.
+        let mut none = |_: &str, _: Match| panic!("found in a batch file");
+        assert_eq!(grep_images(&catalog, "HIMEM", Some("*.BAT"), false, &mut none, &mut |_| ()).unwrap(), 1);
+        assert_eq!(load(&dir.join("notes.txt")).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        // A run carried on from its partial catalog keeps what that has.
+        let mut partial = catalog.clone();
+        partial["images"].as_object_mut().unwrap().remove("set/a.img");
+        partial["images"]["b.img"]["sha256"] = json!("from the first run");
+        save(&partial, &partial_path(&catalog_path)).unwrap();
+        let options = Options { resume: true, ..Options::default() };
+        let report = catalog_with(&dir, &catalog_path, &options).unwrap();
+        assert_eq!((report.cataloged, report.files), (2, 2));
+        let resumed = load(&catalog_path).unwrap();
+        assert_eq!(resumed["images"]["b.img"]["sha256"], "from the first run");
+        assert_eq!(resumed["images"]["set/a.img"], catalog["images"]["set/a.img"]);
+        assert!(!partial_path(&catalog_path).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,326 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{Read, Seek, SeekFrom, Write};
+use byteorder::{ByteOrder, LittleEndian};
+use {bpb_looks_valid, cluster_chain, cluster_limit, cluster_size, fat_copies_match, fat_entry,
+     read_active_fat, set_fat_entry, tree, write_fat, DirEntry, DirEntryAttributes, DiskInfo, Error, FatType,
+     Result, DIR_ENTRY_FILESIZE, DIR_ENTRY_FLC, DIR_ENTRY_FLC_HIGH};
+
+/// Something wrong with a volume. Entries are named by their path, as
+/// `walk_tree` gives it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// FAT copy `n`, counting from 0, differs from the one in use.
+    FatMismatch(u8),
+    /// A cluster in the chains of two entries; `other` is the one found
+    /// first, which keeps it.
+    CrossLink { path: String, cluster: u32, other: String },
+    /// A chain that runs into a free, bad or out-of-range entry, or back into
+    /// itself, after `cluster`.
+    BrokenChain { path: String, cluster: u32 },
+    /// An entry whose first cluster isn't in the data area.
+    BadFirstCluster { path: String, cluster: u32 },
+    /// A file whose chain is longer or shorter than its size needs.
+    ChainLength { path: String, clusters: usize, needed: usize },
+    /// Allocated clusters that no entry's chain reaches, and how many chains
+    /// they make.
+    LostClusters { clusters: usize, chains: usize },
+    /// An entry no DOS would write, such as one with a name it can't type.
+    InvalidEntry { path: String, reason: &'static str },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Problem::FatMismatch(n) => write!(f, "FAT copy {} differs from the one in use", n + 1),
+            Problem::CrossLink { ref path, cluster, ref other } => {
+                write!(f, "{}: cluster {} is also used by {}", path, cluster, other)
+            }
+            Problem::BrokenChain { ref path, cluster } => {
+                write!(f, "{}: chain breaks after cluster {}", path, cluster)
+            }
+            Problem::BadFirstCluster { ref path, cluster } => {
+                write!(f, "{}: first cluster {} is outside the data area", path, cluster)
+            }
+            Problem::ChainLength { ref path, clusters, needed } => {
+                write!(f, "{}: chain has {} clusters, its size needs {}", path, clusters, needed)
+            }
+            Problem::LostClusters { clusters, chains } => {
+                write!(f, "{} allocated clusters in {} chains belong to no file", clusters, chains)
+            }
+            Problem::InvalidEntry { ref path, reason } => write!(f, "{}: {}", path, reason),
+        }
+    }
+}
+
+/// A problem, and whether `Check::repair` fixes it.
+pub struct Finding {
+    pub problem: Problem,
+    pub repairable: bool,
+}
+
+/// What `check` found, along with the repairs worked out for it.
+pub struct Check {
+    pub findings: Vec<Finding>,
+    /// The FAT as it is once repaired.
+    fat: Vec<u8>,
+    /// Entries to rewrite, by the byte offset of their slot: the new first
+    /// cluster and size.
+    entries: Vec<(u64, u32, u32)>,
+}
+
+impl Check {
+    pub fn is_clean(&self) -> bool {
+        self.findings.is_empty()
+    }
+
+    /// Makes the repairs: lost clusters are freed, and chains are cut where
+    /// they break or run into another entry's, or past what the file's size
+    /// needs. Files with too few clusters are shrunk to what they have. The
+    /// repaired FAT is written over every copy, so they agree again. Invalid
+    /// entries are left as they are, for a person to look at.
+    pub fn repair<R: Read + Write + Seek>(&self, info: &DiskInfo, disk_file: &mut R) -> Result<()> {
+        if !self.findings.iter().any(|finding| finding.repairable) {
+            return Ok(());
+        }
+        for &(offset, first_cluster, size) in &self.entries {
+            let mut field = [0u8; 4];
+            if info.fat_type() == FatType::Fat32 {
+                LittleEndian::write_u16(&mut field, (first_cluster >> 16) as u16);
+                disk_file.seek(SeekFrom::Start(offset + DIR_ENTRY_FLC_HIGH as u64))?;
+                disk_file.write_all(&field[..2])?;
+            }
+            LittleEndian::write_u16(&mut field, first_cluster as u16);
+            disk_file.seek(SeekFrom::Start(offset + DIR_ENTRY_FLC as u64))?;
+            disk_file.write_all(&field[..2])?;
+            LittleEndian::write_u32(&mut field, size);
+            disk_file.seek(SeekFrom::Start(offset + DIR_ENTRY_FILESIZE as u64))?;
+            disk_file.write_all(&field)?;
+        }
+        write_fat(info, disk_file, &self.fat)
+    }
+}
+
+/// Characters DOS doesn't allow in a short name, besides control codes.
+const FORBIDDEN_NAME_CHARS: &[u8] = b"\"*+,./:;<=>?[\\]|";
+
+/// Why `entry` looks like nothing DOS would have written, if it does.
+fn entry_problems(entry: &DirEntry) -> Vec<&'static str> {
+    let mut reasons = Vec::new();
+    let name: Vec<u8> = entry.file_name.iter().chain(&entry.file_ext).cloned().collect();
+    // A leading 0x05 stands for 0xE5, which would mark the entry deleted.
+    let bad_char = |(i, &b): (usize, &u8)| {
+        (b < 0x20 && !(i == 0 && b == 0x05)) || b.is_ascii_lowercase() || FORBIDDEN_NAME_CHARS.contains(&b)
+    };
+    if name[0] == b' ' {
+        reasons.push("name starts with a space");
+    } else if name.iter().enumerate().any(bad_char) {
+        reasons.push("name has characters DOS doesn't allow");
+    }
+    if entry.attributes & 0xC0 != 0 {
+        reasons.push("reserved attribute bits are set");
+    }
+    if (entry.attributes & DirEntryAttributes::SubDir as u8) != 0 && entry.file_size != 0 {
+        reasons.push("directory has a file size");
+    }
+    reasons
+}
+
+/// Checks the volume the way CHKDSK does: FAT copies that disagree, chains
+/// that break, are cross-linked or don't match their file's size, clusters
+/// that belong to no file, and entries with impossible names or attributes.
+/// Nothing is written; the repairs are worked out for `Check::repair` to make.
+///
+/// Entries are taken in `walk_tree` order, and where two chains share a
+/// cluster the one found first keeps it, as CHKDSK keeps it for the first.
+pub fn check<R: Read + Seek>(info: &DiskInfo, disk_file: &mut R) -> Result<Check> {
+    if !bpb_looks_valid(info) {
+        return Err(Error::InvalidBootSector("the BPB looks damaged; try `fat12 recover-bpb`".to_string()));
+    }
+    let mut findings = Vec::new();
+    // With mirroring off on FAT32 the other copies are meant to differ.
+    if info.ext_flags & 0x80 == 0 {
+        for n in (0..info.fats).filter(|&n| n != info.active_fat()) {
+            if !fat_copies_match(info, disk_file, info.active_fat(), n).unwrap_or(false) {
+                findings.push(Finding { problem: Problem::FatMismatch(n), repairable: true });
+            }
+        }
+    }
+
+    let mut fat = read_active_fat(info, disk_file)?;
+    let fat_type = info.fat_type();
+    let limit = cluster_limit(info);
+    let cluster_bytes = cluster_size(info);
+    let mut entries = Vec::new();
+    let mut owners: HashMap<u32, String> = HashMap::new();
+    // A FAT32 root directory has clusters of its own.
+    if fat_type == FatType::Fat32 {
+        let root = cluster_chain(info, &fat[..], info.root_cluster);
+        owners.extend(root.into_iter().map(|c| (c, "/".to_string())));
+    }
+    let mut report = |problem: Problem, repairable: bool| findings.push(Finding { problem, repairable });
+
+    for found in tree(info, disk_file, &fat[..], false)? {
+        let entry = &found.entry;
+        let is_dir = (entry.attributes & DirEntryAttributes::SubDir as u8) != 0;
+        for reason in entry_problems(entry) {
+            report(Problem::InvalidEntry { path: found.path.clone(), reason }, false);
+        }
+        if entry.flc == 0 {
+            if is_dir {
+                let reason = "directory has no clusters";
+                report(Problem::InvalidEntry { path: found.path.clone(), reason }, false);
+            } else if entry.file_size != 0 {
+                let needed = (entry.file_size as u64).div_ceil(cluster_bytes) as usize;
+                report(Problem::ChainLength { path: found.path.clone(), clusters: 0, needed }, true);
+                entries.push((found.offset, 0, 0));
+            }
+            continue;
+        }
+        if entry.flc < 2 || entry.flc >= limit {
+            report(Problem::BadFirstCluster { path: found.path.clone(), cluster: entry.flc }, !is_dir);
+            if !is_dir {
+                entries.push((found.offset, 0, 0));
+            }
+            continue;
+        }
+
+        // Walk the chain by hand, to see why it ends.
+        let mut chain: Vec<u32> = Vec::new();
+        let mut seen = HashSet::new();
+        let mut cluster = entry.flc;
+        let cut = loop {
+            if let Some(other) = owners.get(&cluster) {
+                // A directory with no clusters left would read as the root.
+                let repairable = !(is_dir && chain.is_empty());
+                let other = other.clone();
+                report(Problem::CrossLink { path: found.path.clone(), cluster, other }, repairable);
+                break repairable;
+            }
+            chain.push(cluster);
+            seen.insert(cluster);
+            let next = fat_entry(info, &fat[..], cluster).unwrap_or(0);
+            if fat_type.is_end_of_chain(next) {
+                break false;
+            }
+            if next < 2 || next >= limit || seen.contains(&next) {
+                report(Problem::BrokenChain { path: found.path.clone(), cluster }, true);
+                break true;
+            }
+            cluster = next;
+        };
+
+        let mut size = entry.file_size;
+        if !is_dir {
+            let needed = (entry.file_size as u64).div_ceil(cluster_bytes) as usize;
+            if chain.len() != needed {
+                let clusters = chain.len();
+                report(Problem::ChainLength { path: found.path.clone(), clusters, needed }, true);
+            }
+            // The clusters cut off are freed with the lost ones below, unless
+            // an entry found later turns out to own them.
+            if chain.len() > needed {
+                chain.truncate(needed);
+            } else if chain.len() < needed {
+                size = (chain.len() as u64 * cluster_bytes) as u32;
+            }
+        }
+        if cut || chain.len() < seen.len() {
+            if let Some(&last) = chain.last() {
+                set_fat_entry(info, &mut fat, last, fat_type.end_of_chain());
+            }
+        }
+        let first_cluster = chain.first().cloned().unwrap_or(0);
+        if (first_cluster, size) != (entry.flc, entry.file_size) && !(is_dir && chain.is_empty()) {
+            entries.push((found.offset, first_cluster, size));
+        }
+        for &cluster in &chain {
+            owners.insert(cluster, found.path.clone());
+        }
+    }
+
+    let bad = fat_type.bad_cluster();
+    let lost: Vec<u32> = (2..limit)
+        .filter(|c| !owners.contains_key(c))
+        .filter(|&c| fat_entry(info, &fat[..], c).is_some_and(|next| next != 0 && next != bad))
+        .collect();
+    if !lost.is_empty() {
+        // A chain starts at a lost cluster no other lost cluster points to.
+        let pointed_to: HashSet<u32> = lost.iter().filter_map(|&c| fat_entry(info, &fat[..], c)).collect();
+        let chains = lost.iter().filter(|c| !pointed_to.contains(c)).count().max(1);
+        report(Problem::LostClusters { clusters: lost.len(), chains }, true);
+        for &cluster in &lost {
+            set_fat_entry(info, &mut fat, cluster, 0);
+        }
+    }
+    Ok(Check { findings, fat, entries })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::{blank, host_file};
+    use {find_slot, put, read_fat, Fat12Volume};
+
+    #[test]
+    fn finds_and_repairs_damage() {
+        let (info, mut image) = blank();
+        let cluster = cluster_size(&info) as usize;
+        put(&info, &mut image, &host_file("check", "LONG.BIN", &vec![1; 3 * cluster]), "/").unwrap();
+        put(&info, &mut image, &host_file("check", "SHORT.BIN", &vec![2; cluster]), "/").unwrap();
+        assert!(check(&info, &mut image).unwrap().is_clean());
+
+        let (_, _, long) = find_slot(&info, &mut image, "/LONG.BIN").unwrap().unwrap();
+        let (_, _, short) = find_slot(&info, &mut image, "/SHORT.BIN").unwrap().unwrap();
+        let mut fat = read_fat(&info, &mut image).unwrap();
+        let taken = cluster_chain(&info, &fat[..], long.flc)[2];
+        set_fat_entry(&info, &mut fat, short.flc, taken);
+        set_fat_entry(&info, &mut fat, 200, 201);
+        set_fat_entry(&info, &mut fat, 201, FatType::Fat12.end_of_chain());
+        write_fat(&info, &mut image, &fat).unwrap();
+        let second_copy = (info.reserved_sectors as usize + info.fat_sectors() as usize) *
+                          info.bytes_per_sector as usize;
+        image.get_mut()[second_copy + 600] = 0xFF;
+
+        let found = check(&info, &mut image).unwrap();
+        let problems: Vec<Problem> = found.findings.iter().map(|finding| finding.problem.clone()).collect();
+        assert_eq!(problems,
+                   vec![Problem::FatMismatch(1),
+                        Problem::CrossLink {
+                            path: "/SHORT.BIN".to_string(),
+                            cluster: taken,
+                            other: "/LONG.BIN".to_string(),
+                        },
+                        Problem::LostClusters { clusters: 2, chains: 1 }]);
+        assert!(found.findings.iter().all(|finding| finding.repairable));
+
+        found.repair(&info, &mut image).unwrap();
+        assert!(check(&info, &mut image).unwrap().is_clean());
+        let mut volume = Fat12Volume::open(image).unwrap();
+        assert_eq!(volume.read_file("/LONG.BIN").unwrap(), vec![1; 3 * cluster]);
+        assert_eq!(volume.read_file("/SHORT.BIN").unwrap(), vec![2; cluster]);
+    }
+
+    #[test]
+    fn shrinks_files_to_the_clusters_they_have() {
+        let (info, mut image) = blank();
+        let cluster = cluster_size(&info) as usize;
+        put(&info, &mut image, &host_file("check", "CUT.BIN", &vec![3; 2 * cluster]), "/").unwrap();
+        let (_, _, entry) = find_slot(&info, &mut image, "/CUT.BIN").unwrap().unwrap();
+        let mut fat = read_fat(&info, &mut image).unwrap();
+        // The chain now leaves the data area after its first cluster.
+        set_fat_entry(&info, &mut fat, entry.flc, cluster_limit(&info) + 10);
+        write_fat(&info, &mut image, &fat).unwrap();
+
+        let found = check(&info, &mut image).unwrap();
+        let problems: Vec<Problem> = found.findings.iter().map(|finding| finding.problem.clone()).collect();
+        assert_eq!(problems,
+                   vec![Problem::BrokenChain { path: "/CUT.BIN".to_string(), cluster: entry.flc },
+                        Problem::ChainLength { path: "/CUT.BIN".to_string(), clusters: 1, needed: 2 },
+                        Problem::LostClusters { clusters: 1, chains: 1 }]);
+        found.repair(&info, &mut image).unwrap();
+        assert!(check(&info, &mut image).unwrap().is_clean());
+        let mut volume = Fat12Volume::open(image).unwrap();
+        assert_eq!(volume.read_file("/CUT.BIN").unwrap(), vec![3; cluster]);
+    }
+}
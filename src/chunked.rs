@@ -0,0 +1,132 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use backup::sha256_hex;
+
+/// An image can be stored as a text manifest plus content-addressed chunks.
+/// The manifest gives the image length and the SHA-256 of each chunk in order.
+/// Chunks live once under `chunks/` next to the manifest, named by their hash,
+/// so the manifests in one directory share identical chunks, and a chunk that
+/// no longer matches its name has rotted.
+const MAGIC: &str = "fat12-chunks 1";
+/// Eight sectors. Images of the same software tend to differ in a few
+/// sectors, so small aligned chunks are shared between them.
+const CHUNK_SIZE: usize = 4096;
+
+fn chunk_path(manifest: &Path, hash: &str) -> PathBuf {
+    manifest.parent().unwrap_or_else(|| Path::new("")).join("chunks").join(&hash[..2]).join(hash)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Whether `path` is a chunk manifest rather than a raw image.
+pub fn is_manifest(path: &str) -> bool {
+    let mut head = [0; MAGIC.len()];
+    File::open(path).and_then(|mut file| file.read_exact(&mut head)).is_ok() &&
+    &head[..] == MAGIC.as_bytes()
+}
+
+/// Stores `image` at `manifest`, writing the chunks not already stored.
+/// Returns the number of chunks and how many of them were new.
+pub fn write(image: &[u8], manifest: &Path) -> io::Result<(usize, usize)> {
+    let mut text = format!("{}\nlength {}\nchunk-size {}\n", MAGIC, image.len(), CHUNK_SIZE);
+    let mut new = 0;
+    let chunks = image.chunks(CHUNK_SIZE);
+    let count = chunks.len();
+    for chunk in chunks {
+        let hash = sha256_hex(chunk);
+        let path = chunk_path(manifest, &hash);
+        if !path.exists() {
+            fs::create_dir_all(path.parent().unwrap())?;
+            // Write under a temporary name so an interrupted write can't leave
+            // a truncated chunk that looks stored.
+            let partial = path.with_extension("partial");
+            File::create(&partial)?.write_all(chunk)?;
+            fs::rename(&partial, &path)?;
+            new += 1;
+        }
+        text.push_str(&hash);
+        text.push('\n');
+    }
+    let partial = manifest.with_extension("partial");
+    File::create(&partial)?.write_all(text.as_bytes())?;
+    fs::rename(&partial, manifest)?;
+    Ok((count, new))
+}
+
+/// Reassembles the image stored at `manifest`, checking every chunk against
+/// its hash.
+pub fn read(manifest: &Path) -> io::Result<Vec<u8>> {
+    let mut text = String::new();
+    File::open(manifest)?.read_to_string(&mut text)?;
+    let mut lines = text.lines();
+    if lines.next() != Some(MAGIC) {
+        return Err(invalid(format!("{} is not a chunk manifest", manifest.display())));
+    }
+    let mut field = |name: &str| -> io::Result<usize> {
+        lines.next()
+            .and_then(|line| line.strip_prefix(name))
+            .and_then(|value| value.trim().parse().ok())
+            .ok_or_else(|| invalid(format!("{}: missing {}", manifest.display(), name)))
+    };
+    let length = field("length")?;
+    let chunk_size = field("chunk-size")?;
+    let mut image = Vec::with_capacity(length);
+    for hash in lines.filter(|line| !line.trim().is_empty()) {
+        if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid(format!("{}: bad chunk hash {}", manifest.display(), hash)));
+        }
+        let path = chunk_path(manifest, hash);
+        let mut chunk = Vec::new();
+        File::open(&path)
+            .and_then(|mut file| file.read_to_end(&mut chunk))
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        if sha256_hex(&chunk) != hash {
+            return Err(invalid(format!("chunk {} is damaged: its contents no longer match its hash",
+                                       path.display())));
+        }
+        if chunk.len() > chunk_size {
+            return Err(invalid(format!("chunk {} is larger than the chunk size", path.display())));
+        }
+        image.extend_from_slice(&chunk);
+    }
+    if image.len() != length {
+        return Err(invalid(format!("{}: chunks add up to {} bytes, not {}",
+                                   manifest.display(),
+                                   image.len(),
+                                   length)));
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn images_share_chunks_and_rot_is_found() {
+        let dir = env::temp_dir().join(format!("fat12-{}-chunked", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let image: Vec<u8> = (0..CHUNK_SIZE * 3 + 100).map(|n| (n / CHUNK_SIZE) as u8).collect();
+        let first = dir.join("first.f12c");
+        assert_eq!(write(&image, &first).unwrap(), (4, 4));
+        assert!(is_manifest(first.to_str().unwrap()));
+        assert_eq!(read(&first).unwrap(), image);
+
+        // One changed sector is the only new chunk of the second image.
+        let mut changed = image.clone();
+        changed[CHUNK_SIZE + 512] ^= 0xFF;
+        let second = dir.join("second.f12c");
+        assert_eq!(write(&changed, &second).unwrap(), (4, 1));
+        assert_eq!(read(&second).unwrap(), changed);
+
+        let hash = sha256_hex(&changed[CHUNK_SIZE..CHUNK_SIZE * 2]);
+        fs::write(chunk_path(&second, &hash), [0; CHUNK_SIZE]).unwrap();
+        assert_eq!(read(&second).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read(&first).unwrap(), image);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,57 @@
+use std::env;
+use std::io::{self, IsTerminal};
+
+/// When `--color` says to color output.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum When {
+    Never,
+    /// Only when stdout is a terminal, `NO_COLOR` isn't set and `TERM`
+    /// isn't `dumb`.
+    Auto,
+    Always,
+}
+impl When {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "never" => Some(When::Never),
+            "auto" => Some(When::Auto),
+            "always" => Some(When::Always),
+            _ => None,
+        }
+    }
+
+    pub fn enabled(self) -> bool {
+        match self {
+            When::Never => false,
+            When::Always => true,
+            When::Auto => {
+                io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()) &&
+                env::var("TERM").map_or(true, |term| term != "dumb")
+            }
+        }
+    }
+}
+
+/// How an entry is colored in a listing, after `ls`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Style {
+    Plain,
+    Directory,
+    Executable,
+    /// Hidden or system entries, shown dim.
+    Hidden,
+    /// Entries whose clusters don't add up, in red.
+    Damaged,
+}
+
+/// Wraps `text` in the ANSI escapes for `style`.
+pub fn paint(text: &str, style: Style) -> String {
+    let code = match style {
+        Style::Plain => return text.to_string(),
+        Style::Directory => "1;34",
+        Style::Executable => "1;32",
+        Style::Hidden => "2",
+        Style::Damaged => "1;31",
+    };
+    format!("\x1b[{}m{}\x1b[0m", code, text)
+}
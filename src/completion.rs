@@ -0,0 +1,62 @@
+/// Commands offered for completion. The hidden `complete` helper is left out.
+pub const COMMANDS: &[&str] = &[
+    "info", "list", "tree", "cat", "view", "grep", "extract", "put", "cp-image", "mkdir", "browse", "rm",
+    "undo", "stat", "attrib", "touch", "undelete", "export-tracks", "ingest", "locate", "du", "df", "test",
+    "exeinfo", "eas", "mount", "redact", "format", "reformat", "fill", "corrupt", "gen-fixture", "build",
+    "compact-dir", "grow-root", "recluster", "backup", "restore", "pack", "unpack", "log", "recover-bpb",
+    "fits", "rescue", "overlay", "scrub", "catalog", "watch", "search", "dfxml", "bodyfile", "check",
+    "health", "annotate", "completions",
+];
+const SHELLS: &[&str] = &["bash", "zsh", "fish"];
+
+const BASH: &str = r#"_fat12() {
+    local cur="${COMP_WORDS[COMP_CWORD]}"
+    case $COMP_CWORD in
+        1) COMPREPLY=($(compgen -W "@COMMANDS@" -- "$cur")) ;;
+        2) if [ "${COMP_WORDS[1]}" = completions ]; then
+               COMPREPLY=($(compgen -W "@SHELLS@" -- "$cur"))
+           else
+               COMPREPLY=($(compgen -f -- "$cur"))
+           fi ;;
+        *) COMPREPLY=($(fat12 complete "${COMP_WORDS[2]}" "$cur" 2>/dev/null)) ;;
+    esac
+}
+complete -o filenames -F _fat12 fat12
+"#;
+
+const ZSH: &str = r#"_fat12() {
+    case $CURRENT in
+        2) compadd @COMMANDS@ ;;
+        3) if [[ $words[2] == completions ]]; then
+               compadd @SHELLS@
+           else
+               _files
+           fi ;;
+        *) compadd -- ${(f)"$(fat12 complete $words[3] $PREFIX 2>/dev/null)"} ;;
+    esac
+}
+compdef _fat12 fat12
+"#;
+
+const FISH: &str = r#"complete -c fat12 -f
+complete -c fat12 -n '__fish_use_subcommand' -a '@COMMANDS@'
+complete -c fat12 -n '__fish_seen_subcommand_from completions' -a '@SHELLS@'
+complete -c fat12 -n 'not __fish_seen_subcommand_from completions; and test (count (commandline -opc)) -eq 2' -F
+complete -c fat12 -n 'test (count (commandline -opc)) -ge 3' -a '(fat12 complete (commandline -opc)[3] (commandline -ct) 2>/dev/null)'
+"#;
+
+/// Returns the completion script for `shell`, or `None` if it isn't supported.
+///
+/// Path arguments after the image are completed by calling back into
+/// `fat12 complete <image> <prefix>`, so they reflect the image contents.
+pub fn script(shell: &str) -> Option<String> {
+    let template = match shell {
+        "bash" => BASH,
+        "zsh" => ZSH,
+        "fish" => FISH,
+        _ => return None,
+    };
+    Some(template
+        .replace("@COMMANDS@", &COMMANDS.join(" "))
+        .replace("@SHELLS@", &SHELLS.join(" ")))
+}
@@ -0,0 +1,138 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+use byteorder::{ByteOrder, LittleEndian};
+use {cluster_chain, cluster_limit, fat_entry, read_fat, set_fat_entry, tree, write_fat, DirEntryAttributes,
+     DiskInfo, Result, BYTES_PER_SECTOR, FATS, RESERVED_SECTORS, SECTORS_PER_CLUSTER};
+
+/// The damage `corrupt` can do, each the kind a checker or recovery tool has
+/// to cope with.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kind {
+    /// One file's chain runs on into another's.
+    CrossLink,
+    /// A file's chain leads back to one of its own clusters.
+    Loop,
+    /// A BPB field set to something no volume could have.
+    BadBpb,
+    /// Free clusters linked into a chain that belongs to no file.
+    OrphanChain,
+}
+impl Kind {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "crosslink" => Some(Kind::CrossLink),
+            "loop" => Some(Kind::Loop),
+            "bad-bpb" => Some(Kind::BadBpb),
+            "orphan-chain" => Some(Kind::OrphanChain),
+            _ => None,
+        }
+    }
+}
+
+/// SplitMix64: small, and the same on every platform, so a seed always
+/// picks the same victims.
+struct Rng(u64);
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number below `n`, which must not be 0.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// BPB fields `BadBpb` can damage: the name, offset and width of each, and
+/// a value `bpb_looks_valid` rejects.
+const BPB_DAMAGE: &[(&str, usize, usize, u16)] = &[("bytes per sector", BYTES_PER_SECTOR, 2, 0),
+                                                    ("sectors per cluster", SECTORS_PER_CLUSTER, 1, 3),
+                                                    ("reserved sectors", RESERVED_SECTORS, 2, 0),
+                                                    ("FAT count", FATS, 1, 0)];
+
+/// Damages the volume the way `kind` says, picking what to damage with
+/// `seed`, so the same seed does the same damage to the same image. Returns
+/// what was done, or `None` if the volume has nothing to damage that way,
+/// such as fewer than two files with clusters to cross-link. The FAT copies
+/// are kept the same, as a driver writing the damage would keep them; on
+/// FAT32 the backup boot sector is left alone.
+pub fn corrupt<R: Read + Write + Seek>(info: &DiskInfo,
+                                       disk_file: &mut R,
+                                       kind: Kind,
+                                       seed: u64)
+                                       -> Result<Option<String>> {
+    let mut rng = Rng(seed);
+    if kind == Kind::BadBpb {
+        let (name, offset, width, value) = BPB_DAMAGE[rng.below(BPB_DAMAGE.len())];
+        let mut field = [0u8; 2];
+        LittleEndian::write_u16(&mut field, value);
+        disk_file.seek(SeekFrom::Start(offset as u64))?;
+        disk_file.write_all(&field[..width])?;
+        return Ok(Some(format!("{} set to {}", name, value)));
+    }
+
+    let mut fat = read_fat(info, disk_file)?;
+    let files: Vec<(String, Vec<u32>)> = tree(info, disk_file, &fat[..], false)?
+        .into_iter()
+        .filter(|found| (found.entry.attributes & DirEntryAttributes::SubDir as u8) == 0)
+        .map(|found| {
+            let chain = cluster_chain(info, &fat[..], found.entry.flc);
+            (found.path, chain)
+        })
+        .filter(|(_, chain)| !chain.is_empty())
+        .collect();
+    let description = match kind {
+        Kind::CrossLink => {
+            if files.len() < 2 {
+                return Ok(None);
+            }
+            let a = rng.below(files.len());
+            let b = (a + 1 + rng.below(files.len() - 1)) % files.len();
+            let (ref path, ref chain) = files[a];
+            let (ref other, ref other_chain) = files[b];
+            let target = other_chain[rng.below(other_chain.len())];
+            set_fat_entry(info, &mut fat, chain[chain.len() - 1], target);
+            format!("{} now runs on into cluster {} of {}", path, target, other)
+        }
+        Kind::Loop => {
+            if files.is_empty() {
+                return Ok(None);
+            }
+            let (ref path, ref chain) = files[rng.below(files.len())];
+            let from = rng.below(chain.len());
+            let to = rng.below(from + 1);
+            set_fat_entry(info, &mut fat, chain[from], chain[to]);
+            format!("{}: cluster {} now leads back to cluster {}", path, chain[from], chain[to])
+        }
+        Kind::OrphanChain => {
+            let free: Vec<u32> = (2..cluster_limit(info))
+                .filter(|&c| fat_entry(info, &fat[..], c) == Some(0))
+                .collect();
+            if free.is_empty() {
+                return Ok(None);
+            }
+            // Clusters from anywhere on the volume, so the chain is as
+            // scattered as a real lost one can be.
+            let count = 1 + rng.below(free.len().min(8));
+            let mut chain: Vec<u32> = Vec::new();
+            while chain.len() < count {
+                let cluster = free[rng.below(free.len())];
+                if !chain.contains(&cluster) {
+                    chain.push(cluster);
+                }
+            }
+            for pair in chain.windows(2) {
+                set_fat_entry(info, &mut fat, pair[0], pair[1]);
+            }
+            set_fat_entry(info, &mut fat, chain[count - 1], info.fat_type().end_of_chain());
+            let clusters: Vec<String> = chain.iter().map(|c| c.to_string()).collect();
+            format!("clusters {} linked into a chain no file owns", clusters.join(", "))
+        }
+        Kind::BadBpb => unreachable!(),
+    };
+    write_fat(info, disk_file, &fat)?;
+    Ok(Some(description))
+}
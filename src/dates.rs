@@ -0,0 +1,93 @@
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveDateTime};
+
+/// Timestamps given on the command line. FAT stores local time without a
+/// zone, so everything is converted to local time, and times that carry a
+/// zone are shifted into it.
+///
+/// Accepted forms:
+///
+/// * ISO 8601: `2016-03-14`, `2016-03-14T13:37`, `2016-03-14 13:37:42`,
+///   `2016-03-14 13:37:42.25`, `2016-03-14T13:37:42+01:00`
+/// * RFC 2822: `Mon, 14 Mar 2016 13:37:42 +0000`
+/// * relative: `now`, `today`, `yesterday`, `3 days ago`, `90 minutes ago`
+///
+/// A bare date is the start of that day, or its last second if `end_of_day`
+/// is set, for bounds like `--at` that should include the whole day.
+pub fn parse(text: &str, end_of_day: bool) -> Result<NaiveDateTime, String> {
+    let now = Local::now().naive_local();
+    let text = text.trim();
+    let day = |date: NaiveDate| {
+        if end_of_day {
+            date.and_hms_opt(23, 59, 59).unwrap()
+        } else {
+            date.and_hms_opt(0, 0, 0).unwrap()
+        }
+    };
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(text) {
+        return Ok(datetime.with_timezone(&Local).naive_local());
+    }
+    if let Ok(datetime) = DateTime::parse_from_rfc2822(text) {
+        return Ok(datetime.with_timezone(&Local).naive_local());
+    }
+    // `%.f` also matches no fraction at all.
+    for format in ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M"] {
+        if let Ok(datetime) = NaiveDateTime::parse_from_str(text, format) {
+            return Ok(datetime);
+        }
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+        return Ok(day(date));
+    }
+    match text.to_lowercase().as_str() {
+        "now" => return Ok(now),
+        "today" => return Ok(day(now.date())),
+        "yesterday" => return Ok(day(now.date() - Duration::days(1))),
+        _ => (),
+    }
+    if let Some(ago) = relative(text) {
+        return ago.and_then(|ago| now.checked_sub_signed(ago))
+            .ok_or_else(|| format!("out of range: {} (FAT dates run from 1980 to 2107)", text));
+    }
+    Err(format!("invalid date: {} (expected ISO 8601 like 2016-03-14T13:37:42, \
+                 RFC 2822 like \"Mon, 14 Mar 2016 13:37:42 +0000\", or a relative time like \"3 days ago\")",
+                text))
+}
+
+/// Parses `<count> <unit> ago`, e.g. `3 days ago` or `1 week ago`. `None`
+/// if `text` isn't of that form, and `Some(None)` if it is but the time is
+/// too long ago to represent.
+fn relative(text: &str) -> Option<Option<Duration>> {
+    let words: Vec<String> = text.split_whitespace().map(|w| w.to_lowercase()).collect();
+    if words.len() != 3 || words[2] != "ago" {
+        return None;
+    }
+    let count: i64 = words[0].parse().ok()?;
+    let unit = words[1].strip_suffix('s').unwrap_or(&words[1]);
+    Some(match unit {
+        "second" | "sec" => Duration::try_seconds(count),
+        "minute" | "min" => Duration::try_minutes(count),
+        "hour" => Duration::try_hours(count),
+        "day" => Duration::try_days(count),
+        "week" => Duration::try_weeks(count),
+        // Calendar lengths vary; these are the usual approximations.
+        "month" => count.checked_mul(30).and_then(Duration::try_days),
+        "year" => count.checked_mul(365).and_then(Duration::try_days),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_times_out_of_range_are_errors() {
+        let now = Local::now().naive_local();
+        let ago = parse("3 days ago", false).unwrap();
+        assert!((now - ago - Duration::days(3)).num_seconds().abs() < 5);
+        for text in ["99999999999 days ago", "9223372036854775807 years ago", "999999999999999 seconds ago"] {
+            assert!(parse(text, false).unwrap_err().starts_with("out of range: "), "{}", text);
+        }
+        assert!(parse("3 fortnights ago", false).unwrap_err().starts_with("invalid date: "));
+    }
+}
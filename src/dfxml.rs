@@ -0,0 +1,227 @@
+use std::io::{self, Read, Seek, Write};
+use chrono::NaiveDateTime;
+use backup::Sha256Writer;
+use {attribute_string, cluster_chain, cluster_limit, cluster_size, cluster_start, copy_file, dos_date,
+     dos_datetime, fat_entry, for_each_entry, root_dir_start, DirEntry, DirEntryAttributes, DiskInfo, FatTable,
+     TreeEntry};
+
+/// One directory entry as exported: where it sits in the tree, whether it was
+/// deleted, and the byte runs holding its data as `(file offset, image offset,
+/// length)`.
+struct FileObject {
+    path: String,
+    entry: DirEntry,
+    deleted: bool,
+    runs: Vec<(u64, u64, u64)>,
+    sha256: Option<String>,
+}
+
+/// Escapes text for XML. Control characters, which XML 1.0 can't carry at
+/// all, become `?`; they only turn up in names on damaged disks.
+fn xml_escape(text: &str) -> String {
+    let mut out = String::new();
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            c if (c as u32) < 0x20 => out.push('?'),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Merges clusters into runs of adjacent ones, stopping after `size` bytes.
+fn byte_runs(info: &DiskInfo, clusters: &[u32], size: u64) -> Vec<(u64, u64, u64)> {
+    let mut runs: Vec<(u64, u64, u64)> = Vec::new();
+    let mut file_offset = 0;
+    for &cluster in clusters {
+        if file_offset >= size {
+            break;
+        }
+        let len = cluster_size(info).min(size - file_offset);
+        let offset = cluster_start(info, cluster);
+        match runs.last_mut() {
+            Some(run) if run.1 + run.2 == offset => run.2 += len,
+            _ => runs.push((file_offset, offset, len)),
+        }
+        file_offset += len;
+    }
+    runs
+}
+
+/// Works out the byte runs and hash of an entry. A deleted file's runs are a
+/// guess: the clusters that follow its first one, as DOS allocated them when
+/// the disk wasn't fragmented.
+fn file_object<R: Read + Seek, F: FatTable + ?Sized>(info: &DiskInfo,
+                                                     disk_file: &mut R,
+                                                     fat: &F,
+                                                     found: TreeEntry)
+                                                     -> io::Result<FileObject> {
+    let entry = found.entry;
+    let is_dir = (entry.attributes & DirEntryAttributes::SubDir as u8) != 0;
+    let (runs, sha256) = if found.deleted {
+        let clusters: Vec<u32> = (entry.flc..)
+            .take_while(|&c| c >= 2 && c < cluster_limit(info))
+            .take((entry.file_size as u64).div_ceil(cluster_size(info)) as usize)
+            .collect();
+        (byte_runs(info, &clusters, entry.file_size as u64), None)
+    } else if is_dir {
+        let clusters = cluster_chain(info, fat, entry.flc);
+        (byte_runs(info, &clusters, clusters.len() as u64 * cluster_size(info)), None)
+    } else {
+        let clusters = cluster_chain(info, fat, entry.flc);
+        let mut hasher = Sha256Writer::new();
+        copy_file(info, disk_file, fat, &entry, &mut hasher)?;
+        (byte_runs(info, &clusters, entry.file_size as u64), Some(hasher.finish()))
+    };
+    Ok(FileObject { path: found.path, entry, deleted: found.deleted, runs, sha256 })
+}
+
+fn write_time<W: Write>(out: &mut W, element: &str, time: Option<String>) -> io::Result<()> {
+    match time {
+        Some(time) => writeln!(out, "      <{}>{}</{}>", element, time, element),
+        None => Ok(()),
+    }
+}
+
+fn write_object<W: Write>(out: &mut W, object: &FileObject) -> io::Result<()> {
+    let entry = &object.entry;
+    let is_dir = (entry.attributes & DirEntryAttributes::SubDir as u8) != 0;
+    writeln!(out, "    <fileobject>")?;
+    writeln!(out, "      <filename>{}</filename>", xml_escape(object.path.trim_start_matches('/')))?;
+    writeln!(out, "      <filesize>{}</filesize>", entry.file_size)?;
+    writeln!(out, "      <alloc>{}</alloc>", if object.deleted { 0 } else { 1 })?;
+    writeln!(out, "      <name_type>{}</name_type>", if is_dir { "d" } else { "r" })?;
+    writeln!(out, "      <fat12:attributes>{}</fat12:attributes>", attribute_string(entry.attributes))?;
+    writeln!(out, "      <fat12:first_cluster>{}</fat12:first_cluster>", entry.flc)?;
+    let format = |t: NaiveDateTime| t.format("%Y-%m-%dT%H:%M:%S").to_string();
+    write_time(out, "mtime", dos_datetime(entry.last_write_date, entry.last_write_time).map(format))?;
+    write_time(out, "crtime", dos_datetime(entry.create_date, entry.create_time).map(format))?;
+    write_time(out,
+               "atime",
+               dos_date(entry.last_access_date).map(|d| d.format("%Y-%m-%d").to_string()))?;
+    if !object.runs.is_empty() {
+        writeln!(out, "      <byte_runs>")?;
+        for &(file_offset, img_offset, len) in &object.runs {
+            writeln!(out,
+                     "        <byte_run file_offset=\"{}\" img_offset=\"{}\" len=\"{}\"/>",
+                     file_offset,
+                     img_offset,
+                     len)?;
+        }
+        writeln!(out, "      </byte_runs>")?;
+    }
+    if let Some(ref sha256) = object.sha256 {
+        writeln!(out, "      <hashdigest type=\"sha256\">{}</hashdigest>", sha256)?;
+    }
+    writeln!(out, "    </fileobject>")
+}
+
+/// Writes the volume layout, FAT usage and every directory entry, deleted ones
+/// included, as a DFXML document. Layout details DFXML has no element for are
+/// in the `fat12` namespace. Entries are written as they are found, so the
+/// document can be far bigger than the memory used to make it.
+pub fn export<R: Read + Seek, F: FatTable + ?Sized, W: Write>(info: &DiskInfo,
+                                                              disk_file: &mut R,
+                                                              fat: &F,
+                                                              image_name: &str,
+                                                              image_size: u64,
+                                                              out: &mut W)
+                                                              -> io::Result<()> {
+    let limit = cluster_limit(info);
+    let (mut free, mut used, mut bad) = (0, 0, 0);
+    for cluster in 2..limit {
+        match fat_entry(info, fat, cluster).unwrap_or(0) {
+            0 => free += 1,
+            entry if entry == info.fat_type().bad_cluster() => bad += 1,
+            _ => used += 1,
+        }
+    }
+
+    writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(out,
+             "<dfxml xmlns=\"http://www.forensicswiki.org/wiki/Category:Digital_Forensics_XML\" \
+              xmlns:fat12=\"urn:fat12-rs\" version=\"1.0\">")?;
+    writeln!(out, "  <creator>")?;
+    writeln!(out, "    <program>fat12</program>")?;
+    writeln!(out, "    <version>{}</version>", env!("CARGO_PKG_VERSION"))?;
+    writeln!(out, "  </creator>")?;
+    writeln!(out, "  <source>")?;
+    writeln!(out, "    <image_filename>{}</image_filename>", xml_escape(image_name))?;
+    writeln!(out, "    <image_size>{}</image_size>", image_size)?;
+    writeln!(out, "  </source>")?;
+    writeln!(out, "  <volume offset=\"0\">")?;
+    writeln!(out, "    <partition_offset>0</partition_offset>")?;
+    writeln!(out, "    <sector_size>{}</sector_size>", info.bytes_per_sector)?;
+    writeln!(out, "    <block_size>{}</block_size>", cluster_size(info))?;
+    writeln!(out, "    <ftype_str>{}</ftype_str>", info.fat_type().name().to_lowercase())?;
+    writeln!(out, "    <block_count>{}</block_count>", limit - 2)?;
+    writeln!(out, "    <first_block>2</first_block>")?;
+    writeln!(out, "    <last_block>{}</last_block>", limit - 1)?;
+    writeln!(out, "    <fat12:reserved_sectors>{}</fat12:reserved_sectors>", info.reserved_sectors)?;
+    writeln!(out, "    <fat12:fats>{}</fat12:fats>", info.fats)?;
+    writeln!(out, "    <fat12:sectors_per_fat>{}</fat12:sectors_per_fat>", info.fat_sectors())?;
+    writeln!(out, "    <fat12:root_dir_offset>{}</fat12:root_dir_offset>", root_dir_start(info))?;
+    writeln!(out, "    <fat12:root_dir_entries>{}</fat12:root_dir_entries>", info.root_dir_entries)?;
+    writeln!(out, "    <fat12:data_offset>{}</fat12:data_offset>", cluster_start(info, 2))?;
+    writeln!(out,
+             "    <fat12:clusters free=\"{}\" used=\"{}\" bad=\"{}\"/>",
+             free,
+             used,
+             bad)?;
+    for_each_entry(info, disk_file, fat, true, |disk_file, found| {
+        let object = file_object(info, disk_file, fat, found)?;
+        Ok(write_object(out, &object)?)
+    })?;
+    writeln!(out, "  </volume>")?;
+    writeln!(out, "</dfxml>")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backup::sha256_hex;
+    use tests::{blank, host_file};
+    use {put, read_fat, rm};
+
+    #[test]
+    fn file_objects_have_their_runs_hashes_and_deletions() {
+        let (info, mut image) = blank();
+        let contents: Vec<u8> = (0..1500).map(|n| n as u8).collect();
+        put(&info, &mut image, &host_file("dfxml", "A.TXT", b"a"), "/").unwrap();
+        put(&info, &mut image, &host_file("dfxml", "B&C.TXT", b"b"), "/").unwrap();
+        rm(&info, &mut image, "/A.TXT").unwrap();
+        // Into A.TXT's cluster, and two after B&C.TXT's.
+        put(&info, &mut image, &host_file("dfxml", "D.BIN", &contents), "/").unwrap();
+        put(&info, &mut image, &host_file("dfxml", "E.TXT", b"e"), "/").unwrap();
+        rm(&info, &mut image, "/E.TXT").unwrap();
+
+        let fat = read_fat(&info, &mut image).unwrap();
+        let mut out = Vec::new();
+        export(&info, &mut image, &fat[..], "<disk>.img", 1474560, &mut out).unwrap();
+        let xml = String::from_utf8(out).unwrap();
+        assert!(xml.contains("<image_filename>&lt;disk&gt;.img</image_filename>"));
+        assert!(xml.contains("<fat12:clusters free=\"2843\" used=\"4\" bad=\"0\"/>"));
+        assert!(xml.contains("<filename>B&amp;C.TXT</filename>"));
+        let object = |name: &str| {
+            let start = xml.find(&format!("<filename>{}</filename>", name)).unwrap();
+            xml[start..start + xml[start..].find("</fileobject>").unwrap()].to_string()
+        };
+        let file = object("D.BIN");
+        assert!(file.contains("<alloc>1</alloc>"));
+        assert!(file.contains(&format!("<byte_run file_offset=\"0\" img_offset=\"{}\" len=\"512\"/>",
+                                       cluster_start(&info, 2))));
+        assert!(file.contains(&format!("<byte_run file_offset=\"512\" img_offset=\"{}\" len=\"988\"/>",
+                                       cluster_start(&info, 4))));
+        assert!(file.contains(&format!("<hashdigest type=\"sha256\">{}</hashdigest>", sha256_hex(&contents))));
+        let deleted = object("_.TXT");
+        assert!(deleted.contains("<alloc>0</alloc>"));
+        assert!(deleted.contains(&format!("<byte_run file_offset=\"0\" img_offset=\"{}\" len=\"1\"/>",
+                                          cluster_start(&info, 6))));
+        assert!(!deleted.contains("hashdigest"));
+    }
+}
@@ -0,0 +1,107 @@
+//! DR-DOS's file passwords. From DR-DOS 6 on, a FAT12 or FAT16 entry
+//! protected by a password keeps which operations need it in the word at
+//! offset 20, which `DirEntry::ea_handle` holds, and a hash of the password
+//! in place of its creation time. OS/2 keeps its EA handles in the same
+//! word, so it is only taken for rights on a volume without `EA DATA. SF`.
+
+use std::io::{Read, Seek};
+use {ea, DirEntry, DiskInfo, FatType, Result};
+
+// The rights are of the owner, group and world, four bits each from the
+// lowest: read, write, execute and delete, each set if it needs the
+// password.
+const CLASSES: [&str; 3] = ["owner", "group", "world"];
+const OPERATIONS: &str = "rwed";
+
+/// What an entry's password guards.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Protection {
+    pub rights: u16,
+    /// The hash of the password, where the creation time would be.
+    pub password_hash: u16,
+}
+impl Protection {
+    /// The protection of `entry` on a volume `rights_in_use` says keeps
+    /// rights, if it has any. A word with bits past DR-DOS's twelve is taken
+    /// for something else.
+    pub fn of(entry: &DirEntry, rights_in_use: bool) -> Option<Protection> {
+        match entry.ea_handle {
+            rights if rights_in_use && rights != 0 && rights & !0x0FFF == 0 => {
+                Some(Protection { rights, password_hash: entry.create_time })
+            }
+            _ => None,
+        }
+    }
+
+    /// The operations that need the password, as `rwed` with a `-` for each
+    /// that doesn't: once if the owner, group and world are alike, as
+    /// DR-DOS's own PASSWORD sets them, or else for each by name.
+    pub fn modes(&self) -> String {
+        let class = |n: usize| -> String {
+            OPERATIONS.chars()
+                .enumerate()
+                .map(|(bit, c)| if self.rights & (1 << (n * 4 + bit)) != 0 { c } else { '-' })
+                .collect()
+        };
+        if class(0) == class(1) && class(1) == class(2) {
+            return class(0);
+        }
+        let classes: Vec<String> = CLASSES.iter()
+            .enumerate()
+            .map(|(n, name)| format!("{} {}", name, class(n)))
+            .collect();
+        classes.join(", ")
+    }
+}
+
+/// Whether the word at offset 20 of the volume's entries holds DR-DOS's
+/// rights: on FAT12 and FAT16, when there is no `EA DATA. SF`.
+pub fn rights_in_use<R: Read + Seek>(info: &DiskInfo, disk_file: &mut R) -> Result<bool> {
+    Ok(info.fat_type() != FatType::Fat32 && ea::find(info, disk_file)?.is_none())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{ByteOrder, LittleEndian};
+    use chrono::Local;
+    use std::io::{Cursor, SeekFrom, Write};
+    use tests::{blank, host_file};
+    use {copy_between, find_path, find_slot, put, rename, set_attributes, touch, DirEntryAttributes, Times,
+         DIR_ENTRY_CREATETIME, DIR_ENTRY_FLC_HIGH};
+
+    fn protection(info: &DiskInfo, image: &mut Cursor<Vec<u8>>, path: &str) -> Option<Protection> {
+        Protection::of(&find_path(info, image, path).unwrap().unwrap(), true)
+    }
+
+    #[test]
+    fn passwords_survive_edits() {
+        let (info, mut image) = blank();
+        put(&info, &mut image, &host_file("drdos", "SECRET.TXT", b"secret"), "/").unwrap();
+        let (directory, slot, _) = find_slot(&info, &mut image, "/SECRET.TXT").unwrap().unwrap();
+        let offset = directory.slot_offset(slot).unwrap();
+        let mut fields = [0; 8];
+        LittleEndian::write_u16(&mut fields, 0x1234);
+        LittleEndian::write_u16(&mut fields[DIR_ENTRY_FLC_HIGH - DIR_ENTRY_CREATETIME..], 0x0AAA);
+        image.seek(SeekFrom::Start(offset + DIR_ENTRY_CREATETIME as u64)).unwrap();
+        image.write_all(&fields).unwrap();
+
+        // Write and delete need the password, for everyone.
+        assert!(rights_in_use(&info, &mut image).unwrap());
+        let expected = Protection { rights: 0x0AAA, password_hash: 0x1234 };
+        assert_eq!(protection(&info, &mut image, "/SECRET.TXT"), Some(expected));
+        assert_eq!(expected.modes(), "-w-d");
+        let owner = Protection { rights: 0x0008, password_hash: 0 };
+        assert_eq!(owner.modes(), "owner ---d, group ----, world ----");
+
+        // The rights and hash stay through a rename, new attributes and new
+        // times, and go with a copy.
+        rename(&info, &mut image, "/SECRET.TXT", "HIDDEN.TXT").unwrap();
+        set_attributes(&info, &mut image, "/HIDDEN.TXT", DirEntryAttributes::ReadOnly as u8, 0).unwrap();
+        let times = Times { created: Some(Local::now().naive_local()), ..Times::default() };
+        touch(&info, &mut image, "/HIDDEN.TXT", &times).unwrap();
+        copy_between(&info, &mut image.clone(), "/HIDDEN.TXT", &info, &mut image, "/COPY.TXT").unwrap();
+        assert_eq!(protection(&info, &mut image, "/HIDDEN.TXT"), Some(expected));
+        assert_eq!(protection(&info, &mut image, "/COPY.TXT"), Some(expected));
+    }
+}
@@ -0,0 +1,408 @@
+//! OS/2's extended attributes on FAT12 and FAT16. They are kept in one
+//! hidden file in the root, `EA DATA. SF`, and an entry with any has a
+//! handle into it in the word FAT32 uses for its first cluster's high bits.
+//!
+//! The file starts with a header: `ED`, then at 0x20 a table of 240 base
+//! clusters, one for each run of 128 handles, then at 0x200 a table of one
+//! word per handle, the cluster of the handle's set past its run's base, or
+//! 0xFFFF if the handle is free. Clusters count from the start of the file.
+//! Each set starts a cluster: `EA`, its own handle, the count of critical
+//! EAs, at 8 the owner's 8.3 name, at 0x1A the length of the list that
+//! follows, itself included, and from 0x1E the EAs, each packed as flags,
+//! the name's length, the value's length, the name with a NUL after it, and
+//! the value.
+
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use chrono::Local;
+use policy::{self, Action, Mutation};
+use {allocate_clusters, cluster_chain, cluster_size, cluster_start, copy_file, for_each_entry, make_slots,
+     new_entry, open_parent, read_fat, set_fat_entry, write_entry, write_fat, DirEntry, DirEntryAttributes,
+     Directory, DiskInfo, Error, FatType, Placement, Result, DIR_ENTRY_FILESIZE, DIR_ENTRY_FLC};
+
+/// The 8.3 name of the file, as its slot has it.
+pub const EA_FILE: &[u8; 11] = b"EA DATA  SF";
+
+const HEADER_SIGNATURE: &[u8] = b"ED";
+const BASE_TABLE: usize = 0x20;
+const BASE_ENTRIES: usize = 240;
+const HANDLES_PER_BASE: usize = 128;
+const OFFSET_TABLE: usize = 0x200;
+const UNUSED: u16 = 0xFFFF;
+
+const SET_SIGNATURE: &[u8] = b"EA";
+const SET_HANDLE: usize = 2;
+const SET_NEED: usize = 4;
+const SET_OWNER: usize = 8;
+const SET_OWNER_SIZE: usize = 14;
+const SET_LIST: usize = 0x1A;
+const SET_EAS: usize = 0x1E;
+
+/// The flag of an EA the file can't be used without.
+const CRITICAL: u8 = 0x80;
+/// The type OS/2 gives a value that is text, such as `.LONGNAME`.
+const EAT_ASCII: u16 = 0xFFFD;
+
+/// One extended attribute.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ea {
+    pub name: String,
+    /// The value as stored, starting with its two-byte type.
+    pub value: Vec<u8>,
+    /// Whether a program has to understand the EA to use the file, so that
+    /// OS/2 won't let one that doesn't open it.
+    pub critical: bool,
+}
+impl Ea {
+    /// The value as text, if its type is `EAT_ASCII`.
+    pub fn text(&self) -> Option<String> {
+        if self.value.len() < 4 || LittleEndian::read_u16(&self.value) != EAT_ASCII {
+            return None;
+        }
+        let len = LittleEndian::read_u16(&self.value[2..]) as usize;
+        self.value.get(4..4 + len).map(|text| String::from_utf8_lossy(text).into_owned())
+    }
+}
+
+/// The extended attributes of one file or directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EaSet {
+    /// The 8.3 name of the entry the set belongs to, as the set records it.
+    pub owner: String,
+    pub eas: Vec<Ea>,
+}
+
+fn corrupt(why: String) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidData, format!("EA DATA. SF: {}", why)))
+}
+
+/// The set of `eas` for `handle` as the file packs it.
+fn pack(handle: u16, owner: &str, eas: &[Ea]) -> Vec<u8> {
+    let mut set = vec![0; SET_EAS];
+    set[..2].copy_from_slice(SET_SIGNATURE);
+    LittleEndian::write_u16(&mut set[SET_HANDLE..], handle);
+    LittleEndian::write_u32(&mut set[SET_NEED..], eas.iter().filter(|ea| ea.critical).count() as u32);
+    let owner = owner.as_bytes();
+    let len = owner.len().min(SET_OWNER_SIZE - 1);
+    set[SET_OWNER..SET_OWNER + len].copy_from_slice(&owner[..len]);
+    for ea in eas {
+        set.push(if ea.critical { CRITICAL } else { 0 });
+        set.push(ea.name.len() as u8);
+        let mut len = [0; 2];
+        LittleEndian::write_u16(&mut len, ea.value.len() as u16);
+        set.extend_from_slice(&len);
+        set.extend_from_slice(ea.name.as_bytes());
+        set.push(0);
+        set.extend_from_slice(&ea.value);
+    }
+    let list = (set.len() - SET_LIST) as u32;
+    LittleEndian::write_u32(&mut set[SET_LIST..], list);
+    set
+}
+
+/// `EA DATA. SF` as read from a volume.
+pub struct EaFile {
+    data: Vec<u8>,
+    cluster: usize,
+}
+impl EaFile {
+    /// The file in the root of the volume `info` describes, or `None` if it
+    /// has none, as FAT32 never does.
+    pub fn read<R: Read + Seek>(info: &DiskInfo, disk_file: &mut R) -> Result<Option<EaFile>> {
+        let entry = match find(info, disk_file)? {
+            Some((_, entry)) => entry,
+            None => return Ok(None),
+        };
+        let fat = read_fat(info, disk_file)?;
+        let mut data = Vec::with_capacity(entry.file_size as usize);
+        copy_file(info, disk_file, &fat[..], &entry, &mut data)?;
+        if data.len() < OFFSET_TABLE || &data[..2] != HEADER_SIGNATURE {
+            return Err(corrupt("no header".to_string()));
+        }
+        Ok(Some(EaFile { data, cluster: cluster_size(info) as usize }))
+    }
+
+    /// The handles in use, lowest first. The table of them goes on up to
+    /// the first set.
+    fn handles(&self) -> Vec<u16> {
+        let first = (0..BASE_ENTRIES)
+            .map(|run| LittleEndian::read_u16(&self.data[BASE_TABLE + run * 2..]))
+            .filter(|&base| base != 0)
+            .min()
+            .map_or(self.data.len(), |base| base as usize * self.cluster);
+        let table = &self.data[OFFSET_TABLE..first.clamp(OFFSET_TABLE, self.data.len())];
+        let count = (table.len() / 2).min(BASE_ENTRIES * HANDLES_PER_BASE);
+        (1..count as u16).filter(|&handle| LittleEndian::read_u16(&table[handle as usize * 2..]) != UNUSED)
+            .collect()
+    }
+
+    /// The set of `handle` as it is packed in the file.
+    fn packed(&self, handle: u16) -> Result<&[u8]> {
+        let handle = handle as usize;
+        let at = OFFSET_TABLE + handle * 2;
+        if handle / HANDLES_PER_BASE >= BASE_ENTRIES || at + 2 > self.data.len() {
+            return Err(corrupt(format!("no handle {}", handle)));
+        }
+        let offset = LittleEndian::read_u16(&self.data[at..]);
+        if offset == UNUSED {
+            return Err(corrupt(format!("handle {} is free", handle)));
+        }
+        let base = LittleEndian::read_u16(&self.data[BASE_TABLE + handle / HANDLES_PER_BASE * 2..]);
+        let start = (base as usize + (offset & 0x7FFF) as usize) * self.cluster;
+        let set = self.data.get(start..).filter(|set| set.len() >= SET_EAS && &set[..2] == SET_SIGNATURE);
+        let set = set.ok_or_else(|| corrupt(format!("handle {} leads to no set", handle)))?;
+        let end = SET_LIST + LittleEndian::read_u32(&set[SET_LIST..]) as usize;
+        let cut_short = || corrupt(format!("the set of handle {} is cut short", handle));
+        set.get(..end.max(SET_EAS)).ok_or_else(cut_short)
+    }
+
+    /// The set `handle` leads to.
+    pub fn set(&self, handle: u16) -> Result<EaSet> {
+        let set = self.packed(handle)?;
+        let owner = &set[SET_OWNER..SET_OWNER + SET_OWNER_SIZE];
+        let owner = String::from_utf8_lossy(owner.split(|&b| b == 0).next().unwrap_or(owner)).into_owned();
+        let mut eas = Vec::new();
+        let mut rest = &set[SET_EAS..];
+        while rest.len() >= 4 {
+            let name_len = rest[1] as usize;
+            let value_len = LittleEndian::read_u16(&rest[2..]) as usize;
+            let end = 4 + name_len + 1 + value_len;
+            if rest.len() < end {
+                return Err(corrupt(format!("the set of handle {} is cut short", handle)));
+            }
+            eas.push(Ea {
+                name: String::from_utf8_lossy(&rest[4..4 + name_len]).into_owned(),
+                value: rest[4 + name_len + 1..end].to_vec(),
+                critical: rest[0] & CRITICAL != 0,
+            });
+            rest = &rest[end..];
+        }
+        Ok(EaSet { owner, eas })
+    }
+
+    /// Every set, packed, by handle.
+    fn sets(&self) -> Result<BTreeMap<u16, Vec<u8>>> {
+        let mut sets = BTreeMap::new();
+        for handle in self.handles() {
+            sets.insert(handle, self.packed(handle)?.to_vec());
+        }
+        Ok(sets)
+    }
+
+    /// Adds a set of `eas` for `owner` under the lowest free handle and
+    /// returns the handle. The file is laid out again, so sets already there
+    /// can move, but their handles stay the same.
+    fn add(&mut self, owner: &str, eas: &[Ea]) -> Result<u16> {
+        let mut sets = self.sets()?;
+        let handle = (1..).find(|handle| !sets.contains_key(handle)).unwrap();
+        sets.insert(handle, pack(handle, owner, eas));
+        self.data = layout(&sets, self.cluster)?;
+        Ok(handle)
+    }
+}
+
+/// The contents of an `EA DATA. SF` with clusters of `from` bytes laid out
+/// again for clusters of `to` bytes, the sets under the same handles.
+pub(crate) fn relaid(data: Vec<u8>, from: usize, to: usize) -> Result<Vec<u8>> {
+    if data.len() < OFFSET_TABLE || &data[..2] != HEADER_SIGNATURE {
+        return Err(corrupt("no header".to_string()));
+    }
+    layout(&EaFile { data, cluster: from }.sets()?, to)
+}
+
+/// A file holding `sets`, by handle, with clusters of `cluster` bytes.
+fn layout(sets: &BTreeMap<u16, Vec<u8>>, cluster: usize) -> Result<Vec<u8>> {
+    let count = sets.keys().next_back().map_or(1, |&last| last as usize + 1);
+    if count > BASE_ENTRIES * HANDLES_PER_BASE {
+        return Err(Error::NoSpace("EA DATA. SF has no free handle".to_string()));
+    }
+    let tables = (OFFSET_TABLE + count * 2).div_ceil(cluster) * cluster;
+    let mut data = vec![0; tables];
+    data[..2].copy_from_slice(HEADER_SIGNATURE);
+    for offset in data[OFFSET_TABLE..].chunks_exact_mut(2) {
+        LittleEndian::write_u16(offset, UNUSED);
+    }
+    for (&handle, set) in sets {
+        let at = data.len() / cluster;
+        let base_at = BASE_TABLE + handle as usize / HANDLES_PER_BASE * 2;
+        if LittleEndian::read_u16(&data[base_at..]) == 0 {
+            LittleEndian::write_u16(&mut data[base_at..], at as u16);
+        }
+        let offset = at - LittleEndian::read_u16(&data[base_at..]) as usize;
+        if at > 0xFFFF || offset > 0x7FFF {
+            return Err(Error::NoSpace("EA DATA. SF is at its largest".to_string()));
+        }
+        LittleEndian::write_u16(&mut data[OFFSET_TABLE + handle as usize * 2..], offset as u16);
+        data.extend_from_slice(set);
+        data.resize(data.len().div_ceil(cluster) * cluster, 0);
+    }
+    Ok(data)
+}
+
+/// The slot and entry of `EA DATA. SF` in the root, if it is there.
+pub(crate) fn find<R: Read + Seek>(info: &DiskInfo, disk_file: &mut R) -> Result<Option<(usize, DirEntry)>> {
+    if info.fat_type() == FatType::Fat32 {
+        return Ok(None);
+    }
+    let root = Directory::root(info, &read_fat(info, disk_file)?[..]);
+    Ok(root.entries(disk_file)?.into_iter().find(|(_, entry)| {
+        !entry.is_lfn() && [&entry.file_name[..], &entry.file_ext[..]].concat() == EA_FILE[..]
+    }))
+}
+
+/// Writes `file` back over `EA DATA. SF`, growing its chain if it has to, or
+/// makes the file if the volume has none.
+fn write<R: Read + Write + Seek>(info: &DiskInfo, disk_file: &mut R, file: &EaFile) -> Result<()> {
+    let mut fat = read_fat(info, disk_file)?;
+    let cluster = cluster_size(info);
+    let count = (file.data.len() as u64).div_ceil(cluster) as usize;
+    let found = find(info, disk_file)?;
+    let mut chain = match found {
+        Some((_, ref entry)) => cluster_chain(info, &fat[..], entry.flc),
+        None => Vec::new(),
+    };
+    if chain.len() < count {
+        let more = allocate_clusters(info, &mut fat, count - chain.len())
+            .ok_or_else(|| Error::NoSpace("no room for EA DATA. SF to grow".to_string()))?;
+        if let Some(&last) = chain.last() {
+            set_fat_entry(info, &mut fat, last, more[0]);
+        }
+        chain.extend(more);
+    }
+    for (&cluster, chunk) in chain.iter().zip(file.data.chunks(cluster as usize)) {
+        disk_file.seek(SeekFrom::Start(cluster_start(info, cluster)))?;
+        disk_file.write_all(chunk)?;
+    }
+
+    let size = file.data.len() as u32;
+    match found {
+        Some((slot, _)) => {
+            write_fat(info, disk_file, &fat)?;
+            let root = Directory::root(info, &fat[..]);
+            let offset = root.slot_offset(slot).unwrap();
+            let mut bytes = [0; 4];
+            LittleEndian::write_u16(&mut bytes, chain[0] as u16);
+            disk_file.seek(SeekFrom::Start(offset + DIR_ENTRY_FLC as u64))?;
+            disk_file.write_all(&bytes[..2])?;
+            LittleEndian::write_u32(&mut bytes, size);
+            disk_file.seek(SeekFrom::Start(offset + DIR_ENTRY_FILESIZE as u64))?;
+            Ok(disk_file.write_all(&bytes)?)
+        }
+        None => {
+            let path = "/EA DATA. SF".to_string();
+            let attributes = DirEntryAttributes::ReadOnly as u8 | DirEntryAttributes::Hidden as u8 |
+                             DirEntryAttributes::System as u8;
+            let mutation = Mutation { action: Action::Create, path: path.clone(), attributes };
+            let change = policy::check(info, mutation)?;
+            if change.path != path {
+                return Err(Error::Denied(format!("{}: has to be in the root", path)));
+            }
+            let (mut directory, grow_from, parent) = open_parent(info, disk_file, "")?;
+            let slot = make_slots(info, disk_file, &mut fat, &mut directory, grow_from, 1)?;
+            write_fat(info, disk_file, &fat)?;
+            let placement = Placement {
+                directory,
+                grow_from,
+                cluster: parent,
+                short_name: *EA_FILE,
+                long_slots: Vec::new(),
+                case_flags: 0,
+            };
+            let entry = new_entry(EA_FILE, change.attributes, chain[0], size, Local::now().naive_local());
+            write_entry(info, &placement, disk_file, slot, &entry)
+        }
+    }
+}
+
+/// Stores `eas` as a new set in the volume's `EA DATA. SF`, made if need
+/// be, for the entry with the 8.3 name `owner`, and returns the handle to
+/// give the entry. The entry itself is left to the caller.
+pub fn add<R: Read + Write + Seek>(info: &DiskInfo,
+                                   disk_file: &mut R,
+                                   owner: &str,
+                                   eas: &[Ea])
+                                   -> Result<u16> {
+    if info.fat_type() == FatType::Fat32 {
+        return Err(Error::NoSpace("FAT32 has no room for extended attributes".to_string()));
+    }
+    let cluster = cluster_size(info) as usize;
+    let mut file = match EaFile::read(info, disk_file)? {
+        Some(file) => file,
+        None => EaFile { data: layout(&BTreeMap::new(), cluster)?, cluster },
+    };
+    let handle = file.add(owner, eas)?;
+    write(info, disk_file, &file)?;
+    Ok(handle)
+}
+
+/// Every file and directory with extended attributes, by path, with its
+/// handle and set. A handle the file has no set for is left out.
+pub fn list<R: Read + Seek>(info: &DiskInfo, disk_file: &mut R) -> Result<Vec<(String, u16, EaSet)>> {
+    let file = match EaFile::read(info, disk_file)? {
+        Some(file) => file,
+        None => return Ok(Vec::new()),
+    };
+    let fat = read_fat(info, disk_file)?;
+    let mut sets = Vec::new();
+    for_each_entry(info, disk_file, &fat[..], false, |_, found| {
+        let handle = found.entry.ea_handle;
+        if handle != 0 {
+            if let Ok(set) = file.set(handle) {
+                sets.push((found.path, handle, set));
+            }
+        }
+        Ok(())
+    })?;
+    Ok(sets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::{blank, host_file};
+    use relayout::{self, Layout};
+    use {copy_between, find_path, find_slot, put, read_disk_info, rename, DIR_ENTRY_FLC_HIGH};
+
+    #[test]
+    fn eas_follow_their_files() {
+        let (info, mut image) = blank();
+        put(&info, &mut image, &host_file("ea", "README.TXT", b"read me"), "/").unwrap();
+        let long_name = b"\xFD\xFF\x07\x00Read me".to_vec();
+        let eas = vec![Ea { name: ".LONGNAME".to_string(), value: long_name, critical: false },
+                       Ea { name: ".TYPE".to_string(), value: vec![0xDF, 0xFF, 0, 0], critical: true }];
+        assert_eq!(add(&info, &mut image, "README.TXT", &eas).unwrap(), 1);
+        let (directory, slot, _) = find_slot(&info, &mut image, "/README.TXT").unwrap().unwrap();
+        let offset = directory.slot_offset(slot).unwrap() + DIR_ENTRY_FLC_HIGH as u64;
+        image.seek(SeekFrom::Start(offset)).unwrap();
+        image.write_all(&[1, 0]).unwrap();
+
+        // The handle is the entry's, not part of its first cluster.
+        let entry = find_path(&info, &mut image, "/README.TXT").unwrap().unwrap();
+        assert_eq!((entry.ea_handle, entry.flc < 0x10000), (1, true));
+        let file = EaFile::read(&info, &mut image).unwrap().unwrap();
+        assert_eq!(file.set(1).unwrap(), EaSet { owner: "README.TXT".to_string(), eas: eas.clone() });
+        assert_eq!(file.set(1).unwrap().eas[0].text(), Some("Read me".to_string()));
+
+        // A rename keeps the handle, and a copy gets a set of its own.
+        rename(&info, &mut image, "/README.TXT", "INFO.TXT").unwrap();
+        copy_between(&info, &mut image.clone(), "/INFO.TXT", &info, &mut image, "/COPY.TXT").unwrap();
+        let listed: Vec<(String, u16)> = list(&info, &mut image)
+            .unwrap()
+            .into_iter()
+            .map(|(path, handle, set)| {
+                assert_eq!(set.eas, eas);
+                (path, handle)
+            })
+            .collect();
+        assert_eq!(listed, vec![("/INFO.TXT".to_string(), 1), ("/COPY.TXT".to_string(), 2)]);
+        assert_eq!(EaFile::read(&info, &mut image).unwrap().unwrap().set(2).unwrap().owner, "COPY.TXT");
+
+        // Laid out with bigger clusters, the sets are found where they went.
+        let layout = Layout { root_dir_entries: 224, sectors_per_cluster: 4 };
+        relayout::relayout(&info, &mut image, layout).unwrap();
+        let info = read_disk_info(&mut image).unwrap();
+        let file = EaFile::read(&info, &mut image).unwrap().unwrap();
+        assert_eq!((file.set(1).unwrap().eas, file.set(2).unwrap().eas), (eas.clone(), eas));
+    }
+}
@@ -0,0 +1,85 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+/// What can go wrong reading or changing an image. Paths are as given by the
+/// caller, so messages can quote them back.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The boot sector is missing, truncated, or describes no usable layout.
+    InvalidBootSector(String),
+    /// A directory entry points outside the volume.
+    InvalidDirEntry(String),
+    NotFound(String),
+    NotADirectory(String),
+    IsADirectory(String),
+    AlreadyExists(String),
+    /// A name that can't be stored in a directory, even as a long name.
+    InvalidName(String),
+    /// A cluster chain that is broken off, loops, or ends before its file's
+    /// recorded size.
+    CorruptFatChain(String),
+    /// The FAT or a fixed-size directory has no room left.
+    NoSpace(String),
+    /// The change was refused by the `policy` set.
+    Denied(String),
+    /// A deleted entry whose clusters can't be worked out or are in use again.
+    Unrecoverable(String),
+    /// More entries, clusters or output than one of the limits in
+    /// `VolumeOptions` allows.
+    LimitExceeded(String),
+}
+
+pub type Result<T, E = Error> = ::std::result::Result<T, E>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => e.fmt(f),
+            Error::InvalidBootSector(ref why) => write!(f, "invalid boot sector: {}", why),
+            Error::InvalidDirEntry(ref path) => write!(f, "{}: invalid directory entry", path),
+            Error::NotFound(ref path) => write!(f, "{}: no such file or directory", path),
+            Error::NotADirectory(ref path) => write!(f, "{}: not a directory", path),
+            Error::IsADirectory(ref path) => write!(f, "{}: is a directory", path),
+            Error::AlreadyExists(ref path) => write!(f, "{}: already exists", path),
+            Error::InvalidName(ref name) => write!(f, "{}: not a valid file name", name),
+            Error::CorruptFatChain(ref what) => write!(f, "{}: broken cluster chain", what),
+            Error::NoSpace(ref what) => write!(f, "{}", what),
+            Error::Denied(ref why) => write!(f, "{}", why),
+            Error::Unrecoverable(ref why) => write!(f, "can't undelete {}", why),
+            Error::LimitExceeded(ref why) => write!(f, "{}", why),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// For the modules that work on host files and report `io::Error`.
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            Error::NotFound(_) => io::Error::new(io::ErrorKind::NotFound, e.to_string()),
+            Error::AlreadyExists(_) => io::Error::new(io::ErrorKind::AlreadyExists, e.to_string()),
+            Error::Denied(_) => io::Error::new(io::ErrorKind::PermissionDenied, e.to_string()),
+            Error::InvalidBootSector(_) | Error::InvalidDirEntry(_) | Error::CorruptFatChain(_) => {
+                io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+            }
+            _ => io::Error::other(e.to_string()),
+        }
+    }
+}
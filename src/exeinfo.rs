@@ -0,0 +1,79 @@
+use byteorder::{LittleEndian, ByteOrder};
+
+const MZ_HEADER_SIZE: usize = 28;
+
+/// The fixed part of a DOS MZ executable header.
+pub struct MzHeader {
+    pub last_page_bytes: u16,
+    pub pages: u16,
+    pub relocations: u16,
+    pub header_paragraphs: u16,
+    pub min_alloc: u16,
+    pub max_alloc: u16,
+    pub ss: u16,
+    pub sp: u16,
+    pub checksum: u16,
+    pub ip: u16,
+    pub cs: u16,
+    pub reloc_offset: u16,
+    pub overlay_number: u16,
+}
+impl MzHeader {
+    /// Parses the header, or returns `None` if `buf` doesn't start with one.
+    pub fn new(buf: &[u8]) -> Option<Self> {
+        if buf.len() < MZ_HEADER_SIZE || !(buf.starts_with(b"MZ") || buf.starts_with(b"ZM")) {
+            return None;
+        }
+        Some(MzHeader {
+            last_page_bytes: LittleEndian::read_u16(&buf[2..]),
+            pages: LittleEndian::read_u16(&buf[4..]),
+            relocations: LittleEndian::read_u16(&buf[6..]),
+            header_paragraphs: LittleEndian::read_u16(&buf[8..]),
+            min_alloc: LittleEndian::read_u16(&buf[10..]),
+            max_alloc: LittleEndian::read_u16(&buf[12..]),
+            ss: LittleEndian::read_u16(&buf[14..]),
+            sp: LittleEndian::read_u16(&buf[16..]),
+            checksum: LittleEndian::read_u16(&buf[18..]),
+            ip: LittleEndian::read_u16(&buf[20..]),
+            cs: LittleEndian::read_u16(&buf[22..]),
+            reloc_offset: LittleEndian::read_u16(&buf[24..]),
+            overlay_number: LittleEndian::read_u16(&buf[26..]),
+        })
+    }
+
+    pub fn header_size(&self) -> u32 {
+        self.header_paragraphs as u32 * 16
+    }
+
+    /// Size of the executable as loaded by DOS, header included. Anything in
+    /// the file past this point is an overlay.
+    pub fn image_size(&self) -> u32 {
+        let pages = self.pages as u32 * 512;
+        if self.last_page_bytes == 0 {
+            pages
+        } else {
+            pages.saturating_sub(512) + self.last_page_bytes as u32
+        }
+    }
+
+    /// File offset of the first instruction executed.
+    pub fn entry_offset(&self) -> u32 {
+        self.header_size() + ((self.cs as u32 * 16 + self.ip as u32) & 0xFFFFF)
+    }
+}
+
+/// Names the executable packer whose signature appears in the header, if any.
+pub fn packer(buf: &[u8]) -> Option<&'static str> {
+    let at = |offset: usize, sig: &[u8]| {
+        buf.len() >= offset + sig.len() && &buf[offset..offset + sig.len()] == sig
+    };
+    if at(0x1C, b"LZ09") {
+        Some("LZEXE 0.90")
+    } else if at(0x1C, b"LZ91") {
+        Some("LZEXE 0.91")
+    } else if at(0x1E, b"PK") {
+        Some("PKLITE")
+    } else {
+        None
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use {Error, Result};
+
+/// What to do when the host name for an extracted entry is taken, by a file
+/// already on the host or by an earlier entry of the same extraction whose
+/// name differs only in case. Case-insensitive hosts (Windows, macOS) would
+/// write both to the same file, so case twins count as collisions
+/// everywhere.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Collision {
+    /// Stop with `AlreadyExists`.
+    Error,
+    /// Leave the entry out.
+    Skip,
+    /// Add `_2`, `_3`, ... to the name until it is free.
+    Suffix,
+    /// Replace files already on the host. Case twins within one extraction
+    /// still get a suffix, so nothing extracted is overwritten.
+    Overwrite,
+}
+impl Collision {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "error" => Some(Collision::Error),
+            "skip" => Some(Collision::Skip),
+            "suffix" => Some(Collision::Suffix),
+            "overwrite" => Some(Collision::Overwrite),
+            _ => None,
+        }
+    }
+}
+
+/// Device names Windows reserves in every directory, whatever the extension.
+const RESERVED: &[&str] = &["CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6",
+                            "COM7", "COM8", "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6",
+                            "LPT7", "LPT8", "LPT9"];
+
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.find('.') {
+        Some(dot) => (&name[..dot], &name[dot..]),
+        None => (name, ""),
+    }
+}
+
+/// The name an entry gets on the host: always a single path component.
+/// Images can be crafted, and an entry named `..`, `/ETC` or `A\\B` must
+/// not reach outside the directory it is extracted into, so separators,
+/// characters Windows forbids and control characters become `_`, as does a
+/// name made only of dots. Reserved device names get a `_` after the stem
+/// (`CON.TXT` becomes `CON_.TXT`), so the extracted tree can be copied to
+/// Windows.
+pub fn host_name(name: &str) -> String {
+    let mut name: String = name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    if name.chars().all(|c| c == '.') {
+        name = name.replace('.', "_") + "_";
+    }
+    let (stem, extension) = split_extension(&name);
+    if RESERVED.iter().any(|reserved| stem.eq_ignore_ascii_case(reserved)) {
+        format!("{}_{}", stem, extension)
+    } else {
+        name
+    }
+}
+
+/// Creates the file at `path` for an extracted entry, replacing whatever is
+/// there. A symbolic link in its place is removed rather than followed, so
+/// a link planted in the target directory can't redirect the write.
+pub fn create_file(path: &Path) -> io::Result<File> {
+    if fs::symlink_metadata(path).is_ok_and(|m| !m.is_dir()) {
+        fs::remove_file(path)?;
+    }
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+/// Creates the directory at `path` for an extracted directory, or reuses a
+/// real directory already there. A symbolic link is refused, even one to a
+/// directory, since following it would put the files somewhere else.
+pub fn create_dir(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(ref m) if m.file_type().is_symlink() => {
+            Err(io::Error::other(format!("{}: is a symbolic link; not following it", path.display())))
+        }
+        Ok(ref m) if m.is_dir() => Ok(()),
+        Ok(_) => Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                    format!("{}: exists and is not a directory", path.display()))),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => fs::create_dir(path),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether `path`, or a name differing from it only in case, exists, as it
+/// would on a case-insensitive host.
+fn exists_ignoring_case(path: &Path) -> bool {
+    if path.exists() {
+        return true;
+    }
+    let (parent, name) = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => (parent, name.to_string_lossy()),
+        _ => return false,
+    };
+    let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+    match fs::read_dir(parent) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok())
+            .any(|entry| entry.file_name().to_string_lossy().eq_ignore_ascii_case(&name)),
+        Err(_) => false,
+    }
+}
+
+/// Picks host paths for the entries of one extraction, applying a
+/// `Collision` policy.
+pub struct Placer {
+    policy: Collision,
+    /// Paths handed out so far, lower-cased.
+    taken: HashSet<PathBuf>,
+    placed: Vec<PathBuf>,
+}
+impl Placer {
+    pub fn new(policy: Collision) -> Self {
+        Placer { policy, taken: HashSet::new(), placed: Vec::new() }
+    }
+
+    /// The paths handed out so far, in order.
+    pub fn placed(&self) -> &[PathBuf] {
+        &self.placed
+    }
+
+    fn is_taken(&self, path: &Path) -> bool {
+        self.taken.contains(&PathBuf::from(path.to_string_lossy().to_lowercase()))
+    }
+
+    fn take(&mut self, path: &Path) {
+        self.taken.insert(PathBuf::from(path.to_string_lossy().to_lowercase()));
+    }
+
+    /// The host path to write to for `path`, or `None` if the policy skips
+    /// it.
+    pub fn place(&mut self, path: &Path) -> Result<Option<PathBuf>> {
+        let twin = self.is_taken(path);
+        let exists = exists_ignoring_case(path) && self.policy != Collision::Overwrite;
+        let path = if !twin && !exists {
+            path.to_path_buf()
+        } else {
+            match self.policy {
+                Collision::Error => return Err(Error::AlreadyExists(path.display().to_string())),
+                Collision::Skip => {
+                    // The host file stays, so later case twins collide with it.
+                    self.take(path);
+                    return Ok(None);
+                }
+                Collision::Suffix | Collision::Overwrite => self.suffixed(path),
+            }
+        };
+        self.take(&path);
+        self.placed.push(path.clone());
+        Ok(Some(path))
+    }
+
+    /// The first free `NAME_n.EXT` next to `path`.
+    fn suffixed(&self, path: &Path) -> PathBuf {
+        let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let (stem, extension) = split_extension(&name);
+        (2..)
+            .map(|n| path.with_file_name(format!("{}_{}{}", stem, n, extension)))
+            .find(|candidate| !self.is_taken(candidate) && !exists_ignoring_case(candidate))
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::{Read, Write};
+    use std::process;
+
+    /// An empty directory of its own under the system's temporary directory.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("fat12-extract-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn host_name_is_one_component() {
+        assert_eq!(host_name("README.TXT"), "README.TXT");
+        assert_eq!(host_name("."), "__");
+        assert_eq!(host_name(".."), "___");
+        assert_eq!(host_name(""), "_");
+        assert_eq!(host_name("/ETC"), "_ETC");
+        assert_eq!(host_name("../../X"), ".._.._X");
+        assert_eq!(host_name("A\\B"), "A_B");
+        assert_eq!(host_name("C:AUTOEXEC"), "C_AUTOEXEC");
+        assert_eq!(host_name("A\u{0}B\u{1b}.\u{7f}"), "A_B_._");
+        for name in &[".", "..", "/ETC", "../../X", "A\\B", "A/B"] {
+            let path = Path::new("out").join(host_name(name));
+            assert_eq!(path.parent(), Some(Path::new("out")), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn host_name_avoids_reserved_names() {
+        assert_eq!(host_name("CON.TXT"), "CON_.TXT");
+        assert_eq!(host_name("nul"), "nul_");
+        assert_eq!(host_name("CONFIG.SYS"), "CONFIG.SYS");
+    }
+
+    #[test]
+    fn placer_applies_policy() {
+        let dir = scratch("placer");
+        File::create(dir.join("A.TXT")).unwrap();
+
+        assert!(Placer::new(Collision::Error).place(&dir.join("a.txt")).is_err());
+        assert_eq!(Placer::new(Collision::Skip).place(&dir.join("A.TXT")).unwrap(), None);
+        assert_eq!(Placer::new(Collision::Overwrite).place(&dir.join("A.TXT")).unwrap(),
+                   Some(dir.join("A.TXT")));
+
+        let mut placer = Placer::new(Collision::Suffix);
+        assert_eq!(placer.place(&dir.join("A.TXT")).unwrap(), Some(dir.join("A_2.TXT")));
+        assert_eq!(placer.place(&dir.join("B.TXT")).unwrap(), Some(dir.join("B.TXT")));
+        assert_eq!(placer.place(&dir.join("b.txt")).unwrap(), Some(dir.join("b_2.txt")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn create_file_replaces_files() {
+        let dir = scratch("replace");
+        let path = dir.join("A.TXT");
+        fs::write(&path, b"old contents").unwrap();
+        create_file(&path).unwrap().write_all(b"new").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert!(create_file(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn links_are_not_followed() {
+        use std::os::unix::fs::symlink;
+
+        let dir = scratch("links");
+        let outside = dir.join("outside");
+        fs::create_dir(&outside).unwrap();
+        let victim = outside.join("VICTIM");
+        fs::write(&victim, b"keep").unwrap();
+        let target = dir.join("target");
+        fs::create_dir(&target).unwrap();
+
+        symlink(&victim, target.join("A.TXT")).unwrap();
+        create_file(&target.join("A.TXT")).unwrap().write_all(b"evil").unwrap();
+        let mut kept = String::new();
+        File::open(&victim).unwrap().read_to_string(&mut kept).unwrap();
+        assert_eq!(kept, "keep");
+        assert!(!fs::symlink_metadata(target.join("A.TXT")).unwrap().file_type().is_symlink());
+
+        symlink(&outside, target.join("SUB")).unwrap();
+        assert!(create_dir(&target.join("SUB")).is_err());
+        create_dir(&target.join("NEW")).unwrap();
+        create_dir(&target.join("NEW")).unwrap();
+        assert!(create_dir(&target.join("A.TXT")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,66 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use backup::sha256_hex;
+use {Warning, Warnings};
+
+/// A sidecar holding the hash of an image's metadata regions (boot sector,
+/// FATs and root directory) as this tool last wrote them. If the image's
+/// metadata hashes differently later, something else has written to it.
+fn sidecar_path(image_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.f12fp", image_path))
+}
+
+/// Fingerprints are kept once asked for with `--fingerprint`, and from then
+/// on whenever the sidecar exists.
+pub fn enabled(image_path: &str, args: &[String]) -> bool {
+    args.iter().any(|a| a == "--fingerprint") || sidecar_path(image_path).exists()
+}
+
+/// Warns to `warnings` if the image's metadata no longer matches the
+/// fingerprint left by the last write. Does nothing when there is no
+/// fingerprint.
+pub fn check(image_path: &str, metadata: &[u8], warnings: &Warnings) -> io::Result<()> {
+    let mut recorded = String::new();
+    match File::open(sidecar_path(image_path)) {
+        Ok(mut file) => file.read_to_string(&mut recorded)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if recorded.trim() != sha256_hex(metadata) {
+        warnings.warn(Warning::ChangedElsewhere(image_path.to_string()));
+    }
+    Ok(())
+}
+
+pub fn update(image_path: &str, metadata: &[u8]) -> io::Result<()> {
+    writeln!(File::create(sidecar_path(image_path))?, "{}", sha256_hex(metadata))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn metadata_changed_since_the_last_write_is_warned_of() {
+        let image = env::temp_dir().join(format!("fat12-{}-fingerprint.img", std::process::id()));
+        let image = image.to_str().unwrap();
+        let warnings = Warnings::default();
+        let mut metadata = vec![0xF0; 1024];
+        // Nothing to compare against until a fingerprint is asked for.
+        check(image, &metadata, &warnings).unwrap();
+        assert!(!enabled(image, &[]));
+        assert!(enabled(image, &["--fingerprint".to_string()]));
+
+        update(image, &metadata).unwrap();
+        assert!(enabled(image, &[]));
+        check(image, &metadata, &warnings).unwrap();
+        assert_eq!(warnings.take(), []);
+        metadata[512] = 0xF8;
+        check(image, &metadata, &warnings).unwrap();
+        assert_eq!(warnings.take(), [Warning::ChangedElsewhere(image.to_string())]);
+        fs::remove_file(sidecar_path(image)).unwrap();
+    }
+}
@@ -0,0 +1,133 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use geometry::{self, Geometry};
+
+const DIR_ENTRY_SIZE: u64 = 32;
+/// Characters of a long name held by each LFN directory entry.
+const LFN_CHARS_PER_ENTRY: usize = 13;
+
+/// A host file and the space it would take on the target volume.
+pub struct Candidate {
+    pub path: PathBuf,
+    pub size: u64,
+    pub clusters: u64,
+}
+
+/// The space a host directory tree would need on a given geometry.
+pub struct Plan {
+    pub cluster_size: u64,
+    pub data_clusters: u64,
+    pub clusters_needed: u64,
+    pub root_slots: u64,
+    pub root_slots_needed: u64,
+    pub files: Vec<Candidate>,
+}
+impl Plan {
+    pub fn fits(&self) -> bool {
+        self.clusters_needed <= self.data_clusters && self.root_slots_needed <= self.root_slots
+    }
+
+    /// Bytes that would have to be freed for the tree to fit the data area.
+    pub fn shortfall(&self) -> u64 {
+        self.clusters_needed.saturating_sub(self.data_clusters) * self.cluster_size
+    }
+}
+
+/// Whether a name can be stored as a plain 8.3 entry without an LFN chain.
+pub fn is_short_name(name: &str) -> bool {
+    let (base, ext) = match name.rfind('.') {
+        Some(0) => return false,
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    !base.is_empty() && base.len() <= 8 && ext.len() <= 3 && (base.chars().chain(ext.chars())).all(is_short_name_char)
+}
+
+/// Whether DOS allows `c` in an 8.3 name.
+pub fn is_short_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'()-@^_`{}~".contains(c)
+}
+
+/// Directory slots an entry takes: one for the short entry plus any LFN entries.
+fn slots_for(name: &str) -> u64 {
+    if is_short_name(name) {
+        1
+    } else {
+        1 + name.encode_utf16().count().div_ceil(LFN_CHARS_PER_ENTRY) as u64
+    }
+}
+
+/// Adds up the clusters `dir`'s contents need, not counting `dir`'s own
+/// entries, and returns the slots those entries take in `dir`.
+fn walk(dir: &Path, cluster_size: u64, plan: &mut Plan) -> io::Result<u64> {
+    let mut slots = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        slots += slots_for(&name);
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            // A subdirectory holds "." and ".." besides its own entries.
+            let sub_slots = 2 + walk(&entry.path(), cluster_size, plan)?;
+            plan.clusters_needed += (sub_slots * DIR_ENTRY_SIZE).div_ceil(cluster_size);
+        } else {
+            let clusters = metadata.len().div_ceil(cluster_size);
+            plan.clusters_needed += clusters;
+            plan.files.push(Candidate { path: entry.path(), size: metadata.len(), clusters });
+        }
+    }
+    Ok(slots)
+}
+
+/// Works out whether the tree under `dir` would fit on a fresh volume with
+/// `geometry`, accounting for cluster rounding, directory clusters and slots
+/// taken by long names.
+pub fn plan(dir: &Path, geometry: &Geometry) -> io::Result<Plan> {
+    let cluster_size = geometry.sectors_per_cluster as u64 * geometry::SECTOR_SIZE as u64;
+    let data_sectors = geometry.total_sectors as u64 - geometry.root_dir_sector() as u64 -
+                       geometry.root_dir_sectors() as u64;
+    let mut plan = Plan {
+        cluster_size,
+        data_clusters: data_sectors / geometry.sectors_per_cluster as u64,
+        clusters_needed: 0,
+        root_slots: geometry.root_dir_entries as u64,
+        root_slots_needed: 0,
+        files: Vec::new(),
+    };
+    plan.root_slots_needed = walk(dir, cluster_size, &mut plan)?;
+    plan.files.sort_by(|a, b| b.clusters.cmp(&a.clusters).then(a.path.cmp(&b.path)));
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn plans_count_cluster_rounding_directories_and_long_names() {
+        let dir = env::temp_dir().join(format!("fat12-{}-fits", std::process::id()));
+        fs::create_dir_all(dir.join("SUB")).unwrap();
+        fs::write(dir.join("A.TXT"), "a").unwrap();
+        fs::write(dir.join("Long file name.txt"), [0; 600]).unwrap();
+        for n in 0..20 {
+            fs::write(dir.join(format!("SUB/F{}.TXT", n)), "f").unwrap();
+        }
+
+        // SUB's 22 slots take two clusters of 512 bytes and the long name
+        // three slots of the root.
+        let plan1 = plan(&dir, geometry::by_name("1.44M").unwrap()).unwrap();
+        assert_eq!((plan1.clusters_needed, plan1.root_slots_needed), (1 + 2 + 2 + 20, 1 + 3 + 1));
+        assert!(plan1.fits());
+        let plan2 = plan(&dir, geometry::by_name("360K").unwrap()).unwrap();
+        assert_eq!(plan2.clusters_needed, 1 + 1 + 1 + 20);
+
+        fs::write(dir.join("BIG.BIN"), vec![0; 400 * 1024]).unwrap();
+        let plan3 = plan(&dir, geometry::by_name("360K").unwrap()).unwrap();
+        assert!(!plan3.fits());
+        assert_eq!(plan3.shortfall(), (400 + 23 - plan3.data_clusters) * 1024);
+        assert_eq!(plan3.files[0].path, dir.join("BIG.BIN"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,229 @@
+use std::io::Cursor;
+use byteorder::{ByteOrder, LittleEndian};
+use chrono::{NaiveDate, NaiveDateTime};
+use {format, geometry, make_dir, new_entry_at, read_disk_info, rm, split_path, store_file, DirEntryAttributes,
+     DiskInfo, Result, DIR_ENTRY_CREATETIME_FINE, DIR_ENTRY_LASTACCESS, OS_NAME,
+     VOLUME_ID};
+
+/// The kinds of test image `build` makes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Profile {
+    /// A 1.44M disk of long names: aliases up to `~4` and past, names of
+    /// many LFN slots, non-ASCII names, several dots, and long-named
+    /// directories.
+    LfnHeavy,
+    /// A 1.44M disk with directories nested 20 deep, a file at each level,
+    /// and a directory of 200 files spanning many clusters.
+    DeepTree,
+    /// A 720K disk whose files were written into the holes left by deleted
+    /// ones, so their chains jump about.
+    Fragmented,
+    /// A 160K single-sided disk as PC DOS 1.0 left it: no extended BPB, no
+    /// subdirectories, no times of day and only 8.3 names.
+    Dos1x,
+}
+
+pub const PROFILES: &[Profile] = &[Profile::LfnHeavy, Profile::DeepTree, Profile::Fragmented, Profile::Dos1x];
+
+impl Profile {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "lfn-heavy" => Some(Profile::LfnHeavy),
+            "deep-tree" => Some(Profile::DeepTree),
+            "fragmented" => Some(Profile::Fragmented),
+            "dos1x" => Some(Profile::Dos1x),
+            _ => None,
+        }
+    }
+}
+
+/// The serial number every fixture gets, in place of one from the clock.
+const SERIAL: u32 = 0x1234_ABCD;
+
+/// An image being built in memory, with every entry stamped `time`.
+struct Builder {
+    image: Cursor<Vec<u8>>,
+    info: DiskInfo,
+    time: NaiveDateTime,
+}
+impl Builder {
+    fn new(geometry_name: &str, time: NaiveDateTime) -> Result<Self> {
+        let mut image = Cursor::new(Vec::new());
+        format(&mut image, geometry::by_name(geometry_name).expect("a standard geometry"))?;
+        LittleEndian::write_u32(&mut image.get_mut()[VOLUME_ID..], SERIAL);
+        let info = read_disk_info(&mut image)?;
+        Ok(Builder { image, info, time })
+    }
+
+    /// Stores a file whose contents are its path over and over, so a
+    /// misplaced cluster is easy to spot.
+    fn file(&mut self, path: &str, size: usize, attributes: u8) -> Result<()> {
+        self.file_with(path, size, attributes, |_| ())
+    }
+
+    /// `file`, with `adjust` given the entry to change before it is written.
+    fn file_with<A>(&mut self, path: &str, size: usize, attributes: u8, adjust: A) -> Result<()>
+        where A: FnOnce(&mut [u8])
+    {
+        let data: Vec<u8> = format!("{} ", path).bytes().cycle().take(size).collect();
+        let time = self.time;
+        let name = split_path(path).1;
+        store_file(&self.info, &mut self.image, path, name, attributes, &data, |short, attributes, first| {
+            let mut entry = new_entry_at(short, attributes, first, size as u32, time, time);
+            adjust(&mut entry);
+            entry
+        })?;
+        Ok(())
+    }
+
+    fn dir(&mut self, path: &str) -> Result<()> {
+        make_dir(&self.info, &mut self.image, path, self.time)
+    }
+
+    fn rm(&mut self, path: &str) -> Result<()> {
+        rm(&self.info, &mut self.image, path).map(|_| ())
+    }
+}
+
+fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(year, month, day).and_then(|date| date.and_hms_opt(hour, minute, 0)).unwrap()
+}
+
+/// Builds the image for `profile`. The same profile gives the same bytes
+/// every time, on any machine.
+pub fn build(profile: Profile) -> Result<Vec<u8>> {
+    let archive = DirEntryAttributes::Archive as u8;
+    let builder = match profile {
+        Profile::LfnHeavy => {
+            let mut b = Builder::new("1.44M", at(1999, 12, 31, 23, 59))?;
+            for year in 1999..2011 {
+                b.file(&format!("/Annual Report {}.doc", year), 1500, archive)?;
+            }
+            b.file("/A very long file name that takes several LFN slots, as Windows 95 allows up to 255 \
+                    characters.txt",
+                   300,
+                   archive)?;
+            b.file("/Résumé (final).txt", 400, archive)?;
+            b.file("/archive.tar.gz", 2000, archive)?;
+            b.file("/.profile", 100, archive)?;
+            b.file("/lower.txt", 50, archive)?;
+            b.dir("/Program Files")?;
+            b.file("/Program Files/Read Me First.txt", 700, archive)?;
+            b.dir("/Program Files/Sub Folder With A Long Name")?;
+            b.file("/Program Files/Sub Folder With A Long Name/Notes about this folder.txt", 90, archive)?;
+            b
+        }
+        Profile::DeepTree => {
+            let mut b = Builder::new("1.44M", at(2003, 6, 1, 9, 30))?;
+            let mut path = String::new();
+            for depth in 1..21 {
+                path = format!("{}/LEVEL{:02}", path, depth);
+                b.dir(&path)?;
+                b.file(&format!("{}/FILE.TXT", path), 64 * depth, archive)?;
+            }
+            b.dir("/WIDE")?;
+            for n in 0..200 {
+                b.file(&format!("/WIDE/F{:03}.TXT", n), 10 + n, archive)?;
+            }
+            b
+        }
+        Profile::Fragmented => {
+            let mut b = Builder::new("720K", at(1994, 3, 14, 15, 9))?;
+            for n in 0..24 {
+                b.file(&format!("/PIECE{:02}.BIN", n), 3000, archive)?;
+            }
+            for n in (0..24).step_by(2) {
+                b.rm(&format!("/PIECE{:02}.BIN", n))?;
+            }
+            // 40 clusters: the twelve 3-cluster holes, then 4 past the end.
+            b.file("/SCATTER.BIN", 40 * 1024 - 100, archive)?;
+            b.rm("/PIECE05.BIN")?;
+            b.rm("/PIECE13.BIN")?;
+            b.file("/REFILL.TXT", 5 * 1024, archive)?;
+            b
+        }
+        Profile::Dos1x => {
+            let mut b = Builder::new("160K", at(1981, 8, 12, 0, 0))?;
+            // DOS 1.0 kept no extended BPB: no serial, label or FS type.
+            let boot_sector = b.image.get_mut();
+            boot_sector[OS_NAME..OS_NAME + 8].copy_from_slice(b"IBM  1.0");
+            for byte in &mut boot_sector[0x24..0x3E] {
+                *byte = 0;
+            }
+            b.info = read_disk_info(&mut b.image)?;
+            let system = DirEntryAttributes::ReadOnly as u8 | DirEntryAttributes::Hidden as u8 |
+                         DirEntryAttributes::System as u8;
+            // Nor creation or access times; those bytes were reserved.
+            let dos1 = |entry: &mut [u8]| {
+                for byte in &mut entry[DIR_ENTRY_CREATETIME_FINE..DIR_ENTRY_LASTACCESS + 2] {
+                    *byte = 0;
+                }
+            };
+            b.file_with("/IBMBIO.COM", 1920, system, dos1)?;
+            b.file_with("/IBMDOS.COM", 6400, system, dos1)?;
+            for &(name, size) in &[("/COMMAND.COM", 3231), ("/FORMAT.COM", 2560), ("/CHKDSK.COM", 1395),
+                                   ("/BASIC.COM", 10880), ("/AUTOEXEC.BAT", 20)] {
+                b.file_with(name, size, 0, dos1)?;
+            }
+            b
+        }
+    };
+    Ok(builder.image.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {check, health, Fat12Volume};
+
+    fn volume(profile: Profile) -> Fat12Volume<Cursor<Vec<u8>>> {
+        Fat12Volume::open(Cursor::new(build(profile).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn fixtures_are_reproducible_and_clean() {
+        for &profile in PROFILES {
+            let image = build(profile).unwrap();
+            assert!(image == build(profile).unwrap(), "{:?}", profile);
+            let mut image = Cursor::new(image);
+            let info = read_disk_info(&mut image).unwrap();
+            assert!(check::check(&info, &mut image).unwrap().is_clean(), "{:?}", profile);
+        }
+    }
+
+    #[test]
+    fn lfn_heavy_has_long_names() {
+        let entries = volume(Profile::LfnHeavy).walk(false).unwrap();
+        let long = entries.iter().filter(|found| found.entry.long_name.is_some()).count();
+        // 20 in LFN slots, and lower.txt by its case flags.
+        assert_eq!(long, 21);
+        assert!(entries.iter().any(|found| found.path == "/ANNUAL~4.DOC"));
+        let longest = entries.iter().filter_map(|f| f.entry.long_name.as_ref().map(String::len)).max();
+        assert!(longest > Some(90));
+    }
+
+    #[test]
+    fn deep_tree_is_deep() {
+        let entries = volume(Profile::DeepTree).walk(false).unwrap();
+        let depth = entries.iter().map(|found| found.path.matches('/').count()).max();
+        assert_eq!(depth, Some(21));
+        assert_eq!(entries.iter().filter(|found| found.path.starts_with("/WIDE/")).count(), 200);
+    }
+
+    #[test]
+    fn fragmented_is_fragmented() {
+        let mut image = Cursor::new(build(Profile::Fragmented).unwrap());
+        let info = read_disk_info(&mut image).unwrap();
+        let fat = ::read_fat(&info, &mut image).unwrap();
+        let health = health::check(&info, &mut image, &fat[..]).unwrap();
+        assert_eq!(health.fragmented_files, 2);
+    }
+
+    #[test]
+    fn dos1x_has_no_extended_bpb() {
+        let mut volume = volume(Profile::Dos1x);
+        assert!(volume.info().boot_signature != 0x29);
+        let root = volume.root_dir().unwrap();
+        assert!(root.iter().all(|entry| entry.long_name.is_none() && entry.create_date == 0));
+    }
+}
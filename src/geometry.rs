@@ -0,0 +1,308 @@
+use byteorder::{LittleEndian, ByteOrder};
+use {BYTES_PER_SECTOR, SECTORS_PER_CLUSTER, RESERVED_SECTORS, FATS, ROOT_DIR_ENTRIES, TOTAL_SECTORS,
+     MEDIA_DESCRIPTOR, SECTORS_PER_FAT, SECTORS_PER_TRACK, HEADS, HIDDEN_SECTORS,
+     FAT32_TOTAL_SECTORS, DRIVE_NUMBER, BOOT_SIGNATURE, VOLUME_ID, VOLUME_LABEL, VOLUME_LABEL_SIZE,
+     FS_TYPE, FatType};
+
+/// The layout DOS FORMAT uses for a standard floppy size, or one worked out
+/// for another size by `for_size`. All of them have 512-byte sectors, one
+/// reserved sector and two FATs.
+#[derive(Clone, Debug)]
+pub struct Geometry {
+    pub name: &'static str,
+    pub total_sectors: u32,
+    pub media: u8,
+    pub sectors_per_track: u16,
+    pub heads: u16,
+    pub sectors_per_cluster: u8,
+    pub root_dir_entries: u16,
+    pub sectors_per_fat: u16,
+}
+impl Geometry {
+    pub fn size(&self) -> u64 {
+        self.total_sectors as u64 * SECTOR_SIZE as u64
+    }
+
+    pub fn root_dir_sectors(&self) -> u16 {
+        (self.root_dir_entries * 32).div_ceil(SECTOR_SIZE)
+    }
+
+    /// First sector of the root directory.
+    pub fn root_dir_sector(&self) -> u16 {
+        RESERVED_SECTOR_COUNT + FAT_COUNT as u16 * self.sectors_per_fat
+    }
+
+    /// Number of data clusters.
+    pub fn clusters(&self) -> u32 {
+        let metadata = self.root_dir_sector() as u32 + self.root_dir_sectors() as u32;
+        (self.total_sectors - metadata) / self.sectors_per_cluster as u32
+    }
+
+    /// FAT12 or FAT16, going by the cluster count as every FAT driver does.
+    pub fn fat_type(&self) -> FatType {
+        if self.clusters() < 4085 { FatType::Fat12 } else { FatType::Fat16 }
+    }
+}
+
+pub const SECTOR_SIZE: u16 = 512;
+pub const RESERVED_SECTOR_COUNT: u16 = 1;
+pub const FAT_COUNT: u8 = 2;
+
+pub const STANDARD: &[Geometry] = &[
+    Geometry {
+        name: "160K",
+        total_sectors: 320,
+        media: 0xFE,
+        sectors_per_track: 8,
+        heads: 1,
+        sectors_per_cluster: 1,
+        root_dir_entries: 64,
+        sectors_per_fat: 1,
+    },
+    Geometry {
+        name: "180K",
+        total_sectors: 360,
+        media: 0xFC,
+        sectors_per_track: 9,
+        heads: 1,
+        sectors_per_cluster: 1,
+        root_dir_entries: 64,
+        sectors_per_fat: 2,
+    },
+    Geometry {
+        name: "320K",
+        total_sectors: 640,
+        media: 0xFF,
+        sectors_per_track: 8,
+        heads: 2,
+        sectors_per_cluster: 2,
+        root_dir_entries: 112,
+        sectors_per_fat: 1,
+    },
+    Geometry {
+        name: "360K",
+        total_sectors: 720,
+        media: 0xFD,
+        sectors_per_track: 9,
+        heads: 2,
+        sectors_per_cluster: 2,
+        root_dir_entries: 112,
+        sectors_per_fat: 2,
+    },
+    Geometry {
+        name: "720K",
+        total_sectors: 1440,
+        media: 0xF9,
+        sectors_per_track: 9,
+        heads: 2,
+        sectors_per_cluster: 2,
+        root_dir_entries: 112,
+        sectors_per_fat: 3,
+    },
+    Geometry {
+        name: "1.2M",
+        total_sectors: 2400,
+        media: 0xF9,
+        sectors_per_track: 15,
+        heads: 2,
+        sectors_per_cluster: 1,
+        root_dir_entries: 224,
+        sectors_per_fat: 7,
+    },
+    Geometry {
+        name: "1.44M",
+        total_sectors: 2880,
+        media: 0xF0,
+        sectors_per_track: 18,
+        heads: 2,
+        sectors_per_cluster: 1,
+        root_dir_entries: 224,
+        sectors_per_fat: 9,
+    },
+    Geometry {
+        name: "2.88M",
+        total_sectors: 5760,
+        media: 0xF0,
+        sectors_per_track: 36,
+        heads: 2,
+        sectors_per_cluster: 2,
+        root_dir_entries: 240,
+        sectors_per_fat: 9,
+    },
+];
+
+/// Sector counts up to which FAT16 volumes use a cluster size, after the
+/// table Microsoft gives in its FAT specification.
+const FAT16_CLUSTER_SIZES: &[(u32, u8)] = &[(32_680, 2), (262_144, 4), (524_288, 8), (1_048_576, 16),
+                                           (2_097_152, 32), (4_194_304, 64)];
+/// Volumes up to this many sectors, 4.1 MB, are made FAT12.
+const FAT12_MAX_SECTORS: u32 = 8400;
+
+/// The geometry for an image of `size` bytes: the standard floppy one if
+/// there is one, otherwise a hard-disk-style layout (media 0xF8) with FAT12
+/// up to 4.1 MB and FAT16 up to 2 GB. `None` for sizes that aren't whole
+/// sectors, are too small to hold a FAT volume, or would need FAT32.
+pub fn for_size(size: u64) -> Option<Geometry> {
+    if let Some(geometry) = by_size(size) {
+        return Some(geometry.clone());
+    }
+    if !size.is_multiple_of(SECTOR_SIZE as u64) || size > u32::MAX as u64 * SECTOR_SIZE as u64 {
+        return None;
+    }
+    let total_sectors = (size / SECTOR_SIZE as u64) as u32;
+    let (sectors_per_cluster, root_dir_entries) = if total_sectors <= FAT12_MAX_SECTORS {
+        // The smallest clusters that keep the count within FAT12's.
+        let spc = [1, 2, 4, 8, 16, 32, 64].iter().cloned().find(|&spc| {
+            total_sectors / (spc as u32) < 4085 - 2
+        })?;
+        (spc, 224)
+    } else {
+        let &(_, spc) = FAT16_CLUSTER_SIZES.iter().find(|&&(max, _)| total_sectors <= max)?;
+        (spc, 512)
+    };
+    let mut geometry = Geometry {
+        name: "custom",
+        total_sectors,
+        media: 0xF8,
+        sectors_per_track: 63,
+        heads: if total_sectors <= 1024 * 16 * 63 { 16 } else { 255 },
+        sectors_per_cluster,
+        root_dir_entries,
+        sectors_per_fat: 1,
+    };
+    // The FAT has to cover the clusters left once it takes its own room, so
+    // grow it until it does.
+    loop {
+        let used = geometry.root_dir_sector() as u32 + geometry.root_dir_sectors() as u32;
+        if used + sectors_per_cluster as u32 > total_sectors {
+            return None;
+        }
+        let entry_bits = if total_sectors <= FAT12_MAX_SECTORS { 12 } else { 16 };
+        let fat_bytes = (geometry.clusters() as u64 + 2) * entry_bits / 8 + 1;
+        let needed = fat_bytes.div_ceil(SECTOR_SIZE as u64) as u16;
+        if needed <= geometry.sectors_per_fat {
+            break;
+        }
+        geometry.sectors_per_fat = needed;
+    }
+    Some(geometry)
+}
+
+/// Looks up a standard geometry by image size in bytes.
+pub fn by_size(size: u64) -> Option<&'static Geometry> {
+    STANDARD.iter().find(|g| g.size() == size)
+}
+
+/// Looks up a standard geometry by name, e.g. `1.44M`, or by its size in
+/// kilobytes, e.g. `1440k`.
+pub fn by_name(name: &str) -> Option<&'static Geometry> {
+    let name = name.to_lowercase();
+    STANDARD.iter().find(|g| g.name.to_lowercase() == name || format!("{}k", g.size() / 1024) == name)
+}
+
+/// Evidence gathered while guessing an image's geometry.
+pub struct Recovery {
+    pub geometry: &'static Geometry,
+    pub fat_signatures: usize,
+    pub root_dir_plausible: bool,
+}
+
+/// Checks whether a FAT starting at `offset` opens with the media byte
+/// followed by two 0xFF bytes, as every FAT12 does.
+pub(crate) fn has_fat_signature(image: &[u8], offset: usize, media: u8) -> bool {
+    image.get(offset..offset + 3) == Some(&[media, 0xFF, 0xFF][..])
+}
+
+/// Whether a 32-byte slot could be a directory entry: free, deleted, or a
+/// name made of characters DOS allows, with no undefined attribute bits.
+pub(crate) fn plausible_dir_entry(slot: &[u8]) -> bool {
+    if slot[0] == 0x00 || slot[0] == 0xE5 {
+        return true;
+    }
+    if slot[11] & 0xC0 != 0 {
+        return false;
+    }
+    // Long file name entries hold UCS-2 text, not an 8.3 name.
+    if slot[11] & 0x3F == 0x0F {
+        return true;
+    }
+    slot[..11].iter().all(|&c| c >= 0x20 && !b"\"*+,/:;<=>?[\\]|".contains(&c))
+}
+
+/// Infers the geometry of an image whose boot sector can't be trusted, from
+/// its size and the FAT and root directory at the places it implies.
+pub fn recover(image: &[u8]) -> Option<Recovery> {
+    let geometry = by_size(image.len() as u64)?;
+    let sector = SECTOR_SIZE as usize;
+    let fat_signatures = (0..FAT_COUNT as usize)
+        .filter(|n| {
+            let offset = (RESERVED_SECTOR_COUNT as usize + n * geometry.sectors_per_fat as usize) * sector;
+            has_fat_signature(image, offset, geometry.media)
+        })
+        .count();
+    let root_start = geometry.root_dir_sector() as usize * sector;
+    let root_end = root_start + geometry.root_dir_sectors() as usize * sector;
+    let root_dir_plausible = image[root_start..root_end]
+        .chunks(32)
+        .take_while(|slot| slot[0] != 0x00)
+        .all(plausible_dir_entry);
+    Some(Recovery { geometry, fat_signatures, root_dir_plausible })
+}
+
+/// Builds a FAT12 or FAT16 boot sector for `geometry`. The boot code is a stub that
+/// hands back to the BIOS, since a rebuilt sector can't know the original.
+pub fn boot_sector(geometry: &Geometry) -> [u8; 512] {
+    let mut sector = [0u8; 512];
+    write_bpb(&mut sector, geometry);
+    write_boot_stub(&mut sector, 0x3E);
+    sector
+}
+
+/// Writes the jump, OEM name and a boot code stub that hands back to the
+/// BIOS into a boot sector whose code starts at `code_start`: right after
+/// the extended BPB, which is 0x3E, or 0x5A on FAT32. The rest of the code
+/// area is cleared and the 0x55AA signature set.
+pub fn write_boot_stub(sector: &mut [u8], code_start: usize) {
+    sector[..3].copy_from_slice(&[0xEB, (code_start - 2) as u8, 0x90]);
+    sector[3..11].copy_from_slice(b"MSDOS5.0");
+    for byte in &mut sector[code_start..510] {
+        *byte = 0;
+    }
+    // int 18h, then spin in case the BIOS returns.
+    sector[code_start..code_start + 4].copy_from_slice(&[0xCD, 0x18, 0xEB, 0xFE]);
+    sector[510] = 0x55;
+    sector[511] = 0xAA;
+}
+
+/// Writes the BIOS parameter block and extended boot record fields for
+/// `geometry` into a boot sector, leaving the jump, OEM name and code alone.
+pub fn write_bpb(sector: &mut [u8], geometry: &Geometry) {
+    LittleEndian::write_u16(&mut sector[BYTES_PER_SECTOR..], SECTOR_SIZE);
+    sector[SECTORS_PER_CLUSTER] = geometry.sectors_per_cluster;
+    LittleEndian::write_u16(&mut sector[RESERVED_SECTORS..], RESERVED_SECTOR_COUNT);
+    sector[FATS] = FAT_COUNT;
+    LittleEndian::write_u16(&mut sector[ROOT_DIR_ENTRIES..], geometry.root_dir_entries);
+    // Counts too big for 16 bits go in the 32-bit field instead.
+    if geometry.total_sectors <= 0xFFFF {
+        LittleEndian::write_u16(&mut sector[TOTAL_SECTORS..], geometry.total_sectors as u16);
+        LittleEndian::write_u32(&mut sector[FAT32_TOTAL_SECTORS..], 0);
+    } else {
+        LittleEndian::write_u16(&mut sector[TOTAL_SECTORS..], 0);
+        LittleEndian::write_u32(&mut sector[FAT32_TOTAL_SECTORS..], geometry.total_sectors);
+    }
+    sector[MEDIA_DESCRIPTOR] = geometry.media;
+    LittleEndian::write_u16(&mut sector[SECTORS_PER_FAT..], geometry.sectors_per_fat);
+    LittleEndian::write_u16(&mut sector[SECTORS_PER_TRACK..], geometry.sectors_per_track);
+    LittleEndian::write_u16(&mut sector[HEADS..], geometry.heads);
+    LittleEndian::write_u32(&mut sector[HIDDEN_SECTORS..], 0);
+    // BIOS drive 0x80 is the first hard disk, 0 the first floppy.
+    sector[DRIVE_NUMBER] = if geometry.media == 0xF8 { 0x80 } else { 0 };
+    sector[BOOT_SIGNATURE] = 0x29;
+    LittleEndian::write_u32(&mut sector[VOLUME_ID..], 0);
+    sector[VOLUME_LABEL..VOLUME_LABEL + VOLUME_LABEL_SIZE].copy_from_slice(b"NO NAME    ");
+    let fs_type: &[u8; 8] = match geometry.fat_type() {
+        FatType::Fat12 => b"FAT12   ",
+        _ => b"FAT16   ",
+    };
+    sector[FS_TYPE..FS_TYPE + 8].copy_from_slice(fs_type);
+}
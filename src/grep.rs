@@ -0,0 +1,241 @@
+use std::io::{self, Read, Seek, Write};
+use view;
+use {copy_file, for_each_entry, DirEntryAttributes, DiskInfo, FatTable, Result};
+
+/// Whether `name` matches the DOS-style wildcard `pattern`, where `*` stands
+/// for any run of characters and `?` for any one. Case is ignored, as DOS
+/// ignores it.
+pub fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_uppercase().chars().collect();
+    let name: Vec<char> = name.to_uppercase().chars().collect();
+    // The name position to go back to after the last `*`, and its pattern's.
+    let (mut p, mut n, mut star) = (0, 0, None);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// What `grep` looks for, and in which files.
+#[derive(Clone, Debug, Default)]
+pub struct GrepOptions {
+    pub ignore_case: bool,
+    /// Wildcards a file's long or short name has to match, e.g. `*.SYS`; any
+    /// file is searched if there are none.
+    pub include: Vec<String>,
+}
+
+/// A line of a file holding the text searched for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Match {
+    pub path: String,
+    /// The line's number, from 1.
+    pub line: usize,
+    pub text: String,
+}
+
+/// Searches the contents of every file in the volume for `pattern`, read
+/// straight from the image, and passes each line holding it to `found`.
+/// Files are read as DOS text in code page 437 and split into lines at LF,
+/// with the CR of a CR LF dropped and a Ctrl-Z ending the file. Files that
+/// `view::is_text` takes for binary are skipped. Returns how many files were
+/// searched.
+pub fn grep<R, F>(info: &DiskInfo,
+                  disk_file: &mut R,
+                  fat: &F,
+                  pattern: &str,
+                  options: &GrepOptions,
+                  found: &mut dyn FnMut(Match))
+                  -> Result<usize>
+    where R: Read + Seek,
+          F: FatTable + ?Sized
+{
+    let pattern = if options.ignore_case { pattern.to_lowercase() } else { pattern.to_string() };
+    let mut searched = 0;
+    for_each_entry(info, disk_file, fat, false, |disk_file, found_entry| {
+        let entry = &found_entry.entry;
+        if entry.attributes & DirEntryAttributes::SubDir as u8 != 0 {
+            return Ok(());
+        }
+        let included = options.include.is_empty() ||
+                       options.include.iter().any(|include| {
+                           entry.long_name.as_ref().is_some_and(|name| wildcard_match(include, name)) ||
+                           wildcard_match(include, &entry.name())
+                       });
+        if !included {
+            return Ok(());
+        }
+        let mut lines = Lines::new(&found_entry.path, &pattern, options.ignore_case, found);
+        copy_file(info, disk_file, fat, entry, &mut lines)?;
+        if lines.finish() {
+            searched += 1;
+        }
+        Ok(())
+    })?;
+    Ok(searched)
+}
+
+/// A sink for a file's contents that searches it a line at a time, holding
+/// back the first `view::SNIFF_SIZE` bytes until it knows the file is text.
+struct Lines<'a> {
+    path: &'a str,
+    pattern: &'a str,
+    ignore_case: bool,
+    found: &'a mut dyn FnMut(Match),
+    /// `None` until enough has been seen to tell.
+    text: Option<bool>,
+    head: Vec<u8>,
+    line: Vec<u8>,
+    number: usize,
+    /// Set at a Ctrl-Z, after which nothing more is read.
+    ended: bool,
+}
+impl<'a> Lines<'a> {
+    fn new(path: &'a str, pattern: &'a str, ignore_case: bool, found: &'a mut dyn FnMut(Match)) -> Self {
+        Lines {
+            path,
+            pattern,
+            ignore_case,
+            found,
+            text: None,
+            head: Vec::new(),
+            line: Vec::new(),
+            number: 0,
+            ended: false,
+        }
+    }
+
+    fn feed(&mut self, data: &[u8]) {
+        for &byte in data {
+            if self.ended {
+                return;
+            }
+            match byte {
+                b'\n' => self.end_line(),
+                0x1A => self.ended = true,
+                _ => self.line.push(byte),
+            }
+        }
+    }
+
+    fn end_line(&mut self) {
+        self.number += 1;
+        if self.line.last() == Some(&b'\r') {
+            self.line.pop();
+        }
+        let text = view::decode_text(&self.line);
+        let matched = if self.ignore_case {
+            text.to_lowercase().contains(self.pattern)
+        } else {
+            text.contains(self.pattern)
+        };
+        if matched {
+            (self.found)(Match { path: self.path.to_string(), line: self.number, text });
+        }
+        self.line.clear();
+    }
+
+    /// Searches what is left. Returns whether the file was text.
+    fn finish(mut self) -> bool {
+        if self.text.is_none() {
+            let head = std::mem::take(&mut self.head);
+            self.text = Some(view::is_text(&head));
+            if self.text == Some(true) {
+                self.feed(&head);
+            }
+        }
+        if self.text == Some(true) && !self.line.is_empty() && !self.ended {
+            self.end_line();
+        }
+        self.text == Some(true)
+    }
+}
+impl<'a> Write for Lines<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.text {
+            Some(true) => self.feed(buf),
+            Some(false) => (),
+            None => {
+                self.head.extend_from_slice(buf);
+                if self.head.len() >= view::SNIFF_SIZE {
+                    let head = std::mem::take(&mut self.head);
+                    self.text = Some(view::is_text(&head));
+                    if self.text == Some(true) {
+                        self.feed(&head);
+                    }
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::{blank, host_file};
+    use {mkdir, put, read_fat};
+
+    #[test]
+    fn wildcards_match_like_dos() {
+        assert!(wildcard_match("*.SYS", "himem.sys"));
+        assert!(wildcard_match("*", "ANYTHING"));
+        assert!(wildcard_match("A?TO*.B*", "AUTOEXEC.BAT"));
+        assert!(wildcard_match("*.*.TXT", "notes.old.txt"));
+        assert!(!wildcard_match("*.SYS", "CONFIG.SYS.BAK"));
+        assert!(!wildcard_match("?", ""));
+    }
+
+    #[test]
+    fn finds_lines_in_text_files_only() {
+        let (info, mut image) = blank();
+        mkdir(&info, &mut image, "/DOS").unwrap();
+        let config = b"FILES=30\r\nDEVICE=HIMEM.SYS /TESTMEM:OFF\r\nrem device=himem.sys\r\n\x1Adevice=himem";
+        put(&info, &mut image, &host_file("grep", "CONFIG.SYS", config), "/").unwrap();
+        put(&info, &mut image, &host_file("grep", "HIMEM.SYS", b"MZ\0\0DEVICE=HIMEM"), "/DOS").unwrap();
+        let notes = host_file("grep", "Long notes.bat", b"\xCD\xCD DEVICE=HIMEM");
+        put(&info, &mut image, &notes, "/DOS").unwrap();
+        let fat = read_fat(&info, &mut image).unwrap();
+        let search = |options: &GrepOptions, image: &mut _| {
+            let mut matches = Vec::new();
+            let searched = grep(&info, image, &fat[..], "DEVICE=HIMEM", options, &mut |m| matches.push(m))
+                .unwrap();
+            (searched, matches)
+        };
+        let (searched, matches) = search(&GrepOptions::default(), &mut image);
+        assert_eq!(searched, 2);
+        let found = |path: &str, line, text: &str| {
+            Match { path: path.to_string(), line, text: text.to_string() }
+        };
+        assert_eq!(matches,
+                   vec![found("/DOS/LONGNO~1.BAT", 1, "══ DEVICE=HIMEM"),
+                        found("/CONFIG.SYS", 2, "DEVICE=HIMEM.SYS /TESTMEM:OFF")]);
+
+        let include = vec!["*.SYS".to_string(), "*notes*".to_string()];
+        let options = GrepOptions { ignore_case: true, include };
+        let (searched, matches) = search(&options, &mut image);
+        assert_eq!(searched, 2);
+        assert_eq!(matches.iter().map(|m| m.line).collect::<Vec<_>>(), vec![1, 2, 3]);
+    }
+}
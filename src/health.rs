@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Seek};
+use {cluster_chain, cluster_limit, cluster_size, fat_copies_match, fat_entry, for_each_entry, DirEntryAttributes,
+     DiskInfo, FatTable, FatType};
+
+/// A report card for one volume.
+pub struct Health {
+    /// FAT copies after the first that differ from it.
+    pub fat_mismatches: usize,
+    pub bad_clusters: usize,
+    pub files: usize,
+    /// Files whose clusters aren't one contiguous run.
+    pub fragmented_files: usize,
+    /// Consistency problems between the directory tree and the FAT.
+    pub problems: Vec<String>,
+}
+impl Health {
+    /// A score out of 100. Disagreeing FATs and tree problems weigh most,
+    /// since they risk data loss; fragmentation only slows reads.
+    pub fn score(&self) -> u32 {
+        let mut penalty = 0;
+        if self.fat_mismatches > 0 {
+            penalty += 30;
+        }
+        penalty += (self.problems.len() as u32 * 10).min(40);
+        penalty += (self.bad_clusters as u32 * 2).min(20);
+        if self.files > 0 {
+            penalty += (self.fragmented_files as u32 * 10).div_ceil(self.files as u32);
+        }
+        100u32.saturating_sub(penalty)
+    }
+
+    pub fn grade(&self) -> char {
+        match self.score() {
+            90..=100 => 'A',
+            75..=89 => 'B',
+            60..=74 => 'C',
+            40..=59 => 'D',
+            _ => 'F',
+        }
+    }
+}
+
+fn is_contiguous(chain: &[u32]) -> bool {
+    chain.windows(2).all(|pair| pair[1] == pair[0] + 1)
+}
+
+/// Checks the FAT copies against each other, counts bad clusters and
+/// fragmented files, and looks for chains that are cross-linked, don't match
+/// their file's size, or belong to no file at all. `fat` is the volume's FAT,
+/// as `load_fat` gives it.
+pub fn check<R: Read + Seek, F: FatTable + ?Sized>(info: &DiskInfo,
+                                                   disk_file: &mut R,
+                                                   fat: &F)
+                                                   -> io::Result<Health> {
+    let mut fat_mismatches = 0;
+    for n in 1..info.fats {
+        if !fat_copies_match(info, disk_file, 0, n)? {
+            fat_mismatches += 1;
+        }
+    }
+
+    let limit = cluster_limit(info);
+    let bad = info.fat_type().bad_cluster();
+    let bad_clusters = (2..limit).filter(|&c| fat_entry(info, fat, c) == Some(bad)).count();
+
+    let mut health = Health {
+        fat_mismatches,
+        bad_clusters,
+        files: 0,
+        fragmented_files: 0,
+        problems: Vec::new(),
+    };
+    let mut owners: HashMap<u32, String> = HashMap::new();
+    // A FAT32 root directory has clusters of its own.
+    if info.fat_type() == FatType::Fat32 {
+        owners.extend(cluster_chain(info, fat, info.root_cluster).into_iter().map(|c| (c, "/".to_string())));
+    }
+    for_each_entry(info, disk_file, fat, false, |_, found| {
+        let entry = &found.entry;
+        let chain = cluster_chain(info, fat, entry.flc);
+        let is_dir = (entry.attributes & DirEntryAttributes::SubDir as u8) != 0;
+        if is_dir {
+            if chain.is_empty() {
+                health.problems.push(format!("{}: directory has no clusters", found.path));
+            }
+        } else {
+            health.files += 1;
+            let needed = (entry.file_size as u64).div_ceil(cluster_size(info)) as usize;
+            if chain.len() != needed {
+                health.problems.push(format!("{}: chain has {} clusters, its size needs {}",
+                                             found.path,
+                                             chain.len(),
+                                             needed));
+            }
+            if !is_contiguous(&chain) {
+                health.fragmented_files += 1;
+            }
+        }
+        for &cluster in &chain {
+            if let Some(owner) = owners.insert(cluster, found.path.clone()) {
+                health.problems.push(format!("{}: cluster {} is also used by {}", found.path, cluster, owner));
+            }
+        }
+        Ok(())
+    })?;
+
+    let lost = (2..limit)
+        .filter(|&c| !owners.contains_key(&c))
+        .filter(|&c| fat_entry(info, fat, c).is_some_and(|next| next != 0 && next != bad))
+        .count();
+    if lost > 0 {
+        health.problems.push(format!("{} allocated clusters belong to no file", lost));
+    }
+    Ok(health)
+}
@@ -0,0 +1,381 @@
+use backup::sha256_hex;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use ureq::http::Response;
+use ureq::{Agent, Body};
+
+/// How much is fetched at a time. A floppy's boot sector, FATs and root
+/// directory come in a request or two, and a small file in one more.
+pub const BLOCK_SIZE: u64 = 16 * 1024;
+
+/// How much of the image is held in memory when there is no limit.
+const DEFAULT_MEMORY: u64 = 64 << 20;
+
+/// The headers of a GET for the `Range` given, as in `bytes=0-16383`. A
+/// plain server only needs the range; one that wants more, such as a
+/// signature, gets it from here.
+pub type Headers = Arc<dyn Fn(&str) -> Vec<(String, String)> + Send + Sync>;
+
+/// Where, and how much of, an image `HttpImage` keeps once it is fetched.
+#[derive(Clone, Debug, Default)]
+pub struct Cache {
+    /// A directory to keep fetched blocks in from one run to the next, so
+    /// that reading the image again costs no requests. Only an image the
+    /// server gives an ETag or Last-Modified for is kept, in files named for
+    /// the hash of its URL, size and that tag, so a changed image isn't
+    /// mistaken for the old one.
+    pub dir: Option<PathBuf>,
+    /// At most how many bytes of blocks to hold in memory, of which a quarter
+    /// is used as with the FAT. The block read longest ago makes room for a
+    /// new one, and is read from `dir` or fetched again if it's needed.
+    pub max_memory: Option<u64>,
+}
+
+/// An image on a web server, read with ranged GETs a block at a time as it
+/// is read, so a listing or one file costs a few requests rather than the
+/// whole image. Blocks are kept once fetched, as `Cache` says. Clones share
+/// the connection and the blocks, each with its own position.
+#[derive(Clone)]
+pub struct HttpImage {
+    shared: Arc<Shared>,
+    position: u64,
+}
+
+struct Shared {
+    url: String,
+    agent: Agent,
+    headers: Headers,
+    len: u64,
+    blocks: Mutex<Blocks>,
+    disk: Option<Mutex<DiskCache>>,
+}
+
+/// The blocks held in memory, each with when it was last read.
+struct Blocks {
+    held: HashMap<u64, (Arc<Vec<u8>>, u64)>,
+    limit: usize,
+    clock: u64,
+}
+
+impl Blocks {
+    fn get(&mut self, n: u64) -> Option<Arc<Vec<u8>>> {
+        self.clock += 1;
+        let (block, used) = self.held.get_mut(&n)?;
+        *used = self.clock;
+        Some(block.clone())
+    }
+
+    fn insert(&mut self, n: u64, block: Arc<Vec<u8>>) {
+        while self.held.len() >= self.limit {
+            match self.held.iter().min_by_key(|(_, (_, used))| *used).map(|(&n, _)| n) {
+                Some(oldest) => self.held.remove(&oldest),
+                None => break,
+            };
+        }
+        self.clock += 1;
+        self.held.insert(n, (block, self.clock));
+    }
+}
+
+/// Blocks kept on disk: the image's bytes where they are in the image, in a
+/// sparse file, and a byte a block saying which of them are there.
+struct DiskCache {
+    data: File,
+    present: File,
+    have: Vec<u8>,
+}
+
+impl DiskCache {
+    /// Opens the cache for the image `key` names in `dir`, made if it isn't
+    /// there, for `blocks` blocks.
+    fn open(dir: &Path, key: &str, blocks: u64) -> io::Result<DiskCache> {
+        fs::create_dir_all(dir)?;
+        let open = |name: String| {
+            OpenOptions::new().read(true).write(true).create(true).truncate(false).open(dir.join(name))
+        };
+        let data = open(format!("{}.img", key))?;
+        let mut present = open(format!("{}.have", key))?;
+        let mut have = Vec::new();
+        present.read_to_end(&mut have)?;
+        have.resize(blocks as usize, 0);
+        Ok(DiskCache { data, present, have })
+    }
+
+    fn get(&mut self, n: u64, len: usize) -> Option<Vec<u8>> {
+        if self.have.get(n as usize) != Some(&1) {
+            return None;
+        }
+        let mut block = vec![0; len];
+        self.data.seek(SeekFrom::Start(n * BLOCK_SIZE)).ok()?;
+        self.data.read_exact(&mut block).ok()?;
+        Some(block)
+    }
+
+    /// Keeps block `n`, marking it there only once its bytes are written,
+    /// so that a run cut short leaves nothing half stored.
+    fn insert(&mut self, n: u64, block: &[u8]) -> io::Result<()> {
+        self.data.seek(SeekFrom::Start(n * BLOCK_SIZE))?;
+        self.data.write_all(block)?;
+        self.present.seek(SeekFrom::Start(n))?;
+        self.present.write_all(&[1])?;
+        self.have[n as usize] = 1;
+        Ok(())
+    }
+}
+
+/// The headers of a plain server's GET: the range and nothing more.
+pub fn range_only() -> Headers {
+    Arc::new(|range| vec![("Range".to_string(), range.to_string())])
+}
+
+fn error(url: &str, why: impl std::fmt::Display) -> io::Error {
+    io::Error::other(format!("{}: {}", url, why))
+}
+
+/// GETs bytes `start` to `end` of `url`, both included.
+fn get(agent: &Agent, url: &str, headers: &Headers, start: u64, end: u64) -> io::Result<Response<Body>> {
+    let mut request = agent.get(url);
+    for (name, value) in headers(&format!("bytes={}-{}", start, end)) {
+        request = request.header(name.as_str(), value.as_str());
+    }
+    request.call().map_err(|e| error(url, e))
+}
+
+impl HttpImage {
+    /// Opens the image at `url`, fetching its first block to learn its size.
+    /// A server that ignores ranges sends the whole image instead, which is
+    /// then kept.
+    pub fn open(url: &str) -> io::Result<HttpImage> {
+        HttpImage::open_with(url, range_only())
+    }
+
+    /// Opens the image at `url` as `open` does, with `headers` for each GET.
+    pub fn open_with(url: &str, headers: Headers) -> io::Result<HttpImage> {
+        HttpImage::open_cached(url, headers, &Cache::default())
+    }
+
+    /// Opens the image at `url` as `open_with` does, keeping its blocks as
+    /// `cache` says.
+    pub fn open_cached(url: &str, headers: Headers, cache: &Cache) -> io::Result<HttpImage> {
+        let agent = Agent::new_with_defaults();
+        let mut response = get(&agent, url, &headers, 0, BLOCK_SIZE - 1)?;
+        let ranged = response.status().as_u16() == 206;
+        // `bytes 0-16383/1474560`: the size comes after the slash.
+        let total = response.headers()
+            .get("content-range")
+            .and_then(|range| range.to_str().ok())
+            .and_then(|range| range.rsplit('/').next())
+            .and_then(|len| len.trim().parse::<u64>().ok());
+        let tag = ["etag", "last-modified"].iter()
+            .find_map(|name| response.headers().get(*name))
+            .and_then(|tag| tag.to_str().ok())
+            .map(String::from);
+        let data = response.body_mut().with_config().limit(u64::MAX).read_to_vec()
+            .map_err(|e| error(url, e))?;
+        let len = match total {
+            Some(len) if ranged => len,
+            _ if ranged => return Err(error(url, "no size in the server's Content-Range")),
+            _ => data.len() as u64,
+        };
+        // What a server that ignores ranges sent can't be fetched again, so
+        // it is all held.
+        let limit = match cache.max_memory.unwrap_or(DEFAULT_MEMORY) / 4 / BLOCK_SIZE {
+            _ if !ranged => usize::MAX,
+            blocks => blocks.max(1) as usize,
+        };
+        let mut blocks = Blocks { held: HashMap::new(), limit, clock: 0 };
+        let disk = match (&cache.dir, tag) {
+            (Some(dir), Some(tag)) if ranged => {
+                let key = sha256_hex(format!("{}\n{}\n{}", url, len, tag).as_bytes());
+                // Without the cache the image is only slower to read.
+                DiskCache::open(dir, &key, len.div_ceil(BLOCK_SIZE)).ok()
+            }
+            _ => None,
+        };
+        let mut disk = disk.map(Mutex::new);
+        for (i, block) in data.chunks(BLOCK_SIZE as usize).enumerate() {
+            if let Some(ref mut disk) = disk {
+                let _ = disk.get_mut().unwrap().insert(i as u64, block);
+            }
+            blocks.insert(i as u64, Arc::new(block.to_vec()));
+        }
+        let shared = Shared { url: url.to_string(), agent, headers, len, blocks: Mutex::new(blocks), disk };
+        Ok(HttpImage { shared: Arc::new(shared), position: 0 })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.shared.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shared.len == 0
+    }
+
+    /// Block `n`, read from the cache or fetched.
+    fn block(&self, n: u64) -> io::Result<Arc<Vec<u8>>> {
+        let shared = &self.shared;
+        if let Some(block) = shared.blocks.lock().unwrap_or_else(|e| e.into_inner()).get(n) {
+            return Ok(block);
+        }
+        let start = n * BLOCK_SIZE;
+        let end = (start + BLOCK_SIZE).min(shared.len) - 1;
+        let disk = shared.disk.as_ref().map(|disk| disk.lock().unwrap_or_else(|e| e.into_inner()));
+        if let Some(block) = disk.and_then(|mut disk| disk.get(n, (end + 1 - start) as usize)) {
+            let block = Arc::new(block);
+            shared.blocks.lock().unwrap_or_else(|e| e.into_inner()).insert(n, block.clone());
+            return Ok(block);
+        }
+        let mut response = get(&shared.agent, &shared.url, &shared.headers, start, end)?;
+        if response.status().as_u16() != 206 {
+            return Err(error(&shared.url, "the server stopped answering ranged requests"));
+        }
+        // One over, as a body exactly at the limit counts as over it.
+        let data = response.body_mut().with_config().limit(BLOCK_SIZE + 1).read_to_vec()
+            .map_err(|e| error(&shared.url, e))?;
+        if data.len() as u64 != end + 1 - start {
+            return Err(error(&shared.url, format!("a short answer for bytes {}-{}", start, end)));
+        }
+        if let Some(ref disk) = shared.disk {
+            let _ = disk.lock().unwrap_or_else(|e| e.into_inner()).insert(n, &data);
+        }
+        let block = Arc::new(data);
+        shared.blocks.lock().unwrap_or_else(|e| e.into_inner()).insert(n, block.clone());
+        Ok(block)
+    }
+}
+
+impl Read for HttpImage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.shared.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let block = self.block(self.position / BLOCK_SIZE)?;
+        let offset = (self.position % BLOCK_SIZE) as usize;
+        let n = buf.len().min(block.len() - offset);
+        buf[..n].copy_from_slice(&block[offset..offset + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for HttpImage {
+    fn seek(&mut self, to: SeekFrom) -> io::Result<u64> {
+        let position = match to {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(delta) => self.shared.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        self.position = position
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use tests::{blank, host_file};
+    use {put, Fat12Volume};
+
+    /// Serves `image` on localhost, answering ranged GETs, and counts them.
+    /// Each connection is answered on a thread of its own.
+    fn serve(image: Vec<u8>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/disk.img", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counted = requests.clone();
+        let image = Arc::new(image);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let (image, counted) = (image.clone(), counted.clone());
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    loop {
+                        let mut range = None;
+                        let mut line = String::new();
+                        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                            break;
+                        }
+                        while line.trim() != "" {
+                            if let Some(bytes) = line.to_lowercase().strip_prefix("range: bytes=") {
+                                let (start, end) = bytes.trim().split_once('-').unwrap();
+                                range = Some((start.parse::<usize>().unwrap(),
+                                              end.parse::<usize>().unwrap()));
+                            }
+                            line.clear();
+                            reader.read_line(&mut line).unwrap();
+                        }
+                        counted.fetch_add(1, Ordering::SeqCst);
+                        let (start, end) = range.unwrap();
+                        let end = end.min(image.len() - 1);
+                        write!(stream,
+                               "HTTP/1.1 206 Partial Content\r\n\
+                                Content-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\nETag: \"1\"\r\n\r\n",
+                               start,
+                               end,
+                               image.len(),
+                               end + 1 - start)
+                            .unwrap();
+                        stream.write_all(&image[start..end + 1]).unwrap();
+                    }
+                });
+            }
+        });
+        (url, requests)
+    }
+
+    /// An image with a file near its start and one far from it.
+    fn near_and_far() -> Vec<u8> {
+        let (info, mut image) = blank();
+        put(&info, &mut image, &host_file("http", "NEAR.TXT", b"near"), "/").unwrap();
+        put(&info, &mut image, &host_file("http", "FILLER.BIN", &[0; 600 << 10]), "/").unwrap();
+        put(&info, &mut image, &host_file("http", "FAR.TXT", b"far"), "/").unwrap();
+        image.into_inner()
+    }
+
+    #[test]
+    fn reads_remote_images_a_block_at_a_time() {
+        let (url, requests) = serve(near_and_far());
+        let image = HttpImage::open(&url).unwrap();
+        assert_eq!(image.len(), 1440 << 10);
+        let mut volume = Fat12Volume::open(image).unwrap();
+        assert_eq!(volume.read_file("/FAR.TXT").unwrap(), b"far");
+        assert_eq!(volume.read_file("/NEAR.TXT").unwrap(), b"near");
+        // The first block, the root directory's and FAR.TXT's; NEAR.TXT's was
+        // fetched with the root directory.
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+    #[test]
+    fn cached_blocks_are_bounded_in_memory_and_kept_on_disk() {
+        let (url, requests) = serve(near_and_far());
+        let dir = std::env::temp_dir().join(format!("fat12-http-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        // Room for one block: each file read evicts the directory's.
+        let small = Cache { dir: None, max_memory: Some(4 * BLOCK_SIZE) };
+        let image = HttpImage::open_cached(&url, range_only(), &small).unwrap();
+        let mut volume = Fat12Volume::open(image).unwrap();
+        for _ in 0..2 {
+            assert_eq!(volume.read_file("/FAR.TXT").unwrap(), b"far");
+        }
+        assert!(requests.swap(0, Ordering::SeqCst) > 3);
+
+        let cache = Cache { dir: Some(dir.clone()), max_memory: None };
+        for expected in [3, 1] {
+            let image = HttpImage::open_cached(&url, range_only(), &cache).unwrap();
+            let mut volume = Fat12Volume::open(image).unwrap();
+            assert_eq!(volume.read_file("/FAR.TXT").unwrap(), b"far");
+            assert_eq!(volume.read_file("/NEAR.TXT").unwrap(), b"near");
+            // Once kept, opening the image is all that is fetched.
+            assert_eq!(requests.swap(0, Ordering::SeqCst), expected);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
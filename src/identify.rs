@@ -0,0 +1,57 @@
+/// A signature that identifies a file type by the bytes at a fixed offset.
+pub struct Magic {
+    pub offset: usize,
+    pub bytes: &'static [u8],
+    pub description: &'static str,
+}
+
+/// Known signatures, checked in order. Add new formats here.
+pub const MAGICS: &[Magic] = &[
+    Magic { offset: 0, bytes: b"MZ", description: "DOS executable (MZ)" },
+    Magic { offset: 0, bytes: b"ZM", description: "DOS executable (MZ)" },
+    Magic { offset: 0, bytes: b"PK\x03\x04", description: "ZIP archive" },
+    Magic { offset: 0, bytes: b"\x60\xEA", description: "ARJ archive" },
+    Magic { offset: 2, bytes: b"-lh", description: "LZH archive" },
+    Magic { offset: 2, bytes: b"-lz", description: "LZH archive" },
+    Magic { offset: 0, bytes: b"ZOO ", description: "ZOO archive" },
+    Magic { offset: 0, bytes: b"Rar!", description: "RAR archive" },
+    Magic { offset: 0, bytes: b"\x1F\x8B", description: "gzip data" },
+    Magic { offset: 0, bytes: b"\xFFWPC", description: "WordPerfect document" },
+    Magic { offset: 0, bytes: b"\x00\x00\x02\x00\x04\x04", description: "Lotus 1-2-3 worksheet (WKS)" },
+    Magic { offset: 0, bytes: b"\x00\x00\x02\x00\x06\x04", description: "Lotus 1-2-3 worksheet (WK1)" },
+    Magic { offset: 0, bytes: b"\x00\x00\x1A\x00\x00\x10\x04\x00", description: "Lotus 1-2-3 worksheet (WK3)" },
+    Magic { offset: 0, bytes: b"GIF87a", description: "GIF image" },
+    Magic { offset: 0, bytes: b"GIF89a", description: "GIF image" },
+    Magic { offset: 0, bytes: b"\x89PNG", description: "PNG image" },
+    Magic { offset: 0, bytes: b"\xFF\xD8\xFF", description: "JPEG image" },
+    Magic { offset: 0, bytes: b"II*\x00", description: "TIFF image" },
+    Magic { offset: 0, bytes: b"MM\x00*", description: "TIFF image" },
+    Magic { offset: 0, bytes: b"BM", description: "BMP image" },
+    Magic { offset: 0, bytes: b"\x0A\x05\x01", description: "PCX image" },
+];
+
+/// Describes a file from the first bytes of its contents and its 8.3 name.
+pub fn identify(head: &[u8], name: &str) -> &'static str {
+    if head.is_empty() {
+        return "empty";
+    }
+    for magic in MAGICS {
+        if head.len() >= magic.offset + magic.bytes.len() &&
+           &head[magic.offset..magic.offset + magic.bytes.len()] == magic.bytes {
+            return magic.description;
+        }
+    }
+    let ext = name.rsplit('.').next().unwrap_or("");
+    // ARC has no real magic: a 0x1A marker followed by the compression method.
+    if ext == "ARC" && head[0] == 0x1A && head.len() > 1 && head[1] <= 11 {
+        return "ARC archive";
+    }
+    // COM files are raw code; most start with a jump or a common setup opcode.
+    if ext == "COM" && [0xE9, 0xEB, 0xB4, 0xBA, 0xFC, 0x8C].contains(&head[0]) {
+        return "DOS executable (COM)";
+    }
+    if head.iter().all(|&b| b >= 0x20 || b == b'\r' || b == b'\n' || b == b'\t' || b == 0x1A) {
+        return "text";
+    }
+    "data"
+}
@@ -0,0 +1,407 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use byteorder::{ByteOrder, LittleEndian};
+use physical::Physical;
+use Chs;
+
+/// The containers flux tools write decoded disks in, which are read as the
+/// raw image they hold. Greaseweazle and KryoFlux `.img` files are already
+/// raw images and need nothing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Dave Dunfield's ImageDisk: the sectors of each track as read, with
+    /// their IDs and whether they read cleanly.
+    ImageDisk,
+    /// The HxC emulator's HFE: the cells of each track side as the head
+    /// saw them, MFM or FM, which are decoded here.
+    Hfe,
+}
+impl Format {
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::ImageDisk => "ImageDisk",
+            Format::Hfe => "HFE",
+        }
+    }
+
+    fn of(head: &[u8]) -> Option<Format> {
+        if head.starts_with(b"IMD ") {
+            Some(Format::ImageDisk)
+        } else if head.starts_with(b"HXCPICFE") || head.starts_with(b"HXCHFEV3") {
+            Some(Format::Hfe)
+        } else {
+            None
+        }
+    }
+}
+
+/// What a decoded image holds, as a raw image.
+pub struct Decoded {
+    pub image: Vec<u8>,
+    /// The shape most of its tracks have, which the raw image is laid out in.
+    pub physical: Physical,
+    /// The sectors that were missing or read with errors. They are zeroes in
+    /// `image`.
+    pub bad: Vec<Chs>,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// The container `path` is in, or `None` for a raw image or anything else.
+pub fn format_of(path: &str) -> Option<Format> {
+    let mut head = [0; 8];
+    File::open(path).and_then(|mut file| file.read_exact(&mut head)).ok()?;
+    Format::of(&head)
+}
+
+/// Reads and decodes the image at `path`.
+pub fn read(path: &Path) -> io::Result<Decoded> {
+    decode(&fs::read(path)?).map_err(|e| invalid(format!("{}: {}", path.display(), e)))
+}
+
+/// Decodes an ImageDisk or HFE image into the raw image it holds.
+pub fn decode(data: &[u8]) -> io::Result<Decoded> {
+    let tracks = match Format::of(data) {
+        Some(Format::ImageDisk) => decode_imd(data)?,
+        Some(Format::Hfe) => decode_hfe(data)?,
+        None => return Err(invalid("not an ImageDisk or HFE image".to_string())),
+    };
+    assemble(&tracks)
+}
+
+/// A sector as found on a track. `data` is `None` if it couldn't be read.
+struct Sector {
+    id: u8,
+    data: Option<Vec<u8>>,
+}
+
+struct Track {
+    cylinder: u16,
+    head: u16,
+    sectors: Vec<Sector>,
+}
+
+/// Reads through an image a field at a time.
+struct Fields<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+impl<'a> Fields<'a> {
+    fn take(&mut self, count: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() - self.offset < count {
+            return Err(invalid(format!("truncated at byte {}", self.offset)));
+        }
+        self.offset += count;
+        Ok(&self.data[self.offset - count..self.offset])
+    }
+}
+
+/// An ImageDisk image: an ASCII comment ending in 0x1A, then each track as a
+/// mode, cylinder, head, sector count and size code, the sector IDs, and a
+/// record for each sector. Records of full data and records of one byte
+/// that fills the sector alternate by type, from 1; 0 means no data, and
+/// from 5 on the data read with an error.
+fn decode_imd(data: &[u8]) -> io::Result<Vec<Track>> {
+    let comment_end = data.iter()
+        .position(|&b| b == 0x1A)
+        .ok_or_else(|| invalid("the ImageDisk comment has no end".to_string()))?;
+    let mut fields = Fields { data, offset: comment_end + 1 };
+    let mut tracks = Vec::new();
+    while fields.offset < data.len() {
+        let header = fields.take(5)?;
+        let (cylinder, head, count, size_code) = (header[1], header[2], header[3] as usize, header[4]);
+        if size_code > 6 {
+            return Err(invalid(format!("cylinder {} head {} has sectors of varying sizes, which aren't \
+                                        supported",
+                                       cylinder,
+                                       head & 0x0F)));
+        }
+        let size = 128 << size_code;
+        let ids = fields.take(count)?;
+        // Bits 7 and 6 of the head say that maps of the cylinder and head
+        // each sector's ID gives follow.
+        if head & 0x80 != 0 {
+            fields.take(count)?;
+        }
+        if head & 0x40 != 0 {
+            fields.take(count)?;
+        }
+        let mut sectors = Vec::with_capacity(count);
+        for &id in ids {
+            let kind = fields.take(1)?[0];
+            let data = match kind {
+                0 => None,
+                1 | 3 | 5 | 7 => Some(fields.take(size)?.to_vec()),
+                2 | 4 | 6 | 8 => Some(vec![fields.take(1)?[0]; size]),
+                _ => return Err(invalid(format!("unknown sector record type {}", kind))),
+            };
+            sectors.push(Sector { id, data: data.filter(|_| kind < 5) });
+        }
+        tracks.push(Track { cylinder: cylinder as u16, head: (head & 0x0F) as u16, sectors });
+    }
+    Ok(tracks)
+}
+
+const HFE_TRACKS: usize = 9;
+const HFE_SIDES: usize = 10;
+const HFE_TRACK_LIST: usize = 18;
+/// HFE stores a track in 512-byte blocks, each the next 256 bytes of side 0
+/// then of side 1.
+const HFE_BLOCK: usize = 512;
+
+/// An HFE image: a header giving the track and side count and where the
+/// track list is, then each track's cells, sent first bit first from the
+/// low bit of each byte.
+fn decode_hfe(data: &[u8]) -> io::Result<Vec<Track>> {
+    if data.starts_with(b"HXCHFEV3") {
+        return Err(invalid("HFE version 3 images aren't supported; save it as version 1".to_string()));
+    }
+    if data.len() < HFE_BLOCK {
+        return Err(invalid("the HFE header is truncated".to_string()));
+    }
+    let (count, sides) = (data[HFE_TRACKS] as usize, data[HFE_SIDES] as usize);
+    let list = LittleEndian::read_u16(&data[HFE_TRACK_LIST..]) as usize * HFE_BLOCK;
+    let mut tracks = Vec::new();
+    for cylinder in 0..count {
+        let entry = data.get(list + cylinder * 4..list + cylinder * 4 + 4)
+            .ok_or_else(|| invalid("the HFE track list is truncated".to_string()))?;
+        let offset = LittleEndian::read_u16(entry) as usize * HFE_BLOCK;
+        let length = LittleEndian::read_u16(&entry[2..]) as usize;
+        let track = data.get(offset..offset + length)
+            .ok_or_else(|| invalid(format!("track {} is past the end of the image", cylinder)))?;
+        for head in 0..sides.min(2) {
+            let cells: Vec<u8> = track.chunks(HFE_BLOCK)
+                .flat_map(|block| block.iter().skip(head * HFE_BLOCK / 2).take(HFE_BLOCK / 2))
+                .flat_map(|&byte| (0..8).map(move |bit| (byte >> bit) & 1))
+                .collect();
+            tracks.push(Track { cylinder: cylinder as u16, head: head as u16, sectors: scan_cells(&cells) });
+        }
+    }
+    Ok(tracks)
+}
+
+/// The raw cells of an MFM A1 byte with its missing clock, three of which
+/// come before each address mark.
+const MFM_SYNC: u16 = 0x4489;
+/// The raw cells of the FM address marks, whose clock of 0xC7 misses three
+/// bits: ID (0xFE), data (0xFB) and deleted data (0xF8).
+const FM_MARKS: [u16; 3] = [0xF57E, 0xF56F, 0xF56A];
+const ID_MARK: u8 = 0xFE;
+const DATA_MARK: u8 = 0xFB;
+const DELETED_DATA_MARK: u8 = 0xF8;
+
+/// The data bits of 16 cells: every second one, after its clock.
+fn data_bits(word: u16) -> u8 {
+    (0..8).fold(0, |byte, bit| byte << 1 | ((word >> (14 - 2 * bit)) & 1) as u8)
+}
+
+/// The 16 cells at `start` as a word, first cell highest.
+fn cell_word(cells: &[u8], start: usize) -> Option<u16> {
+    Some(cells.get(start..start + 16)?.iter().fold(0, |word, &cell| word << 1 | cell as u16))
+}
+
+/// Decodes `count` bytes from the cells at `start`, or `None` if the track
+/// ends first.
+fn cell_bytes(cells: &[u8], start: usize, count: usize) -> Option<Vec<u8>> {
+    (0..count).map(|n| cell_word(cells, start + n * 16).map(data_bits)).collect()
+}
+
+/// The CRC-CCITT the IBM formats give each ID and data field, from 0xFFFF,
+/// over the field, its mark, and on MFM the A1 bytes before the mark.
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0xFFFF, |crc, &byte| {
+        (0..8).fold(crc ^ (byte as u16) << 8, |crc, _| {
+            if crc & 0x8000 != 0 { crc << 1 ^ 0x1021 } else { crc << 1 }
+        })
+    })
+}
+
+/// Finds the sectors in the cells of one track side, MFM or FM. A data field
+/// is taken for the ID field before it if that one's CRC is right; a data
+/// field with a wrong CRC gives a sector with no data.
+fn scan_cells(cells: &[u8]) -> Vec<Sector> {
+    let mut sectors = Vec::new();
+    // The sector number and size code of the last good ID field.
+    let mut id: Option<(u8, u8)> = None;
+    let mut word: u16 = 0;
+    let mut i = 0;
+    while i < cells.len() {
+        word = word << 1 | cells[i] as u16;
+        i += 1;
+        let mut field = Vec::new();
+        if word == MFM_SYNC {
+            while cell_word(cells, i) == Some(MFM_SYNC) {
+                i += 16;
+            }
+            field.extend_from_slice(&[0xA1; 3]);
+            match cell_bytes(cells, i, 1) {
+                Some(mark) => field.push(mark[0]),
+                None => break,
+            }
+            i += 16;
+        } else if FM_MARKS.contains(&word) {
+            field.push(data_bits(word));
+        } else {
+            continue;
+        }
+        let mark = *field.last().unwrap();
+        let length = match (mark, id) {
+            (ID_MARK, _) => 4,
+            (DATA_MARK, Some((_, size_code))) | (DELETED_DATA_MARK, Some((_, size_code))) => {
+                128 << (size_code & 7)
+            }
+            _ => continue,
+        };
+        let body = match cell_bytes(cells, i, length + 2) {
+            Some(body) => body,
+            None => break,
+        };
+        field.extend_from_slice(&body[..length]);
+        let good = crc16(&field) == ((body[length] as u16) << 8 | body[length + 1] as u16);
+        if mark == ID_MARK {
+            id = if good { Some((body[2], body[3])) } else { None };
+            i += 6 * 16;
+        } else {
+            let (number, _) = id.take().unwrap();
+            let data = if good { Some(body[..length].to_vec()) } else { None };
+            sectors.push(Sector { id: number, data });
+            i += (length + 2) * 16;
+        }
+        word = 0;
+    }
+    sectors
+}
+
+/// Lays the tracks out as a raw image, cylinder by cylinder and head by head,
+/// with the sectors of each in ID order. The layout is the sector count and
+/// size most tracks have; sectors it has but a track doesn't, or that have
+/// no data or the wrong size, are zeroes and listed as bad.
+fn assemble(tracks: &[Track]) -> io::Result<Decoded> {
+    let mut shapes: HashMap<(usize, usize), usize> = HashMap::new();
+    for track in tracks {
+        let size = track.sectors.iter().filter_map(|sector| sector.data.as_ref().map(Vec::len)).max();
+        if let Some(size) = size {
+            *shapes.entry((track.sectors.len(), size)).or_insert(0) += 1;
+        }
+    }
+    let (sectors_per_track, bytes_per_sector) = shapes.into_iter()
+        .max_by_key(|&(shape, count)| (count, shape))
+        .map(|(shape, _)| shape)
+        .ok_or_else(|| invalid("no readable sectors".to_string()))?;
+    let first_id = tracks.iter().flat_map(|track| &track.sectors).map(|sector| sector.id).min().unwrap();
+    let cylinders = tracks.iter().map(|track| track.cylinder).max().unwrap() + 1;
+    let heads = tracks.iter().map(|track| track.head).max().unwrap() + 1;
+    let track_size = sectors_per_track * bytes_per_sector;
+    let mut image = Vec::with_capacity(cylinders as usize * heads as usize * track_size);
+    let mut bad = Vec::new();
+    for cylinder in 0..cylinders {
+        for head in 0..heads {
+            let track = tracks.iter().find(|track| track.cylinder == cylinder && track.head == head);
+            for n in 0..sectors_per_track {
+                let id = first_id as usize + n;
+                // Of the copies of a sector, one that was read.
+                let data = track.and_then(|track| {
+                    track.sectors
+                        .iter()
+                        .filter(|sector| sector.id as usize == id)
+                        .filter_map(|sector| sector.data.as_ref())
+                        .find(|data| data.len() == bytes_per_sector)
+                });
+                match data {
+                    Some(data) => image.extend_from_slice(data),
+                    None => {
+                        image.resize(image.len() + bytes_per_sector, 0);
+                        bad.push(Chs { cylinder: cylinder as u32, head, sector: id as u16 });
+                    }
+                }
+            }
+        }
+    }
+    Ok(Decoded {
+        image,
+        physical: Physical {
+            tracks: cylinders,
+            heads,
+            sectors_per_track: sectors_per_track as u16,
+            bytes_per_sector: bytes_per_sector as u16,
+        },
+        bad,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The MFM cells of `bytes`, each data bit after a clock that is set only
+    /// between two clear data bits.
+    fn mfm(bytes: &[u8], previous: &mut u8) -> Vec<u8> {
+        let mut cells = Vec::new();
+        for &byte in bytes {
+            for bit in (0..8).rev() {
+                let data = (byte >> bit) & 1;
+                cells.push((*previous == 0 && data == 0) as u8);
+                cells.push(data);
+                *previous = data;
+            }
+        }
+        cells
+    }
+
+    /// The cells of an MFM sector with number `id` holding `data`.
+    fn mfm_sector(id: u8, data: &[u8], previous: &mut u8) -> Vec<u8> {
+        let sync: Vec<u8> = (0..16).rev().map(|bit| ((MFM_SYNC >> bit) & 1) as u8).collect();
+        let mut field = vec![0xA1, 0xA1, 0xA1, ID_MARK, 0, 0, id, 0];
+        let crc = crc16(&field);
+        field.extend_from_slice(&[(crc >> 8) as u8, crc as u8]);
+        let mut cells = mfm(&[0x4E; 8], previous);
+        cells.extend(mfm(&[0; 12], previous));
+        cells.extend(sync.iter().cycle().take(48));
+        *previous = 1;
+        cells.extend(mfm(&field[3..], previous));
+        let mut field = vec![0xA1, 0xA1, 0xA1, DATA_MARK];
+        field.extend_from_slice(data);
+        let crc = crc16(&field);
+        field.extend_from_slice(&[(crc >> 8) as u8, crc as u8]);
+        cells.extend(mfm(&[0x4E; 22], previous));
+        cells.extend(mfm(&[0; 12], previous));
+        cells.extend(sync.iter().cycle().take(48));
+        *previous = 1;
+        cells.extend(mfm(&field[3..], previous));
+        cells
+    }
+
+    #[test]
+    fn decodes_imagedisk_records() {
+        let mut image = b"IMD 1.18: 01/01/2000 00:00:00\r\ntest\x1A".to_vec();
+        // One cylinder, one head, two sectors of 128 bytes numbered 1 and 2:
+        // the first filled with 0xE5, the second unreadable.
+        image.extend_from_slice(&[0, 0, 0, 2, 0, 1, 2, 2, 0xE5, 0]);
+        let decoded = decode(&image).unwrap();
+        assert_eq!(decoded.physical,
+                   Physical { tracks: 1, heads: 1, sectors_per_track: 2, bytes_per_sector: 128 });
+        assert_eq!(&decoded.image[..128], &[0xE5; 128][..]);
+        assert_eq!(&decoded.image[128..], &[0; 128][..]);
+        assert_eq!(decoded.bad, vec![Chs { cylinder: 0, head: 0, sector: 2 }]);
+    }
+
+    #[test]
+    fn finds_mfm_sectors_and_checks_their_crcs() {
+        let mut previous = 0;
+        let mut cells = mfm_sector(2, &[0xAB; 128], &mut previous);
+        cells.extend(mfm_sector(1, &[0x12; 128], &mut previous));
+        let sectors = scan_cells(&cells);
+        assert_eq!(sectors.iter().map(|sector| sector.id).collect::<Vec<_>>(), vec![2, 1]);
+        assert_eq!(sectors[0].data, Some(vec![0xAB; 128]));
+        assert_eq!(sectors[1].data, Some(vec![0x12; 128]));
+
+        // Flipping a data cell in the second sector spoils its CRC.
+        let cell = cells.len() - 99;
+        cells[cell] ^= 1;
+        assert_eq!(scan_cells(&cells)[1].data, None);
+    }
+}
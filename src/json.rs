@@ -0,0 +1,144 @@
+use chrono::NaiveDateTime;
+use serde_json::Value;
+use check::{Check, Problem};
+use {bpb_looks_valid, dos_date, dos_datetime, DirEntry, DirEntryAttributes, DiskInfo, FreeSpace, TreeEntry};
+
+/// Text from the boot sector or an entry, which should be ASCII but can be
+/// anything on a damaged disk.
+fn text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+fn time(datetime: Option<NaiveDateTime>) -> Value {
+    datetime.map_or(Value::Null, |t| Value::String(t.format("%Y-%m-%dT%H:%M:%S%.3f").to_string()))
+}
+
+/// Every boot sector field `DiskInfo` has, and the FAT type worked out from
+/// them when the BPB is usable.
+pub fn disk_info(info: &DiskInfo) -> Value {
+    let valid = bpb_looks_valid(info);
+    json!({
+        "os_name": text(&info.os_name),
+        "bytes_per_sector": info.bytes_per_sector,
+        "sectors_per_cluster": info.sectors_per_cluster,
+        "reserved_sectors": info.reserved_sectors,
+        "fats": info.fats,
+        "root_dir_entries": info.root_dir_entries,
+        "total_sectors": info.total_sectors,
+        "large_sectors": info.large_sectors,
+        "sectors_per_fat": info.sectors_per_fat,
+        "sectors_per_track": info.sectors_per_track,
+        "heads": info.heads,
+        "large_sectors_per_fat": info.large_sectors_per_fat,
+        "ext_flags": info.ext_flags,
+        "root_cluster": info.root_cluster,
+        "fs_info_sector": info.fs_info_sector,
+        "backup_boot_sector": info.backup_boot_sector,
+        "boot_signature": info.boot_signature,
+        "volume_id": info.volume_id,
+        "volume_label": text(&info.volume_label),
+        "fs_type": text(&info.fs_type),
+        "bpb_valid": valid,
+        "fat_type": if valid { Value::String(info.fat_type().name().to_string()) } else { Value::Null },
+    })
+}
+
+/// Every field of a directory entry as stored, with its name, attribute
+/// bits and times decoded. Times that don't decode are `null`.
+pub fn dir_entry(entry: &DirEntry) -> Value {
+    let flags: Vec<&str> = [(DirEntryAttributes::ReadOnly as u8, "read_only"),
+                            (DirEntryAttributes::Hidden as u8, "hidden"),
+                            (DirEntryAttributes::System as u8, "system"),
+                            (DirEntryAttributes::VolumeLabel as u8, "volume_label"),
+                            (DirEntryAttributes::SubDir as u8, "directory"),
+                            (DirEntryAttributes::Archive as u8, "archive")]
+        .iter()
+        .filter(|&&(bit, _)| entry.attributes & bit != 0)
+        .map(|&(_, flag)| flag)
+        .collect();
+    json!({
+        "name": entry.name(),
+        "long_name": entry.long_name,
+        "file_name": text(&entry.file_name),
+        "file_ext": text(&entry.file_ext),
+        "attributes": entry.attributes,
+        "flags": flags,
+        "reserved": entry.reserved,
+        "create_time": entry.create_time,
+        "create_date": entry.create_date,
+        "last_access_date": entry.last_access_date,
+        "last_write_time": entry.last_write_time,
+        "last_write_date": entry.last_write_date,
+        "created": time(entry.created()),
+        "accessed": dos_date(entry.last_access_date).map(|d| d.format("%Y-%m-%d").to_string()),
+        "modified": time(dos_datetime(entry.last_write_date, entry.last_write_time)),
+        "first_cluster": entry.flc,
+        "ea_handle": entry.ea_handle,
+        "file_size": entry.file_size,
+    })
+}
+
+/// `dir_entry`, with where `walk_tree` found it.
+pub fn tree_entry(found: &TreeEntry) -> Value {
+    let mut value = dir_entry(&found.entry);
+    value["path"] = json!(found.path);
+    value["offset"] = json!(found.offset);
+    value["deleted"] = json!(found.deleted);
+    value
+}
+
+pub fn free_space(space: &FreeSpace) -> Value {
+    json!({
+        "cluster_size": space.cluster_size,
+        "clusters": space.clusters,
+        "used_clusters": space.used_clusters(),
+        "free_clusters": space.free_clusters,
+        "bad_clusters": space.bad_clusters,
+        "total_bytes": space.total_bytes(),
+        "used_bytes": space.used_bytes(),
+        "free_bytes": space.free_bytes(),
+    })
+}
+
+/// A problem as its kind, the message `Display` gives, and its fields.
+pub fn problem(problem: &Problem) -> Value {
+    let mut value = match *problem {
+        Problem::FatMismatch(copy) => json!({ "kind": "fat_mismatch", "copy": copy }),
+        Problem::CrossLink { ref path, cluster, ref other } => {
+            json!({ "kind": "cross_link", "path": path, "cluster": cluster, "other": other })
+        }
+        Problem::BrokenChain { ref path, cluster } => {
+            json!({ "kind": "broken_chain", "path": path, "cluster": cluster })
+        }
+        Problem::BadFirstCluster { ref path, cluster } => {
+            json!({ "kind": "bad_first_cluster", "path": path, "cluster": cluster })
+        }
+        Problem::ChainLength { ref path, clusters, needed } => {
+            json!({ "kind": "chain_length", "path": path, "clusters": clusters, "needed": needed })
+        }
+        Problem::LostClusters { clusters, chains } => {
+            json!({ "kind": "lost_clusters", "clusters": clusters, "chains": chains })
+        }
+        Problem::InvalidEntry { ref path, reason } => {
+            json!({ "kind": "invalid_entry", "path": path, "reason": reason })
+        }
+    };
+    value["message"] = json!(problem.to_string());
+    value
+}
+
+/// What `check` found, and with `repaired`, what `Check::repair` fixed.
+pub fn check(check: &Check, repaired: bool) -> Value {
+    let findings: Vec<Value> = check.findings
+        .iter()
+        .map(|finding| {
+            let mut value = problem(&finding.problem);
+            value["repairable"] = json!(finding.repairable);
+            if repaired {
+                value["repaired"] = json!(finding.repairable);
+            }
+            value
+        })
+        .collect();
+    json!({ "clean": check.is_clean(), "findings": findings })
+}
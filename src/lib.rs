@@ -0,0 +1,4266 @@
+//! Reading and writing FAT12 floppy images, and FAT16 and FAT32 volumes.
+//!
+//! The free functions work on an image and its parsed boot sector;
+//! `Fat12Volume` bundles the two, read-only unless opened `ReadWrite`. The
+//! image can be anything `Read + Seek`, such as a `File` or a
+//! `Cursor<Vec<u8>>` holding it in memory; the functions that change it also
+//! need `Write`.
+
+extern crate byteorder;
+extern crate chrono;
+extern crate flate2;
+#[cfg(feature = "fuse")]
+extern crate fuser;
+#[macro_use]
+extern crate serde_json;
+extern crate sha2;
+#[cfg(feature = "sftp")]
+extern crate ssh2;
+#[cfg(feature = "http")]
+extern crate ureq;
+
+pub mod annotations;
+pub mod audit;
+pub mod backup;
+pub mod bodyfile;
+pub mod build;
+pub mod catalog;
+pub mod check;
+pub mod chunked;
+pub mod corrupt;
+pub mod dfxml;
+pub mod drdos;
+pub mod ea;
+mod error;
+pub mod exeinfo;
+pub mod extract;
+pub mod fingerprint;
+pub mod fixture;
+pub mod fits;
+pub mod geometry;
+pub mod grep;
+pub mod health;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod identify;
+pub mod ingest;
+pub mod json;
+pub mod lock;
+pub mod memory;
+#[cfg(feature = "fuse")]
+pub mod mount;
+pub mod names;
+pub mod overlay;
+pub mod physical;
+pub mod policy;
+mod pool;
+pub mod relayout;
+pub mod rescue;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod scrub;
+pub mod sectors;
+#[cfg(feature = "sftp")]
+pub mod sftp;
+pub mod spanned;
+pub mod tracks;
+pub mod undelete;
+pub mod undo;
+pub mod unpack;
+pub mod view;
+pub mod warnings;
+pub mod watch;
+
+pub use error::{Error, Result};
+pub use warnings::{Warning, Warnings};
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io::prelude::*;
+use std::marker::PhantomData;
+use std::io::{self, SeekFrom};
+use std::path::Path;
+use std::fs::{self, File};
+use std::sync::Arc;
+use byteorder::{LittleEndian, ByteOrder};
+use chrono::*;
+use policy::{Action, Mutation};
+
+const OS_NAME: usize = 3;
+const OS_NAME_SIZE: usize = 8;
+const BYTES_PER_SECTOR: usize = 11;
+const SECTORS_PER_CLUSTER: usize = 13;
+const RESERVED_SECTORS: usize = 14;
+const FATS: usize = 16;
+const ROOT_DIR_ENTRIES: usize = 17;
+const TOTAL_SECTORS: usize = 19;
+const MEDIA_DESCRIPTOR: usize = 21;
+const SECTORS_PER_FAT: usize = 22;
+const SECTORS_PER_TRACK: usize = 24;
+const HEADS: usize = 26;
+const HIDDEN_SECTORS: usize = 28;
+const FAT32_TOTAL_SECTORS: usize = 32;
+const FAT32_SECTORS_PER_FAT: usize = 36;
+const FAT32_EXT_FLAGS: usize = 40;
+const FAT32_ROOT_CLUSTER: usize = 44;
+const FAT32_FS_INFO: usize = 48;
+const FAT32_BACKUP_BOOT: usize = 50;
+// The extended BPB. FAT32 volumes have it 28 bytes further on, after their
+// own fields.
+const DRIVE_NUMBER: usize = 36;
+const BOOT_SIGNATURE: usize = 38;
+const VOLUME_ID: usize = 39;
+const VOLUME_LABEL: usize = 43;
+const VOLUME_LABEL_SIZE: usize = 11;
+const FS_TYPE: usize = 54;
+const FS_TYPE_SIZE: usize = 8;
+const FAT32_EXT_SHIFT: usize = 28;
+
+pub struct DiskInfo {
+    pub os_name: [u8; 8],
+    pub bytes_per_sector: u16,
+    pub sectors_per_cluster: u8,
+    pub reserved_sectors: u16,
+    pub fats: u8,
+    pub root_dir_entries: u16,
+    pub total_sectors: u16,
+    /// The sector count of volumes too large for `total_sectors`, which is
+    /// then 0.
+    pub large_sectors: u32,
+    pub sectors_per_fat: u16,
+    pub sectors_per_track: u16,
+    pub heads: u16,
+    /// The FAT size of FAT32 volumes, whose `sectors_per_fat` is then 0.
+    /// This and the fields up to `backup_boot_sector` are 0 on other volumes.
+    pub large_sectors_per_fat: u32,
+    /// Bit 7 set means only the FAT numbered in bits 0-3 is in use, rather
+    /// than all of them being kept the same.
+    pub ext_flags: u16,
+    /// First cluster of the root directory, which on FAT32 is a chain like
+    /// any other directory.
+    pub root_cluster: u32,
+    /// Sector of the FSInfo block, which caches the free cluster count.
+    pub fs_info_sector: u16,
+    /// Sector of the boot sector's backup copy.
+    pub backup_boot_sector: u16,
+    pub boot_signature: u8,
+    pub volume_id: u32,
+    pub volume_label: [u8; 11],
+    pub fs_type: [u8; 8],
+    /// The options it was read with, which everything it is given to
+    /// follows.
+    pub options: VolumeOptions,
+}
+impl DiskInfo {
+    /// Reads the fields of the boot sector `buf`, which is taken as it is:
+    /// see `parse` for one that may not be a FAT boot sector at all.
+    pub fn new(buf: &[u8]) -> Self {
+        let fat32 = LittleEndian::read_u16(&buf[SECTORS_PER_FAT..]) == 0;
+        let ext = if fat32 { FAT32_EXT_SHIFT } else { 0 };
+        let fat32_field = |offset: usize| if fat32 { LittleEndian::read_u32(&buf[offset..]) } else { 0 };
+        DiskInfo {
+            os_name: {
+                let mut name = [0; OS_NAME_SIZE];
+                name.copy_from_slice(&buf[OS_NAME..OS_NAME + OS_NAME_SIZE]);
+                name
+            },
+            bytes_per_sector: LittleEndian::read_u16(&buf[BYTES_PER_SECTOR..]),
+            sectors_per_cluster: buf[SECTORS_PER_CLUSTER],
+            reserved_sectors: LittleEndian::read_u16(&buf[RESERVED_SECTORS..]),
+            fats: buf[FATS],
+            root_dir_entries: LittleEndian::read_u16(&buf[ROOT_DIR_ENTRIES..]),
+            total_sectors: LittleEndian::read_u16(&buf[TOTAL_SECTORS..]),
+            large_sectors: LittleEndian::read_u32(&buf[FAT32_TOTAL_SECTORS..]),
+            sectors_per_fat: LittleEndian::read_u16(&buf[SECTORS_PER_FAT..]),
+            sectors_per_track: LittleEndian::read_u16(&buf[SECTORS_PER_TRACK..]),
+            heads: LittleEndian::read_u16(&buf[HEADS..]),
+            large_sectors_per_fat: fat32_field(FAT32_SECTORS_PER_FAT),
+            ext_flags: fat32_field(FAT32_EXT_FLAGS) as u16,
+            root_cluster: fat32_field(FAT32_ROOT_CLUSTER),
+            fs_info_sector: fat32_field(FAT32_FS_INFO) as u16,
+            backup_boot_sector: fat32_field(FAT32_BACKUP_BOOT) as u16,
+            boot_signature: buf[ext + BOOT_SIGNATURE],
+            volume_id: LittleEndian::read_u32(&buf[ext + VOLUME_ID..]),
+            volume_label: {
+                let mut label = [0; VOLUME_LABEL_SIZE];
+                label.copy_from_slice(&buf[ext + VOLUME_LABEL..ext + VOLUME_LABEL + VOLUME_LABEL_SIZE]);
+                label
+            },
+            fs_type: {
+                let mut ft = [0; FS_TYPE_SIZE];
+                ft.copy_from_slice(&buf[ext + FS_TYPE..ext + FS_TYPE + FS_TYPE_SIZE]);
+                ft
+            },
+            options: VolumeOptions::default(),
+        }
+    }
+
+    /// `new`, if `boot_sector_problems` finds nothing wrong with `buf`.
+    /// Otherwise fails with `InvalidBootSector` giving everything it found.
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        let problems = boot_sector_problems(buf);
+        if !problems.is_empty() {
+            return Err(Error::InvalidBootSector(problems.join("; ")));
+        }
+        Ok(DiskInfo::new(buf))
+    }
+
+    /// Whether the boot sector has the FAT32 layout, with the extended BPB
+    /// after the FAT32 fields.
+    pub fn has_fat32_bpb(&self) -> bool {
+        self.sectors_per_fat == 0
+    }
+
+    /// Offset of the extended BPB fields (`DRIVE_NUMBER` onwards) past where
+    /// FAT12 and FAT16 keep them.
+    fn ext_shift(&self) -> usize {
+        if self.has_fat32_bpb() { FAT32_EXT_SHIFT } else { 0 }
+    }
+
+    pub fn fat_sectors(&self) -> u32 {
+        if self.sectors_per_fat != 0 {
+            self.sectors_per_fat as u32
+        } else {
+            self.large_sectors_per_fat
+        }
+    }
+
+    /// The FAT copy that is read, and the only one written if mirroring is
+    /// off.
+    pub fn active_fat(&self) -> u8 {
+        if self.ext_flags & 0x80 != 0 { (self.ext_flags & 0x0F) as u8 } else { 0 }
+    }
+
+    pub fn sector_count(&self) -> u32 {
+        if self.total_sectors != 0 {
+            self.total_sectors as u32
+        } else {
+            self.large_sectors
+        }
+    }
+
+    /// The FAT variant, decided by the number of data clusters the way
+    /// Microsoft's specification does it. The `fs_type` label in the boot
+    /// sector is informational and often wrong, so it isn't consulted.
+    pub fn fat_type(&self) -> FatType {
+        let clusters = cluster_limit(self) - 2;
+        if clusters < 4085 {
+            FatType::Fat12
+        } else if clusters < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        }
+    }
+}
+
+/// How wide the entries of a volume's FAT are.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    /// 32-bit entries, of which only the low 28 bits are the entry; the top
+    /// four are reserved and kept as found.
+    Fat32,
+}
+impl FatType {
+    pub fn name(self) -> &'static str {
+        match self {
+            FatType::Fat12 => "FAT12",
+            FatType::Fat16 => "FAT16",
+            FatType::Fat32 => "FAT32",
+        }
+    }
+
+    /// The entry value marking a cluster as unusable.
+    pub fn bad_cluster(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xFF7,
+            FatType::Fat16 => 0xFFF7,
+            FatType::Fat32 => 0x0FFF_FFF7,
+        }
+    }
+
+    /// Entries above `bad_cluster` end a chain; this is the one written.
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    pub fn is_end_of_chain(self, value: u32) -> bool {
+        value > self.bad_cluster()
+    }
+}
+
+/// What a volume is read and changed with besides its boot sector.
+#[derive(Clone, Default)]
+pub struct VolumeOptions {
+    /// The geometry to lay out an image with no BPB by, such as an 8-inch
+    /// disk. The boot sector is ignored and `physical` works the layout out
+    /// instead.
+    pub geometry: Option<physical::Physical>,
+    /// The most memory to spend on buffers that grow with the image: the FAT,
+    /// directory catalogs and read buffers. `None` means no limit.
+    pub max_memory: Option<u64>,
+    /// Where what is odd about the volume but was worked around is reported.
+    pub warnings: Warnings,
+    /// What decides the creates, deletes and attribute changes made to the
+    /// volume. `None` allows them all.
+    pub policy: Option<policy::Policy>,
+    /// How much of a directory is zeroed after an entry added at its end.
+    pub directory_end: DirectoryEnd,
+    /// How new entries are named. `None` names them as Windows does.
+    pub name_mapping: Option<Arc<dyn names::NameMapping>>,
+    /// The most entries one walk of the directory tree goes through, so a
+    /// damaged or hostile image can't keep a command busy. `None` means no
+    /// limit, as for the two below.
+    pub max_entries: Option<u64>,
+    /// The most clusters followed in one chain. A file whose chain is longer
+    /// can't be read.
+    pub max_chain: Option<u32>,
+    /// The most bytes one file read out of the volume may have.
+    pub max_output: Option<u64>,
+}
+
+/// What is written after a new entry that goes where a directory ended. The
+/// slot after it always becomes the 0x00 end marker, whatever was left
+/// there, so stale bytes past the old end can't turn into entries.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DirectoryEnd {
+    /// Only the end marker.
+    #[default]
+    Marker,
+    /// The rest of the new entry's sector as well, as DOS leaves it.
+    ZeroSector,
+    /// Every slot to the end of the directory, for DOS versions before 2.0
+    /// and tools that read all the slots instead of stopping at the marker.
+    ZeroAll,
+}
+impl DirectoryEnd {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "marker" => Some(DirectoryEnd::Marker),
+            "sector" => Some(DirectoryEnd::ZeroSector),
+            "all" => Some(DirectoryEnd::ZeroAll),
+            _ => None,
+        }
+    }
+}
+
+pub fn read_disk_info<R: Read + Seek>(disk_file: &mut R) -> Result<DiskInfo> {
+    read_disk_info_with(disk_file, &VolumeOptions::default())
+}
+
+/// Like `read_disk_info`, but the `DiskInfo` keeps `options`, and the layout
+/// comes from their geometry if they give one.
+pub fn read_disk_info_with<R: Read + Seek>(disk_file: &mut R, options: &VolumeOptions) -> Result<DiskInfo> {
+    let mut info = match options.geometry {
+        Some(ref physical) => physical::disk_info(disk_file, physical)?,
+        None => read_boot_sector(disk_file)?,
+    };
+    info.options = options.clone();
+    Ok(info)
+}
+
+fn read_boot_sector<R: Read + Seek>(disk_file: &mut R) -> Result<DiskInfo> {
+    let mut buf = [0u8; 512];
+    disk_file.seek(SeekFrom::Start(0))?;
+    disk_file.read_exact(&mut buf).map_err(|e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => {
+            Error::InvalidBootSector("the image is shorter than a boot sector".to_string())
+        }
+        _ => Error::Io(e),
+    })?;
+    DiskInfo::parse(&buf)
+}
+
+/// The boot sector signature, the last two bytes of the sector.
+const BOOT_SECTOR_SIGNATURE: usize = 510;
+/// The largest cluster Windows can mount; 32 KB is the most DOS formats.
+const MAX_CLUSTER_SIZE: u32 = 64 * 1024;
+
+/// What is wrong with `sector` as a FAT boot sector: a missing 0x55AA
+/// signature or jump instruction, bytes per sector that aren't a power of
+/// two from 128 to 4096, a cluster size that isn't a power of two or is
+/// over 64 KB, or a media descriptor no FAT disk has. Empty if nothing is.
+/// Anything shorter than 512 bytes is no boot sector at all.
+pub fn boot_sector_problems(sector: &[u8]) -> Vec<String> {
+    if sector.len() < BOOT_SECTOR_SIGNATURE + 2 {
+        return vec![format!("only {} bytes, not a 512-byte sector", sector.len())];
+    }
+    let mut problems = Vec::new();
+    let signature = &sector[BOOT_SECTOR_SIGNATURE..BOOT_SECTOR_SIGNATURE + 2];
+    if signature != [0x55, 0xAA] {
+        problems.push(format!("no 0x55AA signature at offset 510 (found 0x{:02X}{:02X})",
+                              signature[0],
+                              signature[1]));
+    }
+    // A short jump, usually followed by a NOP, or a near jump over the BPB.
+    if sector[0] != 0xEB && sector[0] != 0xE9 {
+        problems.push(format!("no jump instruction at the start (found 0x{:02X})", sector[0]));
+    }
+    let bytes_per_sector = LittleEndian::read_u16(&sector[BYTES_PER_SECTOR..]);
+    if !bytes_per_sector.is_power_of_two() || !(128..=4096).contains(&bytes_per_sector) {
+        problems.push(format!("{} bytes per sector", bytes_per_sector));
+    }
+    let sectors_per_cluster = sector[SECTORS_PER_CLUSTER];
+    if !sectors_per_cluster.is_power_of_two() {
+        problems.push(format!("{} sectors per cluster", sectors_per_cluster));
+    } else if bytes_per_sector.is_power_of_two() &&
+              bytes_per_sector as u32 * sectors_per_cluster as u32 > MAX_CLUSTER_SIZE {
+        problems.push(format!("clusters of {} bytes", bytes_per_sector as u32 * sectors_per_cluster as u32));
+    }
+    let media = sector[MEDIA_DESCRIPTOR];
+    if media != 0xF0 && media < 0xF8 {
+        problems.push(format!("media descriptor 0x{:02X}", media));
+    }
+    problems
+}
+
+/// Whether the BPB fields that the layout depends on have sane values. When
+/// they don't, the boot sector was probably zeroed or overwritten.
+pub fn bpb_looks_valid(info: &DiskInfo) -> bool {
+    let root_ok = if info.has_fat32_bpb() {
+        info.root_dir_entries == 0 && info.root_cluster >= 2 && info.root_cluster < cluster_limit(info)
+    } else {
+        info.root_dir_entries > 0
+    };
+    info.bytes_per_sector.is_power_of_two() && info.bytes_per_sector >= 128 &&
+    info.sectors_per_cluster.is_power_of_two() && info.reserved_sectors >= 1 &&
+    (info.fats == 1 || info.fats == 2) && info.fat_sectors() > 0 && root_ok
+}
+
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FS_INFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FS_INFO_TRAIL_SIGNATURE: u32 = 0xAA55_0000;
+const FS_INFO_FREE_COUNT: usize = 488;
+const FS_INFO_NEXT_FREE: usize = 492;
+/// What the FSInfo fields hold when the count or hint isn't known.
+const FS_INFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+/// The FSInfo block of a FAT32 volume: hints kept so the free space needn't
+/// be counted at mount time. Either may be out of date, or `None` if not
+/// known.
+pub struct FsInfo {
+    pub free_clusters: Option<u32>,
+    /// Where to start looking for a free cluster.
+    pub next_free: Option<u32>,
+}
+
+/// Reads the FSInfo block, or `None` if the volume isn't FAT32 or the block's
+/// signatures are wrong.
+pub fn read_fs_info<R: Read + Seek>(info: &DiskInfo, disk_file: &mut R) -> Result<Option<FsInfo>> {
+    if info.fat_type() != FatType::Fat32 || info.fs_info_sector == 0 {
+        return Ok(None);
+    }
+    let mut sector = [0u8; 512];
+    disk_file.seek(SeekFrom::Start(info.fs_info_sector as u64 * info.bytes_per_sector as u64))?;
+    disk_file.read_exact(&mut sector)?;
+    if LittleEndian::read_u32(&sector) != FS_INFO_LEAD_SIGNATURE ||
+       LittleEndian::read_u32(&sector[484..]) != FS_INFO_STRUCT_SIGNATURE ||
+       LittleEndian::read_u32(&sector[508..]) != FS_INFO_TRAIL_SIGNATURE {
+        return Ok(None);
+    }
+    let hint = |offset: usize| Some(LittleEndian::read_u32(&sector[offset..])).filter(|&n| n != FS_INFO_UNKNOWN);
+    Ok(Some(FsInfo { free_clusters: hint(FS_INFO_FREE_COUNT), next_free: hint(FS_INFO_NEXT_FREE) }))
+}
+
+/// Brings the FSInfo hints in line with `fat`, if the volume has a valid
+/// FSInfo block.
+fn write_fs_info<R: Read + Write + Seek>(info: &DiskInfo, disk_file: &mut R, fat: &[u8]) -> Result<()> {
+    if read_fs_info(info, disk_file)?.is_none() {
+        return Ok(());
+    }
+    let mut free = (2..cluster_limit(info)).filter(|&c| fat_entry(info, fat, c) == Some(0));
+    let next_free = free.next();
+    let free_clusters = next_free.map_or(0, |_| free.count() as u32 + 1);
+    let mut fields = [0u8; 8];
+    LittleEndian::write_u32(&mut fields, free_clusters);
+    LittleEndian::write_u32(&mut fields[4..], next_free.unwrap_or(FS_INFO_UNKNOWN));
+    let start = info.fs_info_sector as u64 * info.bytes_per_sector as u64;
+    disk_file.seek(SeekFrom::Start(start + FS_INFO_FREE_COUNT as u64))?;
+    Ok(disk_file.write_all(&fields)?)
+}
+
+const DIR_ENTRY_SIZE: usize = 32;
+const DIR_ENTRY_NAME_SIZE: usize = 8;
+const DIR_ENTRY_EXT: usize = 8;
+const DIR_ENTRY_EXT_SIZE: usize = 3;
+const DIR_ENTRY_ATTRS: usize = 11;
+const DIR_ENTRY_RESERVED: usize = 12;
+const DIR_ENTRY_CREATETIME_FINE: usize = 13;
+/// The flags in the reserved byte at offset 12 with which Windows NT shows
+/// the base name or the extension of an 8.3 name in lower case.
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+const DIR_ENTRY_CREATETIME: usize = 14;
+const DIR_ENTRY_CREATEDATE: usize = 16;
+const DIR_ENTRY_LASTACCESS: usize = 18;
+const DIR_ENTRY_FLC_HIGH: usize = 20;
+const DIR_ENTRY_WRITETIME: usize = 22;
+const DIR_ENTRY_WRITEDATE: usize = 24;
+const DIR_ENTRY_FLC: usize = 26;
+const DIR_ENTRY_FILESIZE: usize = 28;
+
+pub enum DirEntryAttributes {
+    ReadOnly = 0x01,
+    Hidden = 0x02,
+    System = 0x04,
+    VolumeLabel = 0x08,
+    SubDir = 0x10,
+    Archive = 0x20,
+}
+
+pub struct DirEntry {
+    pub file_name: [u8; DIR_ENTRY_NAME_SIZE],
+    pub file_ext: [u8; DIR_ENTRY_EXT_SIZE],
+    pub attributes: u8,
+    pub reserved: u16,
+    pub create_time: u16,
+    pub create_date: u16,
+    pub last_access_date: u16,
+    pub last_write_time: u16,
+    pub last_write_date: u16,
+    /// The first cluster. Its high 16 bits are only used by FAT32 and are 0
+    /// elsewhere.
+    pub flc: u32,
+    /// The word FAT32 keeps the first cluster's high bits in, which FAT12
+    /// and FAT16 leave to the system: OS/2's handle for the entry's extended
+    /// attributes in `EA DATA. SF`, or DR-DOS's password rights, or 0 for
+    /// neither. Always 0 on FAT32.
+    pub ea_handle: u16,
+    pub file_size: u32,
+    /// The VFAT long name from the LFN slots before the entry, if they are
+    /// intact and belong to it, or else the name `cased_name` gives. Only
+    /// `Directory::entries` fills this in.
+    pub long_name: Option<String>,
+}
+impl DirEntry {
+    /// The entry in a slot as FAT32 lays it out, with all of the word at
+    /// offset 20 taken as the first cluster's high bits.
+    pub fn new(buf: &[u8]) -> Self {
+        DirEntry {
+            file_name: {
+                let mut name = [b' '; DIR_ENTRY_NAME_SIZE];
+                name.copy_from_slice(&buf[0..DIR_ENTRY_NAME_SIZE]);
+                name
+            },
+            file_ext: {
+                let mut ext = [b' '; DIR_ENTRY_EXT_SIZE];
+                ext.copy_from_slice(&buf[DIR_ENTRY_EXT..DIR_ENTRY_EXT + DIR_ENTRY_EXT_SIZE]);
+                ext
+            },
+            attributes: buf[DIR_ENTRY_ATTRS],
+            reserved: LittleEndian::read_u16(&buf[DIR_ENTRY_RESERVED..]),
+            create_time: LittleEndian::read_u16(&buf[DIR_ENTRY_CREATETIME..]),
+            create_date: LittleEndian::read_u16(&buf[DIR_ENTRY_CREATEDATE..]),
+            last_access_date: LittleEndian::read_u16(&buf[DIR_ENTRY_LASTACCESS..]),
+            last_write_time: LittleEndian::read_u16(&buf[DIR_ENTRY_WRITETIME..]),
+            last_write_date: LittleEndian::read_u16(&buf[DIR_ENTRY_WRITEDATE..]),
+            flc: (LittleEndian::read_u16(&buf[DIR_ENTRY_FLC_HIGH..]) as u32) << 16 |
+                 LittleEndian::read_u16(&buf[DIR_ENTRY_FLC..]) as u32,
+            ea_handle: 0,
+            file_size: LittleEndian::read_u32(&buf[DIR_ENTRY_FILESIZE..]),
+            long_name: None,
+        }
+    }
+
+    /// The entry in a slot of a volume of `fat_type`: on FAT12 and FAT16 the
+    /// word at offset 20 is the EA handle rather than part of the cluster.
+    pub fn read(buf: &[u8], fat_type: FatType) -> Self {
+        let mut entry = DirEntry::new(buf);
+        if fat_type != FatType::Fat32 {
+            entry.ea_handle = (entry.flc >> 16) as u16;
+            entry.flc &= 0xFFFF;
+        }
+        entry
+    }
+
+    /// The 32-byte slot for the entry, as `new` or `read` reads it.
+    pub fn to_bytes(&self) -> [u8; DIR_ENTRY_SIZE] {
+        let mut slot = [0u8; DIR_ENTRY_SIZE];
+        slot[..DIR_ENTRY_NAME_SIZE].copy_from_slice(&self.file_name);
+        slot[DIR_ENTRY_EXT..DIR_ENTRY_EXT + DIR_ENTRY_EXT_SIZE].copy_from_slice(&self.file_ext);
+        slot[DIR_ENTRY_ATTRS] = self.attributes;
+        LittleEndian::write_u16(&mut slot[DIR_ENTRY_RESERVED..], self.reserved);
+        LittleEndian::write_u16(&mut slot[DIR_ENTRY_CREATETIME..], self.create_time);
+        LittleEndian::write_u16(&mut slot[DIR_ENTRY_CREATEDATE..], self.create_date);
+        LittleEndian::write_u16(&mut slot[DIR_ENTRY_LASTACCESS..], self.last_access_date);
+        LittleEndian::write_u16(&mut slot[DIR_ENTRY_FLC_HIGH..], (self.flc >> 16) as u16 | self.ea_handle);
+        LittleEndian::write_u16(&mut slot[DIR_ENTRY_WRITETIME..], self.last_write_time);
+        LittleEndian::write_u16(&mut slot[DIR_ENTRY_WRITEDATE..], self.last_write_date);
+        LittleEndian::write_u16(&mut slot[DIR_ENTRY_FLC..], self.flc as u16);
+        LittleEndian::write_u32(&mut slot[DIR_ENTRY_FILESIZE..], self.file_size);
+        slot
+    }
+
+    /// The creation time, to the hundredth of a second the byte at offset 13
+    /// adds to the two-second DOS time.
+    pub fn created(&self) -> Option<NaiveDateTime> {
+        let centiseconds = (self.reserved >> 8) as i64;
+        let created = dos_datetime(self.create_date, self.create_time)?;
+        // Anything past 199 isn't a valid count, so it is left out.
+        Some(if centiseconds < 200 { created + Duration::milliseconds(centiseconds * 10) } else { created })
+    }
+
+    /// Whether this is an LFN slot holding part of a long name rather than an
+    /// entry of its own.
+    pub fn is_lfn(&self) -> bool {
+        self.attributes & 0x3F == LFN_ATTRIBUTES
+    }
+
+    /// The 8.3 name in the lower case the flags at offset 12 give its base
+    /// name or extension, as Windows NT shows it, e.g. `readme.TXT`. `None`
+    /// if neither flag is set.
+    pub fn cased_name(&self) -> Option<String> {
+        let flags = self.reserved as u8;
+        if flags & (CASE_LOWER_BASE | CASE_LOWER_EXT) == 0 || self.is_lfn() {
+            return None;
+        }
+        let part = |bytes: &[u8], flag: u8| {
+            let part = String::from_utf8_lossy(bytes).trim().to_string();
+            if flags & flag != 0 { part.to_lowercase() } else { part }
+        };
+        let (name, ext) = (part(&self.file_name, CASE_LOWER_BASE), part(&self.file_ext, CASE_LOWER_EXT));
+        Some(if ext.is_empty() { name } else { format!("{}.{}", name, ext) })
+    }
+
+    /// The 8.3 name as displayed, e.g. `README.TXT` or `DOCS`.
+    pub fn name(&self) -> String {
+        let name = String::from_utf8_lossy(&self.file_name);
+        let ext = String::from_utf8_lossy(&self.file_ext);
+        if ext.trim().is_empty() {
+            name.trim().to_string()
+        } else {
+            format!("{}.{}", name.trim(), ext.trim())
+        }
+    }
+}
+
+/// The attribute bits of an LFN slot: read-only, hidden, system and volume
+/// label all at once, which DOS skips over.
+const LFN_ATTRIBUTES: u8 = 0x0F;
+const LFN_ORDER: usize = 0;
+const LFN_LAST: u8 = 0x40;
+const LFN_CHECKSUM: usize = 13;
+/// Where the 13 UCS-2 characters of an LFN slot's part of the name are.
+const LFN_CHARS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// The checksum LFN slots carry of the 11 name bytes of their short entry,
+/// so a long name left behind when DOS rewrote the entry can be told apart.
+pub fn lfn_checksum(short_name: &[u8]) -> u8 {
+    short_name[..11].iter().fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// Puts together long names from the LFN slots of a directory as they are
+/// read. The slots come last part first, numbered down to 1, before the short
+/// entry they belong to.
+#[derive(Default)]
+struct LongName {
+    checksum: u8,
+    /// The order number of the slot expected next, 0 if none is.
+    next: u8,
+    /// The parts seen so far, last part first.
+    parts: Vec<[u16; 13]>,
+    /// Whether slots were dropped for being out of sequence since the last
+    /// short entry.
+    broken: bool,
+}
+impl LongName {
+    fn add_slot(&mut self, slot: &[u8]) {
+        let order = slot[LFN_ORDER];
+        let number = order & 0x1F;
+        if order & LFN_LAST != 0 {
+            // A new name starting before the last one was finished.
+            self.broken |= !self.parts.is_empty();
+            self.parts.clear();
+            self.checksum = slot[LFN_CHECKSUM];
+        } else if number == 0 || number != self.next || slot[LFN_CHECKSUM] != self.checksum {
+            // Out of sequence, so whatever was gathered isn't a whole name.
+            self.parts.clear();
+            self.next = 0;
+            self.broken = true;
+            return;
+        }
+        let mut part = [0u16; 13];
+        for (unit, &offset) in part.iter_mut().zip(LFN_CHARS.iter()) {
+            *unit = LittleEndian::read_u16(&slot[offset..]);
+        }
+        self.parts.push(part);
+        self.next = number.wrapping_sub(1);
+    }
+
+    /// The name gathered, if it is complete and its checksum matches the
+    /// short entry `slot`, named `name`. Starts over either way, warning about
+    /// slots that made no name.
+    fn finish(&mut self, slot: &[u8], name: &str, warnings: &Warnings) -> Option<String> {
+        let complete = !self.parts.is_empty() && self.next == 0 && self.checksum == lfn_checksum(slot);
+        let parts = std::mem::take(&mut self.parts);
+        let broken = std::mem::replace(&mut self.broken, false);
+        self.next = 0;
+        if !complete {
+            if broken || !parts.is_empty() {
+                warnings.warn(Warning::OrphanedLongName(Some(name.to_string())));
+            }
+            return None;
+        }
+        // The name ends at a 0 unit unless it fills its last slot exactly;
+        // the rest of that slot is padded with 0xFFFF.
+        let units: Vec<u16> = parts.iter()
+            .rev()
+            .flat_map(|part| part.iter().cloned())
+            .take_while(|&unit| unit != 0)
+            .collect();
+        Some(std::char::decode_utf16(units).map(|c| c.unwrap_or(std::char::REPLACEMENT_CHARACTER)).collect())
+    }
+
+    /// Starts over at a deleted entry.
+    fn reset(&mut self, warnings: &Warnings) {
+        if self.broken || !self.parts.is_empty() {
+            warnings.warn(Warning::OrphanedLongName(None));
+        }
+        self.parts.clear();
+        self.next = 0;
+        self.broken = false;
+    }
+}
+
+/// The entries of a directory read as bytes, as `Directory::entries` gives
+/// them, with what they turn up reported to `warnings`. A slot cut short at
+/// the end is left out.
+pub fn decode_dir(slots: &[u8], fat_type: FatType, warnings: &Warnings) -> Vec<(usize, DirEntry)> {
+    let mut long_name = LongName::default();
+    let mut entries = Vec::new();
+    for (i, slot) in slots.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+        if slot[0] == 0x00 {
+            break;
+        }
+        if slot[0] == 0xE5 {
+            long_name.reset(warnings);
+            continue;
+        }
+        let mut entry = DirEntry::read(slot, fat_type);
+        if entry.is_lfn() {
+            long_name.add_slot(slot);
+        } else {
+            entry.long_name = long_name.finish(slot, &entry.name(), warnings).or_else(|| entry.cased_name());
+            if entry.attributes & DirEntryAttributes::VolumeLabel as u8 == 0 {
+                check_timestamps(&entry, warnings);
+            }
+        }
+        entries.push((i, entry));
+    }
+    entries
+}
+
+/// The long name the LFN slots in `slots` spell for the short entry in the
+/// last slot, or `None` if they don't make a whole name with its checksum.
+/// Slots before a deleted one or another short entry don't count.
+pub fn long_name(slots: &[u8]) -> Option<String> {
+    let warnings = Warnings::default();
+    let mut long_name = LongName::default();
+    let slots: Vec<&[u8]> = slots.chunks_exact(DIR_ENTRY_SIZE).collect();
+    let (short, lfn) = slots.split_last()?;
+    for slot in lfn {
+        if slot[0] == 0xE5 {
+            long_name.reset(&warnings);
+        } else if DirEntry::new(slot).is_lfn() {
+            long_name.add_slot(slot);
+        } else {
+            long_name.finish(slot, "", &warnings);
+        }
+    }
+    long_name.finish(short, "", &warnings)
+}
+
+/// Warns about the timestamps of `entry` that are set but invalid. A date of
+/// 0 means the field was never filled in, which is normal.
+fn check_timestamps(entry: &DirEntry, warnings: &Warnings) {
+    let fields = [("creation time", entry.create_date, Some(entry.create_time)),
+                  ("modification time", entry.last_write_date, Some(entry.last_write_time)),
+                  ("access date", entry.last_access_date, None)];
+    for &(field, date, time) in &fields {
+        let valid = match time {
+            Some(time) => dos_datetime(date, time).is_some(),
+            None => dos_date(date).is_some(),
+        };
+        if date != 0 && !valid {
+            warnings.warn(Warning::BadTimestamp { name: entry.name(), field });
+        }
+    }
+}
+
+/// Byte offset of the fixed root directory of FAT12 and FAT16 volumes, right
+/// after the FATs. On FAT32 that is where the data area starts.
+pub fn root_dir_start(info: &DiskInfo) -> u64 {
+    info.bytes_per_sector as u64 *
+    (info.reserved_sectors as u64 + info.fats as u64 * info.fat_sectors() as u64)
+}
+
+pub fn cluster_size(info: &DiskInfo) -> u64 {
+    info.bytes_per_sector as u64 * info.sectors_per_cluster as u64
+}
+
+/// Byte offset of data cluster `cluster`. Clusters are numbered from 2.
+pub fn cluster_start(info: &DiskInfo, cluster: u32) -> u64 {
+    let root_dir_size = (info.root_dir_entries as u64 * DIR_ENTRY_SIZE as u64)
+        .div_ceil(info.bytes_per_sector as u64) * info.bytes_per_sector as u64;
+    root_dir_start(info) + root_dir_size + (cluster as u64 - 2) * cluster_size(info)
+}
+
+/// A sector's place on the disk as the drive addresses it. Sectors count
+/// from 1, cylinders and heads from 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Chs {
+    pub cylinder: u32,
+    pub head: u16,
+    pub sector: u16,
+}
+impl fmt::Display for Chs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}/{}", self.cylinder, self.head, self.sector)
+    }
+}
+
+/// The cylinder, head and sector of logical sector `lba`, counted from the
+/// start of the volume, by the BPB's sectors per track and heads. `None` if
+/// the BPB gives no geometry.
+pub fn lba_to_chs(info: &DiskInfo, lba: u32) -> Option<Chs> {
+    let (spt, heads) = (info.sectors_per_track as u32, info.heads as u32);
+    if spt == 0 || heads == 0 {
+        return None;
+    }
+    Some(Chs {
+        cylinder: lba / (spt * heads),
+        head: (lba / spt % heads) as u16,
+        sector: (lba % spt + 1) as u16,
+    })
+}
+
+/// The logical sector at `chs`, the inverse of `lba_to_chs`. `None` if the
+/// BPB gives no geometry or `chs` has a head or sector it doesn't have.
+pub fn chs_to_lba(info: &DiskInfo, chs: Chs) -> Option<u32> {
+    let (spt, heads) = (info.sectors_per_track as u32, info.heads as u32);
+    if chs.sector == 0 || chs.sector as u32 > spt || chs.head as u32 >= heads {
+        return None;
+    }
+    (chs.cylinder.checked_mul(heads)? + chs.head as u32).checked_mul(spt)?.checked_add(chs.sector as u32 - 1)
+}
+
+/// Reads up to `max` bytes from the start of a file. Only the first cluster is
+/// read, which is as far as can be read without following the FAT.
+pub fn read_file_head<R: Read + Seek>(info: &DiskInfo,
+                                      disk_file: &mut R,
+                                      entry: &DirEntry,
+                                      max: usize)
+                                      -> Result<Vec<u8>> {
+    if entry.flc < 2 {
+        return Ok(Vec::new());
+    }
+    let len = max.min(entry.file_size as usize).min(cluster_size(info) as usize);
+    let mut head = vec![0; len];
+    disk_file.seek(SeekFrom::Start(cluster_start(info, entry.flc)))?;
+    disk_file.read_exact(&mut head)?;
+    Ok(head)
+}
+
+/// Number of the last data cluster, plus one.
+pub fn cluster_limit(info: &DiskInfo) -> u32 {
+    let data_sectors = (info.sector_count() as u64 * info.bytes_per_sector as u64)
+        .saturating_sub(cluster_start(info, 2)) / info.bytes_per_sector as u64;
+    (data_sectors / info.sectors_per_cluster as u64) as u32 + 2
+}
+
+/// Reads the first FAT, or on FAT32 the active one. The others are copies of
+/// it, and any that differ are reported to the volume's `Warnings`, since
+/// writing the FAT back replaces them.
+pub fn read_fat<R: Read + Seek>(info: &DiskInfo, disk_file: &mut R) -> Result<Vec<u8>> {
+    let fat = read_active_fat(info, disk_file)?;
+    // With mirroring off on FAT32 the other copies are left alone, and may
+    // differ. A copy cut off by the end of the image counts as differing.
+    if info.ext_flags & 0x80 == 0 {
+        for n in (0..info.fats).filter(|&n| n != info.active_fat()) {
+            if !fat_copies_match(info, disk_file, info.active_fat(), n).unwrap_or(false) {
+                info.options.warnings.warn(Warning::FatCopyMismatch(n));
+            }
+        }
+    }
+    Ok(fat)
+}
+
+/// Reads the FAT `read_fat` does, without comparing the copies.
+fn read_active_fat<R: Read + Seek>(info: &DiskInfo, disk_file: &mut R) -> Result<Vec<u8>> {
+    let len = info.fat_sectors() as u64 * info.bytes_per_sector as u64;
+    let fat_start = info.reserved_sectors as u64 * info.bytes_per_sector as u64;
+    // Checked before anything is allocated, so a BPB claiming a huge FAT
+    // fails here rather than running out of memory.
+    let image_len = disk_file.seek(SeekFrom::End(0))?;
+    if fat_start + (info.active_fat() as u64 + 1) * len > image_len {
+        let why = format!("the FAT, of {} bytes, runs past the end of the image", len);
+        return Err(Error::InvalidBootSector(why));
+    }
+    let mut fat = vec![0; len as usize];
+    disk_file.seek(SeekFrom::Start(fat_start + info.active_fat() as u64 * len))?;
+    disk_file.read_exact(&mut fat)?;
+    Ok(fat)
+}
+
+/// Whether FAT copies `a` and `b` are the same, compared a piece at a time so
+/// neither has to be held whole.
+pub fn fat_copies_match<R: Read + Seek>(info: &DiskInfo, disk_file: &mut R, a: u8, b: u8) -> Result<bool> {
+    let len = info.fat_sectors() as u64 * info.bytes_per_sector as u64;
+    let fat_start = info.reserved_sectors as u64 * info.bytes_per_sector as u64;
+    let piece = memory::buffer_size(info.options.max_memory, len as usize) as u64;
+    let (mut first, mut second) = (vec![0; piece as usize], vec![0; piece as usize]);
+    let mut done = 0;
+    while done < len {
+        let size = piece.min(len - done) as usize;
+        disk_file.seek(SeekFrom::Start(fat_start + a as u64 * len + done))?;
+        disk_file.read_exact(&mut first[..size])?;
+        disk_file.seek(SeekFrom::Start(fat_start + b as u64 * len + done))?;
+        disk_file.read_exact(&mut second[..size])?;
+        if first[..size] != second[..size] {
+            return Ok(false);
+        }
+        done += size as u64;
+    }
+    Ok(true)
+}
+
+/// A FAT to look entries up in: one read whole into memory, as `read_fat`
+/// returns it, or a `PagedFat` reading it from the image as it goes.
+pub trait FatTable {
+    /// Fills `buf` with the FAT's bytes from `offset` on. Returns false if
+    /// they aren't all there.
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> bool;
+}
+impl FatTable for [u8] {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> bool {
+        match self.get(offset..offset + buf.len()) {
+            Some(bytes) => {
+                buf.copy_from_slice(bytes);
+                true
+            }
+            None => false,
+        }
+    }
+}
+impl FatTable for Vec<u8> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> bool {
+        self[..].read_at(offset, buf)
+    }
+}
+impl<F: FatTable + ?Sized> FatTable for Box<F> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> bool {
+        (**self).read_at(offset, buf)
+    }
+}
+
+const FAT_PAGE_SIZE: usize = 4096;
+
+/// A FAT read from the image a page at a time as entries are looked up, for
+/// FATs too big to read whole under the memory limit. At most a quarter of
+/// the limit is kept, dropping the oldest pages first. A page that can't be
+/// read counts as past the end of the FAT, so a chain walked through it stops
+/// there as it would at a damaged entry.
+pub struct PagedFat<R> {
+    file: RefCell<R>,
+    start: u64,
+    len: u64,
+    pages: RefCell<HashMap<u64, Vec<u8>>>,
+    order: RefCell<VecDeque<u64>>,
+    max_pages: usize,
+}
+impl<R: Read + Seek> PagedFat<R> {
+    /// Pages in the same FAT `read_fat` would read from `file`.
+    pub fn new(info: &DiskInfo, file: R) -> Self {
+        let len = info.fat_sectors() as u64 * info.bytes_per_sector as u64;
+        let fat_start = info.reserved_sectors as u64 * info.bytes_per_sector as u64;
+        let budget = info.options.max_memory.map_or(1 << 20, |limit| limit / 4);
+        PagedFat {
+            file: RefCell::new(file),
+            start: fat_start + info.active_fat() as u64 * len,
+            len,
+            pages: RefCell::new(HashMap::new()),
+            order: RefCell::new(VecDeque::new()),
+            max_pages: (budget / FAT_PAGE_SIZE as u64).max(1) as usize,
+        }
+    }
+
+    fn load(&self, page: u64) -> Option<Vec<u8>> {
+        let offset = page * FAT_PAGE_SIZE as u64;
+        let mut bytes = vec![0; self.len.checked_sub(offset)?.min(FAT_PAGE_SIZE as u64) as usize];
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(self.start + offset)).ok()?;
+        file.read_exact(&mut bytes).ok()?;
+        Some(bytes)
+    }
+}
+impl<R: Read + Seek> FatTable for PagedFat<R> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> bool {
+        let mut done = 0;
+        while done < buf.len() {
+            let at = offset + done;
+            let page = (at / FAT_PAGE_SIZE) as u64;
+            let mut pages = self.pages.borrow_mut();
+            if !pages.contains_key(&page) {
+                let bytes = match self.load(page) {
+                    Some(bytes) => bytes,
+                    None => return false,
+                };
+                let mut order = self.order.borrow_mut();
+                if order.len() >= self.max_pages {
+                    if let Some(oldest) = order.pop_front() {
+                        pages.remove(&oldest);
+                    }
+                }
+                order.push_back(page);
+                pages.insert(page, bytes);
+            }
+            let bytes = &pages[&page][at % FAT_PAGE_SIZE..];
+            let len = bytes.len().min(buf.len() - done);
+            if len == 0 {
+                return false;
+            }
+            buf[done..done + len].copy_from_slice(&bytes[..len]);
+            done += len;
+        }
+        true
+    }
+}
+
+/// The first FAT, or on FAT32 the active one: read whole if the memory limit
+/// allows, otherwise paged in from `disk_file` as it is used. The FAT gets
+/// `disk_file` to itself, so pass it a handle of its own, such as a
+/// `File::try_clone`.
+pub fn load_fat<R: Read + Seek + 'static>(info: &DiskInfo, mut disk_file: R) -> Result<Box<dyn FatTable>> {
+    if memory::allows(info.options.max_memory, info.fat_sectors() as u64 * info.bytes_per_sector as u64) {
+        Ok(Box::new(read_fat(info, &mut disk_file)?))
+    } else {
+        Ok(Box::new(PagedFat::new(info, disk_file)))
+    }
+}
+
+/// Decodes the FAT12 entry for `cluster`.
+pub fn fat12_entry(fat: &[u8], cluster: u16) -> Option<u16> {
+    decode_entry(FatType::Fat12, fat, cluster as u32).map(|entry| entry as u16)
+}
+
+/// Decodes the FAT entry for `cluster`, whichever width the volume's entries
+/// are.
+pub fn fat_entry<F: FatTable + ?Sized>(info: &DiskInfo, fat: &F, cluster: u32) -> Option<u32> {
+    decode_entry(info.fat_type(), fat, cluster)
+}
+
+/// FAT12 entries are 12 bits, packed two to every three bytes: an even entry
+/// takes the low 12 bits of the little-endian word at its offset, an odd one
+/// the high 12 bits.
+fn decode_entry<F: FatTable + ?Sized>(fat_type: FatType, fat: &F, cluster: u32) -> Option<u32> {
+    let mut bytes = [0u8; 4];
+    match fat_type {
+        FatType::Fat12 => {
+            if !fat.read_at(cluster as usize * 3 / 2, &mut bytes[..2]) {
+                return None;
+            }
+            let pair = LittleEndian::read_u16(&bytes);
+            Some(if cluster & 1 == 0 { pair & 0x0FFF } else { pair >> 4 } as u32)
+        }
+        FatType::Fat16 => {
+            if !fat.read_at(cluster as usize * 2, &mut bytes[..2]) {
+                return None;
+            }
+            Some(LittleEndian::read_u16(&bytes) as u32)
+        }
+        FatType::Fat32 => {
+            if !fat.read_at(cluster as usize * 4, &mut bytes) {
+                return None;
+            }
+            Some(LittleEndian::read_u32(&bytes) & 0x0FFF_FFFF)
+        }
+    }
+}
+
+/// The clusters of the chain starting at `first`, in order. The walk stops at
+/// the end-of-chain marker, or early at a free, bad or out-of-range entry or a
+/// cluster seen before, so a damaged FAT can't send it round in circles.
+pub fn cluster_chain<F: FatTable + ?Sized>(info: &DiskInfo, fat: &F, first: u32) -> Vec<u32> {
+    let limit = cluster_limit(info);
+    let mut chain = Vec::new();
+    // Only the chain's own clusters are remembered, so a short chain on a big
+    // FAT32 volume stays cheap.
+    let mut seen = HashSet::new();
+    let mut cluster = first;
+    let most = info.options.max_chain.unwrap_or(u32::MAX) as usize;
+    while cluster >= 2 && cluster < limit && chain.len() < most && seen.insert(cluster) {
+        chain.push(cluster);
+        cluster = match fat_entry(info, fat, cluster) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    chain
+}
+
+/// How a volume's data clusters are used, as `free_space` counts them.
+pub struct FreeSpace {
+    pub cluster_size: u64,
+    pub clusters: u32,
+    pub free_clusters: u32,
+    /// Clusters marked bad, which count as neither used nor free.
+    pub bad_clusters: u32,
+}
+impl FreeSpace {
+    pub fn used_clusters(&self) -> u32 {
+        self.clusters - self.free_clusters - self.bad_clusters
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.clusters as u64 * self.cluster_size
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.used_clusters() as u64 * self.cluster_size
+    }
+
+    pub fn free_bytes(&self) -> u64 {
+        self.free_clusters as u64 * self.cluster_size
+    }
+}
+
+/// Counts the free and bad entries in `fat`. Unlike the FSInfo free count on
+/// FAT32, which is only a hint, this is always right.
+pub fn free_space<F: FatTable + ?Sized>(info: &DiskInfo, fat: &F) -> FreeSpace {
+    let bad = info.fat_type().bad_cluster();
+    let mut space = FreeSpace {
+        cluster_size: cluster_size(info),
+        clusters: 0,
+        free_clusters: 0,
+        bad_clusters: 0,
+    };
+    for cluster in 2..cluster_limit(info) {
+        space.clusters += 1;
+        match fat_entry(info, fat, cluster) {
+            Some(0) => space.free_clusters += 1,
+            Some(entry) if entry == bad => space.bad_clusters += 1,
+            _ => (),
+        }
+    }
+    space
+}
+
+/// What a file or directory takes up, as `du` gives it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Usage {
+    /// The path, ending in `/` for a directory below the one asked about.
+    pub path: String,
+    /// The bytes its files hold.
+    pub logical: u64,
+    /// The bytes of the clusters given to it.
+    pub allocated: u64,
+}
+
+/// The usage of the file at `path`, or of every file and directory below the
+/// directory there, each before its contents, and last of `path` itself. A
+/// directory counts the files under it at any depth and the clusters of the
+/// directories themselves, its own included, so `/` comes to the space
+/// `free_space` has as used unless clusters are lost. `None` if there is
+/// nothing at `path`.
+pub fn disk_usage<R: Read + Seek, F: FatTable + ?Sized>(info: &DiskInfo,
+                                                       disk_file: &mut R,
+                                                       fat: &F,
+                                                       path: &str)
+                                                       -> Result<Option<Vec<Usage>>> {
+    let top = path.trim_end_matches('/');
+    let directory = match Directory::open(info, disk_file, top)? {
+        Some(directory) => directory,
+        None => {
+            let usage = find_path(info, disk_file, path)?.map(|entry| {
+                vec![Usage {
+                         path: path.to_string(),
+                         logical: entry.file_size as u64,
+                         allocated: allocated_size(info, &entry),
+                     }]
+            });
+            return Ok(usage);
+        }
+    };
+    let chain_size = |first: u32| cluster_chain(info, fat, first).len() as u64 * cluster_size(info);
+    let own = match find_path(info, disk_file, top)? {
+        Some(entry) => chain_size(entry.flc),
+        None if info.fat_type() == FatType::Fat32 => chain_size(info.root_cluster),
+        // The root directory of FAT12 and FAT16 has no clusters.
+        None => 0,
+    };
+    let mut found = Vec::new();
+    walk_tree(info, disk_file, fat, &directory, top, false, &mut HashSet::new(), &mut |_, entry| {
+        found.push(entry);
+        Ok(())
+    })?;
+    // The totals of every directory, keyed by path, `top` included.
+    let mut totals: HashMap<&str, (u64, u64)> = HashMap::new();
+    totals.insert(top, (0, own));
+    for entry in &found {
+        let is_dir = entry.entry.attributes & DirEntryAttributes::SubDir as u8 != 0;
+        let (logical, allocated) = if is_dir {
+            (0, chain_size(entry.entry.flc))
+        } else {
+            (entry.entry.file_size as u64, allocated_size(info, &entry.entry))
+        };
+        let mut parent = &entry.path[..];
+        if is_dir {
+            totals.entry(parent).or_insert((0, 0)).1 += allocated;
+        }
+        while parent.len() > top.len() {
+            parent = split_path(parent).0;
+            let total = totals.entry(parent).or_insert((0, 0));
+            total.0 += logical;
+            total.1 += allocated;
+        }
+    }
+    let mut usage: Vec<Usage> = found.iter()
+        .map(|entry| {
+            if entry.entry.attributes & DirEntryAttributes::SubDir as u8 != 0 {
+                let (logical, allocated) = totals[&entry.path[..]];
+                Usage { path: format!("{}/", entry.path), logical, allocated }
+            } else {
+                Usage {
+                    path: entry.path.clone(),
+                    logical: entry.entry.file_size as u64,
+                    allocated: allocated_size(info, &entry.entry),
+                }
+            }
+        })
+        .collect();
+    let (logical, allocated) = totals[top];
+    usage.push(Usage { path: if top.is_empty() { "/" } else { top }.to_string(), logical, allocated });
+    Ok(Some(usage))
+}
+
+/// The runs of free clusters in `fat`, lowest first, each as its first
+/// cluster and length. Bad clusters break a run like used ones do.
+pub fn free_extents<F: FatTable + ?Sized>(info: &DiskInfo, fat: &F) -> Vec<(u32, u32)> {
+    let mut extents: Vec<(u32, u32)> = Vec::new();
+    for cluster in (2..cluster_limit(info)).filter(|&c| fat_entry(info, fat, c) == Some(0)) {
+        match extents.last_mut() {
+            Some(last) if last.0 + last.1 == cluster => last.1 += 1,
+            _ => extents.push((cluster, 1)),
+        }
+    }
+    extents
+}
+
+/// The first FAT of a volume, with the range of clusters it can refer to.
+pub struct Fat {
+    bytes: Vec<u8>,
+    fat_type: FatType,
+    limit: u32,
+}
+impl Fat {
+    pub fn load<R: Read + Seek>(info: &DiskInfo, disk_file: &mut R) -> Result<Self> {
+        Ok(Fat::from_bytes(info, read_fat(info, disk_file)?))
+    }
+
+    /// Wraps a FAT already read, e.g. by `read_fat`.
+    pub fn from_bytes(info: &DiskInfo, bytes: Vec<u8>) -> Self {
+        Fat::of_type(info.fat_type(), cluster_limit(info), bytes)
+    }
+
+    /// Wraps FAT bytes with no volume around them, for a volume of type
+    /// `fat_type` whose clusters are numbered below `limit`.
+    pub fn of_type(fat_type: FatType, limit: u32, bytes: Vec<u8>) -> Self {
+        Fat { bytes, fat_type, limit }
+    }
+
+    /// The raw FAT, for the functions that take one as bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    pub fn entry(&self, cluster: u32) -> Option<u32> {
+        decode_entry(self.fat_type, &self.bytes, cluster)
+    }
+
+    /// The clusters of the chain starting at `first`; none if `first` is 0,
+    /// as for an empty file. Unlike `cluster_chain`, damage is reported: the
+    /// walk ends with a `CorruptFatChain` error at a free, bad or out-of-range
+    /// entry, or one leading back into the chain.
+    pub fn chain(&self, first: u32) -> Chain<'_> {
+        Chain {
+            fat: self,
+            next: if first == 0 { None } else { Some(first) },
+            previous: None,
+            seen: HashSet::new(),
+        }
+    }
+}
+impl FatTable for Fat {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> bool {
+        self.bytes.read_at(offset, buf)
+    }
+}
+
+/// The iterator returned by `Fat::chain`.
+pub struct Chain<'a> {
+    fat: &'a Fat,
+    next: Option<u32>,
+    previous: Option<u32>,
+    seen: HashSet<u32>,
+}
+impl<'a> Iterator for Chain<'a> {
+    type Item = Result<u32>;
+
+    fn next(&mut self) -> Option<Result<u32>> {
+        let cluster = self.next.take()?;
+        // Blame the entry that led here, or the first cluster itself.
+        let at = self.previous.unwrap_or(cluster);
+        let broken = Some(Err(Error::CorruptFatChain(format!("cluster {}", at))));
+        if cluster < 2 || cluster >= self.fat.limit || !self.seen.insert(cluster) {
+            return broken;
+        }
+        match self.fat.entry(cluster) {
+            Some(next) if self.fat.fat_type.is_end_of_chain(next) => (),
+            Some(next) => self.next = Some(next),
+            None => return broken,
+        }
+        self.previous = Some(cluster);
+        Some(Ok(cluster))
+    }
+}
+
+/// Sets the FAT12 entry for `cluster`, leaving the neighbouring entry that
+/// shares its middle byte alone.
+pub fn set_fat12_entry(fat: &mut [u8], cluster: u16, value: u16) {
+    let offset = cluster as usize * 3 / 2;
+    let pair = LittleEndian::read_u16(&fat[offset..]);
+    let pair = if cluster & 1 == 0 {
+        (pair & 0xF000) | (value & 0x0FFF)
+    } else {
+        (pair & 0x000F) | (value << 4)
+    };
+    LittleEndian::write_u16(&mut fat[offset..], pair);
+}
+
+/// Sets the FAT entry for `cluster`, whichever width the volume's entries
+/// are.
+pub fn set_fat_entry(info: &DiskInfo, fat: &mut [u8], cluster: u32, value: u32) {
+    match info.fat_type() {
+        FatType::Fat12 => set_fat12_entry(fat, cluster as u16, value as u16),
+        FatType::Fat16 => LittleEndian::write_u16(&mut fat[cluster as usize * 2..], value as u16),
+        FatType::Fat32 => {
+            let offset = cluster as usize * 4;
+            let reserved = LittleEndian::read_u32(&fat[offset..]) & 0xF000_0000;
+            LittleEndian::write_u32(&mut fat[offset..], reserved | (value & 0x0FFF_FFFF));
+        }
+    }
+}
+
+/// Writes `fat` over every FAT copy, keeping them identical, or only over the
+/// active one if a FAT32 volume has mirroring off. The FSInfo free count is
+/// updated to match.
+pub fn write_fat<R: Read + Write + Seek>(info: &DiskInfo,
+                                         disk_file: &mut R,
+                                         fat: &[u8])
+                                         -> Result<()> {
+    let fat_start = info.reserved_sectors as u64 * info.bytes_per_sector as u64;
+    for n in 0..info.fats {
+        if info.ext_flags & 0x80 != 0 && n != info.active_fat() {
+            continue;
+        }
+        disk_file.seek(SeekFrom::Start(fat_start + n as u64 * fat.len() as u64))?;
+        disk_file.write_all(fat)?;
+    }
+    write_fs_info(info, disk_file, fat)
+}
+
+/// Allocates `count` free clusters, lowest first, and links them into a
+/// chain in `fat`. Returns `None`, leaving `fat` untouched, if there aren't
+/// enough.
+pub fn allocate_clusters(info: &DiskInfo, fat: &mut [u8], count: usize) -> Option<Vec<u32>> {
+    let clusters: Vec<u32> = (2..cluster_limit(info))
+        .filter(|&cluster| fat_entry(info, fat, cluster) == Some(0))
+        .take(count)
+        .collect();
+    if clusters.len() < count {
+        return None;
+    }
+    for pair in clusters.windows(2) {
+        set_fat_entry(info, fat, pair[0], pair[1]);
+    }
+    if let Some(&last) = clusters.last() {
+        set_fat_entry(info, fat, last, info.fat_type().end_of_chain());
+    }
+    Some(clusters)
+}
+
+/// Copies a file's contents to `out` cluster by cluster, following its FAT
+/// chain and stopping at the recorded size. Returns how many bytes were
+/// written, which falls short of the size if the chain ends too soon.
+pub fn copy_file<R: Read + Seek, F: FatTable + ?Sized, W: Write>(info: &DiskInfo,
+                                                                 disk_file: &mut R,
+                                                                 fat: &F,
+                                                                 entry: &DirEntry,
+                                                                 out: &mut W)
+                                                                 -> Result<u64> {
+    if let Some(max) = info.options.max_output.filter(|&max| entry.file_size as u64 > max) {
+        return Err(Error::LimitExceeded(format!("{}: {} bytes, over the output limit of {}",
+                                                entry.name(),
+                                                entry.file_size,
+                                                max)));
+    }
+    let mut remaining = entry.file_size as u64;
+    let mut buf = vec![0; cluster_size(info) as usize];
+    let chain = cluster_chain(info, fat, entry.flc);
+    for &cluster in &chain {
+        if remaining == 0 {
+            break;
+        }
+        let len = remaining.min(buf.len() as u64) as usize;
+        disk_file.seek(SeekFrom::Start(cluster_start(info, cluster)))?;
+        disk_file.read_exact(&mut buf[..len])?;
+        out.write_all(&buf[..len])?;
+        remaining -= len as u64;
+    }
+    match info.options.max_chain {
+        Some(max) if remaining > 0 && chain.len() == max as usize => {
+            let why = format!("{}: more than the chain limit of {} clusters", entry.name(), max);
+            Err(Error::LimitExceeded(why))
+        }
+        _ => Ok(entry.file_size as u64 - remaining),
+    }
+}
+
+/// The 32-byte slots of a directory, wherever they are stored.
+///
+/// Slots are kept as a list of extents, each a byte offset and a slot count,
+/// so the fixed root directory region and cluster-chained subdirectories can
+/// be handled the same way. Slots are numbered across extents in order.
+#[derive(Clone)]
+pub struct Directory {
+    pub extents: Vec<(u64, usize)>,
+    /// The volume's, for reading the entries.
+    fat_type: FatType,
+    /// The volume's, for what reading the entries turns up.
+    warnings: Warnings,
+}
+impl Directory {
+    /// The root directory. On FAT12 and FAT16 it is one fixed run of sectors
+    /// after the FATs, which can't grow past the BPB's root entry count and
+    /// doesn't need `fat`; on FAT32 it is the chain from the root cluster.
+    pub fn root<F: FatTable + ?Sized>(info: &DiskInfo, fat: &F) -> Self {
+        if info.fat_type() == FatType::Fat32 {
+            Directory::chain(info, fat, info.root_cluster)
+        } else {
+            Directory {
+                extents: vec![(root_dir_start(info), info.root_dir_entries as usize)],
+                fat_type: info.fat_type(),
+                warnings: info.options.warnings.clone(),
+            }
+        }
+    }
+
+    /// A subdirectory, stored in the clusters of the chain from `first`.
+    pub fn chain<F: FatTable + ?Sized>(info: &DiskInfo, fat: &F, first: u32) -> Self {
+        let slots_per_cluster = cluster_size(info) as usize / DIR_ENTRY_SIZE;
+        let mut extents: Vec<(u64, usize)> = Vec::new();
+        for cluster in cluster_chain(info, fat, first) {
+            let offset = cluster_start(info, cluster);
+            match extents.last_mut() {
+                Some(last) if last.0 + (last.1 * DIR_ENTRY_SIZE) as u64 == offset => {
+                    last.1 += slots_per_cluster
+                }
+                _ => extents.push((offset, slots_per_cluster)),
+            }
+        }
+        Directory { extents, fat_type: info.fat_type(), warnings: info.options.warnings.clone() }
+    }
+
+    /// Follows a slash-separated path of names, short or long, down from the
+    /// root, e.g. `/DOCS/2016`. Returns `None` if a component is missing or not a
+    /// directory, and fails with `InvalidDirEntry` if one points outside the
+    /// data area.
+    pub fn open<R: Read + Seek>(info: &DiskInfo,
+                                disk_file: &mut R,
+                                path: &str)
+                                -> Result<Option<Self>> {
+        // Only the chains on the way are needed, so the FAT is paged in
+        // rather than read whole.
+        let mut directory = Directory::root(info, &PagedFat::new(info, &mut *disk_file));
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let entry = match directory.find(disk_file, name)? {
+                Some((_, entry)) if (entry.attributes & DirEntryAttributes::SubDir as u8) != 0 => entry,
+                _ => return Ok(None),
+            };
+            // A ".." entry pointing at cluster 0 leads back to the root.
+            let fat = PagedFat::new(info, &mut *disk_file);
+            directory = if entry.flc == 0 {
+                Directory::root(info, &fat)
+            } else if entry.flc < 2 || entry.flc >= cluster_limit(info) {
+                return Err(Error::InvalidDirEntry(path.to_string()));
+            } else {
+                Directory::chain(info, &fat, entry.flc)
+            };
+        }
+        Ok(Some(directory))
+    }
+
+    /// Byte offset of slot `slot` in the image.
+    pub fn slot_offset(&self, slot: usize) -> Option<u64> {
+        let mut slot = slot;
+        for &(offset, count) in &self.extents {
+            if slot < count {
+                return Some(offset + (slot * DIR_ENTRY_SIZE) as u64);
+            }
+            slot -= count;
+        }
+        None
+    }
+
+    /// Reads every slot, used or not, as consecutive 32-byte records.
+    pub fn read_slots<R: Read + Seek>(&self, disk_file: &mut R) -> Result<Vec<u8>> {
+        let mut slots = Vec::new();
+        for &(offset, count) in &self.extents {
+            let start = slots.len();
+            slots.resize(start + count * DIR_ENTRY_SIZE, 0);
+            disk_file.seek(SeekFrom::Start(offset))?;
+            disk_file.read_exact(&mut slots[start..])?;
+        }
+        Ok(slots)
+    }
+
+    /// Writes back every slot, as returned by `read_slots`.
+    pub fn write_slots<R: Read + Write + Seek>(&self,
+                                               disk_file: &mut R,
+                                               slots: &[u8])
+                                               -> Result<()> {
+        let mut start = 0;
+        for &(offset, count) in &self.extents {
+            let end = start + count * DIR_ENTRY_SIZE;
+            disk_file.seek(SeekFrom::Start(offset))?;
+            disk_file.write_all(&slots[start..end])?;
+            start = end;
+        }
+        Ok(())
+    }
+
+    /// Reads the live (non-deleted) entries along with their slot numbers,
+    /// stopping at the end-of-directory marker. LFN slots are included as they
+    /// are, and the long names they spell are decoded into the `long_name` of
+    /// the entries they belong to. Slots that spell no name and invalid
+    /// timestamps are reported to the volume's `Warnings`.
+    pub fn entries<R: Read + Seek>(&self,
+                                   disk_file: &mut R)
+                                   -> Result<Vec<(usize, DirEntry)>> {
+        Ok(decode_dir(&self.read_slots(disk_file)?, self.fat_type, &self.warnings))
+    }
+
+    /// The first slot free for a new entry: deleted, or past the end marker.
+    pub fn free_slot<R: Read + Seek>(&self, disk_file: &mut R) -> Result<Option<usize>> {
+        self.free_slots(disk_file, 1)
+    }
+
+    /// The first of `count` free slots in a row, for an entry with LFN slots
+    /// before it.
+    pub fn free_slots<R: Read + Seek>(&self, disk_file: &mut R, count: usize) -> Result<Option<usize>> {
+        let slots = self.read_slots(disk_file)?;
+        let mut past_end = false;
+        let mut run = 0;
+        for (i, slot) in slots.chunks(DIR_ENTRY_SIZE).enumerate() {
+            past_end |= slot[0] == 0x00;
+            if past_end || slot[0] == 0xE5 {
+                run += 1;
+                if run == count {
+                    return Ok(Some(i + 1 - count));
+                }
+            } else {
+                run = 0;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Every run of free slots, in order. A run of deleted slots is kept apart
+    /// from the never-used ones after the end marker, which are always the
+    /// last run, since a new entry there has to be followed by a new marker.
+    pub fn free_runs<R: Read + Seek>(&self, disk_file: &mut R) -> Result<Vec<SlotRun>> {
+        let slots = self.read_slots(disk_file)?;
+        let mut runs: Vec<SlotRun> = Vec::new();
+        let mut past_end = false;
+        for (i, slot) in slots.chunks(DIR_ENTRY_SIZE).enumerate() {
+            let unused = past_end || slot[0] == 0x00;
+            if !unused && slot[0] != 0xE5 {
+                continue;
+            }
+            match runs.last_mut() {
+                Some(run) if run.first + run.count == i && run.unused == unused => run.count += 1,
+                _ => {
+                    runs.push(SlotRun { first: i, count: 1, offset: self.slot_offset(i).unwrap(), unused })
+                }
+            }
+            past_end = unused;
+        }
+        Ok(runs)
+    }
+
+    pub fn write_slot<R: Read + Write + Seek>(&self,
+                                              disk_file: &mut R,
+                                              slot: usize,
+                                              data: &[u8])
+                                              -> Result<()> {
+        disk_file.seek(SeekFrom::Start(self.slot_offset(slot).unwrap()))?;
+        Ok(disk_file.write_all(data)?)
+    }
+
+    /// Looks up a live entry by its 8.3 name or its long name, ignoring case.
+    pub fn find<R: Read + Seek>(&self,
+                                disk_file: &mut R,
+                                name: &str)
+                                -> Result<Option<(usize, DirEntry)>> {
+        Ok(self.entries(disk_file)?
+            .into_iter()
+            .filter(|(_, entry)| !entry.is_lfn())
+            .find(|(_, entry)| {
+                entry.name().eq_ignore_ascii_case(name) ||
+                entry.long_name.as_ref().is_some_and(|long| long.to_lowercase() == name.to_lowercase())
+            }))
+    }
+}
+
+/// Free slots in a row in a directory, as `Directory::free_runs` finds them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SlotRun {
+    /// The first slot, numbered as `Directory` numbers them.
+    pub first: usize,
+    pub count: usize,
+    /// Byte offset of the first slot. A run in a subdirectory can go on into
+    /// a cluster elsewhere; `Directory::slot_offset` finds each slot.
+    pub offset: u64,
+    /// Whether the slots are past the end marker, rather than deleted entries.
+    pub unused: bool,
+}
+
+/// Looks up the entry a slash-separated path names, e.g. `/DOCS/NOTE.TXT`.
+/// The root itself has no entry.
+pub fn find_path<R: Read + Seek>(info: &DiskInfo,
+                                 disk_file: &mut R,
+                                 path: &str)
+                                 -> Result<Option<DirEntry>> {
+    Ok(find_slot(info, disk_file, path)?.map(|(_, _, entry)| entry))
+}
+
+/// Splits a path into its parent directory and last name, e.g. `/DOCS` and
+/// `NOTE.TXT`.
+pub fn split_path(path: &str) -> (&str, &str) {
+    let path = path.trim_end_matches('/');
+    match path.rfind('/') {
+        Some(slash) => (&path[..slash], &path[slash + 1..]),
+        None => ("", path),
+    }
+}
+
+/// Like `find_path`, but also returns the directory holding the entry and its
+/// slot there, for changing the entry in place.
+pub fn find_slot<R: Read + Seek>(info: &DiskInfo,
+                                 disk_file: &mut R,
+                                 path: &str)
+                                 -> Result<Option<(Directory, usize, DirEntry)>> {
+    let (parent, name) = split_path(path);
+    if name.is_empty() {
+        return Ok(None);
+    }
+    let directory = match Directory::open(info, disk_file, parent)? {
+        Some(directory) => directory,
+        None => return Ok(None),
+    };
+    Ok(directory.find(disk_file, name)?.map(|(slot, entry)| (directory, slot, entry)))
+}
+
+/// An entry found walking the tree: its path from the root, e.g.
+/// `/DOCS/NOTE.TXT`, and the byte offset of its slot in the image.
+pub struct TreeEntry {
+    pub path: String,
+    pub offset: u64,
+    pub entry: DirEntry,
+    pub deleted: bool,
+}
+
+/// Passes every file and subdirectory below `directory` to `visit`, depth
+/// first, each before its contents. `visit` is lent `disk_file` too, to read
+/// the entry's data. Labels, LFN slots and the `.` and `..` entries are left out,
+/// though long names are decoded into the entries. A subdirectory whose
+/// cluster was already visited isn't entered again, so a damaged tree that
+/// loops back on itself is walked once, with a warning. With
+/// `deleted`, deleted entries are included, their lost first character shown
+/// as `_`; deleted directories aren't entered, as their clusters may have been
+/// reused.
+#[allow(clippy::too_many_arguments)]
+pub fn walk_tree<R: Read + Seek, F: FatTable + ?Sized>(info: &DiskInfo,
+                                                      disk_file: &mut R,
+                                                      fat: &F,
+                                                      directory: &Directory,
+                                                      path: &str,
+                                                      deleted: bool,
+                                                      seen: &mut HashSet<u32>,
+                                                      visit: &mut dyn FnMut(&mut R, TreeEntry) -> Result<()>)
+                                                      -> Result<()> {
+    walk_counted(info, disk_file, fat, directory, path, deleted, seen, &mut 0, visit)
+}
+
+/// `walk_tree`, with `scanned` counting the slots gone through for the
+/// volume's `max_entries`.
+#[allow(clippy::too_many_arguments)]
+fn walk_counted<R: Read + Seek, F: FatTable + ?Sized>(info: &DiskInfo,
+                                                     disk_file: &mut R,
+                                                     fat: &F,
+                                                     directory: &Directory,
+                                                     path: &str,
+                                                     deleted: bool,
+                                                     seen: &mut HashSet<u32>,
+                                                     scanned: &mut u64,
+                                                     visit: &mut dyn FnMut(&mut R, TreeEntry) -> Result<()>)
+                                                     -> Result<()> {
+    let slots = directory.read_slots(disk_file)?;
+    let mut long_name = LongName::default();
+    for (slot, data) in slots.chunks(DIR_ENTRY_SIZE).enumerate() {
+        if data[0] == 0x00 {
+            break;
+        }
+        *scanned += 1;
+        if let Some(max) = info.options.max_entries.filter(|&max| *scanned > max) {
+            let path = if path.is_empty() { "/" } else { path };
+            return Err(Error::LimitExceeded(format!("{}: more than the limit of {} entries", path, max)));
+        }
+        let is_deleted = data[0] == 0xE5;
+        let mut entry = DirEntry::read(data, info.fat_type());
+        if is_deleted {
+            long_name.reset(&info.options.warnings);
+        } else if entry.is_lfn() {
+            long_name.add_slot(data);
+        } else {
+            entry.long_name = long_name.finish(data, &entry.name(), &info.options.warnings)
+                .or_else(|| entry.cased_name());
+        }
+        let mut name = entry.name();
+        if (is_deleted && !deleted) || (entry.attributes & DirEntryAttributes::VolumeLabel as u8) != 0 ||
+           name == "." || name == ".." {
+            continue;
+        }
+        if is_deleted {
+            name = format!("_{}", name.chars().skip(1).collect::<String>());
+        }
+        let entry_path = format!("{}/{}", path, name);
+        let subdir = if (entry.attributes & DirEntryAttributes::SubDir as u8) != 0 && !is_deleted &&
+                        entry.flc >= 2 {
+            if seen.insert(entry.flc) {
+                Some(Directory::chain(info, fat, entry.flc))
+            } else {
+                info.options.warnings.warn(Warning::DirectoryCycle(entry_path.clone()));
+                None
+            }
+        } else {
+            None
+        };
+        visit(disk_file, TreeEntry {
+            path: entry_path.clone(),
+            offset: directory.slot_offset(slot).unwrap(),
+            entry,
+            deleted: is_deleted,
+        })?;
+        if let Some(subdir) = subdir {
+            walk_counted(info, disk_file, fat, &subdir, &entry_path, deleted, seen, scanned, visit)?;
+        }
+    }
+    Ok(())
+}
+
+/// Passes every entry in the volume to `visit` as `walk_tree` finds it from
+/// the root, without collecting them, so memory doesn't grow with the number
+/// of files.
+pub fn for_each_entry<R, F, V>(info: &DiskInfo,
+                               disk_file: &mut R,
+                               fat: &F,
+                               deleted: bool,
+                               mut visit: V)
+                               -> Result<()>
+    where R: Read + Seek,
+          F: FatTable + ?Sized,
+          V: FnMut(&mut R, TreeEntry) -> Result<()>
+{
+    let mut seen = HashSet::new();
+    // A FAT32 root has a cluster of its own that a damaged entry could lead
+    // back to.
+    if info.fat_type() == FatType::Fat32 {
+        seen.insert(info.root_cluster);
+    }
+    walk_tree(info,
+              disk_file,
+              fat,
+              &Directory::root(info, fat),
+              "",
+              deleted,
+              &mut seen,
+              &mut visit)
+}
+
+/// Every entry in the volume, as found by `walk_tree` from the root.
+pub fn tree<R: Read + Seek, F: FatTable + ?Sized>(info: &DiskInfo,
+                                                  disk_file: &mut R,
+                                                  fat: &F,
+                                                  deleted: bool)
+                                                  -> Result<Vec<TreeEntry>> {
+    let mut entries = Vec::new();
+    for_each_entry(info, disk_file, fat, deleted, |_, found| {
+        entries.push(found);
+        Ok(())
+    })?;
+    Ok(entries)
+}
+
+/// A `Fat12Volume` that can only be read. Nothing it offers writes to the
+/// image, whatever the image allows, so code given one can't change it:
+///
+/// ```compile_fail
+/// let image = std::io::Cursor::new(std::fs::read("disk.img").unwrap());
+/// let mut volume = fat12::Fat12Volume::open(image).unwrap();
+/// volume.rm("/COMMAND.COM").unwrap();
+/// ```
+pub enum ReadOnly {}
+
+/// A `Fat12Volume` that can be changed as well as read, opened with
+/// `open_writable`.
+pub enum ReadWrite {}
+
+/// A FAT volume: the image, its boot sector parameters and its FAT. It is
+/// `ReadOnly` as `open` gives it; `open_writable` gives a `ReadWrite` one,
+/// which can also create, delete and change entries.
+///
+/// ```no_run
+/// let file = std::fs::File::open("disk.img").unwrap();
+/// let mut volume = fat12::Fat12Volume::open(file).unwrap();
+/// for entry in volume.root_dir().unwrap() {
+///     println!("{} {}", entry.name(), entry.file_size);
+/// }
+/// let readme = volume.read_file("/README.TXT").unwrap();
+///
+/// // The same, from an image already in memory.
+/// let image = std::fs::read("disk.img").unwrap();
+/// let mut volume = fat12::Fat12Volume::open(std::io::Cursor::new(image)).unwrap();
+/// ```
+pub struct Fat12Volume<R = File, M = ReadOnly> {
+    file: R,
+    info: DiskInfo,
+    fat: Fat,
+    mode: PhantomData<M>,
+}
+impl<R: Read + Seek> Fat12Volume<R, ReadOnly> {
+    /// Reads the boot sector and FAT of the image in `file`. Fails with
+    /// `InvalidBootSector` if the BPB doesn't describe a usable layout.
+    pub fn open(file: R) -> Result<Self> {
+        Self::open_with(file, &VolumeOptions::default())
+    }
+
+    /// Like `open`, but read with `options`, as `read_disk_info_with` does.
+    pub fn open_with(file: R, options: &VolumeOptions) -> Result<Self> {
+        Fat12Volume::load(file, options)
+    }
+}
+impl<R: Read + Write + Seek> Fat12Volume<R, ReadWrite> {
+    /// Like `open`, but the volume can be changed too.
+    pub fn open_writable(file: R) -> Result<Self> {
+        Self::open_writable_with(file, &VolumeOptions::default())
+    }
+
+    /// Like `open_with`, but the volume can be changed too. The changes are
+    /// decided by the options' policy, if they have one.
+    pub fn open_writable_with(file: R, options: &VolumeOptions) -> Result<Self> {
+        Fat12Volume::load(file, options)
+    }
+
+    /// Copies the host file `host_path` into the volume at `path`, as `put`
+    /// does.
+    pub fn put(&mut self, host_path: &Path, path: &str) -> Result<()> {
+        let done = put(&self.info, &mut self.file, host_path, path);
+        self.reload_fat()?;
+        done
+    }
+
+    /// Creates the directory at `path`, as `mkdir` does.
+    pub fn mkdir(&mut self, path: &str) -> Result<()> {
+        let done = mkdir(&self.info, &mut self.file, path);
+        self.reload_fat()?;
+        done
+    }
+
+    /// Deletes the file at `path`, as `rm` does.
+    pub fn rm(&mut self, path: &str) -> Result<undo::Deletion> {
+        let done = rm(&self.info, &mut self.file, path);
+        self.reload_fat()?;
+        done
+    }
+
+    /// Deletes the empty directory at `path`, as `rmdir` does.
+    pub fn rmdir(&mut self, path: &str) -> Result<undo::Deletion> {
+        let done = rmdir(&self.info, &mut self.file, path);
+        self.reload_fat()?;
+        done
+    }
+
+    /// Renames the entry at `path`, as `rename` does.
+    pub fn rename(&mut self, path: &str, new_name: &str) -> Result<()> {
+        let done = rename(&self.info, &mut self.file, path, new_name);
+        self.reload_fat()?;
+        done
+    }
+
+    /// Changes the attributes of the entry at `path`, as `set_attributes`
+    /// does.
+    pub fn set_attributes(&mut self, path: &str, set: u8, clear: u8) -> Result<u8> {
+        set_attributes(&self.info, &mut self.file, path, set, clear)
+    }
+
+    /// The same volume, from now on only to be read.
+    pub fn into_read_only(self) -> Fat12Volume<R, ReadOnly> {
+        Fat12Volume { file: self.file, info: self.info, fat: self.fat, mode: PhantomData }
+    }
+
+    /// Reads the FAT again after a change, which may have written to it
+    /// even if it failed partway.
+    fn reload_fat(&mut self) -> Result<()> {
+        self.fat = Fat::load(&self.info, &mut self.file)?;
+        Ok(())
+    }
+}
+impl<R: Read + Seek, M> Fat12Volume<R, M> {
+    fn load(mut file: R, options: &VolumeOptions) -> Result<Self> {
+        let info = read_disk_info_with(&mut file, options)?;
+        if !bpb_looks_valid(&info) {
+            return Err(Error::InvalidBootSector("it doesn't describe a FAT volume".to_string()));
+        }
+        let fat = Fat::load(&info, &mut file)?;
+        Ok(Fat12Volume { file, info, fat, mode: PhantomData })
+    }
+
+    pub fn info(&self) -> &DiskInfo {
+        &self.info
+    }
+
+    /// What was found odd but worked around since the last call, oldest
+    /// first, as `Warnings::take` gives it.
+    pub fn take_warnings(&self) -> Vec<Warning> {
+        self.info.options.warnings.take()
+    }
+
+    pub fn fat(&self) -> &Fat {
+        &self.fat
+    }
+
+    /// The live entries of the root directory, LFN slots and labels included.
+    pub fn root_dir(&mut self) -> Result<Vec<DirEntry>> {
+        self.read_dir("/")
+    }
+
+    /// The live entries of the directory at `path`, e.g. `/DOCS/2016`.
+    pub fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>> {
+        let directory = match Directory::open(&self.info, &mut self.file, path)? {
+            Some(directory) => directory,
+            None if self.entry(path)?.is_some() => return Err(Error::NotADirectory(path.to_string())),
+            None => return Err(Error::NotFound(path.to_string())),
+        };
+        Ok(directory.entries(&mut self.file)?.into_iter().map(|(_, entry)| entry).collect())
+    }
+
+    /// The entry at `path`, if there is one.
+    pub fn entry(&mut self, path: &str) -> Result<Option<DirEntry>> {
+        find_path(&self.info, &mut self.file, path)
+    }
+
+    /// The contents of the file at `path`. Fails with `CorruptFatChain` if
+    /// its cluster chain ends before its recorded size.
+    pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>> {
+        let entry = self.entry(path)?.ok_or_else(|| Error::NotFound(path.to_string()))?;
+        if (entry.attributes & DirEntryAttributes::SubDir as u8) != 0 {
+            return Err(Error::IsADirectory(path.to_string()));
+        }
+        let mut contents = Vec::new();
+        if copy_file(&self.info, &mut self.file, self.fat.as_bytes(), &entry, &mut contents)? < entry.file_size as u64 {
+            return Err(Error::CorruptFatChain(path.to_string()));
+        }
+        Ok(contents)
+    }
+
+    /// Every entry in the volume, depth first, with its path.
+    pub fn walk(&mut self, deleted: bool) -> Result<Vec<TreeEntry>> {
+        tree(&self.info, &mut self.file, self.fat.as_bytes(), deleted)
+    }
+
+    pub fn free_space(&self) -> FreeSpace {
+        free_space(&self.info, self.fat.as_bytes())
+    }
+
+    /// The runs of free clusters, as `free_extents` gives them, for planning
+    /// where data goes before writing it.
+    pub fn free_extents(&self) -> Vec<(u32, u32)> {
+        free_extents(&self.info, self.fat.as_bytes())
+    }
+
+    /// The runs of free slots in the directory at `path`, as
+    /// `Directory::free_runs` gives them. A directory whose slots are all in
+    /// use has none, though a subdirectory can still grow by a cluster.
+    pub fn free_dir_slots(&mut self, path: &str) -> Result<Vec<SlotRun>> {
+        match Directory::open(&self.info, &mut self.file, path)? {
+            Some(directory) => directory.free_runs(&mut self.file),
+            None if self.entry(path)?.is_some() => Err(Error::NotADirectory(path.to_string())),
+            None => Err(Error::NotFound(path.to_string())),
+        }
+    }
+
+    /// Where logical sector `lba` is on the disk, as `lba_to_chs` gives it.
+    pub fn lba_to_chs(&self, lba: u32) -> Option<Chs> {
+        lba_to_chs(&self.info, lba)
+    }
+
+    pub fn chs_to_lba(&self, chs: Chs) -> Option<u32> {
+        chs_to_lba(&self.info, chs)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.file
+    }
+}
+
+/// What `redact` should scrub from an image.
+pub struct RedactOptions {
+    pub wipe_labels: bool,
+    pub zero_timestamps: bool,
+    pub strip_deleted: bool,
+    /// Zero what is left of each file's last cluster past its end.
+    pub zero_slack: bool,
+}
+
+/// What `compact_dir` removed.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Compaction {
+    /// Deleted slots, and LFN slots that spell no long name for the entry
+    /// after them.
+    pub slots: usize,
+    /// Clusters freed from the end of the directory's chain.
+    pub clusters: usize,
+}
+
+/// Compacts the directory at `path`: its deleted slots and the LFN slots
+/// that make no whole long name are dropped, and the rest moved up in order,
+/// each long name's slots right before its short entry. The clusters at the
+/// end of a subdirectory's chain left holding no entries are then freed,
+/// keeping the first. The fixed root directory of FAT12 and FAT16 only has
+/// its slots compacted.
+pub fn compact_dir<R: Read + Write + Seek>(info: &DiskInfo,
+                                           disk_file: &mut R,
+                                           path: &str)
+                                           -> Result<Compaction> {
+    let first = if path.trim_matches('/').is_empty() {
+        if info.fat_type() == FatType::Fat32 { Some(info.root_cluster) } else { None }
+    } else {
+        let entry = find_path(info, disk_file, path)?.ok_or_else(|| Error::NotFound(path.to_string()))?;
+        if (entry.attributes & DirEntryAttributes::SubDir as u8) == 0 {
+            return Err(Error::NotADirectory(path.to_string()));
+        }
+        if entry.flc < 2 || entry.flc >= cluster_limit(info) {
+            return Err(Error::InvalidDirEntry(path.to_string()));
+        }
+        Some(entry.flc)
+    };
+    let mut fat = read_fat(info, disk_file)?;
+    let directory = match first {
+        Some(first) => Directory::chain(info, &fat[..], first),
+        None => Directory::root(info, &fat[..]),
+    };
+    let slots = directory.read_slots(disk_file)?;
+    let used = slots.chunks(DIR_ENTRY_SIZE).take_while(|slot| slot[0] != 0x00).count();
+    let mut kept: Vec<&[u8]> = Vec::new();
+    // The live LFN slots since the last short entry or deleted slot.
+    let mut pending: Vec<&[u8]> = Vec::new();
+    for slot in slots.chunks(DIR_ENTRY_SIZE).take(used) {
+        if slot[0] == 0xE5 {
+            pending.clear();
+        } else if slot[DIR_ENTRY_ATTRS] & 0x3F == LFN_ATTRIBUTES {
+            pending.push(slot);
+        } else {
+            // Only the run from the last slot starting a name can be whole.
+            let starts_name = |lfn: &&[u8]| lfn[LFN_ORDER] & LFN_LAST != 0;
+            let start = pending.iter().rposition(starts_name).unwrap_or(pending.len());
+            let run = &pending[start..];
+            let mut long_name = LongName::default();
+            for lfn in run {
+                long_name.add_slot(lfn);
+            }
+            if long_name.finish(slot, "", &Warnings::default()).is_some() {
+                kept.extend_from_slice(run);
+            }
+            kept.push(slot);
+            pending.clear();
+        }
+    }
+    let removed = used - kept.len();
+    if removed > 0 {
+        let mut compacted = kept.concat();
+        compacted.resize(slots.len(), 0);
+        directory.write_slots(disk_file, &compacted)?;
+    }
+    let mut freed = 0;
+    if let Some(first) = first {
+        let chain = cluster_chain(info, &fat[..], first);
+        let needed = kept.len().div_ceil(cluster_size(info) as usize / DIR_ENTRY_SIZE).max(1);
+        if chain.len() > needed {
+            set_fat_entry(info, &mut fat, chain[needed - 1], info.fat_type().end_of_chain());
+            for &cluster in &chain[needed..] {
+                set_fat_entry(info, &mut fat, cluster, 0);
+            }
+            // The entries were moved out of the clusters first, so an
+            // interrupted compaction leaves them empty rather than lost.
+            write_fat(info, disk_file, &fat)?;
+            freed = chain.len() - needed;
+        }
+    }
+    Ok(Compaction { slots: removed, clusters: freed })
+}
+
+/// The earliest DOS timestamp, 1980-01-01 00:00:00, used in place of real ones.
+const DOS_EPOCH_DATE: u16 = (1 << 5) | 1;
+const DOS_EPOCH_TIME: u16 = 0;
+
+/// Scrubs identifying metadata from the boot sector and every directory, and
+/// with `zero_slack` the slack of every file, returning a line of report for
+/// each kind of data removed. Labels are only looked for in the root, where
+/// DOS keeps them.
+pub fn redact<R: Read + Write + Seek>(info: &DiskInfo,
+                                      disk_file: &mut R,
+                                      options: &RedactOptions)
+                                      -> Result<Vec<String>> {
+    let mut report = Vec::new();
+
+    // The label and serial only exist when the extended BPB is present;
+    // otherwise those bytes are boot code.
+    if options.wipe_labels && info.boot_signature == 0x29 &&
+       (info.volume_id != 0 || &info.volume_label != b"NO NAME    ") {
+        let mut boot_sector = [0u8; 512];
+        disk_file.seek(SeekFrom::Start(0))?;
+        disk_file.read_exact(&mut boot_sector)?;
+        report.push(format!("boot sector label '{}' and serial {:04X}-{:04X} removed",
+                            String::from_utf8_lossy(&info.volume_label).trim(),
+                            info.volume_id >> 16,
+                            info.volume_id & 0xFFFF));
+        let ext = info.ext_shift();
+        LittleEndian::write_u32(&mut boot_sector[ext + VOLUME_ID..], 0);
+        boot_sector[ext + VOLUME_LABEL..ext + VOLUME_LABEL + VOLUME_LABEL_SIZE].copy_from_slice(b"NO NAME    ");
+        disk_file.seek(SeekFrom::Start(0))?;
+        disk_file.write_all(&boot_sector)?;
+    }
+
+    let fat = read_fat(info, disk_file)?;
+    let found = tree(info, disk_file, &fat[..], false)?;
+    let is_dir = |entry: &DirEntry| (entry.attributes & DirEntryAttributes::SubDir as u8) != 0;
+    let mut directories = vec![Directory::root(info, &fat[..])];
+    directories.extend(found.iter()
+        .filter(|found| is_dir(&found.entry) && found.entry.flc >= 2)
+        .map(|found| Directory::chain(info, &fat[..], found.entry.flc)));
+    let (mut labels, mut retimed, mut stripped) = (0, 0, 0);
+    for (n, directory) in directories.iter().enumerate() {
+        let mut slots = directory.read_slots(disk_file)?;
+        let changes = labels + retimed + stripped;
+        for slot in slots.chunks_mut(DIR_ENTRY_SIZE) {
+            if slot[0] == 0x00 {
+                break;
+            }
+            let attributes = slot[DIR_ENTRY_ATTRS];
+            let is_lfn = attributes & 0x0F == 0x0F;
+            if slot[0] == 0xE5 {
+                if options.strip_deleted && slot[1..].iter().any(|&byte| byte != 0) {
+                    // Keep the deletion marker so the slot doesn't end the directory.
+                    for byte in slot[1..].iter_mut() {
+                        *byte = 0;
+                    }
+                    stripped += 1;
+                }
+            } else if options.wipe_labels && n == 0 && !is_lfn &&
+                      (attributes & DirEntryAttributes::VolumeLabel as u8) != 0 {
+                report.push(format!("root directory label '{}' removed",
+                                    String::from_utf8_lossy(&slot[..11]).trim()));
+                slot[0] = 0xE5;
+                for byte in slot[1..].iter_mut() {
+                    *byte = 0;
+                }
+                labels += 1;
+            } else if options.zero_timestamps && !is_lfn {
+                slot[DIR_ENTRY_CREATETIME_FINE] = 0;
+                LittleEndian::write_u16(&mut slot[DIR_ENTRY_CREATETIME..], DOS_EPOCH_TIME);
+                LittleEndian::write_u16(&mut slot[DIR_ENTRY_CREATEDATE..], DOS_EPOCH_DATE);
+                LittleEndian::write_u16(&mut slot[DIR_ENTRY_LASTACCESS..], DOS_EPOCH_DATE);
+                LittleEndian::write_u16(&mut slot[DIR_ENTRY_WRITETIME..], DOS_EPOCH_TIME);
+                LittleEndian::write_u16(&mut slot[DIR_ENTRY_WRITEDATE..], DOS_EPOCH_DATE);
+                retimed += 1;
+            }
+        }
+        if labels + retimed + stripped > changes {
+            directory.write_slots(disk_file, &slots)?;
+        }
+    }
+    if retimed > 0 {
+        report.push(format!("timestamps reset on {} entries", retimed));
+    }
+    if stripped > 0 {
+        report.push(format!("{} deleted entries scrubbed", stripped));
+    }
+
+    if options.zero_slack {
+        let cluster_bytes = cluster_size(info);
+        let (mut files, mut bytes) = (0, 0);
+        for entry in found.iter().map(|found| &found.entry).filter(|entry| !is_dir(entry)) {
+            let used = entry.file_size as u64 % cluster_bytes;
+            let needed = (entry.file_size as u64).div_ceil(cluster_bytes) as usize;
+            // Only the last cluster's tail: any clusters past it belong to
+            // the file only by a damaged chain.
+            let last = match cluster_chain(info, &fat[..], entry.flc).get(needed.wrapping_sub(1)) {
+                Some(&last) if used != 0 => last,
+                _ => continue,
+            };
+            let mut slack = vec![0; (cluster_bytes - used) as usize];
+            disk_file.seek(SeekFrom::Start(cluster_start(info, last) + used))?;
+            disk_file.read_exact(&mut slack)?;
+            if slack.iter().any(|&byte| byte != 0) {
+                disk_file.seek(SeekFrom::Start(cluster_start(info, last) + used))?;
+                disk_file.write_all(&vec![0; slack.len()])?;
+                files += 1;
+                bytes += slack.len();
+            }
+        }
+        if files > 0 {
+            report.push(format!("{} bytes of slack zeroed in {} files", bytes, files));
+        }
+    }
+    Ok(report)
+}
+
+/// Encodes a local time as a DOS date and time, or `None` outside the years
+/// 1980 to 2107 that a DOS date can hold. Seconds are rounded down to even.
+pub fn dos_timestamp(datetime: NaiveDateTime) -> Option<(u16, u16)> {
+    let year = datetime.year() - 1980;
+    if !(0..128).contains(&year) {
+        return None;
+    }
+    let date = ((year as u16) << 9) | ((datetime.month() as u16) << 5) | datetime.day() as u16;
+    let time = ((datetime.hour() as u16) << 11) | ((datetime.minute() as u16) << 5) |
+               (datetime.second() as u16 / 2);
+    Some((date, time))
+}
+
+/// The hundredths of a second that the creation time's fine field adds to
+/// `dos_timestamp`'s even seconds, from 0 to 199.
+pub fn dos_time_fine(datetime: NaiveDateTime) -> u8 {
+    ((datetime.second() % 2) * 100 + datetime.nanosecond().min(999_999_999) / 10_000_000) as u8
+}
+
+/// Packs a name that fits 8.3 into the 11 name bytes of a directory entry,
+/// upper-cased as DOS stores it.
+pub fn short_name(name: &str) -> Option<[u8; 11]> {
+    if !fits::is_short_name(name) {
+        return None;
+    }
+    let name = name.to_uppercase();
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (&name[..], ""),
+    };
+    let mut packed = [b' '; 11];
+    packed[..base.len()].copy_from_slice(base.as_bytes());
+    packed[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    // A leading 0xE5 would read as a deleted entry; DOS stores it as 0x05.
+    if packed[0] == 0xE5 {
+        packed[0] = 0x05;
+    }
+    Some(packed)
+}
+
+/// The case flags, as `DirEntry::cased_name` reads them, that show the 8.3
+/// name of `name` as `name` is cased. `None` if its base name or extension
+/// mixes upper and lower case, which only a long name can keep.
+pub fn case_flags(name: &str) -> Option<u8> {
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    let flag = |part: &str, flag: u8| {
+        match (part.chars().any(char::is_lowercase), part.chars().any(char::is_uppercase)) {
+            (true, true) => None,
+            (true, false) => Some(flag),
+            _ => Some(0),
+        }
+    };
+    Some(flag(base, CASE_LOWER_BASE)? | flag(ext, CASE_LOWER_EXT)?)
+}
+
+/// The most UTF-16 units a long name can have.
+const LFN_MAX_LEN: usize = 255;
+
+/// Whether `name` can be stored as a long name: not just dots and spaces,
+/// not too long, and free of the characters Windows forbids.
+fn is_long_name(name: &str) -> bool {
+    !name.trim_matches(|c| c == '.' || c == ' ').is_empty() && name.encode_utf16().count() <= LFN_MAX_LEN &&
+    !name.chars().any(|c| c.is_control() || "\"*/:<>?\\|".contains(c))
+}
+
+/// Derives the 8.3 alias Windows gives a long name: upper-cased, without
+/// spaces, leading dots or any dot but the last, with characters DOS
+/// doesn't allow replaced by `_`, and a `~1` tail, or `~2` and so on while
+/// `taken` says the alias is used.
+pub fn alias_name<T: Fn(&[u8; 11]) -> bool>(name: &str, taken: T) -> Option<[u8; 11]> {
+    let name = name.trim_start_matches('.');
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    let clean = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| if fits::is_short_name_char(c) { c.to_ascii_uppercase() as u8 } else { b'_' })
+            .collect()
+    };
+    let (base, ext) = (clean(base), clean(ext));
+    let mut packed = [b' '; 11];
+    let ext_len = ext.len().min(3);
+    packed[8..8 + ext_len].copy_from_slice(&ext[..ext_len]);
+    for n in 1..1_000_000 {
+        let tail = format!("~{}", n);
+        let keep = base.len().min(8 - tail.len());
+        packed[..8].copy_from_slice(b"        ");
+        packed[..keep].copy_from_slice(&base[..keep]);
+        packed[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        if !taken(&packed) {
+            return Some(packed);
+        }
+    }
+    None
+}
+
+/// The LFN slots that store `name` for the short entry `short_name`, in the
+/// order they go in the directory: last part first, each part 13 UCS-2
+/// characters, the last ended by a 0 and padded with 0xFFFF.
+fn lfn_slots(name: &str, short_name: &[u8; 11]) -> Vec<[u8; DIR_ENTRY_SIZE]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    if !units.len().is_multiple_of(LFN_CHARS.len()) {
+        units.push(0);
+    }
+    while !units.len().is_multiple_of(LFN_CHARS.len()) {
+        units.push(0xFFFF);
+    }
+    let count = units.len() / LFN_CHARS.len();
+    let checksum = lfn_checksum(short_name);
+    units.chunks(LFN_CHARS.len())
+        .enumerate()
+        .rev()
+        .map(|(i, part)| {
+            let mut slot = [0u8; DIR_ENTRY_SIZE];
+            slot[LFN_ORDER] = (i + 1) as u8 | if i + 1 == count { LFN_LAST } else { 0 };
+            slot[DIR_ENTRY_ATTRS] = LFN_ATTRIBUTES;
+            slot[LFN_CHECKSUM] = checksum;
+            for (&unit, &offset) in part.iter().zip(LFN_CHARS.iter()) {
+                LittleEndian::write_u16(&mut slot[offset..], unit);
+            }
+            slot
+        })
+        .collect()
+}
+
+/// Finds `count` free slots in a row in `directory`, first growing a
+/// subdirectory a cluster at a time while it has no such run. A FAT12 or
+/// FAT16 root directory has a fixed size and can't grow. On success
+/// `directory` and `fat` reflect any new clusters; the FAT is left for the
+/// caller to write.
+fn make_slots<R: Read + Write + Seek>(info: &DiskInfo,
+                                      disk_file: &mut R,
+                                      fat: &mut [u8],
+                                      directory: &mut Directory,
+                                      first_cluster: Option<u32>,
+                                      count: usize)
+                                      -> Result<usize> {
+    loop {
+        if let Some(slot) = directory.free_slots(disk_file, count)? {
+            return Ok(slot);
+        }
+        let first = match first_cluster {
+            Some(first) => first,
+            None => return Err(Error::NoSpace("the root directory is full".to_string())),
+        };
+        // A first cluster outside the data area leaves no chain to grow.
+        let last = *cluster_chain(info, fat, first)
+            .last()
+            .ok_or_else(|| Error::CorruptFatChain(format!("directory cluster {} is out of range", first)))?;
+        let cluster = allocate_clusters(info, fat, 1)
+            .ok_or_else(|| Error::NoSpace("no free cluster to grow the directory".to_string()))?[0];
+        set_fat_entry(info, fat, last, cluster);
+        disk_file.seek(SeekFrom::Start(cluster_start(info, cluster)))?;
+        disk_file.write_all(&vec![0; cluster_size(info) as usize])?;
+        *directory = Directory::chain(info, fat, first);
+    }
+}
+
+/// Where a new entry goes: the directory, the names it is stored under, and the
+/// clusters that locate the directory.
+struct Placement {
+    directory: Directory,
+    /// The first cluster to grow the directory from when it is full; `None`
+    /// for a FAT12 or FAT16 root, which can't grow.
+    grow_from: Option<u32>,
+    /// The cluster a `..` entry in a new subdirectory points at: the
+    /// directory's, or 0 for the root.
+    cluster: u32,
+    short_name: [u8; 11],
+    /// LFN slots to write before the entry when the name doesn't fit 8.3.
+    long_slots: Vec<[u8; DIR_ENTRY_SIZE]>,
+    /// The case flags for the entry's 8.3 name, when it is the entry's name.
+    case_flags: u8,
+}
+
+/// Works out where an entry named `name` goes in the directory `parent`,
+/// checking that the name is valid and not already taken. Its short name,
+/// and whether it gets LFN slots, come from the volume's name mapping: by
+/// default a name that doesn't fit 8.3 gets a `~N` alias and LFN slots.
+fn place_entry<R: Read + Seek>(info: &DiskInfo,
+                               disk_file: &mut R,
+                               parent: &str,
+                               name: &str)
+                               -> Result<Placement> {
+    if short_name(name).is_none() && !is_long_name(name) {
+        return Err(Error::InvalidName(name.to_string()));
+    }
+    let mapping = names::mapping(info);
+    let (directory, grow_from, cluster) = open_parent(info, disk_file, parent)?;
+    if directory.find(disk_file, name)?.is_some() {
+        return Err(Error::AlreadyExists(format!("{}/{}", parent, name)));
+    }
+    let taken: HashSet<Vec<u8>> = directory.entries(disk_file)?
+        .into_iter()
+        .filter(|(_, entry)| !entry.is_lfn())
+        .map(|(_, entry)| [&entry.file_name[..], &entry.file_ext[..]].concat())
+        .collect();
+    let taken = |short: &[u8; 11]| taken.contains(&short[..]);
+    let short_name = match mapping.short_name(name, &taken) {
+        Some(short) if taken(&short) => return Err(Error::AlreadyExists(format!("{}/{}", parent, name))),
+        Some(short) => short,
+        None => return Err(Error::NoSpace(format!("{}/{}: no free short name", parent, name))),
+    };
+    let long_slots = if mapping.long_name(name, &short_name) {
+        lfn_slots(name, &short_name)
+    } else {
+        Vec::new()
+    };
+    let case_flags = match self::short_name(name) {
+        Some(short) if short == short_name && long_slots.is_empty() => case_flags(name).unwrap_or(0),
+        _ => 0,
+    };
+    Ok(Placement { directory, grow_from, cluster, short_name, long_slots, case_flags })
+}
+
+/// The directory at `parent` that new entries go in, with the cluster to grow
+/// it from and the one a `..` entry points at, as `Placement` has them.
+fn open_parent<R: Read + Seek>(info: &DiskInfo,
+                               disk_file: &mut R,
+                               parent: &str)
+                               -> Result<(Directory, Option<u32>, u32)> {
+    let parent_entry = find_path(info, disk_file, parent)?;
+    let directory = match Directory::open(info, disk_file, parent)? {
+        Some(directory) => directory,
+        None if parent_entry.is_some() => return Err(Error::NotADirectory(parent.to_string())),
+        None => return Err(Error::NotFound(parent.to_string())),
+    };
+    let cluster = parent_entry.as_ref().map_or(0, |entry| entry.flc);
+    let grow_from = match parent_entry {
+        Some(entry) => Some(entry.flc).filter(|&flc| flc >= 2),
+        // A FAT32 root is a chain and can grow like any other directory.
+        None if info.fat_type() == FatType::Fat32 => Some(info.root_cluster),
+        None => None,
+    };
+    Ok((directory, grow_from, cluster))
+}
+
+/// Splits `path` into the directory a new entry goes in and its name. A
+/// path naming an existing directory, or the root, means the entry goes in it
+/// under `default_name`.
+fn target_path<R: Read + Seek>(info: &DiskInfo,
+                               disk_file: &mut R,
+                               path: &str,
+                               default_name: &str)
+                               -> Result<(String, String)> {
+    match find_path(info, disk_file, path)? {
+        Some(ref entry) if (entry.attributes & DirEntryAttributes::SubDir as u8) != 0 => {
+            Ok((path.trim_end_matches('/').to_string(), default_name.to_string()))
+        }
+        Some(_) => Err(Error::AlreadyExists(path.to_string())),
+        None if path.trim_matches('/').is_empty() => Ok((String::new(), default_name.to_string())),
+        None => {
+            let (parent, name) = split_path(path);
+            Ok((parent.to_string(), name.to_string()))
+        }
+    }
+}
+
+/// A fresh 32-byte entry, created now and last written at `modified`.
+fn new_entry(short_name: &[u8; 11],
+             attributes: u8,
+             first_cluster: u32,
+             size: u32,
+             modified: NaiveDateTime)
+             -> [u8; DIR_ENTRY_SIZE] {
+    new_entry_at(short_name, attributes, first_cluster, size, Local::now().naive_local(), modified)
+}
+
+/// Like `new_entry`, but created at `created` rather than now.
+fn new_entry_at(short_name: &[u8; 11],
+                attributes: u8,
+                first_cluster: u32,
+                size: u32,
+                created: NaiveDateTime,
+                modified: NaiveDateTime)
+                -> [u8; DIR_ENTRY_SIZE] {
+    let (write_date, write_time) = dos_timestamp(modified).unwrap_or((DOS_EPOCH_DATE, DOS_EPOCH_TIME));
+    let (create_date, create_time) = dos_timestamp(created).unwrap_or((DOS_EPOCH_DATE, DOS_EPOCH_TIME));
+    let mut entry = [0u8; DIR_ENTRY_SIZE];
+    entry[..11].copy_from_slice(short_name);
+    entry[DIR_ENTRY_ATTRS] = attributes;
+    if dos_timestamp(created).is_some() {
+        entry[DIR_ENTRY_CREATETIME_FINE] = dos_time_fine(created);
+    }
+    LittleEndian::write_u16(&mut entry[DIR_ENTRY_CREATETIME..], create_time);
+    LittleEndian::write_u16(&mut entry[DIR_ENTRY_CREATEDATE..], create_date);
+    LittleEndian::write_u16(&mut entry[DIR_ENTRY_LASTACCESS..], create_date);
+    LittleEndian::write_u16(&mut entry[DIR_ENTRY_WRITETIME..], write_time);
+    LittleEndian::write_u16(&mut entry[DIR_ENTRY_WRITEDATE..], write_date);
+    LittleEndian::write_u16(&mut entry[DIR_ENTRY_FLC_HIGH..], (first_cluster >> 16) as u16);
+    LittleEndian::write_u16(&mut entry[DIR_ENTRY_FLC..], first_cluster as u16);
+    LittleEndian::write_u32(&mut entry[DIR_ENTRY_FILESIZE..], size);
+    entry
+}
+
+/// Times to give an entry. Those left `None` keep the value they have, or on
+/// a new entry the usual one. Only the date of the last access is stored.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Times {
+    pub created: Option<NaiveDateTime>,
+    pub modified: Option<NaiveDateTime>,
+    pub accessed: Option<NaiveDateTime>,
+}
+impl Times {
+    /// The times of the host file at `path`. Its creation time is left out
+    /// where the host file system doesn't record one.
+    pub fn of_host_file(path: &Path) -> Result<Times> {
+        let metadata = fs::metadata(path)?;
+        let local = |time: std::time::SystemTime| DateTime::<Local>::from(time).naive_local();
+        Ok(Times {
+            created: metadata.created().ok().map(local),
+            modified: Some(local(metadata.modified()?)),
+            accessed: metadata.accessed().ok().map(local),
+        })
+    }
+}
+
+/// Writes the times `times` gives into a 32-byte entry. Times before 1980 or
+/// after 2107 are clamped to the range a DOS date can hold.
+fn stamp(entry: &mut [u8], times: &Times) {
+    let clamp = |datetime: NaiveDateTime| {
+        let first = NaiveDate::from_ymd_opt(1980, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
+        let last = NaiveDate::from_ymd_opt(2107, 12, 31).unwrap().and_hms_milli_opt(23, 59, 59, 990).unwrap();
+        let datetime = datetime.max(first).min(last);
+        (dos_timestamp(datetime).unwrap(), dos_time_fine(datetime))
+    };
+    if let Some(created) = times.created {
+        let ((date, time), fine) = clamp(created);
+        entry[DIR_ENTRY_CREATETIME_FINE] = fine;
+        LittleEndian::write_u16(&mut entry[DIR_ENTRY_CREATETIME..], time);
+        LittleEndian::write_u16(&mut entry[DIR_ENTRY_CREATEDATE..], date);
+    }
+    if let Some(modified) = times.modified {
+        let ((date, time), _) = clamp(modified);
+        LittleEndian::write_u16(&mut entry[DIR_ENTRY_WRITETIME..], time);
+        LittleEndian::write_u16(&mut entry[DIR_ENTRY_WRITEDATE..], date);
+    }
+    if let Some(accessed) = times.accessed {
+        let ((date, _), _) = clamp(accessed);
+        LittleEndian::write_u16(&mut entry[DIR_ENTRY_LASTACCESS..], date);
+    }
+}
+
+/// Writes `entry` and any LFN slots before it from `slot` on. The short
+/// entry goes last, so nothing appears before its long name is in place.
+/// Past the directory's end, the slots after it are zeroed as the volume's
+/// `DirectoryEnd` says.
+fn write_entry<R: Read + Write + Seek>(info: &DiskInfo,
+                                       placement: &Placement,
+                                       disk_file: &mut R,
+                                       slot: usize,
+                                       entry: &[u8])
+                                       -> Result<()> {
+    let directory = &placement.directory;
+    let slots = directory.read_slots(disk_file)?;
+    let after = slot + placement.long_slots.len() + 1;
+    let at_end = slots.chunks(DIR_ENTRY_SIZE).take(slot + 1).any(|slot| slot[0] == 0x00);
+    if at_end && after * DIR_ENTRY_SIZE < slots.len() {
+        // The end goes first, so nothing past it shows once the entry does.
+        let sector = |slot: usize| directory.slot_offset(slot).unwrap() / info.bytes_per_sector as u64;
+        let last = match info.options.directory_end {
+            DirectoryEnd::Marker => after,
+            DirectoryEnd::ZeroSector => {
+                let mut last = after;
+                while (last + 1) * DIR_ENTRY_SIZE < slots.len() && sector(last + 1) == sector(after - 1) {
+                    last += 1;
+                }
+                last
+            }
+            DirectoryEnd::ZeroAll => slots.len() / DIR_ENTRY_SIZE - 1,
+        };
+        for tail in after..last + 1 {
+            directory.write_slot(disk_file, tail, &[0; DIR_ENTRY_SIZE])?;
+        }
+    }
+    for (i, long_slot) in placement.long_slots.iter().enumerate() {
+        placement.directory.write_slot(disk_file, slot + i, long_slot)?;
+    }
+    let mut entry = entry.to_vec();
+    entry[DIR_ENTRY_RESERVED] |= placement.case_flags;
+    placement.directory.write_slot(disk_file, slot + placement.long_slots.len(), &entry)
+}
+
+/// Copies the host file `host_path` into the image at `path`, or into the
+/// directory `path` names under the host file's own name. A name that
+/// doesn't fit 8.3 is stored as a long name, with LFN slots before an entry
+/// named by its alias. The entry gets the host file's modification time as
+/// its write time.
+pub fn put<R: Read + Write + Seek>(info: &DiskInfo,
+                                   disk_file: &mut R,
+                                   host_path: &Path,
+                                   path: &str)
+                                   -> Result<()> {
+    put_with_times(info, disk_file, host_path, path, &Times::default())
+}
+
+/// `put`, with the entry's times set from `times` where it gives them.
+pub fn put_with_times<R: Read + Write + Seek>(info: &DiskInfo,
+                                              disk_file: &mut R,
+                                              host_path: &Path,
+                                              path: &str,
+                                              times: &Times)
+                                              -> Result<()> {
+    let mut data = Vec::new();
+    File::open(host_path)?.read_to_end(&mut data)?;
+    let modified: DateTime<Local> = fs::metadata(host_path)?.modified()?.into();
+
+    let host_name = host_path.file_name().unwrap_or_default().to_string_lossy();
+    let host_name = names::mapping(info).image_name(&host_name);
+    let size = data.len() as u32;
+    let archive = DirEntryAttributes::Archive as u8;
+    store_file(info, disk_file, path, &host_name, archive, &data, |short_name, attributes, first| {
+        let mut entry = new_entry(short_name, attributes, first, size, modified.naive_local());
+        stamp(&mut entry, times);
+        entry
+    })?;
+    Ok(())
+}
+
+/// Copies several host files into the directory `dir`, each under its own
+/// name and with its own `Times`. They are checked with `preflight` first, so
+/// when they don't all fit, nothing is written and the error says how much
+/// room is missing.
+pub fn put_files<R: Read + Write + Seek>(info: &DiskInfo,
+                                         disk_file: &mut R,
+                                         files: &[(&Path, Times)],
+                                         dir: &str)
+                                         -> Result<()> {
+    let dir = dir.trim_end_matches('/');
+    let mut sizes = Vec::with_capacity(files.len());
+    for &(host_path, _) in files {
+        let name = host_path.file_name().unwrap_or_default().to_string_lossy();
+        let name = names::mapping(info).image_name(&name);
+        sizes.push((name, fs::metadata(host_path)?.len()));
+    }
+    let preflight = preflight(info, disk_file, dir, &sizes)?;
+    if !preflight.fits() {
+        return Err(Error::NoSpace(preflight.shortfall(dir)));
+    }
+    for &(host_path, ref times) in files {
+        put_with_times(info, disk_file, host_path, &format!("{}/", dir), times)?;
+    }
+    Ok(())
+}
+
+/// Sorts `files` into the order `order` names them in, by file name and
+/// ignoring case, with the ones it doesn't name after them as they were.
+/// Names in `order` that aren't among the files are passed over.
+///
+/// `put_files` stores files in the order they come: each entry in the first
+/// free slots of the directory, and each file's contents in the lowest free
+/// clusters. On a fresh volume, then, the order is both the entries' and the
+/// clusters', which is how `IO.SYS` and `MSDOS.SYS` are made to come first,
+/// as DOS boot sectors expect.
+pub fn order_files<T>(files: &mut [(&Path, T)], order: &[String]) {
+    files.sort_by_key(|&(host_path, _)| {
+        let name = host_path.file_name().unwrap_or_default().to_string_lossy().to_uppercase();
+        order.iter().position(|listed| listed.to_uppercase() == name).unwrap_or(order.len())
+    });
+}
+
+/// Copies the file at `src_path` in one image to `dst_path` in another, or
+/// into the directory `dst_path` names under its own name, long or short.
+/// The copy keeps the original's attributes and timestamps, and OS/2
+/// extended attributes in a set of its own, but on FAT32, which can't hold
+/// them. Fails with `CorruptFatChain` if the original's chain ends before
+/// its size.
+pub fn copy_between<S, D>(src_info: &DiskInfo,
+                          src: &mut S,
+                          src_path: &str,
+                          dst_info: &DiskInfo,
+                          dst: &mut D,
+                          dst_path: &str)
+                          -> Result<()>
+    where S: Read + Seek,
+          D: Read + Write + Seek
+{
+    let entry = find_path(src_info, src, src_path)?.ok_or_else(|| Error::NotFound(src_path.to_string()))?;
+    if (entry.attributes & DirEntryAttributes::SubDir as u8) != 0 {
+        return Err(Error::IsADirectory(src_path.to_string()));
+    }
+    let mut data = Vec::new();
+    let fat = read_fat(src_info, src)?;
+    if copy_file(src_info, src, &fat[..], &entry, &mut data)? < entry.file_size as u64 {
+        return Err(Error::CorruptFatChain(src_path.to_string()));
+    }
+    // The handle leads into the source's EA DATA. SF, so it isn't copied
+    // with the rest of the entry; the set it leads to is. DR-DOS's rights
+    // are copied as they are, where the destination keeps them too.
+    let eas = match entry.ea_handle {
+        0 => None,
+        handle => match ea::EaFile::read(src_info, src)? {
+            Some(file) => Some(file.set(handle)?.eas),
+            None => None,
+        },
+    };
+    let rights_in_use = eas.is_none() && drdos::rights_in_use(src_info, src)?;
+    let protected = drdos::Protection::of(&entry, rights_in_use).is_some();
+    let rights = if protected && drdos::rights_in_use(dst_info, dst)? { entry.ea_handle } else { 0 };
+    let name = entry.long_name.clone().unwrap_or_else(|| entry.name());
+    let attributes = entry.attributes;
+    let mut owner = String::new();
+    let offset = store_file(dst_info, dst, dst_path, &name, attributes, &data, |short, attributes, first| {
+        let mut slot = entry.to_bytes();
+        slot[..11].copy_from_slice(short);
+        slot[DIR_ENTRY_ATTRS] = attributes;
+        LittleEndian::write_u16(&mut slot[DIR_ENTRY_FLC_HIGH..], (first >> 16) as u16 | rights);
+        LittleEndian::write_u16(&mut slot[DIR_ENTRY_FLC..], first as u16);
+        owner = DirEntry::new(&slot).name();
+        slot
+    })?;
+    if protected && rights == 0 {
+        dst_info.options.warnings.warn(Warning::PasswordNotCopied(owner.clone()));
+    }
+    if let Some(eas) = eas {
+        if dst_info.fat_type() == FatType::Fat32 {
+            dst_info.options.warnings.warn(Warning::EasNotCopied(owner));
+        } else {
+            let mut handle = [0; 2];
+            LittleEndian::write_u16(&mut handle, ea::add(dst_info, dst, &owner, &eas)?);
+            dst.seek(SeekFrom::Start(offset + DIR_ENTRY_FLC_HIGH as u64))?;
+            dst.write_all(&handle)?;
+        }
+    }
+    Ok(())
+}
+
+/// What new files would take in one directory, against the room there is,
+/// as `preflight` works it out.
+#[derive(Debug, PartialEq)]
+pub struct Preflight {
+    pub files: usize,
+    pub bytes: u64,
+    pub cluster_size: u64,
+    /// Clusters the files' contents take, each rounded up to whole clusters.
+    pub data_clusters: u64,
+    /// Clusters the directory has to grow by to hold the new entries.
+    pub directory_clusters: u64,
+    pub free_clusters: u64,
+    /// Directory slots the new entries take, their LFN slots included.
+    pub slots: u64,
+    pub long_name_slots: u64,
+    /// Slots that find no room in a directory that can't grow: a FAT12 or
+    /// FAT16 root, which has a fixed number.
+    pub missing_slots: u64,
+}
+impl Preflight {
+    pub fn clusters_needed(&self) -> u64 {
+        self.data_clusters + self.directory_clusters
+    }
+
+    pub fn missing_clusters(&self) -> u64 {
+        self.clusters_needed().saturating_sub(self.free_clusters)
+    }
+
+    pub fn fits(&self) -> bool {
+        self.missing_clusters() == 0 && self.missing_slots == 0
+    }
+
+    /// What is short, a line for each of clusters and slots, after a line
+    /// saying the files won't fit in `dir`.
+    pub fn shortfall(&self, dir: &str) -> String {
+        let dir = if dir.is_empty() { "/" } else { dir };
+        let mut lines = vec![format!("{} files ({} bytes) won't fit in {}:", self.files, self.bytes, dir)];
+        if self.missing_clusters() > 0 {
+            lines.push(format!("  they need {} clusters of {} bytes ({} for data, {} to grow the directory) \
+                                but {} are free: {} clusters ({} bytes) short",
+                               self.clusters_needed(),
+                               self.cluster_size,
+                               self.data_clusters,
+                               self.directory_clusters,
+                               self.free_clusters,
+                               self.missing_clusters(),
+                               self.missing_clusters() * self.cluster_size));
+        }
+        if self.missing_slots > 0 {
+            lines.push(format!("  their entries take {} slots ({} for long names) and the root directory \
+                                can't grow: {} slots short",
+                               self.slots,
+                               self.long_name_slots,
+                               self.missing_slots));
+        }
+        lines.join("\n")
+    }
+}
+
+/// Works out whether new files named and sized as `files` would fit in the
+/// directory `dir`, without changing anything. Contents are rounded up to
+/// whole clusters, and every entry takes a slot, with LFN slots in a row
+/// before it for a name that doesn't fit 8.3. The slots are placed the way
+/// they would be written, in the first free run long enough, growing a
+/// subdirectory a cluster at a time when there is none.
+pub fn preflight<R: Read + Seek>(info: &DiskInfo,
+                                 disk_file: &mut R,
+                                 dir: &str,
+                                 files: &[(String, u64)])
+                                 -> Result<Preflight> {
+    let (directory, grow_from, _) = open_parent(info, disk_file, dir)?;
+    let fat = read_fat(info, disk_file)?;
+    let size = cluster_size(info);
+    let mut free: Vec<bool> = Vec::new();
+    let mut past_end = false;
+    for slot in directory.read_slots(disk_file)?.chunks(DIR_ENTRY_SIZE) {
+        past_end |= slot[0] == 0x00;
+        free.push(past_end || slot[0] == 0xE5);
+    }
+    let mut preflight = Preflight {
+        files: files.len(),
+        bytes: files.iter().map(|&(_, bytes)| bytes).sum(),
+        cluster_size: size,
+        data_clusters: files.iter().map(|&(_, bytes)| bytes.div_ceil(size)).sum(),
+        directory_clusters: 0,
+        free_clusters: free_space(info, &fat[..]).free_clusters as u64,
+        slots: 0,
+        long_name_slots: 0,
+        missing_slots: 0,
+    };
+    let mapping = names::mapping(info);
+    for (name, _) in files {
+        let long_slots = match mapping.short_name(name, &|_| false) {
+            Some(ref short) if !mapping.long_name(name, short) => 0,
+            _ => name.encode_utf16().count().div_ceil(LFN_CHARS.len()),
+        };
+        let count = long_slots + 1;
+        preflight.slots += count as u64;
+        preflight.long_name_slots += long_slots as u64;
+        loop {
+            let start = (0..free.len().saturating_sub(count - 1))
+                .find(|&i| free[i..i + count].iter().all(|&free| free));
+            if let Some(start) = start {
+                free[start..start + count].iter_mut().for_each(|f| *f = false);
+                break;
+            }
+            if grow_from.is_none() {
+                preflight.missing_slots += count as u64;
+                break;
+            }
+            preflight.directory_clusters += 1;
+            free.extend(std::iter::repeat_n(true, size as usize / DIR_ENTRY_SIZE));
+        }
+    }
+    Ok(preflight)
+}
+
+/// Stores `data` as a new file at `path`, or in the directory `path` names
+/// under `default_name`. `make_entry` gives its directory entry, from the
+/// short name chosen for it and its first cluster. Returns where the entry's
+/// slot is.
+fn store_file<R, E>(info: &DiskInfo,
+                    disk_file: &mut R,
+                    path: &str,
+                    default_name: &str,
+                    attributes: u8,
+                    data: &[u8],
+                    make_entry: E)
+                    -> Result<u64>
+    where R: Read + Write + Seek,
+          E: FnOnce(&[u8; 11], u8, u32) -> [u8; DIR_ENTRY_SIZE]
+{
+    let (mut parent, mut name) = target_path(info, disk_file, path, default_name)?;
+    let requested = format!("{}/{}", parent, name);
+    let mutation = Mutation { action: Action::Create, path: requested.clone(), attributes };
+    let change = policy::check(info, mutation)?;
+    if change.path != requested {
+        let target = target_path(info, disk_file, &change.path, &name)?;
+        parent = target.0;
+        name = target.1;
+    }
+    let mut placement = place_entry(info, disk_file, &parent, &name)?;
+    let preflight = preflight(info, disk_file, &parent, &[(name.clone(), data.len() as u64)])?;
+    if !preflight.fits() {
+        return Err(Error::NoSpace(preflight.shortfall(&parent)));
+    }
+
+    let mut fat = read_fat(info, disk_file)?;
+    let count = (data.len() as u64).div_ceil(cluster_size(info)) as usize;
+    let clusters = allocate_clusters(info, &mut fat, count)
+        .ok_or_else(|| Error::NoSpace(format!("not enough free space for {} bytes", data.len())))?;
+    let slot = make_slots(info,
+                          disk_file,
+                          &mut fat,
+                          &mut placement.directory,
+                          placement.grow_from,
+                          placement.long_slots.len() + 1)?;
+    for (cluster, chunk) in clusters.iter().zip(data.chunks(cluster_size(info) as usize)) {
+        disk_file.seek(SeekFrom::Start(cluster_start(info, *cluster)))?;
+        disk_file.write_all(chunk)?;
+    }
+
+    let entry = make_entry(&placement.short_name, change.attributes, clusters.first().map_or(0, |&c| c));
+    // The FAT goes first, so an interrupted put leaves lost clusters rather
+    // than an entry pointing at clusters still marked free.
+    write_fat(info, disk_file, &fat)?;
+    write_entry(info, &placement, disk_file, slot, &entry)?;
+    Ok(placement.directory.slot_offset(slot + placement.long_slots.len()).unwrap())
+}
+
+/// Creates an empty directory at `path`: a cluster holding only `.` and
+/// `..`, and its entry in the parent, which grows by a cluster if it is full.
+pub fn mkdir<R: Read + Write + Seek>(info: &DiskInfo,
+                                     disk_file: &mut R,
+                                     path: &str)
+                                     -> Result<()> {
+    make_dir(info, disk_file, path, Local::now().naive_local())
+}
+
+/// `mkdir`, with the directory and its `.` and `..` entries stamped `now`.
+fn make_dir<R: Read + Write + Seek>(info: &DiskInfo,
+                                    disk_file: &mut R,
+                                    path: &str,
+                                    now: NaiveDateTime)
+                                    -> Result<()> {
+    let (parent, name) = split_path(path);
+    if name.is_empty() {
+        return Err(Error::AlreadyExists(path.to_string()));
+    }
+    let subdir = DirEntryAttributes::SubDir as u8;
+    let requested = format!("{}/{}", parent, name);
+    let mutation = Mutation { action: Action::Create, path: requested, attributes: subdir };
+    let change = policy::check(info, mutation)?;
+    let (parent, name) = split_path(&change.path);
+    if name.is_empty() {
+        return Err(Error::AlreadyExists(change.path.clone()));
+    }
+    let mut placement = place_entry(info, disk_file, parent, name)?;
+
+    let mut fat = read_fat(info, disk_file)?;
+    let cluster = allocate_clusters(info, &mut fat, 1)
+        .ok_or_else(|| Error::NoSpace("no free cluster for the directory".to_string()))?[0];
+    let slot = make_slots(info,
+                          disk_file,
+                          &mut fat,
+                          &mut placement.directory,
+                          placement.grow_from,
+                          placement.long_slots.len() + 1)?;
+
+    let mut contents = vec![0; cluster_size(info) as usize];
+    contents[..DIR_ENTRY_SIZE].copy_from_slice(&new_entry_at(b".          ", subdir, cluster, 0, now, now));
+    contents[DIR_ENTRY_SIZE..2 * DIR_ENTRY_SIZE]
+        .copy_from_slice(&new_entry_at(b"..         ", subdir, placement.cluster, 0, now, now));
+    disk_file.seek(SeekFrom::Start(cluster_start(info, cluster)))?;
+    disk_file.write_all(&contents)?;
+
+    write_fat(info, disk_file, &fat)?;
+    let entry = new_entry_at(&placement.short_name, change.attributes, cluster, 0, now, now);
+    write_entry(info, &placement, disk_file, slot, &entry)
+}
+
+/// Deletes the file at `path` the way DOS does: the first byte of its entry,
+/// and of any LFN entries before it, becomes 0xE5, and its clusters are freed
+/// in every FAT copy. The data itself is left where it was. Returns what
+/// was changed, for `undo::undo` to change back.
+pub fn rm<R: Read + Write + Seek>(info: &DiskInfo,
+                                  disk_file: &mut R,
+                                  path: &str)
+                                  -> Result<undo::Deletion> {
+    remove(info, disk_file, path, false)
+}
+
+/// Deletes the empty directory at `path` as `rm` deletes a file, as RMDIR
+/// does. A directory with entries besides `.` and `..` is refused.
+pub fn rmdir<R: Read + Write + Seek>(info: &DiskInfo,
+                                     disk_file: &mut R,
+                                     path: &str)
+                                     -> Result<undo::Deletion> {
+    remove(info, disk_file, path, true)
+}
+
+/// `rm`, or with `is_dir` `rmdir`.
+fn remove<R: Read + Write + Seek>(info: &DiskInfo,
+                                  disk_file: &mut R,
+                                  path: &str,
+                                  is_dir: bool)
+                                  -> Result<undo::Deletion> {
+    let (directory, slot, entry) = find_slot(info, disk_file, path)?
+        .ok_or_else(|| Error::NotFound(path.to_string()))?;
+    match (entry.attributes & DirEntryAttributes::SubDir as u8 != 0, is_dir) {
+        (true, false) => return Err(Error::IsADirectory(path.to_string())),
+        (false, true) => return Err(Error::NotADirectory(path.to_string())),
+        (true, true) => {
+            let contents = Directory::open(info, disk_file, path)?
+                .ok_or_else(|| Error::NotADirectory(path.to_string()))?;
+            let in_use = contents.entries(disk_file)?.into_iter().any(|(_, entry)| {
+                !entry.is_lfn() && entry.name() != "." && entry.name() != ".."
+            });
+            if in_use {
+                let why = format!("{}: directory not empty", path);
+                return Err(Error::Io(io::Error::new(io::ErrorKind::DirectoryNotEmpty, why)));
+            }
+        }
+        (false, false) => (),
+    }
+    let mutation = Mutation { action: Action::Delete, path: path.to_string(), attributes: entry.attributes };
+    policy::check(info, mutation)?;
+    let mut fat = read_fat(info, disk_file)?;
+    let mut chain = Vec::new();
+    for cluster in cluster_chain(info, &fat, entry.flc) {
+        chain.push((cluster, fat_entry(info, &fat, cluster).unwrap_or(0)));
+        set_fat_entry(info, &mut fat, cluster, 0);
+    }
+    // Free the clusters first, so an interrupted rm leaves a file with a
+    // broken chain rather than clusters nothing owns.
+    write_fat(info, disk_file, &fat)?;
+    let slots = directory.read_slots(disk_file)?;
+    let mut deletion = undo::Deletion { path: path.to_string(), slots: Vec::new(), chain };
+    for slot in first_long_slot(&slots, slot)..slot + 1 {
+        let mut bytes = [0; DIR_ENTRY_SIZE];
+        bytes.copy_from_slice(&slots[slot * DIR_ENTRY_SIZE..(slot + 1) * DIR_ENTRY_SIZE]);
+        deletion.slots.push((directory.slot_offset(slot).unwrap(), bytes));
+        directory.write_slot(disk_file, slot, &[0xE5])?;
+    }
+    Ok(deletion)
+}
+
+/// The first of the LFN slots right before the entry at `slot` among a
+/// directory's `slots`, or `slot` itself if it has none.
+fn first_long_slot(slots: &[u8], slot: usize) -> usize {
+    let mut first = slot;
+    while first > 0 {
+        let previous = &slots[(first - 1) * DIR_ENTRY_SIZE..first * DIR_ENTRY_SIZE];
+        if previous[0] == 0xE5 || previous[DIR_ENTRY_ATTRS] & 0x3F != LFN_ATTRIBUTES {
+            break;
+        }
+        first -= 1;
+    }
+    first
+}
+
+/// Renames the file or directory at `path` to `new_name` in the same
+/// directory, as REN does. The entry keeps its clusters, attributes and
+/// times; only its name and LFN slots change, and a subdirectory's `..`
+/// entries need nothing as it stays where it is. The old slots are freed
+/// first, so the new name can differ from the old in case alone and can
+/// reuse them, and are put back if the new name can't be placed.
+pub fn rename<R: Read + Write + Seek>(info: &DiskInfo,
+                                      disk_file: &mut R,
+                                      path: &str,
+                                      new_name: &str)
+                                      -> Result<()> {
+    if new_name.contains('/') {
+        return Err(Error::InvalidName(new_name.to_string()));
+    }
+    let (directory, slot, entry) = find_slot(info, disk_file, path)?
+        .ok_or_else(|| Error::NotFound(path.to_string()))?;
+    let (parent, _) = split_path(path);
+    let renamed = format!("{}/{}", parent, new_name);
+    let mutation = Mutation { action: Action::Rename, path: renamed, attributes: entry.attributes };
+    policy::check(info, mutation)?;
+
+    let slots = directory.read_slots(disk_file)?;
+    let first = first_long_slot(&slots, slot);
+    for slot in first..slot + 1 {
+        directory.write_slot(disk_file, slot, &[0xE5])?;
+    }
+    let mut placement = match place_entry(info, disk_file, parent, new_name) {
+        Ok(placement) => placement,
+        Err(e) => {
+            for slot in first..slot + 1 {
+                let old = &slots[slot * DIR_ENTRY_SIZE..(slot + 1) * DIR_ENTRY_SIZE];
+                directory.write_slot(disk_file, slot, old)?;
+            }
+            return Err(e);
+        }
+    };
+    let mut fat = read_fat(info, disk_file)?;
+    let new_slot = make_slots(info,
+                              disk_file,
+                              &mut fat,
+                              &mut placement.directory,
+                              placement.grow_from,
+                              placement.long_slots.len() + 1)?;
+    write_fat(info, disk_file, &fat)?;
+    let mut bytes = slots[slot * DIR_ENTRY_SIZE..(slot + 1) * DIR_ENTRY_SIZE].to_vec();
+    bytes[..11].copy_from_slice(&placement.short_name);
+    bytes[DIR_ENTRY_RESERVED] &= !(CASE_LOWER_BASE | CASE_LOWER_EXT);
+    write_entry(info, &placement, disk_file, new_slot, &bytes)
+}
+
+/// The attribute bits ATTRIB can change. The others say what an entry is, and
+/// changing them would turn a file into a directory or a label.
+pub const CHANGEABLE_ATTRIBUTES: u8 = DirEntryAttributes::ReadOnly as u8 | DirEntryAttributes::Hidden as u8 |
+                                      DirEntryAttributes::System as u8 | DirEntryAttributes::Archive as u8;
+
+/// Sets the bits of `set` and clears those of `clear` in the attributes of the
+/// entry at `path`, as ATTRIB does, and returns the attributes it has now.
+/// Bits outside `CHANGEABLE_ATTRIBUTES` are left alone. Nothing is written if
+/// nothing changes.
+pub fn set_attributes<R: Read + Write + Seek>(info: &DiskInfo,
+                                              disk_file: &mut R,
+                                              path: &str,
+                                              set: u8,
+                                              clear: u8)
+                                              -> Result<u8> {
+    let (directory, slot, entry) = find_slot(info, disk_file, path)?
+        .ok_or_else(|| Error::NotFound(path.to_string()))?;
+    let mut attributes = (entry.attributes | (set & CHANGEABLE_ATTRIBUTES)) &
+                         !(clear & CHANGEABLE_ATTRIBUTES);
+    if attributes != entry.attributes {
+        let change = Mutation { action: Action::SetAttributes, path: path.to_string(), attributes };
+        attributes = policy::check(info, change)?.attributes;
+    }
+    if attributes != entry.attributes {
+        let offset = directory.slot_offset(slot).unwrap() + DIR_ENTRY_ATTRS as u64;
+        disk_file.seek(SeekFrom::Start(offset))?;
+        disk_file.write_all(&[attributes])?;
+    }
+    Ok(attributes)
+}
+
+/// Sets the times of the file or directory at `path` from `times`, like
+/// `touch`. If nothing is there, an empty file is created with them.
+pub fn touch<R: Read + Write + Seek>(info: &DiskInfo,
+                                     disk_file: &mut R,
+                                     path: &str,
+                                     times: &Times)
+                                     -> Result<()> {
+    // The root has no entry to hold its times.
+    if path.trim_matches('/').is_empty() {
+        return Err(Error::NotFound(path.to_string()));
+    }
+    let (directory, slot) = match find_slot(info, disk_file, path)? {
+        Some((directory, slot, _)) => (directory, slot),
+        None => {
+            let archive = DirEntryAttributes::Archive as u8;
+            let now = Local::now().naive_local();
+            store_file(info, disk_file, path, "", archive, &[], |short_name, attributes, first| {
+                let mut entry = new_entry(short_name, attributes, first, 0, now);
+                stamp(&mut entry, times);
+                entry
+            })?;
+            return Ok(());
+        }
+    };
+    let offset = directory.slot_offset(slot).unwrap();
+    let mut entry = [0u8; DIR_ENTRY_SIZE];
+    disk_file.seek(SeekFrom::Start(offset))?;
+    disk_file.read_exact(&mut entry)?;
+    // DR-DOS keeps a password's hash where the creation time would be, so
+    // that much of a new creation time is left out.
+    let rights_in_use = times.created.is_some() && drdos::rights_in_use(info, disk_file)?;
+    let protection = drdos::Protection::of(&DirEntry::read(&entry, info.fat_type()), rights_in_use);
+    stamp(&mut entry, times);
+    if let Some(protection) = protection {
+        LittleEndian::write_u16(&mut entry[DIR_ENTRY_CREATETIME..], protection.password_hash);
+    }
+    disk_file.seek(SeekFrom::Start(offset))?;
+    Ok(disk_file.write_all(&entry)?)
+}
+
+/// How `reformat` empties a volume.
+pub struct ReformatOptions {
+    /// Zero the data area too, not just the FATs and root directory.
+    pub full: bool,
+    /// With `full`, read each cluster back and mark the ones that fail bad.
+    pub scan: bool,
+    /// Keep the volume label, in the boot sector and the root directory.
+    pub keep_label: bool,
+    /// Keep the serial number rather than making a new one.
+    pub keep_serial: bool,
+    /// Keep the jump, OEM name and boot code, so a boot disk stays one.
+    pub keep_bootcode: bool,
+}
+
+/// What `reformat` did.
+pub struct Reformat {
+    /// Data clusters zeroed; 0 for a quick format.
+    pub zeroed: u64,
+    /// Clusters marked bad in the new FAT.
+    pub bad_clusters: usize,
+    /// The serial number the volume has now, if it has an extended BPB.
+    pub volume_id: Option<u32>,
+}
+
+/// A serial number made from the current time, as DOS FORMAT makes them.
+fn new_volume_id() -> u32 {
+    let now = Local::now().naive_local();
+    let low = ((now.month() << 8) | now.day()) + ((now.second() << 8) | (now.nanosecond() / 10_000_000).min(99));
+    let high = ((now.hour() << 8) | now.minute()) + now.year() as u32;
+    (high << 16).wrapping_add(low)
+}
+
+/// Empties the volume the way DOS FORMAT does. A quick format only starts
+/// the FATs and root directory over, keeping bad cluster marks, since those
+/// clusters are no better than before. A full one also zeroes the data area,
+/// and with `scan` reads each cluster back, marking the ones that don't come
+/// back as zeros bad instead of keeping the old marks.
+///
+/// Like FORMAT, it gives the volume a new serial number, clears its label
+/// and writes new boot code, unless told to keep them. A boot sector without
+/// an extended BPB has neither serial nor label, and its code is left alone,
+/// as the code starts where those fields would be.
+pub fn reformat<R: Read + Write + Seek>(info: &DiskInfo,
+                                        disk_file: &mut R,
+                                        options: &ReformatOptions)
+                                        -> Result<Reformat> {
+    reformat_with_progress(info, disk_file, options, |_, _| ())
+}
+
+/// Reformats as `reformat` does, calling `progress` with the clusters done
+/// so far and the clusters in all after each one.
+pub fn reformat_with_progress<R, F>(info: &DiskInfo,
+                                    disk_file: &mut R,
+                                    options: &ReformatOptions,
+                                    mut progress: F)
+                                    -> Result<Reformat>
+    where R: Read + Write + Seek,
+          F: FnMut(u64, u64)
+{
+    if !bpb_looks_valid(info) {
+        return Err(Error::InvalidBootSector("the BPB looks damaged; try `fat12 recover-bpb`".to_string()));
+    }
+    let old_fat = read_fat(info, disk_file)?;
+    let root = Directory::root(info, &old_fat);
+    let label = root.read_slots(disk_file)?
+        .chunks(DIR_ENTRY_SIZE)
+        .take_while(|slot| slot[0] != 0x00)
+        .find(|slot| {
+            slot[0] != 0xE5 && slot[DIR_ENTRY_ATTRS] & 0x3F != LFN_ATTRIBUTES &&
+            slot[DIR_ENTRY_ATTRS] & DirEntryAttributes::VolumeLabel as u8 != 0
+        })
+        .map(|slot| slot.to_vec())
+        .filter(|_| options.keep_label);
+    let (full, scan) = (options.full, options.scan);
+
+    let fat_type = info.fat_type();
+    let mut fat = vec![0; old_fat.len()];
+    // Entry 0 holds the media descriptor.
+    set_fat_entry(info, &mut fat, 0, fat_entry(info, &old_fat, 0).unwrap_or(fat_type.end_of_chain()));
+    set_fat_entry(info, &mut fat, 1, fat_type.end_of_chain());
+    let size = cluster_size(info) as usize;
+    let zeros = vec![0; size];
+    let mut readback = vec![0; size];
+    let mut report = Reformat { zeroed: 0, bad_clusters: 0, volume_id: None };
+    let clusters = cluster_limit(info) as u64 - 2;
+    for cluster in 2..cluster_limit(info) {
+        progress(cluster as u64 - 2, clusters);
+        let was_bad = fat_entry(info, &old_fat, cluster) == Some(fat_type.bad_cluster());
+        let bad = if full && scan {
+            let start = cluster_start(info, cluster);
+            let written = disk_file.seek(SeekFrom::Start(start))
+                .and_then(|_| disk_file.write_all(&zeros))
+                .and_then(|_| disk_file.seek(SeekFrom::Start(start)))
+                .and_then(|_| disk_file.read_exact(&mut readback));
+            report.zeroed += 1;
+            written.is_err() || readback != zeros
+        } else {
+            if full && !was_bad {
+                disk_file.seek(SeekFrom::Start(cluster_start(info, cluster)))?;
+                disk_file.write_all(&zeros)?;
+                report.zeroed += 1;
+            }
+            was_bad
+        };
+        if bad {
+            set_fat_entry(info, &mut fat, cluster, fat_type.bad_cluster());
+            report.bad_clusters += 1;
+        }
+    }
+    progress(clusters, clusters);
+
+    let mut slots = if fat_type == FatType::Fat32 {
+        set_fat_entry(info, &mut fat, info.root_cluster, fat_type.end_of_chain());
+        vec![0; size]
+    } else {
+        vec![0; info.root_dir_entries as usize * DIR_ENTRY_SIZE]
+    };
+    if let Some(label) = label {
+        slots[..DIR_ENTRY_SIZE].copy_from_slice(&label);
+    }
+    Directory::root(info, &fat).write_slots(disk_file, &slots)?;
+    write_fat(info, disk_file, &fat)?;
+
+    let mut boot_sector = [0u8; 512];
+    disk_file.seek(SeekFrom::Start(0))?;
+    disk_file.read_exact(&mut boot_sector)?;
+    let ext = info.ext_shift();
+    if info.boot_signature == 0x29 {
+        if !options.keep_serial {
+            LittleEndian::write_u32(&mut boot_sector[ext + VOLUME_ID..], new_volume_id());
+        }
+        if !options.keep_label {
+            boot_sector[ext + VOLUME_LABEL..ext + VOLUME_LABEL + VOLUME_LABEL_SIZE].copy_from_slice(b"NO NAME    ");
+        }
+        if !options.keep_bootcode {
+            geometry::write_boot_stub(&mut boot_sector, ext + FS_TYPE + FS_TYPE_SIZE);
+        }
+        report.volume_id = Some(LittleEndian::read_u32(&boot_sector[ext + VOLUME_ID..]));
+    }
+    disk_file.seek(SeekFrom::Start(0))?;
+    disk_file.write_all(&boot_sector)?;
+    // FAT32 keeps a copy of the boot sector for when the first is damaged.
+    if info.has_fat32_bpb() && info.backup_boot_sector != 0 {
+        disk_file.seek(SeekFrom::Start(info.backup_boot_sector as u64 * info.bytes_per_sector as u64))?;
+        disk_file.write_all(&boot_sector)?;
+    }
+    Ok(report)
+}
+
+/// Writes a blank volume of `geometry` from the start of `disk_file`: a boot
+/// sector with a new serial number, the FAT copies with only the media
+/// descriptor and end-of-chain entries set, and an empty root directory. The
+/// image is extended to its full size, but the data area isn't written, so
+/// fill a new file rather than one with old data in it. Returns the serial
+/// number.
+pub fn format<W: Write + Seek>(disk_file: &mut W, geometry: &geometry::Geometry) -> Result<u32> {
+    let mut boot_sector = geometry::boot_sector(geometry);
+    let volume_id = new_volume_id();
+    LittleEndian::write_u32(&mut boot_sector[VOLUME_ID..], volume_id);
+    disk_file.seek(SeekFrom::Start(0))?;
+    disk_file.write_all(&boot_sector)?;
+
+    let sector_size = geometry::SECTOR_SIZE as usize;
+    let mut fat = vec![0; geometry.sectors_per_fat as usize * sector_size];
+    // Entry 0 holds the media descriptor and entry 1 end of chain, each with
+    // the high bits set.
+    let start: &[u8] = match geometry.fat_type() {
+        FatType::Fat12 => &[geometry.media, 0xFF, 0xFF],
+        _ => &[geometry.media, 0xFF, 0xFF, 0xFF],
+    };
+    fat[..start.len()].copy_from_slice(start);
+    for _ in 0..geometry::FAT_COUNT {
+        disk_file.write_all(&fat)?;
+    }
+    disk_file.write_all(&vec![0; geometry.root_dir_sectors() as usize * sector_size])?;
+
+    disk_file.seek(SeekFrom::Start(geometry.total_sectors as u64 * sector_size as u64 - 1))?;
+    disk_file.write_all(&[0])?;
+    Ok(volume_id)
+}
+
+/// A cluster's worth of its own number: `CLUSTER=` and the number in eight
+/// hex digits, over and over, so each 16-byte row of a hex dump says which
+/// cluster it came from.
+pub fn cluster_stamp(info: &DiskInfo, cluster: u32) -> Vec<u8> {
+    let stamp = format!("CLUSTER={:08X}", cluster);
+    stamp.bytes().cycle().take(cluster_size(info) as usize).collect()
+}
+
+/// Fills every free cluster with `cluster_stamp`, for checking that code
+/// following chains lands on the clusters it should. Files and bad clusters
+/// are left alone. Returns how many clusters were stamped.
+pub fn fill_free<R: Read + Write + Seek>(info: &DiskInfo, disk_file: &mut R) -> Result<u64> {
+    fill_free_with_progress(info, disk_file, |_, _| ())
+}
+
+/// Fills as `fill_free` does, calling `progress` with the clusters stamped
+/// so far and the free clusters in all after each one.
+pub fn fill_free_with_progress<R, F>(info: &DiskInfo, disk_file: &mut R, mut progress: F) -> Result<u64>
+    where R: Read + Write + Seek,
+          F: FnMut(u64, u64)
+{
+    let fat = read_fat(info, disk_file)?;
+    let free: Vec<u32> = (2..cluster_limit(info)).filter(|&c| fat_entry(info, &fat, c) == Some(0)).collect();
+    let mut stamped = 0;
+    progress(0, free.len() as u64);
+    for cluster in free.iter().cloned() {
+        disk_file.seek(SeekFrom::Start(cluster_start(info, cluster)))?;
+        disk_file.write_all(&cluster_stamp(info, cluster))?;
+        stamped += 1;
+        progress(stamped, free.len() as u64);
+    }
+    Ok(stamped)
+}
+
+/// Formats attribute bits as `RHSVDA`, with `-` for each bit that is clear.
+pub fn attribute_string(attributes: u8) -> String {
+    "RHSVDA".chars()
+        .enumerate()
+        .map(|(bit, c)| if attributes & (1 << bit) != 0 { c } else { '-' })
+        .collect()
+}
+
+/// Bytes a file occupies on disk: its size rounded up to whole clusters.
+pub fn allocated_size(info: &DiskInfo, entry: &DirEntry) -> u64 {
+    let cluster = cluster_size(info);
+    (entry.file_size as u64).div_ceil(cluster) * cluster
+}
+
+pub fn dos_date(date: u16) -> Option<NaiveDate> {
+    NaiveDate::from_ymd_opt((date >> 9) as i32 + 1980, (date & 0x01E0) as u32 >> 5, date as u32 & 0x001F)
+}
+
+/// Decodes a DOS date and time. The time field counts seconds in units of two.
+pub fn dos_datetime(date: u16, time: u16) -> Option<NaiveDateTime> {
+    dos_date(date)?.and_hms_opt(time as u32 >> 11, (time & 0x07E0) as u32 >> 5, (time & 0x001F) as u32 * 2)
+}
+
+pub fn read_image<R: Read + Seek>(disk_file: &mut R) -> Result<Vec<u8>> {
+    let mut image = Vec::new();
+    disk_file.seek(SeekFrom::Start(0))?;
+    disk_file.read_to_end(&mut image)?;
+    Ok(image)
+}
+
+/// Reads the boot sector, FATs and root directory: everything before the
+/// data area. Only the boot sector is read if it or the BPB can't be trusted.
+pub fn read_metadata<R: Read + Seek>(disk_file: &mut R) -> Result<Vec<u8>> {
+    let len = match read_disk_info(disk_file) {
+        Ok(ref info) if bpb_looks_valid(info) => cluster_start(info, 2),
+        Ok(_) | Err(Error::InvalidBootSector(_)) => 512,
+        Err(e) => return Err(e),
+    };
+    let mut metadata = Vec::new();
+    disk_file.seek(SeekFrom::Start(0))?;
+    disk_file.take(len).read_to_end(&mut metadata)?;
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+    use std::process;
+
+    /// A freshly formatted 1.44M image.
+    pub(crate) fn blank() -> (DiskInfo, Cursor<Vec<u8>>) {
+        let mut image = Cursor::new(Vec::new());
+        format(&mut image, geometry::by_name("1.44M").unwrap()).unwrap();
+        (read_disk_info(&mut image).unwrap(), image)
+    }
+
+    /// A host file named `name` holding `data`, in a directory of the test's
+    /// own, for `put` to copy.
+    pub(crate) fn host_file(test: &str, name: &str, data: &[u8]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("fat12-{}-{}", process::id(), test));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    /// The names of the live entries of the directory at `path`: each short
+    /// name with the long name it has, if any.
+    pub(crate) fn names(image: &mut Cursor<Vec<u8>>, path: &str) -> Vec<(String, Option<String>)> {
+        let info = read_disk_info(image).unwrap();
+        Directory::open(&info, image, path)
+            .unwrap()
+            .unwrap()
+            .entries(image)
+            .unwrap()
+            .into_iter()
+            .filter(|(_, entry)| !entry.is_lfn())
+            .map(|(_, entry)| (entry.name(), entry.long_name))
+            .collect()
+    }
+
+    #[test]
+    fn names_that_fit_8_3_keep_their_case() {
+        let (info, mut image) = blank();
+        for name in ["lower.txt", "Mixed.Txt", "UPPER.TXT", "notes.TXT", "Makefile"] {
+            put(&info, &mut image, &host_file("case", name, b"x"), "/").unwrap();
+        }
+        assert_eq!(names(&mut image, "/"),
+                   vec![("LOWER.TXT".to_string(), Some("lower.txt".to_string())),
+                        ("MIXED.TXT".to_string(), Some("Mixed.Txt".to_string())),
+                        ("UPPER.TXT".to_string(), None),
+                        ("NOTES.TXT".to_string(), Some("notes.TXT".to_string())),
+                        ("MAKEFILE".to_string(), Some("Makefile".to_string()))]);
+        // Only the names a part of which mixes cases take LFN slots.
+        let fat = read_fat(&info, &mut image).unwrap();
+        let root = Directory::root(&info, &fat).read_slots(&mut image).unwrap();
+        let slots: Vec<&[u8]> = root.chunks(DIR_ENTRY_SIZE).take_while(|slot| slot[0] != 0).collect();
+        assert_eq!(slots.len(), 7);
+        assert_eq!(slots[0][DIR_ENTRY_RESERVED], CASE_LOWER_BASE | CASE_LOWER_EXT);
+        assert_eq!(slots[2][DIR_ENTRY_RESERVED], 0);
+        assert_eq!(slots[4][DIR_ENTRY_RESERVED], CASE_LOWER_BASE);
+        for path in ["/lower.txt", "/LOWER.TXT", "/mixed.txt", "/Notes.Txt"] {
+            assert!(find_path(&info, &mut image, path).unwrap().is_some(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn long_names_get_numbered_aliases() {
+        let (info, mut image) = blank();
+        mkdir(&info, &mut image, "/DOCS").unwrap();
+        put(&info, &mut image, &host_file("aliases", "Annual Report.txt", b"one"), "/DOCS").unwrap();
+        put(&info, &mut image, &host_file("aliases", "Annual Report 2.txt", b"two"), "/DOCS").unwrap();
+        put(&info, &mut image, &host_file("aliases", "readme.txt", b"three"), "/DOCS").unwrap();
+        assert_eq!(names(&mut image, "/DOCS"),
+                   vec![(".".to_string(), None),
+                        ("..".to_string(), None),
+                        ("ANNUAL~1.TXT".to_string(), Some("Annual Report.txt".to_string())),
+                        ("ANNUAL~2.TXT".to_string(), Some("Annual Report 2.txt".to_string())),
+                        ("README.TXT".to_string(), Some("readme.txt".to_string()))]);
+        let mut volume = Fat12Volume::open(image).unwrap();
+        assert_eq!(volume.read_file("/DOCS/Annual Report 2.txt").unwrap(), b"two");
+        assert_eq!(volume.read_file("/docs/annual~1.txt").unwrap(), b"one");
+        let mut image = volume.into_inner();
+        match put(&info, &mut image, &host_file("aliases", "readme.txt", b""), "/DOCS") {
+            Err(Error::AlreadyExists(_)) => {}
+            other => panic!("expected AlreadyExists, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn writable_volumes_keep_their_fat_current() {
+        let (_, image) = blank();
+        let mut volume = Fat12Volume::open_writable(image).unwrap();
+        let free = volume.free_space().free_clusters;
+        volume.mkdir("/DOCS").unwrap();
+        volume.put(&host_file("volume", "Notes.txt", &[b'n'; 1000]), "/DOCS").unwrap();
+        assert_eq!(volume.free_space().free_clusters, free - 3);
+        let hidden = volume.set_attributes("/DOCS/Notes.txt", DirEntryAttributes::Hidden as u8, 0).unwrap();
+        assert_ne!(hidden & DirEntryAttributes::Hidden as u8, 0);
+        assert_eq!(volume.rm("/DOCS/NOTES.TXT").unwrap().chain.len(), 2);
+        let mut volume = volume.into_read_only();
+        assert_eq!(volume.free_space().free_clusters, free - 1);
+        assert!(volume.entry("/DOCS/Notes.txt").unwrap().is_none());
+    }
+
+    #[test]
+    fn free_clusters_and_slots_come_in_runs() {
+        let (info, mut image) = blank();
+        for name in ["A.TXT", "B.TXT", "C.TXT"] {
+            put(&info, &mut image, &host_file("free", name, name.as_bytes()), "/").unwrap();
+        }
+        mkdir(&info, &mut image, "/DOCS").unwrap();
+        rm(&info, &mut image, "/B.TXT").unwrap();
+        let mut fat = read_fat(&info, &mut image).unwrap();
+        set_fat_entry(&info, &mut fat, 100, info.fat_type().bad_cluster());
+        write_fat(&info, &mut image, &fat).unwrap();
+
+        // B.TXT's cluster, then the rest but for the bad one.
+        let mut volume = Fat12Volume::open(image).unwrap();
+        let limit = cluster_limit(&info);
+        assert_eq!(volume.free_extents(), [(3, 1), (6, 100 - 6), (101, limit - 101)]);
+        let root = root_dir_start(&info);
+        let slots = info.root_dir_entries as usize;
+        assert_eq!(volume.free_dir_slots("/").unwrap(),
+                   [SlotRun { first: 1, count: 1, offset: root + 32, unused: false },
+                    SlotRun { first: 4, count: slots - 4, offset: root + 4 * 32, unused: true }]);
+        assert_eq!(volume.free_dir_slots("/DOCS").unwrap(),
+                   [SlotRun { first: 2, count: 14, offset: cluster_start(&info, 5) + 2 * 32, unused: true }]);
+        assert!(matches!(volume.free_dir_slots("/A.TXT"), Err(Error::NotADirectory(_))));
+        assert!(matches!(volume.free_dir_slots("/NONE"), Err(Error::NotFound(_))));
+    }
+
+    #[test]
+    fn disk_usage_counts_directories_as_df_does() {
+        let (info, mut image) = blank();
+        mkdir(&info, &mut image, "/A").unwrap();
+        mkdir(&info, &mut image, "/A/B").unwrap();
+        // Enough entries for B to take a second cluster.
+        for i in 0..20 {
+            put(&info, &mut image, &host_file("du", &format!("F{}.TXT", i), &[0; 700]), "/A/B").unwrap();
+        }
+        put(&info, &mut image, &host_file("du", "TOP.TXT", b"top"), "/").unwrap();
+        let fat = read_fat(&info, &mut image).unwrap();
+        let usage = disk_usage(&info, &mut image, &fat, "/").unwrap().unwrap();
+        let of = |path: &str| usage.iter().find(|u| u.path == path).unwrap().clone();
+        // 20 files of two 512-byte clusters, B with two clusters and A with one.
+        assert_eq!(of("/A/B/"), Usage { path: "/A/B/".to_string(), logical: 20 * 700, allocated: 42 * 512 });
+        assert_eq!(of("/A/"), Usage { path: "/A/".to_string(), logical: 20 * 700, allocated: 43 * 512 });
+        assert_eq!(usage.last().unwrap().path, "/");
+        assert_eq!(usage.last().unwrap().allocated, 44 * 512);
+        assert_eq!(usage.last().unwrap().allocated, free_space(&info, &fat).used_bytes());
+        let usage = disk_usage(&info, &mut image, &fat, "/A/B").unwrap().unwrap();
+        assert_eq!(usage.last().unwrap().allocated, 42 * 512);
+        assert_eq!(disk_usage(&info, &mut image, &fat, "/NONE").unwrap(), None);
+    }
+
+    #[test]
+    fn volumes_keep_to_their_limits() {
+        let (info, mut image) = blank();
+        mkdir(&info, &mut image, "/DIR").unwrap();
+        for name in ["A.TXT", "B.TXT", "C.TXT"] {
+            put(&info, &mut image, &host_file("limits", name, &[1; 2000]), "/DIR").unwrap();
+        }
+        let options = |change: &dyn Fn(&mut VolumeOptions)| {
+            let mut options = VolumeOptions::default();
+            change(&mut options);
+            options
+        };
+        let open = |options: VolumeOptions| Fat12Volume::open_with(image.clone(), &options).unwrap();
+        let limited = |e: Error| matches!(e, Error::LimitExceeded(_));
+        // DIR, its `.` and `..`, and the files in it.
+        assert_eq!(open(options(&|o| o.max_entries = Some(6))).walk(false).unwrap().len(), 4);
+        assert!(limited(open(options(&|o| o.max_entries = Some(5))).walk(false).err().unwrap()));
+        assert_eq!(open(options(&|o| o.max_chain = Some(4))).read_file("/DIR/A.TXT").unwrap().len(), 2000);
+        assert!(limited(open(options(&|o| o.max_chain = Some(3))).read_file("/DIR/A.TXT").unwrap_err()));
+        assert!(limited(open(options(&|o| o.max_output = Some(1999))).read_file("/DIR/A.TXT").unwrap_err()));
+
+        // A FAT said to be bigger than the image is refused before it's read.
+        let mut boot = image.into_inner();
+        LittleEndian::write_u16(&mut boot[22..], 0xFFFF);
+        let mut image = Cursor::new(boot);
+        let info = read_disk_info(&mut image).unwrap();
+        assert!(matches!(read_fat(&info, &mut image), Err(Error::InvalidBootSector(_))));
+    }
+
+    #[test]
+    fn rm_frees_the_entry_its_long_name_and_its_clusters() {
+        let (info, mut image) = blank();
+        let free = free_space(&info, &read_fat(&info, &mut image).unwrap()).free_clusters;
+        let data = vec![0x5A; 3 * cluster_size(&info) as usize - 1];
+        put(&info, &mut image, &host_file("rm", "A long file name.bin", &data), "/").unwrap();
+        mkdir(&info, &mut image, "/SUB").unwrap();
+        assert_eq!(free_space(&info, &read_fat(&info, &mut image).unwrap()).free_clusters, free - 4);
+
+        rm(&info, &mut image, "/A long file name.bin").unwrap();
+        assert_eq!(names(&mut image, "/"), vec![("SUB".to_string(), None)]);
+        assert_eq!(free_space(&info, &read_fat(&info, &mut image).unwrap()).free_clusters, free - 1);
+        // Both LFN slots and the entry are marked deleted.
+        let slots = Directory::root(&info, &[][..]).read_slots(&mut image).unwrap();
+        assert_eq!([slots[0], slots[DIR_ENTRY_SIZE], slots[2 * DIR_ENTRY_SIZE]], [0xE5; 3]);
+        match rm(&info, &mut image, "/SUB") {
+            Err(Error::IsADirectory(_)) => {}
+            other => panic!("expected IsADirectory, got {:?}", other),
+        }
+        // Directories go with rmdir once they are empty.
+        put(&info, &mut image, &host_file("rm", "IN.TXT", b"in"), "/SUB").unwrap();
+        match rmdir(&info, &mut image, "/SUB") {
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::DirectoryNotEmpty => {}
+            other => panic!("expected DirectoryNotEmpty, got {:?}", other),
+        }
+        rm(&info, &mut image, "/SUB/IN.TXT").unwrap();
+        rmdir(&info, &mut image, "/SUB").unwrap();
+        assert_eq!(names(&mut image, "/"), vec![]);
+        assert_eq!(free_space(&info, &read_fat(&info, &mut image).unwrap()).free_clusters, free);
+        match rm(&info, &mut image, "/GONE.TXT") {
+            Err(Error::NotFound(_)) => {}
+            other => panic!("expected NotFound, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rename_keeps_the_entry_under_its_new_name() {
+        let (info, mut image) = blank();
+        put(&info, &mut image, &host_file("rename", "A long file name.bin", b"data"), "/").unwrap();
+        mkdir(&info, &mut image, "/SUB").unwrap();
+        put(&info, &mut image, &host_file("rename", "IN.TXT", b"in"), "/SUB").unwrap();
+        let flc = find_path(&info, &mut image, "/A long file name.bin").unwrap().unwrap().flc;
+
+        rename(&info, &mut image, "/A long file name.bin", "short.bin").unwrap();
+        rename(&info, &mut image, "/SUB", "Sub dir").unwrap();
+        assert_eq!(names(&mut image, "/"),
+                   vec![("SHORT.BIN".to_string(), Some("short.bin".to_string())),
+                        ("SUBDIR~1".to_string(), Some("Sub dir".to_string()))]);
+        assert_eq!(find_path(&info, &mut image, "/SHORT.BIN").unwrap().unwrap().flc, flc);
+        assert!(find_path(&info, &mut image, "/Sub dir/IN.TXT").unwrap().is_some());
+        // Only the case changes, in the slots the entry had.
+        rename(&info, &mut image, "/short.bin", "SHORT.BIN").unwrap();
+        assert_eq!(names(&mut image, "/")[0], ("SHORT.BIN".to_string(), None));
+
+        match rename(&info, &mut image, "/SHORT.BIN", "Sub dir") {
+            Err(Error::AlreadyExists(_)) => {}
+            other => panic!("expected AlreadyExists, got {:?}", other),
+        }
+        assert_eq!(names(&mut image, "/")[0], ("SHORT.BIN".to_string(), None));
+        match rename(&info, &mut image, "/GONE.TXT", "B.TXT") {
+            Err(Error::NotFound(_)) => {}
+            other => panic!("expected NotFound, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn touched_times_are_kept_to_the_hundredth_and_clamped() {
+        let (info, mut image) = blank();
+        let at = |y: i32, mo: u32, d: u32, h: u32, mi: u32, s: u32, ms: u32| {
+            NaiveDate::from_ymd_opt(y, mo, d).unwrap().and_hms_milli_opt(h, mi, s, ms).unwrap()
+        };
+        let times = Times { created: Some(at(1994, 3, 1, 12, 34, 57, 450)), ..Times::default() };
+        put_with_times(&info, &mut image, &host_file("touch", "A.TXT", b"a"), "/", &times).unwrap();
+        let entry = find_path(&info, &mut image, "/A.TXT").unwrap().unwrap();
+        assert_eq!(entry.created(), times.created);
+
+        // Times not given are left alone, and those DOS can't hold clamped.
+        let later = Times {
+            modified: Some(at(1970, 1, 1, 0, 0, 0, 0)),
+            accessed: Some(at(2200, 6, 1, 8, 0, 0, 0)),
+            ..Times::default()
+        };
+        touch(&info, &mut image, "/A.TXT", &later).unwrap();
+        let entry = find_path(&info, &mut image, "/A.TXT").unwrap().unwrap();
+        assert_eq!(entry.created(), times.created);
+        let modified = dos_datetime(entry.last_write_date, entry.last_write_time);
+        assert_eq!(modified, Some(at(1980, 1, 1, 0, 0, 0, 0)));
+        assert_eq!(dos_date(entry.last_access_date), NaiveDate::from_ymd_opt(2107, 12, 31));
+
+        // A missing file is made, empty, and the root has no times to set.
+        touch(&info, &mut image, "/NEW.TXT", &later).unwrap();
+        let entry = find_path(&info, &mut image, "/NEW.TXT").unwrap().unwrap();
+        assert_eq!((entry.file_size, entry.last_write_date), (0, DOS_EPOCH_DATE));
+        assert!(matches!(touch(&info, &mut image, "/", &later), Err(Error::NotFound(_))));
+    }
+
+    #[test]
+    fn the_volume_policy_decides_mutations() {
+        let (mut info, mut image) = blank();
+        info.options.policy = Some(policy::Policy::new(|mutation| match mutation.action {
+            Action::Create if mutation.path.ends_with("/KEEP") => policy::Decision::Allow,
+            Action::Create => policy::Decision::Deny("read-mostly".to_string()),
+            _ => policy::Decision::Allow,
+        }));
+        mkdir(&info, &mut image, "/KEEP").unwrap();
+        match mkdir(&info, &mut image, "/TEMP") {
+            Err(Error::Denied(_)) => {}
+            other => panic!("expected Denied, got {:?}", other),
+        }
+        // Another volume of the same image has a policy of its own.
+        let info = read_disk_info(&mut image).unwrap();
+        mkdir(&info, &mut image, "/TEMP").unwrap();
+    }
+
+    #[test]
+    fn entries_added_at_the_end_keep_it_marked() {
+        let ends = [(DirectoryEnd::Marker, 1), (DirectoryEnd::ZeroSector, 13), (DirectoryEnd::ZeroAll, 221)];
+        for &(end, zeroed) in &ends {
+            let (mut info, mut image) = blank();
+            info.options.directory_end = end;
+            // Stale entries past the end marker, as a tool that only wrote the
+            // marker might leave them.
+            let root = root_dir_start(&info) as usize;
+            for slot in 3..224 {
+                image.get_mut()[root + slot * DIR_ENTRY_SIZE..][..11].copy_from_slice(b"STALE   TXT");
+            }
+            put(&info, &mut image, &host_file("dir-end", "A long name.txt", b"a"), "/").unwrap();
+            let long_name = Some("A long name.txt".to_string());
+            assert_eq!(names(&mut image, "/"), vec![("ALONGN~1.TXT".to_string(), long_name)]);
+            let zeroed_after = image.get_ref()[root + 3 * DIR_ENTRY_SIZE..root + 224 * DIR_ENTRY_SIZE]
+                .chunks(DIR_ENTRY_SIZE)
+                .take_while(|slot| slot.iter().all(|&byte| byte == 0))
+                .count();
+            assert_eq!(zeroed_after, zeroed, "{:?}", end);
+        }
+    }
+
+    #[test]
+    fn system_files_are_protected_from_rm() {
+        let (mut info, mut image) = blank();
+        put(&info, &mut image, &host_file("protect", "IO.SYS", b"boot"), "/").unwrap();
+        put(&info, &mut image, &host_file("protect", "JUNK.TXT", b"junk"), "/").unwrap();
+        let system = DirEntryAttributes::Hidden as u8 | DirEntryAttributes::System as u8;
+        set_attributes(&info, &mut image, "/IO.SYS", system, 0).unwrap();
+        info.options.policy = Some(policy::protect_system());
+        match rm(&info, &mut image, "/IO.SYS") {
+            Err(Error::Denied(why)) => assert_eq!(why, "/IO.SYS: hidden or system file"),
+            other => panic!("expected Denied, got {:?}", other),
+        }
+        assert!(matches!(rename(&info, &mut image, "/IO.SYS", "OLD.SYS"), Err(Error::Denied(_))));
+        rm(&info, &mut image, "/JUNK.TXT").unwrap();
+        assert_eq!(names(&mut image, "/"), vec![("IO.SYS".to_string(), None)]);
+    }
+
+    /// What the root directory reads as with `slots` at its start: each
+    /// short name with its long name, and the warnings reading it gave.
+    fn read_root(slots: &[[u8; DIR_ENTRY_SIZE]]) -> (Vec<(String, Option<String>)>, Vec<Warning>) {
+        let (info, mut image) = blank();
+        let directory = Directory::root(&info, &[][..]);
+        let mut contents = directory.read_slots(&mut image).unwrap();
+        contents[..slots.len() * DIR_ENTRY_SIZE].copy_from_slice(&slots.concat());
+        directory.write_slots(&mut image, &contents).unwrap();
+        let names = directory.entries(&mut image)
+            .unwrap()
+            .into_iter()
+            .filter(|(_, entry)| !entry.is_lfn())
+            .map(|(_, entry)| (entry.name(), entry.long_name))
+            .collect();
+        (names, info.options.warnings.take())
+    }
+
+    fn file_entry(short_name: &[u8; 11]) -> [u8; DIR_ENTRY_SIZE] {
+        let modified = NaiveDate::from_ymd_opt(2020, 1, 1).unwrap().and_hms_opt(12, 0, 0).unwrap();
+        new_entry(short_name, DirEntryAttributes::Archive as u8, 0, 0, modified)
+    }
+
+    #[test]
+    fn long_names_are_decoded_only_from_whole_runs() {
+        let name = "A rather long file name.txt";
+        let short = *b"ARATHE~1TXT";
+        let mut slots = lfn_slots(name, &short);
+        assert_eq!(slots.len(), 3);
+        slots.push(file_entry(&short));
+        let with_name = |long_name: Option<&str>| {
+            vec![("ARATHE~1.TXT".to_string(), long_name.map(String::from))]
+        };
+        assert_eq!(read_root(&slots), (with_name(Some(name)), vec![]));
+        assert_eq!(long_name(&slots.concat()), Some(name.to_string()));
+        // A slot cut short isn't read.
+        assert_eq!(decode_dir(&slots.concat()[..127], FatType::Fat12, &Warnings::default()).len(), 3);
+        let orphaned = vec![Warning::OrphanedLongName(Some("ARATHE~1.TXT".to_string()))];
+
+        // Slots made for another short name, as when DOS renamed the file.
+        let mut renamed = lfn_slots(name, b"OTHER   TXT");
+        renamed.push(file_entry(&short));
+        assert_eq!(read_root(&renamed), (with_name(None), orphaned.clone()));
+
+        let mut swapped = slots.clone();
+        swapped.swap(1, 2);
+        assert_eq!(read_root(&swapped), (with_name(None), orphaned.clone()));
+        assert_eq!(long_name(&swapped.concat()), None);
+
+        // A run missing its first part.
+        let mut cut = slots.clone();
+        cut.remove(2);
+        assert_eq!(read_root(&cut), (with_name(None), orphaned));
+
+        // Slots left before a deleted entry belong to nothing.
+        let mut deleted = slots.clone();
+        deleted[3][0] = 0xE5;
+        deleted.push(file_entry(b"NEXT    TXT"));
+        assert_eq!(read_root(&deleted),
+                   (vec![("NEXT.TXT".to_string(), None)], vec![Warning::OrphanedLongName(None)]));
+    }
+
+    #[test]
+    fn puts_are_checked_before_anything_is_written() {
+        let (info, mut image) = blank();
+        mkdir(&info, &mut image, "/DIR").unwrap();
+        let hosts: Vec<PathBuf> = (0..75)
+            .map(|n| host_file("preflight", &format!("Long name {:02}.txt", n), &[0; 600]))
+            .collect();
+        let files: Vec<(&Path, Times)> = hosts.iter()
+            .map(|host| (host.as_path(), Times::default()))
+            .collect();
+        let sizes: Vec<(String, u64)> = (0..20).map(|n| (format!("Long name {:02}.txt", n), 600)).collect();
+        let plan = preflight(&info, &mut image, "/DIR", &sizes).unwrap();
+        // Two clusters for each file, and three more for the 62 slots.
+        assert_eq!((plan.data_clusters, plan.directory_clusters, plan.slots, plan.long_name_slots),
+                   (40, 3, 60, 40));
+        put_files(&info, &mut image, &files[..20], "/DIR").unwrap();
+        let (_, _, dir) = find_slot(&info, &mut image, "/DIR").unwrap().unwrap();
+        assert_eq!(cluster_chain(&info, &read_fat(&info, &mut image).unwrap()[..], dir.flc).len(), 4);
+
+        // The root has 223 slots left besides DIR's, and the last of 75 files
+        // finds no three in a row.
+        let before = image.get_ref().clone();
+        match put_files(&info, &mut image, &files, "/") {
+            Err(Error::NoSpace(why)) => assert!(why.ends_with("can't grow: 3 slots short"), "{}", why),
+            other => panic!("expected NoSpace, got {:?}", other),
+        }
+        // 2803 clusters are left free.
+        let big = host_file("preflight", "BIG.BIN", &vec![0; 1_440_000]);
+        match put(&info, &mut image, &big, "/") {
+            Err(Error::NoSpace(why)) => assert!(why.contains("10 clusters (5120 bytes) short"), "{}", why),
+            other => panic!("expected NoSpace, got {:?}", other),
+        }
+        assert!(image.get_ref() == &before);
+    }
+
+    #[test]
+    fn ordered_files_come_first_in_entries_and_clusters() {
+        let (info, mut image) = blank();
+        let hosts: Vec<PathBuf> = ["README.TXT", "MSDOS.SYS", "COMMAND.COM", "IO.SYS"].iter()
+            .map(|name| host_file("order", name, &[0; 700]))
+            .collect();
+        let mut files: Vec<(&Path, Times)> = hosts.iter()
+            .map(|host| (host.as_path(), Times::default()))
+            .collect();
+        order_files(&mut files, &["io.sys".to_string(), "MSDOS.SYS".to_string(), "DRVSPACE.BIN".to_string()]);
+        put_files(&info, &mut image, &files, "/").unwrap();
+        let order = ["IO.SYS", "MSDOS.SYS", "README.TXT", "COMMAND.COM"];
+        assert_eq!(names(&mut image, "/").into_iter().map(|(name, _)| name).collect::<Vec<_>>(), order);
+        let first_clusters: Vec<u32> = order.iter()
+            .map(|name| find_path(&info, &mut image, name).unwrap().unwrap().flc)
+            .collect();
+        assert_eq!(first_clusters, vec![2, 4, 6, 8]);
+    }
+
+    #[test]
+    fn compacting_a_directory_frees_its_empty_clusters() {
+        let (info, mut image) = blank();
+        mkdir(&info, &mut image, "/DIR").unwrap();
+        // Sixteen slots to a cluster: 20 files of three slots each and the
+        // `.` and `..` entries take four clusters.
+        for n in 0..20 {
+            let name = format!("Long name {:02}.txt", n);
+            put(&info, &mut image, &host_file("compact", &name, name.as_bytes()), "/DIR").unwrap();
+        }
+        for n in 0..18 {
+            rm(&info, &mut image, &format!("/DIR/Long name {:02}.txt", n)).unwrap();
+        }
+        let (_, _, dir) = find_slot(&info, &mut image, "/DIR").unwrap().unwrap();
+        assert_eq!(cluster_chain(&info, &read_fat(&info, &mut image).unwrap()[..], dir.flc).len(), 4);
+        // A slot of a name no entry follows, before the two files left, and
+        // the last file's long name missing its first slot.
+        let directory = Directory::chain(&info, &read_fat(&info, &mut image).unwrap()[..], dir.flc);
+        let mut slots = directory.read_slots(&mut image).unwrap();
+        let slot = |n: usize| n * DIR_ENTRY_SIZE..(n + 1) * DIR_ENTRY_SIZE;
+        slots[slot(2 + 18 * 3 - 1)].copy_from_slice(&lfn_slots("Nobody's name", b"NOBODY  TXT")[0]);
+        slots[slot(2 + 19 * 3)][0] = 0xE5;
+        directory.write_slots(&mut image, &slots).unwrap();
+
+        let compaction = compact_dir(&info, &mut image, "/DIR").unwrap();
+        assert_eq!(compaction, Compaction { slots: 2 + 20 * 3 - 6, clusters: 3 });
+        assert_eq!(cluster_chain(&info, &read_fat(&info, &mut image).unwrap()[..], dir.flc), vec![dir.flc]);
+        assert_eq!(names(&mut image, "/DIR"),
+                   vec![(".".to_string(), None),
+                        ("..".to_string(), None),
+                        ("LONGN~19.TXT".to_string(), Some("Long name 18.txt".to_string())),
+                        ("LONGN~20.TXT".to_string(), None)]);
+        assert_eq!(compact_dir(&info, &mut image, "/DIR").unwrap(), Compaction { slots: 0, clusters: 0 });
+    }
+
+    #[test]
+    fn fill_and_reformat_report_progress() {
+        let (info, mut image) = blank();
+        put(&info, &mut image, &host_file("progress", "A.TXT", b"a"), "/").unwrap();
+        let mut reports = Vec::new();
+        let stamped = fill_free_with_progress(&info, &mut image, |done, total| reports.push((done, total)));
+        let stamped = stamped.unwrap();
+        assert_eq!(reports.len() as u64, stamped + 1);
+        assert_eq!(reports.first(), Some(&(0, stamped)));
+        assert_eq!(reports.last(), Some(&(stamped, stamped)));
+
+        let mut last = (0, 0);
+        let options = ReformatOptions {
+            full: true,
+            scan: false,
+            keep_label: false,
+            keep_serial: false,
+            keep_bootcode: false,
+        };
+        reformat_with_progress(&info, &mut image, &options, |done, total| {
+            assert!(done >= last.0 && done <= total);
+            last = (done, total);
+        }).unwrap();
+        assert_eq!(last, (stamped + 1, stamped + 1));
+    }
+
+    /// A disk whose sectors in `bad` take writes without keeping them.
+    struct FlakyDisk {
+        image: Cursor<Vec<u8>>,
+        bad: std::ops::Range<u64>,
+    }
+    impl Read for FlakyDisk {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.image.read(buf)
+        }
+    }
+    impl Write for FlakyDisk {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.bad.contains(&self.image.position()) {
+                self.image.seek(SeekFrom::Current(buf.len() as i64))?;
+                return Ok(buf.len());
+            }
+            self.image.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    impl Seek for FlakyDisk {
+        fn seek(&mut self, to: SeekFrom) -> io::Result<u64> {
+            self.image.seek(to)
+        }
+    }
+
+    #[test]
+    fn quick_formats_keep_the_data_and_full_ones_scan_it() {
+        let (info, mut image) = blank();
+        put(&info, &mut image, &host_file("reformat", "A.TXT", &[0xAB; 600]), "/").unwrap();
+        let mut fat = read_fat(&info, &mut image).unwrap();
+        set_fat_entry(&info, &mut fat, 100, info.fat_type().bad_cluster());
+        write_fat(&info, &mut image, &fat).unwrap();
+        let clusters = cluster_limit(&info) as u64 - 2;
+        let data = cluster_start(&info, 2) as usize;
+        let options = |full: bool, scan: bool| ReformatOptions {
+            full,
+            scan,
+            keep_label: false,
+            keep_serial: false,
+            keep_bootcode: false,
+        };
+        let bad = |image: &mut Cursor<Vec<u8>>| {
+            let fat = read_fat(&info, image).unwrap();
+            let is_bad = |c: &u32| fat_entry(&info, &fat, *c) == Some(info.fat_type().bad_cluster());
+            (2..cluster_limit(&info)).filter(is_bad).collect::<Vec<_>>()
+        };
+
+        // A quick format empties the root and FAT but leaves the data, and
+        // the bad mark, where they were.
+        let quick = reformat(&info, &mut image, &options(false, false)).unwrap();
+        assert_eq!((quick.zeroed, quick.bad_clusters), (0, 1));
+        assert_eq!(names(&mut image, "/"), []);
+        assert_eq!(image.get_ref()[data], 0xAB);
+        assert_eq!(bad(&mut image), [100]);
+
+        // A full one zeroes the data, skipping the cluster marked bad.
+        let mut kept = image.clone();
+        let full = reformat(&info, &mut kept, &options(true, false)).unwrap();
+        assert_eq!((full.zeroed, full.bad_clusters), (clusters - 1, 1));
+        assert!(kept.get_ref()[data..].iter().all(|&byte| byte == 0));
+        assert_eq!(bad(&mut kept), [100]);
+
+        // A scan marks bad what doesn't read back, and only that.
+        let start = cluster_start(&info, 7);
+        let mut disk = FlakyDisk { image, bad: start..start + cluster_size(&info) };
+        disk.image.get_mut()[start as usize] = 0xCD;
+        let scanned = reformat(&info, &mut disk, &options(true, true)).unwrap();
+        assert_eq!((scanned.zeroed, scanned.bad_clusters), (clusters, 1));
+        assert_eq!(bad(&mut disk.image), [7]);
+    }
+
+    #[test]
+    fn reformats_keep_the_label_serial_and_boot_code_when_told() {
+        let (_, mut image) = blank();
+        {
+            let sector = &mut image.get_mut()[..512];
+            sector[OS_NAME..OS_NAME + OS_NAME_SIZE].copy_from_slice(b"IBM  3.3");
+            LittleEndian::write_u32(&mut sector[VOLUME_ID..], 0x1234_ABCD);
+            sector[VOLUME_LABEL..VOLUME_LABEL + VOLUME_LABEL_SIZE].copy_from_slice(b"GAMES DISK ");
+            sector[0x100] = 0xCD;
+        }
+        let info = read_disk_info(&mut image).unwrap();
+        let root = root_dir_start(&info) as usize;
+        image.get_mut()[root..root + 11].copy_from_slice(b"GAMES DISK ");
+        image.get_mut()[root + DIR_ENTRY_ATTRS] = DirEntryAttributes::VolumeLabel as u8;
+        let before = image.get_ref()[..512].to_vec();
+        let root_label = image.get_ref()[root..root + DIR_ENTRY_SIZE].to_vec();
+
+        let keep = ReformatOptions {
+            full: false,
+            scan: false,
+            keep_label: true,
+            keep_serial: true,
+            keep_bootcode: true,
+        };
+        let mut kept = image.clone();
+        assert_eq!(reformat(&info, &mut kept, &keep).unwrap().volume_id, Some(0x1234_ABCD));
+        assert_eq!(kept.get_ref()[..512], before[..]);
+        assert_eq!(kept.get_ref()[root..root + DIR_ENTRY_SIZE], root_label[..]);
+
+        let fresh = ReformatOptions { keep_label: false, keep_serial: false, keep_bootcode: false, ..keep };
+        let volume_id = reformat(&info, &mut image, &fresh).unwrap().volume_id;
+        let info = read_disk_info(&mut image).unwrap();
+        assert_ne!(volume_id, Some(0x1234_ABCD));
+        assert_eq!(Some(info.volume_id), volume_id);
+        assert_eq!(&info.volume_label, b"NO NAME    ");
+        assert_eq!(&image.get_ref()[OS_NAME..OS_NAME + OS_NAME_SIZE], b"MSDOS5.0");
+        assert_eq!(image.get_ref()[0x100], 0);
+        assert!(image.get_ref()[root..root + DIR_ENTRY_SIZE].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn redacting_reaches_subdirectories_and_slack() {
+        let (info, mut image) = blank();
+        mkdir(&info, &mut image, "/SUB").unwrap();
+        fill_free(&info, &mut image).unwrap();
+        put(&info, &mut image, &host_file("redact", "KEEP.TXT", b"kept"), "/SUB").unwrap();
+        put(&info, &mut image, &host_file("redact", "GONE.TXT", b"gone"), "/SUB").unwrap();
+        rm(&info, &mut image, "/SUB/GONE.TXT").unwrap();
+        let options = RedactOptions {
+            wipe_labels: false,
+            zero_timestamps: true,
+            strip_deleted: true,
+            zero_slack: true,
+        };
+        assert_eq!(redact(&info, &mut image, &options).unwrap(),
+                   vec!["timestamps reset on 4 entries",
+                        "1 deleted entries scrubbed",
+                        "508 bytes of slack zeroed in 1 files"]);
+        assert_eq!(redact(&info, &mut image, &options).unwrap(), vec!["timestamps reset on 4 entries"]);
+
+        let (directory, slot, entry) = find_slot(&info, &mut image, "/SUB/KEEP.TXT").unwrap().unwrap();
+        assert_eq!((entry.last_write_date, entry.last_write_time), (DOS_EPOCH_DATE, DOS_EPOCH_TIME));
+        let slots = directory.read_slots(&mut image).unwrap();
+        assert!(slots[(slot + 1) * DIR_ENTRY_SIZE + 1..(slot + 2) * DIR_ENTRY_SIZE].iter().all(|&b| b == 0));
+        let start = cluster_start(&info, entry.flc) as usize;
+        let cluster = &image.get_ref()[start..start + cluster_size(&info) as usize];
+        assert_eq!(&cluster[..4], b"kept");
+        assert!(cluster[4..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn sectors_translate_to_cylinders_heads_and_sectors_and_back() {
+        let (mut info, _) = blank();
+        let chs = |cylinder: u32, head: u16, sector: u16| Chs { cylinder, head, sector };
+        // 18 sectors a track on each of two heads.
+        for (lba, at) in [(0, chs(0, 0, 1)), (17, chs(0, 0, 18)), (18, chs(0, 1, 1)), (36, chs(1, 0, 1)),
+                          (2879, chs(79, 1, 18))] {
+            assert_eq!(lba_to_chs(&info, lba), Some(at));
+            assert_eq!(chs_to_lba(&info, at), Some(lba));
+        }
+        assert_eq!(lba_to_chs(&info, 33).unwrap().to_string(), "0/1/16");
+        for bad in [chs(0, 0, 0), chs(0, 0, 19), chs(0, 2, 1), chs(u32::MAX, 1, 18)] {
+            assert_eq!(chs_to_lba(&info, bad), None);
+        }
+        info.sectors_per_track = 0;
+        assert_eq!(lba_to_chs(&info, 0), None);
+        assert_eq!(chs_to_lba(&info, chs(0, 0, 1)), None);
+    }
+
+    #[test]
+    fn boot_sectors_are_validated() {
+        let (_, image) = blank();
+        let good = image.get_ref()[..512].to_vec();
+        assert!(boot_sector_problems(&good).is_empty());
+        assert_eq!(boot_sector_problems(&good[..100]), vec!["only 100 bytes, not a 512-byte sector"]);
+        assert!(DiskInfo::parse(&good).is_ok());
+        let problems = |patches: &[(usize, u8)]| {
+            let mut sector = good.clone();
+            for &(offset, value) in patches {
+                sector[offset] = value;
+            }
+            boot_sector_problems(&sector)
+        };
+        assert_eq!(problems(&[(BOOT_SECTOR_SIGNATURE, 0)]),
+                   vec!["no 0x55AA signature at offset 510 (found 0x00AA)"]);
+        assert_eq!(problems(&[(0, 0x90)]), vec!["no jump instruction at the start (found 0x90)"]);
+        assert!(problems(&[(0, 0xE9)]).is_empty());
+        // 768 and 8192 bytes per sector.
+        assert_eq!(problems(&[(BYTES_PER_SECTOR + 1, 3)]), vec!["768 bytes per sector"]);
+        assert_eq!(problems(&[(BYTES_PER_SECTOR + 1, 0x20)]), vec!["8192 bytes per sector"]);
+        assert_eq!(problems(&[(SECTORS_PER_CLUSTER, 3)]), vec!["3 sectors per cluster"]);
+        assert_eq!(problems(&[(SECTORS_PER_CLUSTER, 0)]), vec!["0 sectors per cluster"]);
+        assert!(problems(&[(SECTORS_PER_CLUSTER, 128)]).is_empty());
+        assert_eq!(problems(&[(BYTES_PER_SECTOR + 1, 4), (SECTORS_PER_CLUSTER, 128)]),
+                   vec!["clusters of 131072 bytes"]);
+        assert!(problems(&[(MEDIA_DESCRIPTOR, 0xF8)]).is_empty());
+        assert_eq!(problems(&[(MEDIA_DESCRIPTOR, 0xF4)]), vec!["media descriptor 0xF4"]);
+
+        // Every problem is reported at once.
+        let mut zeroed = Cursor::new(vec![0; 512]);
+        match read_disk_info(&mut zeroed) {
+            Err(Error::InvalidBootSector(why)) => assert_eq!(why.split("; ").count(), 5),
+            other => panic!("expected InvalidBootSector, got {:?}", other.map(|_| ())),
+        }
+        match read_disk_info(&mut Cursor::new(vec![0xEB; 100])) {
+            Err(Error::InvalidBootSector(why)) => assert_eq!(why, "the image is shorter than a boot sector"),
+            other => panic!("expected InvalidBootSector, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn the_fat_type_follows_the_cluster_count() {
+        let (_, image) = blank();
+        let mut sector = image.get_ref()[..512].to_vec();
+        // The label says FAT12 throughout, and is ignored.
+        assert_eq!(&sector[FS_TYPE..FS_TYPE + FS_TYPE_SIZE], b"FAT12   ");
+        LittleEndian::write_u16(&mut sector[TOTAL_SECTORS..], 0);
+        // 33 sectors come before the data area, and clusters are a sector.
+        let types = [(4084, FatType::Fat12), (4085, FatType::Fat16), (65524, FatType::Fat16),
+                     (65525, FatType::Fat32)];
+        for &(clusters, fat_type) in &types {
+            LittleEndian::write_u32(&mut sector[FAT32_TOTAL_SECTORS..], 33 + clusters);
+            assert_eq!((clusters, DiskInfo::new(&sector).fat_type()), (clusters, fat_type));
+        }
+    }
+
+    #[test]
+    fn fat16_volumes_are_read_and_written() {
+        let mut image = Cursor::new(Vec::new());
+        format(&mut image, &geometry::for_size(16 << 20).unwrap()).unwrap();
+        let info = read_disk_info(&mut image).unwrap();
+        assert_eq!(info.fat_type(), FatType::Fat16);
+        let data: Vec<u8> = (0..3 * cluster_size(&info) as usize).map(|n| n as u8).collect();
+        mkdir(&info, &mut image, "/DIR").unwrap();
+        put(&info, &mut image, &host_file("fat16", "DATA.BIN", &data), "/DIR").unwrap();
+
+        let (_, _, entry) = find_slot(&info, &mut image, "/DIR/DATA.BIN").unwrap().unwrap();
+        let fat = read_fat(&info, &mut image).unwrap();
+        // Entries are 16 bits, each the next cluster's number.
+        let at = entry.flc as usize * 2;
+        assert_eq!(LittleEndian::read_u16(&fat[at..]) as u32, entry.flc + 1);
+        assert_eq!(cluster_chain(&info, &fat[..], entry.flc), vec![entry.flc, entry.flc + 1, entry.flc + 2]);
+        assert_eq!(fat_entry(&info, &fat[..], entry.flc + 2), Some(0xFFFF));
+        let mut volume = Fat12Volume::open(image).unwrap();
+        assert_eq!(volume.read_file("/DIR/DATA.BIN").unwrap(), data);
+    }
+
+    /// The clusters `Fat::chain` gives from `first`, and where it says the
+    /// chain broke.
+    fn walk(fat: &Fat, first: u32) -> Vec<std::result::Result<u32, String>> {
+        fat.chain(first)
+            .map(|next| match next {
+                Ok(cluster) => Ok(cluster),
+                Err(Error::CorruptFatChain(at)) => Err(at),
+                Err(e) => panic!("expected CorruptFatChain, got {:?}", e),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chains_end_where_the_fat_is_damaged() {
+        let (info, mut image) = blank();
+        let mut bytes = read_fat(&info, &mut image).unwrap();
+        let limit = cluster_limit(&info);
+        let links = [(2, 3), (3, 4), (4, 0xFFF), (5, 6), (6, 5), (7, limit), (8, 0), (9, 0xFF7)];
+        for &(cluster, next) in &links {
+            set_fat_entry(&info, &mut bytes, cluster, next);
+        }
+        let fat = Fat::from_bytes(&info, bytes);
+        assert_eq!(walk(&fat, 2), vec![Ok(2), Ok(3), Ok(4)]);
+        assert_eq!(walk(&fat, 0), vec![]);
+        // A loop, a link out of the data area, a free entry and a bad one are
+        // each blamed on the cluster that led to them.
+        assert_eq!(walk(&fat, 5), vec![Ok(5), Ok(6), Err("cluster 6".to_string())]);
+        assert_eq!(walk(&fat, 7), vec![Ok(7), Err("cluster 7".to_string())]);
+        assert_eq!(walk(&fat, 8), vec![Ok(8), Err("cluster 8".to_string())]);
+        assert_eq!(walk(&fat, 9), vec![Ok(9), Err("cluster 9".to_string())]);
+        assert_eq!(walk(&fat, limit), vec![Err(format!("cluster {}", limit))]);
+        assert_eq!(fat.entry(limit * 2), None);
+    }
+
+    #[test]
+    fn growing_a_directory_with_no_chain_fails() {
+        let (_, mut image) = blank();
+        // Every sector there is: too many clusters for FAT16, so the volume
+        // reads as FAT32 with its root at cluster 0, which has no chain.
+        image.get_mut()[TOTAL_SECTORS..TOTAL_SECTORS + 2].copy_from_slice(&[0, 0]);
+        image.get_mut()[FAT32_TOTAL_SECTORS..FAT32_TOTAL_SECTORS + 4].copy_from_slice(&[0xFF; 4]);
+        let info = read_disk_info(&mut image).unwrap();
+        assert_eq!(info.fat_type(), FatType::Fat32);
+        match mkdir(&info, &mut image, "/NEW") {
+            Err(Error::CorruptFatChain(_)) => {}
+            other => panic!("expected CorruptFatChain, got {:?}", other.map(|_| ())),
+        }
+    }
+}
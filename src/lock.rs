@@ -0,0 +1,167 @@
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Read, Write};
+use std::ops::{Deref, DerefMut};
+use std::path::PathBuf;
+use std::process;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use {Warning, Warnings};
+
+/// An advisory lock on an image file, held until dropped.
+///
+/// Writers take an exclusive lock and leave a `<image>.lock` sidecar naming
+/// themselves, so a command that finds the image busy can report who holds it.
+/// The lock itself is released by the OS if the holder dies, which is how a
+/// leftover sidecar is recognised as stale.
+pub struct ImageLock {
+    sidecar: Option<PathBuf>,
+}
+impl Drop for ImageLock {
+    fn drop(&mut self) {
+        if let Some(ref sidecar) = self.sidecar {
+            let _ = fs::remove_file(sidecar);
+        }
+    }
+}
+
+fn sidecar_path(image_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.lock", image_path))
+}
+
+fn read_holder(sidecar: &PathBuf) -> Option<String> {
+    let mut holder = String::new();
+    File::open(sidecar).ok()?.read_to_string(&mut holder).ok()?;
+    Some(holder.trim().to_string())
+}
+
+fn busy(image_path: &str) -> io::Error {
+    let holder = read_holder(&sidecar_path(image_path)).unwrap_or_else(|| "another process".into());
+    io::Error::new(io::ErrorKind::WouldBlock,
+                   format!("{} is locked by {}", image_path, holder))
+}
+
+/// Takes a shared lock for a command that only reads the image.
+pub fn shared(file: &File, image_path: &str) -> io::Result<ImageLock> {
+    match file.try_lock_shared() {
+        Ok(()) => Ok(ImageLock { sidecar: None }),
+        Err(TryLockError::WouldBlock) => Err(busy(image_path)),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+/// Takes an exclusive lock for a command that modifies the image. A sidecar
+/// left by a holder that is gone is reported to `warnings` and replaced.
+pub fn exclusive(file: &File,
+                 image_path: &str,
+                 command: &str,
+                 warnings: &Warnings)
+                 -> io::Result<ImageLock> {
+    match file.try_lock() {
+        Ok(()) => (),
+        Err(TryLockError::WouldBlock) => return Err(busy(image_path)),
+        Err(TryLockError::Error(e)) => return Err(e),
+    }
+    let sidecar = sidecar_path(image_path);
+    if let Some(holder) = read_holder(&sidecar) {
+        warnings.warn(Warning::StaleLock(holder));
+    }
+    let mut marker = File::create(&sidecar)?;
+    writeln!(marker, "pid {} ({})", process::id(), command)?;
+    Ok(ImageLock { sidecar: Some(sidecar) })
+}
+
+/// An image shared by the threads of one process, such as a FUSE mount's and
+/// a command's: any number of them may read it at once, or one write to it.
+/// Each gets a handle of its own, locked as `shared` and `exclusive` lock
+/// it, so other processes are kept out as well.
+pub struct SharedImage {
+    path: String,
+    lock: RwLock<()>,
+}
+
+/// An open handle on a `SharedImage`, which keeps the threads' lock `G` on
+/// it until dropped.
+pub struct ImageHandle<G> {
+    file: File,
+    _lock: ImageLock,
+    _guard: G,
+}
+
+pub type ImageReader<'a> = ImageHandle<RwLockReadGuard<'a, ()>>;
+pub type ImageWriter<'a> = ImageHandle<RwLockWriteGuard<'a, ()>>;
+
+impl SharedImage {
+    pub fn new(image_path: &str) -> SharedImage {
+        SharedImage { path: image_path.to_string(), lock: RwLock::new(()) }
+    }
+
+    /// Opens the image for reading, first waiting for any thread writing it.
+    pub fn read(&self) -> io::Result<ImageReader<'_>> {
+        let guard = self.lock.read().unwrap_or_else(|e| e.into_inner());
+        let file = File::open(&self.path)?;
+        let lock = shared(&file, &self.path)?;
+        Ok(ImageHandle { file, _lock: lock, _guard: guard })
+    }
+
+    /// Opens the image for `command` to write, first waiting for every other
+    /// thread to be done with it.
+    pub fn write(&self, command: &str, warnings: &Warnings) -> io::Result<ImageWriter<'_>> {
+        let guard = self.lock.write().unwrap_or_else(|e| e.into_inner());
+        let file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        let lock = exclusive(&file, &self.path, command, warnings)?;
+        Ok(ImageHandle { file, _lock: lock, _guard: guard })
+    }
+}
+
+impl<G> Deref for ImageHandle<G> {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.file
+    }
+}
+impl<G> DerefMut for ImageHandle<G> {
+    fn deref_mut(&mut self) -> &mut File {
+        &mut self.file
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn threads_share_reads_and_take_turns_writing() {
+        let path = env::temp_dir().join(format!("fat12-lock-{}.img", process::id()));
+        fs::write(&path, b"image").unwrap();
+        let path = path.to_string_lossy().into_owned();
+        let image = SharedImage::new(&path);
+        let warnings = Warnings::default();
+        // A sidecar with no lock behind it is stale.
+        fs::write(sidecar_path(&path), "pid 1 (put)").unwrap();
+
+        let (first, second) = (image.read().unwrap(), image.read().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut writer = image.write("put", &warnings).unwrap();
+                writer.write_all(b"IMAGE").unwrap();
+                sender.send(read_holder(&sidecar_path(&path))).unwrap();
+            });
+            // The writer waits for both readers.
+            assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+            drop((first, second));
+            let holder = receiver.recv().unwrap().unwrap();
+            assert!(holder.starts_with(&format!("pid {} ", process::id())));
+        });
+        assert_eq!(warnings.take(), vec![Warning::StaleLock("pid 1 (put)".to_string())]);
+        assert!(!sidecar_path(&path).exists());
+        let mut contents = String::new();
+        image.read().unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "IMAGE");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,201 +1,3044 @@
-extern crate byteorder;
 extern crate chrono;
+extern crate fat12;
+#[cfg(feature = "tui")]
+extern crate ratatui;
+#[macro_use]
+extern crate serde_json;
 
+mod color;
+mod completion;
+mod dates;
+mod progress;
+#[cfg(feature = "tui")]
+mod tui;
+
+use std::collections::{BTreeMap, HashSet};
 use std::env;
+use std::process;
+use std::sync::OnceLock;
 use std::io::prelude::*;
+use std::io::IsTerminal;
 use std::io::SeekFrom;
-use std::fs::File;
-use byteorder::{LittleEndian, ByteOrder};
+use std::path::{Path, PathBuf};
+use std::fs::{self, File, OpenOptions};
 use chrono::*;
+use fat12::*;
+
+/// How `list` should format its output.
+struct ListOptions {
+    identify: bool,
+    human: bool,
+    bare: bool,
+    /// Whether hidden and system entries are listed too.
+    all: bool,
+    color: bool,
+    /// Whether to show where each entry and its clusters are in the image.
+    offsets: bool,
+    date_format: String,
+    /// Notes for this image's files, by path, when listing `--annotations`.
+    annotations: Option<BTreeMap<String, annotations::Annotation>>,
+    /// The ddrescue map of the image, to note which entries have sectors
+    /// that weren't recovered.
+    map: Option<rescue::Rescue>,
+    /// Print the entries as a JSON array instead, with every field.
+    json: bool,
+    /// Whether every directory below is listed too.
+    recursive: bool,
+    /// Whether entries are named by their whole path in the image, short
+    /// names as the other commands take them, in one table for all the
+    /// directories listed. Long names aren't shown then.
+    full_paths: bool,
+}
+
+/// One line of `list`, before the columns are lined up.
+struct Row {
+    kind: char,
+    size: String,
+    name: String,
+    date: String,
+    file_type: &'static str,
+    offsets: String,
+    long_name: String,
+    note: String,
+    style: color::Style,
+}
+
+/// How `list` colors `entry`. It is damaged if its first cluster is outside
+/// the data area, or if it is a file whose chain doesn't match its size.
+fn list_style<F: FatTable + ?Sized>(info: &DiskInfo, fat: &F, entry: &DirEntry) -> color::Style {
+    let is_dir = (entry.attributes & DirEntryAttributes::SubDir as u8) != 0;
+    let damaged = if entry.flc == 0 {
+        // Empty files have no clusters, and ".." has 0 for the root.
+        if is_dir { entry.name() != ".." } else { entry.file_size > 0 }
+    } else if entry.flc < 2 || entry.flc >= cluster_limit(info) {
+        true
+    } else {
+        !is_dir && cluster_chain(info, fat, entry.flc).len() as u64 !=
+                   (entry.file_size as u64).div_ceil(cluster_size(info))
+    };
+    let hidden = DirEntryAttributes::Hidden as u8 | DirEntryAttributes::System as u8;
+    if damaged {
+        color::Style::Damaged
+    } else if is_dir {
+        color::Style::Directory
+    } else if ["EXE", "COM", "BAT"].iter().any(|ext| entry.file_ext.starts_with(ext.as_bytes())) {
+        color::Style::Executable
+    } else if entry.attributes & hidden != 0 {
+        color::Style::Hidden
+    } else {
+        color::Style::Plain
+    }
+}
+
+/// The byte ranges of the image that the clusters of `chain` take, with
+/// consecutive clusters merged, as `START-END` in hex, `END` included.
+fn cluster_extents(info: &DiskInfo, chain: &[u32]) -> String {
+    let mut runs: Vec<(u64, u64)> = Vec::new();
+    for &cluster in chain {
+        let start = cluster_start(info, cluster);
+        match runs.last_mut() {
+            Some(run) if run.1 == start => run.1 += cluster_size(info),
+            _ => runs.push((start, start + cluster_size(info))),
+        }
+    }
+    if runs.is_empty() {
+        return "-".to_string();
+    }
+    runs.iter().map(|&(start, end)| format!("0x{:X}-0x{:X}", start, end - 1)).collect::<Vec<_>>().join(",")
+}
+
+/// `text` colored as `style`, padded with spaces to `width` characters.
+fn padded(text: &str, width: usize, style: color::Style, color: bool) -> String {
+    let padding = " ".repeat(width.saturating_sub(text.chars().count()));
+    if color {
+        color::paint(text, style) + &padding
+    } else {
+        text.to_string() + &padding
+    }
+}
+
+/// Formats a byte count the way `ls -h` does: 156, 1.4K, 23K, 1.2M.
+fn human_size(size: u64) -> String {
+    let mut value = size as f64;
+    for unit in ["", "K", "M", "G"] {
+        if value < 1024.0 || unit == "G" {
+            return if unit.is_empty() {
+                size.to_string()
+            } else if value < 10.0 {
+                format!("{:.1}{}", value, unit)
+            } else {
+                format!("{:.0}{}", value, unit)
+            };
+        }
+        value /= 1024.0;
+    }
+    unreachable!()
+}
+
+/// Lists the entries of `directories`, each given with its path, as one
+/// table.
+fn list_dir<S: Source>(info: &DiskInfo,
+                       disk_file: &mut S,
+                       directories: &[(String, Directory)],
+                       options: &ListOptions)
+                       -> Result<()> {
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    // The FAT is only needed to tell damaged entries apart by color and to
+    // find their clusters.
+    let fat = if options.color || options.offsets || options.map.is_some() {
+        Some(fat_of(info, disk_file)?)
+    } else {
+        None
+    };
+    let rights_in_use = drdos::rights_in_use(info, disk_file)?;
+    let mut rows = Vec::new();
+    let mut values = Vec::new();
+    for (path, directory) in directories {
+        for (slot, entry) in directory.entries(disk_file)? {
+            let skipped = if options.all { DirEntryAttributes::VolumeLabel as u8 } else { 0x0F };
+            if entry.is_lfn() || (entry.attributes & skipped) != 0 {
+                continue;
+            }
+            let entry_path = format!("{}/{}", path, entry.name());
+            if options.full_paths && (entry.name() == "." || entry.name() == "..") {
+                continue;
+            }
+            let unrecovered = match (&options.map, &fat) {
+                (Some(map), Some(fat)) if entry.name() != "." && entry.name() != ".." => {
+                    map.unrecovered(info, &**fat, &entry)
+                }
+                _ => Vec::new(),
+            };
+            let protection = drdos::Protection::of(&entry, rights_in_use);
+            if options.json {
+                let mut value = json::dir_entry(&entry);
+                if let Some(protection) = protection {
+                    value["created"] = serde_json::Value::Null;
+                    value["password"] = protection.modes().into();
+                }
+                value["slot"] = slot.into();
+                value["slot_offset"] = directory.slot_offset(slot).unwrap().into();
+                if options.full_paths {
+                    value["path"] = entry_path.into();
+                }
+                if options.map.is_some() {
+                    value["unrecovered_sectors"] = unrecovered.into();
+                }
+                values.push(value);
+                continue;
+            }
+            let name = if options.full_paths { entry_path.clone() } else { entry.name() };
+            let style = match fat {
+                Some(ref fat) if options.color => list_style(info, fat, &entry),
+                _ => color::Style::Plain,
+            };
+            if options.bare {
+                writeln!(out, "{}", padded(&name, 0, style, options.color))?;
+                continue;
+            }
+            let is_dir = (entry.attributes & DirEntryAttributes::SubDir as u8) != 0;
+            let size = if options.human {
+                human_size(entry.file_size as u64)
+            } else {
+                entry.file_size.to_string()
+            };
+            // Zeroed or garbage dates show as `-` rather than a made-up time,
+            // as do those whose time is a password's hash.
+            let date = dos_datetime(entry.create_date, entry.create_time)
+                .filter(|_| protection.is_none())
+                .map_or("-".to_string(), |t| t.format(&options.date_format).to_string());
+            let kind = if options.identify && !is_dir {
+                let head = read_file_head(info, disk_file, &entry, 512)?;
+                identify::identify(&head, &entry.name())
+            } else {
+                ""
+            };
+            let mut notes: Vec<String> = options.annotations
+                .as_ref()
+                .and_then(|notes| notes.get(&annotations::normalize_path(&entry_path)))
+                .map(|note| note.summary())
+                .into_iter()
+                .collect();
+            if let Some(protection) = protection {
+                notes.push(format!("password: {}", protection.modes()));
+            }
+            if !unrecovered.is_empty() {
+                let noun = if unrecovered.len() == 1 { "sector" } else { "sectors" };
+                notes.push(format!("unrecovered {} {}", noun, rescue::sector_ranges(&unrecovered)));
+            }
+            let note = if notes.is_empty() { String::new() } else { format!("# {}", notes.join("; ")) };
+            // The long name goes last, as in `dir /x`: paths on the image are
+            // still given by the short name, which keeps its column.
+            rows.push(Row {
+                kind: if is_dir { 'd' } else { 'f' },
+                size,
+                name,
+                date,
+                file_type: kind,
+                offsets: match fat {
+                    Some(ref fat) if options.offsets => {
+                        format!("@0x{:X} {}",
+                                directory.slot_offset(slot).unwrap(),
+                                cluster_extents(info, &cluster_chain(info, fat, entry.flc)))
+                    }
+                    _ => String::new(),
+                },
+                long_name: entry.long_name.clone().unwrap_or_default(),
+                note,
+                style,
+            });
+        }
+    }
+    if options.json {
+        writeln!(out, "{}", serde_json::Value::Array(values))?;
+        return Ok(());
+    }
+    let size_width = rows.iter().map(|r| r.size.len()).max().unwrap_or(0);
+    let name_width = rows.iter().map(|r| r.name.len()).max().unwrap_or(0);
+    let date_width = rows.iter().map(|r| r.date.len()).max().unwrap_or(0);
+    let type_width = rows.iter().map(|r| r.file_type.len()).max().unwrap_or(0);
+    let offsets_width = rows.iter().map(|r| r.offsets.len()).max().unwrap_or(0);
+    let long_width = rows.iter().map(|r| r.long_name.chars().count()).max().unwrap_or(0);
+    for row in rows {
+        // A whole path goes last, where its length doesn't push the other
+        // columns out.
+        let name = if options.full_paths {
+            String::new()
+        } else {
+            format!(" {}", padded(&row.name, name_width, row.style, options.color))
+        };
+        let mut line = format!("{} {:>size_width$}{} {:date_width$}",
+                               row.kind,
+                               row.size,
+                               name,
+                               row.date,
+                               size_width = size_width,
+                               date_width = date_width);
+        if options.identify {
+            line.push_str(&format!(" {:type_width$}", row.file_type, type_width = type_width));
+        }
+        if options.offsets {
+            line.push_str(&format!(" {:offsets_width$}", row.offsets, offsets_width = offsets_width));
+        }
+        if options.full_paths {
+            line.push(' ');
+            line.push_str(&padded(&row.name, 0, row.style, options.color));
+        } else if long_width > 0 {
+            line.push(' ');
+            line.push_str(&padded(&row.long_name, long_width, row.style, options.color && !row.long_name.is_empty()));
+        }
+        if !row.note.is_empty() {
+            line.push(' ');
+            line.push_str(&row.note);
+        }
+        writeln!(out, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
+/// Prints the root directory entries whose names start with `prefix`, one per
+/// line, for use by the shell completion scripts.
+fn complete_rootdir<S: Source>(info: &DiskInfo,
+                               disk_file: &mut S,
+                               prefix: &str)
+                               -> Result<()> {
+    let (lead, prefix) = if let Some(rest) = prefix.strip_prefix('/') {
+        ("/", rest)
+    } else {
+        ("", prefix)
+    };
+    let prefix = prefix.to_uppercase();
+    for (_, entry) in Directory::root(info, &*fat_of(info, disk_file)?).entries(disk_file)? {
+        if (entry.attributes & DirEntryAttributes::VolumeLabel as u8) != 0 {
+            continue;
+        }
+        let name = entry.name();
+        if name.starts_with(&prefix) {
+            println!("{}{}", lead, name);
+        }
+    }
+    Ok(())
+}
+
+/// Prints the MZ header details of `name`, or of every .EXE and .COM file in
+/// the root directory when no name is given.
+fn print_exeinfo<S: Source>(info: &DiskInfo,
+                            disk_file: &mut S,
+                            name: Option<&str>)
+                            -> Result<()> {
+    for (_, entry) in Directory::root(info, &*fat_of(info, disk_file)?).entries(disk_file)? {
+        let entry_name = entry.name();
+        let wanted = match name {
+            Some(name) => entry_name.eq_ignore_ascii_case(name.trim_start_matches('/')),
+            None => entry_name.ends_with(".EXE") || entry_name.ends_with(".COM"),
+        };
+        if !wanted || (entry.attributes & DirEntryAttributes::SubDir as u8) != 0 {
+            continue;
+        }
+        let head = read_file_head(info, disk_file, &entry, 512)?;
+        let header = match exeinfo::MzHeader::new(&head) {
+            Some(header) => header,
+            None => {
+                if entry_name.ends_with(".COM") {
+                    println!("{}: COM executable, {} bytes{}",
+                             entry_name,
+                             entry.file_size,
+                             if entry.file_size > 0xFF00 { " (too large to load)" } else { "" });
+                } else {
+                    println!("{}: not an MZ executable", entry_name);
+                }
+                continue;
+            }
+        };
+        println!("{}: MZ executable", entry_name);
+        println!("  file size:   {}", entry.file_size);
+        println!("  load image:  {} bytes ({} byte header)",
+                 header.image_size(),
+                 header.header_size());
+        if entry.file_size > header.image_size() {
+            println!("  overlay:     {} bytes", entry.file_size - header.image_size());
+        }
+        println!("  relocations: {} (table at 0x{:X})",
+                 header.relocations,
+                 header.reloc_offset);
+        println!("  entry point: {:04X}:{:04X} (file offset 0x{:X})",
+                 header.cs,
+                 header.ip,
+                 header.entry_offset());
+        println!("  stack:       {:04X}:{:04X}", header.ss, header.sp);
+        println!("  memory:      min {} / max {} paragraphs",
+                 header.min_alloc,
+                 header.max_alloc);
+        println!("  checksum:    0x{:04X}", header.checksum);
+        if header.overlay_number != 0 {
+            println!("  overlay no.: {}", header.overlay_number);
+        }
+        if let Some(packer) = exeinfo::packer(&head) {
+            println!("  packer:      {}", packer);
+        }
+    }
+    Ok(())
+}
+
+/// Prints the OS/2 extended attributes of the file or directory at `path`,
+/// or of every one that has any: each EA's name, and its value as text when
+/// it is text and as bytes otherwise. Returns false if there is nothing at
+/// `path`.
+fn print_eas<S: Source>(info: &DiskInfo, disk_file: &mut S, path: Option<&str>) -> Result<bool> {
+    let handle = match path {
+        Some(path) => match find_path(info, disk_file, path)? {
+            Some(entry) => Some(entry.ea_handle),
+            None => {
+                eprintln!("fat12: {}: no such file or directory", path);
+                return Ok(false);
+            }
+        },
+        None => None,
+    };
+    let sets = ea::list(info, disk_file)?;
+    for (path, _, set) in sets.into_iter().filter(|found| handle.is_none_or(|handle| handle == found.1)) {
+        println!("{}", path);
+        for ea in set.eas {
+            let value = match ea.text() {
+                Some(text) => format!("{:?}", text),
+                None => {
+                    let bytes: Vec<String> = ea.value.iter().take(16).map(|b| format!("{:02X}", b)).collect();
+                    let more = if ea.value.len() > 16 { " ..." } else { "" };
+                    format!("{} bytes: {}{}", ea.value.len(), bytes.join(" "), more)
+                }
+            };
+            println!("  {:<16} {}{}", ea.name, value, if ea.critical { " (critical)" } else { "" });
+        }
+    }
+    Ok(true)
+}
+
+/// Looks up the file at `path` for reading its contents, reporting on stderr
+/// when there is none or it's a directory.
+fn find_file<S: Source>(info: &DiskInfo,
+                        disk_file: &mut S,
+                        path: &str)
+                        -> Result<Option<DirEntry>> {
+    match find_path(info, disk_file, path)? {
+        None => eprintln!("fat12: {}: no such file", path),
+        Some(ref entry) if (entry.attributes & DirEntryAttributes::SubDir as u8) != 0 => {
+            eprintln!("fat12: {}: is a directory", path)
+        }
+        Some(entry) => return Ok(Some(entry)),
+    }
+    Ok(None)
+}
+
+/// Writes the contents of the file at `path` to stdout. Returns false when
+/// there is no such file or it is cut short by a broken chain.
+fn cat<S: Source>(info: &DiskInfo, disk_file: &mut S, path: &str) -> Result<bool> {
+    let entry = match find_file(info, disk_file, path)? {
+        Some(entry) => entry,
+        None => return Ok(false),
+    };
+    let fat = fat_of(info, disk_file)?;
+    let stdout = std::io::stdout();
+    let written = copy_file(info, disk_file, &fat, &entry, &mut stdout.lock())?;
+    if written < entry.file_size as u64 {
+        eprintln!("fat12: {}: cluster chain ends after {} of {} bytes",
+                  path,
+                  written,
+                  entry.file_size);
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Shows the file at `path` as text, with code page 437 glyphs and DOS line
+/// ends undone, or as a hex dump if it looks binary or `hex` says so. On a
+/// terminal the output goes through `$PAGER`, `less` by default, unless
+/// `page` is false or the pager can't be run. Returns false as `cat` does.
+fn view<S: Source>(info: &DiskInfo,
+                   disk_file: &mut S,
+                   path: &str,
+                   hex: Option<bool>,
+                   page: bool)
+                   -> Result<bool> {
+    let entry = match find_file(info, disk_file, path)? {
+        Some(entry) => entry,
+        None => return Ok(false),
+    };
+    let fat = fat_of(info, disk_file)?;
+    let mut data = Vec::with_capacity(entry.file_size as usize);
+    let written = copy_file(info, disk_file, &fat, &entry, &mut data)?;
+    let mut shown = Vec::new();
+    if hex.unwrap_or_else(|| !view::is_text(&data)) {
+        view::hex_dump(&data, &mut shown)?;
+    } else {
+        shown = view::decode_text(&data).into_bytes();
+        if !shown.is_empty() && !shown.ends_with(b"\n") {
+            shown.push(b'\n');
+        }
+    }
+    if !(page && std::io::stdout().is_terminal() && pipe_to_pager(&shown)) {
+        std::io::stdout().write_all(&shown)?;
+    }
+    if written < entry.file_size as u64 {
+        eprintln!("fat12: {}: cluster chain ends after {} of {} bytes",
+                  path,
+                  written,
+                  entry.file_size);
+        return Ok(false);
+    }
+    Ok(true)
+}
 
-const OS_NAME: usize = 3;
-const OS_NAME_SIZE: usize = 8;
-const BYTES_PER_SECTOR: usize = 11;
-const SECTORS_PER_CLUSTER: usize = 13;
-const RESERVED_SECTORS: usize = 14;
-const FATS: usize = 16;
-const ROOT_DIR_ENTRIES: usize = 17;
-const TOTAL_SECTORS: usize = 19;
-const SECTORS_PER_FAT: usize = 22;
-const SECTORS_PER_TRACK: usize = 24;
-const HEADS: usize = 26;
-const FAT32_TOTAL_SECTORS: usize = 32;
-const BOOT_SIGNATURE: usize = 38;
-const VOLUME_ID: usize = 39;
-const VOLUME_LABEL: usize = 43;
-const VOLUME_LABEL_SIZE: usize = 11;
-const FS_TYPE: usize = 54;
-const FS_TYPE_SIZE: usize = 54;
-
-struct DiskInfo {
-    os_name: [u8; 8],
-    bytes_per_sector: u16,
-    sectors_per_cluster: u8,
-    reserved_sectors: u16,
-    fats: u8,
-    root_dir_entries: u16,
-    total_sectors: u16,
-    sectors_per_fat: u16,
-    sectors_per_track: u16,
-    heads: u16,
-    boot_signature: u8,
-    volume_id: u32,
-    volume_label: [u8; 11],
-    fs_type: [u8; 54],
-}
-impl DiskInfo {
-    fn new(buf: &[u8]) -> Self {
-        DiskInfo {
-            os_name: {
-                let mut name = [0; OS_NAME_SIZE];
-                name.copy_from_slice(&buf[OS_NAME..OS_NAME + OS_NAME_SIZE]);
-                name
-            },
-            bytes_per_sector: LittleEndian::read_u16(&buf[BYTES_PER_SECTOR..]),
-            sectors_per_cluster: buf[SECTORS_PER_CLUSTER],
-            reserved_sectors: LittleEndian::read_u16(&buf[RESERVED_SECTORS..]),
-            fats: buf[FATS],
-            root_dir_entries: LittleEndian::read_u16(&buf[ROOT_DIR_ENTRIES..]),
-            total_sectors: LittleEndian::read_u16(&buf[TOTAL_SECTORS..]),
-            sectors_per_fat: LittleEndian::read_u16(&buf[SECTORS_PER_FAT..]),
-            sectors_per_track: LittleEndian::read_u16(&buf[SECTORS_PER_TRACK..]),
-            heads: LittleEndian::read_u16(&buf[HEADS..]),
-            boot_signature: buf[BOOT_SIGNATURE],
-            volume_id: LittleEndian::read_u32(&buf[VOLUME_ID..]),
-            volume_label: {
-                let mut label = [0; VOLUME_LABEL_SIZE];
-                label.copy_from_slice(&buf[VOLUME_LABEL..VOLUME_LABEL + VOLUME_LABEL_SIZE]);
-                label
-            },
-            fs_type: {
-                let mut ft = [0; FS_TYPE_SIZE];
-                ft.copy_from_slice(&buf[FS_TYPE..FS_TYPE + FS_TYPE_SIZE]);
-                ft
-            },
-        }
-    }
-}
-
-fn read_disk_info(disk_file: &mut File) -> Result<DiskInfo, std::io::Error> {
-    let mut buf = [0u8; 512];
-    try!(disk_file.read_exact(&mut buf));
-    Ok(DiskInfo::new(&buf))
-}
-
-const DIR_ENTRY_SIZE: usize = 32;
-const DIR_ENTRY_NAME_SIZE: usize = 8;
-const DIR_ENTRY_EXT: usize = 8;
-const DIR_ENTRY_EXT_SIZE: usize = 3;
-const DIR_ENTRY_ATTRS: usize = 11;
-const DIR_ENTRY_RESERVED: usize = 12;
-const DIR_ENTRY_CREATETIME: usize = 14;
-const DIR_ENTRY_CREATEDATE: usize = 16;
-const DIR_ENTRY_LASTACCESS: usize = 18;
-const DIR_ENTRY_WRITETIME: usize = 22;
-const DIR_ENTRY_WRITEDATE: usize = 24;
-const DIR_ENTRY_FLC: usize = 26;
-const DIR_ENTRY_FILESIZE: usize = 28;
-
-enum DirEntryAttributes {
-    ReadOnly = 0x01,
-    Hidden = 0x02,
-    System = 0x04,
-    VolumeLabel = 0x08,
-    SubDir = 0x10,
-    Archive = 0x20,
-}
-
-struct DirEntry {
-    file_name: [u8; DIR_ENTRY_NAME_SIZE],
-    file_ext: [u8; DIR_ENTRY_EXT_SIZE],
-    attributes: u8,
-    reserved: u16,
-    create_time: u16,
-    create_date: u16,
-    last_access_date: u16,
-    last_write_time: u16,
-    last_write_date: u16,
-    flc: u16,
-    file_size: u32,
-}
-impl DirEntry {
-    fn new(buf: &[u8]) -> Self {
-        DirEntry {
-            file_name: {
-                let mut name = [' ' as u8; DIR_ENTRY_NAME_SIZE];
-                name.copy_from_slice(&buf[0..DIR_ENTRY_NAME_SIZE]);
-                name
-            },
-            file_ext: {
-                let mut ext = [' ' as u8; DIR_ENTRY_EXT_SIZE];
-                ext.copy_from_slice(&buf[DIR_ENTRY_EXT..DIR_ENTRY_EXT + DIR_ENTRY_EXT_SIZE]);
-                ext
-            },
-            attributes: buf[DIR_ENTRY_ATTRS],
-            reserved: LittleEndian::read_u16(&buf[DIR_ENTRY_RESERVED..]),
-            create_time: LittleEndian::read_u16(&buf[DIR_ENTRY_CREATETIME..]),
-            create_date: LittleEndian::read_u16(&buf[DIR_ENTRY_CREATEDATE..]),
-            last_access_date: LittleEndian::read_u16(&buf[DIR_ENTRY_LASTACCESS..]),
-            last_write_time: LittleEndian::read_u16(&buf[DIR_ENTRY_WRITETIME..]),
-            last_write_date: LittleEndian::read_u16(&buf[DIR_ENTRY_WRITETIME..]),
-            flc: LittleEndian::read_u16(&buf[DIR_ENTRY_FLC..]),
-            file_size: LittleEndian::read_u32(&buf[DIR_ENTRY_FILESIZE..]),
-        }
-    }
-}
-
-fn list_rootdir(info: &DiskInfo, disk_file: &mut File) -> Result<(), std::io::Error> {
-    let root_dir_start =
-        (info.bytes_per_sector * (info.fats as u16 * info.sectors_per_fat + 1)) as u64;
-    disk_file.seek(SeekFrom::Start(root_dir_start))?;
-    let mut entry_buf = [0; DIR_ENTRY_SIZE];
-    for _ in 0..info.root_dir_entries {
-        disk_file.read_exact(&mut entry_buf)?;
-        if LittleEndian::read_u16(&entry_buf) & 0xFFF0 == 0 {
-            break;
-        }
-
-        let entry = DirEntry::new(&entry_buf);
-        if (entry.attributes & 0x0F) != 0 {
+/// Feeds `shown` to the pager. False if the pager couldn't be started.
+fn pipe_to_pager(shown: &[u8]) -> bool {
+    let pager = env::var("PAGER").unwrap_or_else(|_| "less".to_string());
+    let mut words = pager.split_whitespace();
+    let program = match words.next() {
+        Some(program) => program,
+        None => return false,
+    };
+    let mut child = match process::Command::new(program).args(words).stdin(process::Stdio::piped()).spawn() {
+        Ok(child) => child,
+        Err(_) => return false,
+    };
+    // A pager quit early closes the pipe, which is no error here.
+    let _ = child.stdin.take().unwrap().write_all(shown);
+    let _ = child.wait();
+    true
+}
+
+/// A DOS timestamp, which is in local time, as a host file time.
+fn host_time(datetime: NaiveDateTime) -> Option<std::time::SystemTime> {
+    Local.from_local_datetime(&datetime).earliest().map(|t| t.into())
+}
+
+/// Copies a file's contents to `host_path`, opened with `create`, and gives
+/// the copy the entry's write and access times, or those in `times` where it
+/// has them. Returns false if the chain was cut short.
+#[allow(clippy::too_many_arguments)]
+fn write_file<S: Source>(info: &DiskInfo,
+                         disk_file: &mut S,
+                         fat: &dyn FatTable,
+                         entry: &DirEntry,
+                         path: &str,
+                         host_path: &Path,
+                         create: fn(&Path) -> std::io::Result<File>,
+                         given: &Times)
+                         -> Result<bool> {
+    let mut out = create(host_path)
+        .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", host_path.display(), e)))?;
+    let written = copy_file(info, disk_file, fat, entry, &mut out)?;
+    let mut times = fs::FileTimes::new();
+    let modified = given.modified.or_else(|| dos_datetime(entry.last_write_date, entry.last_write_time));
+    if let Some(modified) = modified.and_then(host_time) {
+        times = times.set_modified(modified);
+    }
+    // Only the date of the last access is recorded.
+    let accessed = given.accessed
+        .or_else(|| dos_date(entry.last_access_date).and_then(|date| date.and_hms_opt(0, 0, 0)));
+    if let Some(accessed) = accessed.and_then(host_time) {
+        times = times.set_accessed(accessed);
+    }
+    out.set_times(times)?;
+    if written < entry.file_size as u64 {
+        eprintln!("fat12: {}: cluster chain ends after {} of {} bytes",
+                  path,
+                  written,
+                  entry.file_size);
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Copies everything in `directory` into `host_dir`, which must exist.
+/// `seen` holds the first clusters of the directories being copied, so
+/// a directory that links back to one of them isn't followed forever.
+#[allow(clippy::too_many_arguments)]
+fn extract_dir<S: Source>(info: &DiskInfo,
+                          disk_file: &mut S,
+                          fat: &dyn FatTable,
+                          directory: &Directory,
+                          path: &str,
+                          host_dir: &Path,
+                          placer: &mut extract::Placer,
+                          seen: &mut HashSet<u32>,
+                          times: &Times)
+                          -> Result<bool> {
+    let mut ok = true;
+    for (_, entry) in directory.entries(disk_file)? {
+        let name = entry.name();
+        let is_lfn = entry.attributes & 0x0F == 0x0F;
+        if is_lfn || (entry.attributes & DirEntryAttributes::VolumeLabel as u8) != 0 || name == "." ||
+           name == ".." {
             continue;
         }
-        let is_dir = (entry.attributes | DirEntryAttributes::SubDir as u8) == 0;
-        print!("{} {} {}",
-               if is_dir { 'd' } else { 'f' },
-               entry.file_size,
-               std::str::from_utf8(&entry.file_name).unwrap().trim());
-        if !is_dir {
-            print!(".{}", std::str::from_utf8(&entry.file_ext).unwrap().trim());
+        let path = format!("{}/{}", path, name);
+        let host_path = match placer.place(&host_dir.join(extract::host_name(&name)))? {
+            Some(host_path) => host_path,
+            None => {
+                eprintln!("fat12: {}: skipped, its host name is taken", path);
+                continue;
+            }
+        };
+        if (entry.attributes & DirEntryAttributes::SubDir as u8) == 0 {
+            ok &= write_file(info, disk_file, fat, &entry, &path, &host_path, extract::create_file, times)?;
+        } else if entry.flc < 2 || entry.flc >= cluster_limit(info) {
+            return Err(Error::InvalidDirEntry(path));
+        } else if seen.insert(entry.flc) {
+            extract::create_dir(&host_path)?;
+            let directory = Directory::chain(info, fat, entry.flc);
+            ok &= extract_dir(info, disk_file, fat, &directory, &path, &host_path, placer, seen, times)?;
+            seen.remove(&entry.flc);
+        } else {
+            eprintln!("fat12: {}: skipped, it links back to a directory above it", path);
+            ok = false;
+        }
+    }
+    Ok(ok)
+}
+
+/// Copies the file at `path` out of the image to `host_path`, or into it if it
+/// is a directory. A directory in the image, or the root, is copied with
+/// everything in it. Host names that are taken, or differ from another only
+/// in case, are dealt with by `placer`'s policy.
+fn extract<S: Source>(info: &DiskInfo,
+                      disk_file: &mut S,
+                      path: &str,
+                      host_path: &Path,
+                      placer: &mut extract::Placer,
+                      times: &Times)
+                      -> Result<bool> {
+    let fat = fat_of(info, disk_file)?;
+    let entry = find_path(info, disk_file, path)?;
+    let is_root = path.trim_matches('/').is_empty();
+    let entry = match entry {
+        None if is_root => None,
+        None => {
+            eprintln!("fat12: {}: no such file", path);
+            return Ok(false);
+        }
+        Some(entry) => Some(entry),
+    };
+    let target = match entry {
+        Some(ref entry) if host_path.is_dir() => host_path.join(extract::host_name(&entry.name())),
+        _ => host_path.to_path_buf(),
+    };
+    match entry {
+        Some(ref entry) if (entry.attributes & DirEntryAttributes::SubDir as u8) == 0 => {
+            match placer.place(&target)? {
+                // A target named by the caller is written through, even if
+                // it is a link like /dev/stdout.
+                Some(target) => {
+                    write_file(info, disk_file, &fat, entry, path, &target, |p| File::create(p), times)
+                }
+                None => {
+                    eprintln!("fat12: {}: skipped, {} already exists", path, target.display());
+                    Ok(true)
+                }
+            }
+        }
+        _ => {
+            let directory = Directory::open(info, disk_file, path)?.unwrap_or_else(|| Directory::root(info, &fat));
+            // The target was named by the caller, so it may be reached through
+            // links; only what comes from the image is created link-safely.
+            fs::create_dir_all(&target)?;
+            extract_dir(info,
+                        disk_file,
+                        &fat,
+                        &directory,
+                        path.trim_end_matches('/'),
+                        &target,
+                        placer,
+                        &mut HashSet::new(),
+                        times)
         }
-        println!(" {}", to_datetime(entry.create_date, entry.create_time));
+    }
+}
+
+/// Prints every entry in the volume below a `/` for the root, each indented
+/// under its directory, with its size and modification time. Directories
+/// that loop back on themselves are shown but not entered again.
+fn print_tree<S: Source>(info: &DiskInfo,
+                         disk_file: &mut S,
+                         deleted: bool,
+                         human: bool,
+                         json: bool)
+                         -> Result<()> {
+    let fat = fat_of(info, disk_file)?;
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    if json {
+        let entries = tree(info, disk_file, &*fat, deleted)?;
+        writeln!(out, "{}", serde_json::Value::Array(entries.iter().map(json::tree_entry).collect()))?;
+        return Ok(());
+    }
+    writeln!(out, "{:>10} {:19} /", "", "")?;
+    for_each_entry(info, disk_file, &*fat, deleted, |_, found| {
+        let entry = &found.entry;
+        let is_dir = (entry.attributes & DirEntryAttributes::SubDir as u8) != 0;
+        let size = if is_dir {
+            "-".to_string()
+        } else if human {
+            human_size(entry.file_size as u64)
+        } else {
+            entry.file_size.to_string()
+        };
+        let date = dos_datetime(entry.last_write_date, entry.last_write_time)
+            .map_or("-".to_string(), |t| t.format("%Y-%m-%d %H:%M:%S").to_string());
+        let depth = found.path.matches('/').count();
+        let name = found.path.rsplit('/').next().unwrap_or_default();
+        let mut line = format!("{:>10} {:19} {}{}{}",
+                               size,
+                               date,
+                               "  ".repeat(depth),
+                               name,
+                               if is_dir { "/" } else { "" });
+        if let Some(ref long_name) = entry.long_name {
+            line.push_str(&format!(" ({})", long_name));
+        }
+        if found.deleted {
+            line.push_str(" [deleted]");
+        }
+        writeln!(out, "{}", line)?;
+        Ok(())
+    })
+}
+
+/// Reports logical size, allocated size and slack for the file at `path`, or
+/// for every file and subtree below the directory there, as `disk_usage`
+/// counts them, and last for `path` itself.
+fn du<S: Source>(info: &DiskInfo, disk_file: &mut S, path: &str) -> Result<bool> {
+    let fat = fat_of(info, disk_file)?;
+    let usage = match disk_usage(info, disk_file, &*fat, path)? {
+        Some(usage) => usage,
+        None => {
+            eprintln!("fat12: {}: no such file", path);
+            return Ok(false);
+        }
+    };
+    println!("{:>10} {:>10} {:>10}  path", "logical", "allocated", "slack");
+    for usage in usage {
+        println!("{:>10} {:>10} {:>10}  {}",
+                 usage.logical,
+                 usage.allocated,
+                 usage.allocated - usage.logical,
+                 usage.path);
+    }
+    Ok(true)
+}
+
+/// Prints the volume's size, what is used and what is free, as `df` does,
+/// then the same in clusters.
+fn print_df(space: &FreeSpace, human: bool) {
+    let bytes = |n: u64| if human { human_size(n) } else { n.to_string() };
+    let usable = space.used_clusters() as u64 + space.free_clusters as u64;
+    // Rounded up, as `df` rounds it, so a volume with anything on it isn't 0%.
+    let percent = if usable == 0 { 0 } else { (space.used_clusters() as u64 * 100).div_ceil(usable) };
+    println!("{:>10} {:>10} {:>10} {:>4}", "size", "used", "avail", "use%");
+    println!("{:>10} {:>10} {:>10} {:>3}%",
+             bytes(space.total_bytes()),
+             bytes(space.used_bytes()),
+             bytes(space.free_bytes()),
+             percent);
+    println!("{} clusters of {} bytes: {} used, {} free, {} bad",
+             space.clusters,
+             space.cluster_size,
+             space.used_clusters(),
+             space.free_clusters,
+             space.bad_clusters);
+}
+
+/// Evaluates `test` predicates such as `--exists /KERNEL.SYS`. Returns whether
+/// they all hold, or a message if one of them can't be evaluated.
+fn check_predicates<S: Source>(info: &DiskInfo,
+                               disk_file: &mut S,
+                               predicates: &[String])
+                               -> Result<bool, String> {
+    let fat = fat_of(info, disk_file).map_err(|e| e.to_string())?;
+    let mut holds = true;
+    for pair in predicates.chunks(2) {
+        let (predicate, path) = match *pair {
+            [ref predicate, ref path] => (predicate.as_str(), path.as_str()),
+            _ => return Err(format!("{} needs an argument", pair[0])),
+        };
+        // Whether `path` is a directory, or `None` if there's nothing there.
+        let mut is_dir = || -> Result<Option<bool>, String> {
+            if path.trim_matches('/').is_empty() {
+                return Ok(Some(true));
+            }
+            let entry = find_path(info, disk_file, path).map_err(|e| format!("{}: {}", path, e))?;
+            Ok(entry.map(|e| (e.attributes & DirEntryAttributes::SubDir as u8) != 0))
+        };
+        holds &= match predicate {
+            "--exists" => is_dir()?.is_some(),
+            "--is-dir" => is_dir()? == Some(true),
+            "--is-file" => is_dir()? == Some(false),
+            "--min-free" => {
+                let bytes = memory::parse_size(path).ok_or_else(|| format!("invalid size: {}", path))?;
+                free_space(info, &*fat).free_bytes() >= bytes
+            }
+            _ => return Err(format!("unknown test: {}", predicate)),
+        };
+    }
+    Ok(holds)
+}
+
+/// Prints the deleted entries of the live directories, and for each the name
+/// and clusters it would get back or why it can't be restored.
+fn list_deleted<S: Source>(info: &DiskInfo, disk_file: &mut S) -> Result<()> {
+    for deleted in undelete::find(info, disk_file)? {
+        let name = deleted.name_with(deleted.first_char.unwrap_or(b'?'));
+        let is_dir = (deleted.entry.attributes & DirEntryAttributes::SubDir as u8) != 0;
+        let kind = if is_dir { "dir" } else { "" };
+        let outcome = match deleted.chain {
+            Ok(ref chain) => format!("recoverable as {} ({} clusters)", name, chain.len()),
+            Err(why) => format!("not recoverable: {}", why),
+        };
+        println!("{:<30} {:>3} {:>10}  {}", deleted.path, kind, deleted.entry.file_size, outcome);
     }
     Ok(())
 }
 
-fn to_datetime(date: u16, time: u16) -> NaiveDateTime {
-    NaiveDate::from_ymd((date >> 9) as i32 + 1980, (date & 0x01E0) as u32 >> 5, date as u32 & 0x001F)
-        .and_hms(time as u32 >> 11, (time & 0x07E0) as u32 >> 5, (time & 0x001F) as u32)
+/// Prints the cylinder, head and sectors of each cluster of the file or
+/// directory at `path`, a line for every track a cluster touches, so data can
+/// be lined up with the physical tracks. The root directory of FAT12 and
+/// FAT16, which has no clusters, is given by its sectors.
+fn locate<S: Source>(info: &DiskInfo, disk_file: &mut S, path: &str) -> Result<bool> {
+    if lba_to_chs(info, 0).is_none() {
+        return Err(Error::InvalidBootSector("the BPB gives no sectors per track or heads".to_string()));
+    }
+    let fat = fat_of(info, disk_file)?;
+    let sector = info.bytes_per_sector as u64;
+    let first = match find_path(info, disk_file, path)? {
+        Some(entry) => entry.flc,
+        None if path.trim_matches('/').is_empty() && info.has_fat32_bpb() => info.root_cluster,
+        None if path.trim_matches('/').is_empty() => 0,
+        None => {
+            eprintln!("fat12: {}: no such file", path);
+            return Ok(false);
+        }
+    };
+    // Runs of sectors, by the cluster they belong to if any.
+    let runs: Vec<(Option<u32>, u32, u32)> = if first >= 2 {
+        cluster_chain(info, &*fat, first)
+            .into_iter()
+            .map(|c| (Some(c), (cluster_start(info, c) / sector) as u32, info.sectors_per_cluster as u32))
+            .collect()
+    } else if path.trim_matches('/').is_empty() {
+        let root_start = (root_dir_start(info) / sector) as u32;
+        vec![(None, root_start, (cluster_start(info, 2) / sector) as u32 - root_start)]
+    } else {
+        Vec::new()
+    };
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    if runs.is_empty() {
+        writeln!(out, "{} has no clusters", path)?;
+        return Ok(true);
+    }
+    writeln!(out, "{:>8} {:>10} {:>8} {:>4} {:>9}", "cluster", "lba", "cylinder", "head", "sectors")?;
+    for (cluster, mut lba, mut count) in runs {
+        while count > 0 {
+            let chs = lba_to_chs(info, lba).unwrap();
+            let in_track = (info.sectors_per_track - chs.sector + 1) as u32;
+            let n = count.min(in_track);
+            writeln!(out, "{:>8} {:>10} {:>8} {:>4} {:>9}",
+                     cluster.map_or("-".to_string(), |c| c.to_string()),
+                     lba,
+                     chs.cylinder,
+                     chs.head,
+                     format!("{}-{}", chs.sector, chs.sector as u32 + n - 1))?;
+            lba += n;
+            count -= n;
+        }
+    }
+    Ok(true)
+}
+
+/// Prints every field of the directory entry at `path`, along with how its
+/// chain is laid out.
+fn stat<S: Source>(info: &DiskInfo,
+                   disk_file: &mut S,
+                   path: &str,
+                   json: bool,
+                   offsets: bool)
+                   -> Result<bool> {
+    let fat = fat_of(info, disk_file)?;
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    let (directory, slot, entry) = match find_slot(info, disk_file, path)? {
+        Some(found) => found,
+        None => {
+            eprintln!("fat12: {}: no such file", path);
+            return Ok(false);
+        }
+    };
+    let protection = drdos::Protection::of(&entry, drdos::rights_in_use(info, disk_file)?);
+    // A password's hash takes the place of the creation time.
+    let created = match protection {
+        Some(_) => dos_date(entry.create_date).map(|d| d.to_string()),
+        None => entry.created().map(|t| t.to_string()),
+    };
+    let accessed = dos_date(entry.last_access_date).map(|d| d.to_string());
+    let modified = dos_datetime(entry.last_write_date, entry.last_write_time).map(|t| t.to_string());
+    let allocated = allocated_size(info, &entry);
+    let clusters = allocated / cluster_size(info);
+    let slot_offset = directory.slot_offset(slot).unwrap();
+    let parent = match split_path(path).0 {
+        "" => "root",
+        parent => parent,
+    };
+    let chain = cluster_chain(info, &*fat, entry.flc);
+    // A fragment ends wherever the next cluster isn't the one after.
+    let breaks = chain.windows(2).filter(|pair| pair[1] != pair[0] + 1).count();
+    let fragments = if chain.is_empty() { 0 } else { breaks + 1 };
+
+    if json {
+        let mut fields = json!({
+            "name": entry.name(),
+            "attributes": attribute_string(entry.attributes),
+            "size": entry.file_size,
+            "first_cluster": entry.flc,
+            "ea_handle": entry.ea_handle,
+            "password": protection.map(|protection| protection.modes()),
+            "clusters": clusters,
+            "allocated": allocated,
+            "slack": allocated - entry.file_size as u64,
+            "chain_length": chain.len(),
+            "fragments": fragments,
+            "created": created,
+            "accessed": accessed,
+            "modified": modified,
+            "directory": parent,
+            "slot": slot,
+            "slot_offset": slot_offset,
+        });
+        if offsets {
+            let starts: Vec<u64> = chain.iter().map(|&c| cluster_start(info, c)).collect();
+            fields["cluster_offsets"] = json!(starts);
+        }
+        writeln!(out, "{}", fields)?;
+        return Ok(true);
+    }
+    let invalid = |date: u16, time: u16| format!("invalid (date 0x{:04X}, time 0x{:04X})", date, time);
+    let mut lines = vec![
+        ("name", entry.name()),
+        ("attributes", format!("{} (0x{:02X})", attribute_string(entry.attributes), entry.attributes)),
+        ("size", format!("{} bytes", entry.file_size)),
+        ("first cluster", entry.flc.to_string()),
+        ("allocated", format!("{} bytes in {} clusters", allocated, clusters)),
+        ("slack", format!("{} bytes", allocated - entry.file_size as u64)),
+        ("chain", format!("{} clusters in {} fragments", chain.len(), fragments)),
+        ("created", created.unwrap_or_else(|| invalid(entry.create_date, entry.create_time))),
+        ("accessed", accessed.unwrap_or_else(|| invalid(entry.last_access_date, 0))),
+        ("modified", modified.unwrap_or_else(|| invalid(entry.last_write_date, entry.last_write_time))),
+        ("directory slot", format!("{} #{} at byte 0x{:X}", parent, slot, slot_offset)),
+    ];
+    match protection {
+        Some(protection) => lines.push(("password", protection.modes())),
+        None if entry.ea_handle != 0 => lines.push(("EA handle", entry.ea_handle.to_string())),
+        None => (),
+    }
+    if offsets {
+        let clusters: Vec<String> = chain.iter()
+            .map(|&c| format!("{} at 0x{:X}", c, cluster_start(info, c)))
+            .collect();
+        lines.push(("clusters", if clusters.is_empty() { "none".to_string() } else { clusters.join(", ") }));
+    }
+    for &(label, ref value) in &lines {
+        writeln!(out, "{:<15} {}", format!("{}:", label), value)?;
+    }
+    Ok(true)
+}
+
+/// Returns the value following `flag` in `args`, e.g. `--to DIR`.
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter().position(|a| a == flag).and_then(|i| args.get(i + 1)).map(|v| v.as_str())
+}
+
+/// What an image is read from: a local file, or with the `http`, `sftp` or
+/// `s3` feature a URL. The FAT may be paged in through a handle of its own alongside.
+trait Source: Read + Seek + Sized + 'static {
+    fn duplicate(&self) -> Result<Self>;
+}
+impl Source for File {
+    fn duplicate(&self) -> Result<Self> {
+        Ok(self.try_clone()?)
+    }
+}
+#[cfg(feature = "http")]
+impl Source for http::HttpImage {
+    fn duplicate(&self) -> Result<Self> {
+        Ok(self.clone())
+    }
+}
+#[cfg(feature = "sftp")]
+impl Source for sftp::SftpImage {
+    fn duplicate(&self) -> Result<Self> {
+        Ok(self.reopen()?)
+    }
+}
+
+/// The image's FAT, read whole or paged in within its memory limit.
+fn fat_of<S: Source>(info: &DiskInfo, disk_file: &S) -> Result<Box<dyn FatTable>> {
+    load_fat(info, disk_file.duplicate()?)
+}
+
+/// The ddrescue map given with `--map FILE`, or else the `<image>.map` next
+/// to the image, if there is one. Unrecovered sectors in the volume's
+/// metadata are warned about as it is loaded.
+fn load_map(args: &[String], info: &DiskInfo) -> Option<rescue::Rescue> {
+    let sidecar = format!("{}.map", args[2]);
+    let path = match flag_value(args, "--map") {
+        Some(path) => path.to_string(),
+        None if args[2] != STREAM && Path::new(&sidecar).exists() => sidecar,
+        None => return None,
+    };
+    let text = fs::read_to_string(&path).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+    let map = rescue::read_map(&text).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+    for (what, sectors) in map.unrecovered_metadata(info) {
+        let sectors = rescue::sector_ranges(&sectors);
+        warnings().warn(Warning::Unrecovered { what: what.to_string(), sectors });
+    }
+    Some(map)
+}
+
+/// Warns about each file at or below `path` with sectors `map` has as not
+/// recovered, so that what was extracted from them can be told apart.
+fn warn_unrecovered<S: Source>(info: &DiskInfo,
+                               disk_file: &mut S,
+                               path: &str,
+                               map: &rescue::Rescue)
+                               -> Result<()> {
+    let fat = fat_of(info, disk_file)?;
+    let warn = |path: &str, entry: &DirEntry| {
+        let sectors = map.unrecovered(info, &*fat, entry);
+        if !sectors.is_empty() {
+            let sectors = rescue::sector_ranges(&sectors);
+            warnings().warn(Warning::Unrecovered { what: path.to_string(), sectors });
+        }
+    };
+    match Directory::open(info, disk_file, path)? {
+        Some(directory) => {
+            walk_tree(info,
+                      disk_file,
+                      &*fat,
+                      &directory,
+                      path.trim_end_matches('/'),
+                      false,
+                      &mut HashSet::new(),
+                      &mut |_, found| {
+                          warn(&found.path, &found.entry);
+                          Ok(())
+                      })
+        }
+        None => {
+            if let Some(entry) = find_path(info, disk_file, path)? {
+                warn(path, &entry);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// The SHA-256 of the whole image, which annotations are keyed by.
+fn hash_image<S: Source>(disk_file: &mut S) -> Result<String> {
+    disk_file.seek(SeekFrom::Start(0))?;
+    Ok(backup::sha256_reader(disk_file)?)
+}
+
+/// The image name that stands for stdin, or for stdout when writing.
+const STREAM: &str = "-";
+
+/// Copies `input` into an anonymous temporary file, since commands need to
+/// seek around the image. The file is unlinked at once where the OS allows it.
+fn spool<R: Read>(input: &mut R) -> Result<File> {
+    let path = env::temp_dir().join(format!("fat12-{}.img", process::id()));
+    let mut file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+    let _ = fs::remove_file(&path);
+    std::io::copy(input, &mut file)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+/// Where a mutating command reports: stderr when the image itself is being
+/// written to stdout, so the two don't mix.
+fn report(args: &[String]) -> Box<dyn Write> {
+    if args[2] == STREAM {
+        Box::new(std::io::stderr())
+    } else {
+        Box::new(std::io::stdout())
+    }
+}
+
+/// Reports whether the host directory `dir` fits on a fresh volume of the named
+/// geometry, and which files are the best candidates to drop if it doesn't.
+fn print_fits(dir: &str, geometry_name: &str) -> bool {
+    let geometry = geometry::by_name(geometry_name)
+        .unwrap_or_else(|| fail(&format!("unknown geometry: {}", geometry_name)));
+    let plan = fits::plan(Path::new(dir), geometry).unwrap_or_else(|e| fail(&format!("{}: {}", dir, e)));
+    println!("target: {} ({} clusters of {} bytes, {} root entries)",
+             geometry.name,
+             plan.data_clusters,
+             plan.cluster_size,
+             plan.root_slots);
+    println!("needed: {} clusters ({} bytes), {} root entries",
+             plan.clusters_needed,
+             plan.clusters_needed * plan.cluster_size,
+             plan.root_slots_needed);
+    if plan.fits() {
+        println!("fits, with {} bytes to spare",
+                 (plan.data_clusters - plan.clusters_needed) * plan.cluster_size);
+        return true;
+    }
+    if plan.root_slots_needed > plan.root_slots {
+        println!("does not fit: the root directory needs {} more entries",
+                 plan.root_slots_needed - plan.root_slots);
+    }
+    if plan.shortfall() > 0 {
+        println!("does not fit: {} bytes short", plan.shortfall());
+        println!("largest files:");
+        let mut freed = 0;
+        for file in plan.files.iter().take(10) {
+            freed += file.clusters * plan.cluster_size;
+            println!("  {:>10}  {}{}",
+                     file.size,
+                     file.path.display(),
+                     if freed >= plan.shortfall() { "  <- enough once removed" } else { "" });
+            if freed >= plan.shortfall() {
+                break;
+            }
+        }
+    }
+    false
+}
+
+/// Where every image the command reads reports what the library found odd
+/// but worked around, so it is printed however the command ends.
+static WARNINGS: OnceLock<Warnings> = OnceLock::new();
+
+fn warnings() -> &'static Warnings {
+    WARNINGS.get_or_init(Warnings::default)
+}
+
+/// Prints the warnings, on stderr and marked as such, so they stand apart
+/// from both output and errors. With `--progress-json` they are `warning`
+/// events among the rest.
+fn print_warnings() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() > 2 && progress::enabled(&args) {
+        let mut out = report(&args);
+        for warning in warnings().take() {
+            let _ = writeln!(out, "{}", progress::warning_event(&args[1], &warning.to_string(), None));
+        }
+        return;
+    }
+    for warning in warnings().take() {
+        eprintln!("fat12: warning: {}", warning);
+    }
+}
+
+/// Exits with `code` once the warnings collected so far are printed.
+fn exit(code: i32) -> ! {
+    print_warnings();
+    process::exit(code);
+}
+
+/// Prints how fat12 is run and the commands it has, and exits with 1.
+fn usage() -> ! {
+    eprintln!("usage: fat12 COMMAND IMAGE [ARGS...]");
+    eprintln!("commands: {}", completion::COMMANDS.join(" "));
+    exit(1);
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("fat12: {}", message);
+    exit(1);
+}
+
+/// Reports an error from the library and exits: with 2 when a path in the
+/// image doesn't exist or is the wrong kind, 3 when the image is damaged,
+/// and 1 for anything else.
+fn exit_with(error: Error) -> ! {
+    // Output cut short by `| head` ends the command quietly, with the status
+    // of one killed by SIGPIPE as other tools are.
+    if let Error::Io(ref e) = error {
+        if e.kind() == std::io::ErrorKind::BrokenPipe {
+            exit(141);
+        }
+    }
+    eprintln!("fat12: {}", error);
+    exit(match error {
+        Error::NotFound(_) | Error::NotADirectory(_) | Error::IsADirectory(_) => 2,
+        Error::InvalidBootSector(_) | Error::InvalidDirEntry(_) | Error::CorruptFatChain(_) => 3,
+        _ => 1,
+    });
+}
+
+/// Exits through `exit_with` instead of panicking when a command fails.
+trait OrExit<T> {
+    fn or_exit(self) -> T;
+}
+impl<T, E: Into<Error>> OrExit<T> for Result<T, E> {
+    fn or_exit(self) -> T {
+        self.unwrap_or_else(|e| exit_with(e.into()))
+    }
+}
+
+/// Opens the image named in `args` for writing under an exclusive lock, runs
+/// `mutate` on it and records the change in the image's audit log, if any.
+/// With `create`, a missing image is created empty first.
+///
+/// An image named `-` is read from stdin (or starts empty with `create`) and
+/// written to stdout once modified. It has no sidecars to lock or log. A
+/// chunked image is modified in a temporary copy and stored again after,
+/// and so is one at an `sftp://` or `s3://` URL, which has no sidecars
+/// either.
+fn modify_image<T, F>(args: &[String], create: bool, mutate: F) -> T
+    where F: FnOnce(&mut File) -> Result<T>
+{
+    let (command, disk_path) = (&args[1], &args[2]);
+    if disk_path == STREAM {
+        let mut disk_file = if create {
+            spool(&mut std::io::empty())
+        } else {
+            spool(&mut std::io::stdin())
+        }.unwrap_or_else(|e| fail(&format!("stdin: {}", e)));
+        let result = mutate(&mut disk_file).or_exit();
+        let image = read_image(&mut disk_file).or_exit();
+        std::io::stdout().write_all(&image).unwrap_or_else(|e| fail(&format!("stdout: {}", e)));
+        return result;
+    }
+    if is_url(disk_path) {
+        fail(&format!("{}: images on the web can't be written; download it first", disk_path));
+    }
+    if is_sftp(disk_path) {
+        return modify_remote(disk_path, create, mutate).or_exit();
+    }
+    if is_s3(disk_path) {
+        return modify_s3(disk_path, create, mutate).or_exit();
+    }
+    if let Some(format) = ingest::format_of(disk_path) {
+        fail(&format!("{} images can't be written; convert it with `fat12 ingest {} OUT.img` first",
+                      format.name(),
+                      disk_path));
+    }
+    let mut image_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(create)
+        .truncate(false)
+        .open(disk_path)
+        .unwrap_or_else(|e| fail(&format!("{}: {}", disk_path, e)));
+    let lock = lock::exclusive(&image_file, disk_path, command, warnings())
+        .unwrap_or_else(|e| fail(&e.to_string()));
+    // Exiting skips destructors, so release the lock and its sidecar first.
+    let result = modify_locked(args, &mut image_file, mutate);
+    drop(lock);
+    result.or_exit()
+}
+
+/// The part of `modify_image` done under the image's lock, which hands every
+/// error back for the lock to be released before exiting.
+fn modify_locked<T, F>(args: &[String], image_file: &mut File, mutate: F) -> Result<T>
+    where F: FnOnce(&mut File) -> Result<T>
+{
+    let disk_path = &args[2];
+    let chunked = chunked::is_manifest(disk_path);
+    // An overlay takes the changes, and the image under it stays as it was.
+    let mut overlay = if overlay::is_overlay(disk_path) {
+        Some(overlay::Overlay::load(Path::new(disk_path))?)
+    } else {
+        None
+    };
+    let mut spooled;
+    let disk_file = if chunked {
+        spooled = spool(&mut &chunked::read(Path::new(disk_path))?[..])?;
+        &mut spooled
+    } else if let Some(ref overlay) = overlay {
+        spooled = spool(&mut &overlay.image()?[..])?;
+        &mut spooled
+    } else {
+        image_file
+    };
+    let fingerprint = fingerprint::enabled(disk_path, args);
+    if fingerprint && disk_file.metadata()?.len() > 0 {
+        fingerprint::check(disk_path, &read_metadata(disk_file)?, warnings())?;
+    }
+    let audit = audit::enabled(disk_path, args);
+    let before = if audit { read_image(disk_file)? } else { Vec::new() };
+    let result = mutate(disk_file)?;
+    if audit {
+        let after = read_image(disk_file)?;
+        audit::record(disk_path, &args[1..], &before, &after)?;
+    }
+    if chunked {
+        chunked::write(&read_image(disk_file)?, Path::new(disk_path))?;
+    }
+    if let Some(ref mut overlay) = overlay {
+        overlay.update(&overlay.read_base()?, &read_image(disk_file)?);
+        overlay.save(Path::new(disk_path))?;
+    }
+    if fingerprint {
+        fingerprint::update(disk_path, &read_metadata(disk_file)?)?;
+    }
+    Ok(result)
+}
+
+/// Lists what `check` found and sums it up. With `repaired`, the repairs have
+/// been made and each problem says whether it was fixed. Returns whether the
+/// volume is clean now.
+fn print_check(check: &check::Check, repaired: bool, out: &mut dyn Write) -> bool {
+    for finding in &check.findings {
+        let note = match (repaired, finding.repairable) {
+            (true, true) => " (repaired)",
+            (true, false) => " (left alone)",
+            (false, _) => "",
+        };
+        writeln!(out, "{}{}", finding.problem, note).or_exit();
+    }
+    let repairable = check.findings.iter().filter(|finding| finding.repairable).count();
+    if check.is_clean() {
+        writeln!(out, "no problems found").or_exit();
+    } else if repaired {
+        writeln!(out, "{} problems, {} repaired", check.findings.len(), repairable).or_exit();
+    } else {
+        writeln!(out, "{} problems, {} of them repairable with --repair", check.findings.len(), repairable)
+            .or_exit();
+    }
+    check.is_clean() || (repaired && repairable == check.findings.len())
+}
+
+/// Opens the image at `disk_path` for reading, or spools stdin for `-`. The
+/// image stays locked against writers as long as the lock is kept. Returns
+/// the options to read it with: `options`, but for an ImageDisk or HFE image
+/// with no BPB and no geometry given, laid out by its own tracks.
+fn open_image(args: &[String],
+              disk_path: &str,
+              options: VolumeOptions)
+              -> (File, VolumeOptions, Option<lock::ImageLock>) {
+    if disk_path == STREAM {
+        let disk_file = spool(&mut std::io::stdin()).unwrap_or_else(|e| fail(&format!("stdin: {}", e)));
+        return (disk_file, options, None);
+    }
+    let mut options = options;
+    let mut disk_file = File::open(disk_path).unwrap_or_else(|e| fail(&format!("{}: {}", disk_path, e)));
+    let lock = lock::shared(&disk_file, disk_path).unwrap_or_else(|e| fail(&e.to_string()));
+    if chunked::is_manifest(disk_path) {
+        let image = chunked::read(Path::new(disk_path)).unwrap_or_else(|e| fail(&e.to_string()));
+        disk_file = spool(&mut &image[..]).or_exit();
+    }
+    if overlay::is_overlay(disk_path) {
+        let overlay = overlay::Overlay::load(Path::new(disk_path)).unwrap_or_else(|e| fail(&e.to_string()));
+        let image = overlay.image().unwrap_or_else(|e| fail(&e.to_string()));
+        disk_file = spool(&mut &image[..]).or_exit();
+    }
+    if ingest::format_of(disk_path).is_some() {
+        let decoded = decode_image(disk_path);
+        if !decoded.bad.is_empty() {
+            let count = decoded.bad.len();
+            warnings().warn(Warning::UnreadableSectors { image: disk_path.to_string(), count });
+        }
+        // With no BPB to go by, the image's own track layout is the geometry.
+        if options.geometry.is_none() && !has_boot_sector(&decoded.image) {
+            options.geometry = Some(decoded.physical);
+        }
+        disk_file = spool(&mut &decoded.image[..]).or_exit();
+    }
+    if fingerprint::enabled(disk_path, args) {
+        fingerprint::check(disk_path, &read_metadata(&mut disk_file).or_exit(), warnings()).or_exit();
+    }
+    (disk_file, options, Some(lock))
+}
+
+/// Whether the image named is a URL, which is read from the web server with
+/// the `http` feature.
+fn is_url(disk_path: &str) -> bool {
+    disk_path.starts_with("http://") || disk_path.starts_with("https://")
+}
+
+/// Where what is fetched of a remote image is kept for next time: the
+/// directory `--cache-dir DIR` names, nowhere with `--no-cache`, and
+/// otherwise `$XDG_CACHE_HOME/fat12` or `~/.cache/fat12`. The flags are
+/// taken out of `args`.
+fn cache_dir(args: &mut Vec<String>) -> Option<PathBuf> {
+    if let Some(i) = args.iter().position(|a| a == "--no-cache") {
+        args.remove(i);
+        return None;
+    }
+    if let Some(i) = args.iter().position(|a| a == "--cache-dir") {
+        let dir = args.get(i + 1).cloned().unwrap_or_else(|| fail("--cache-dir needs a directory"));
+        args.drain(i..i + 2);
+        return Some(PathBuf::from(dir));
+    }
+    env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .map(|dir| dir.join("fat12"))
+}
+
+/// Opens the image at `url`, fetching only as much of it as commands read.
+#[cfg(feature = "http")]
+fn open_url(url: &str, dir: Option<PathBuf>, max_memory: Option<u64>) -> http::HttpImage {
+    let cache = http::Cache { dir, max_memory };
+    http::HttpImage::open_cached(url, http::range_only(), &cache).unwrap_or_else(|e| fail(&e.to_string()))
+}
+
+#[cfg(not(feature = "http"))]
+fn open_url(_: &str, _: Option<PathBuf>, _: Option<u64>) -> File {
+    fail("this fat12 was built without HTTP support; rebuild it with `--features http`")
+}
+
+/// Whether the image named is on an SFTP server, which is read and written
+/// with the `sftp` feature.
+fn is_sftp(disk_path: &str) -> bool {
+    disk_path.starts_with("sftp://")
+}
+
+#[cfg(feature = "sftp")]
+fn open_sftp(url: &str) -> sftp::SftpImage {
+    sftp::SftpImage::open(url).unwrap_or_else(|e| fail(&e.to_string()))
+}
+
+#[cfg(not(feature = "sftp"))]
+fn open_sftp(_: &str) -> File {
+    fail("this fat12 was built without SFTP support; rebuild it with `--features sftp`")
+}
+
+/// The part of `modify_image` for an image on an SFTP server, which is
+/// modified in a temporary copy. Only the sectors that changed are written
+/// back, or the whole image if its size changed.
+#[cfg(feature = "sftp")]
+fn modify_remote<T, F>(disk_path: &str, create: bool, mutate: F) -> Result<T>
+    where F: FnOnce(&mut File) -> Result<T>
+{
+    let mut remote = sftp::SftpImage::open_writable(disk_path, create)?;
+    let before = read_image(&mut remote)?;
+    let mut disk_file = spool(&mut &before[..])?;
+    let result = mutate(&mut disk_file)?;
+    remote.store(&before, &read_image(&mut disk_file)?)?;
+    Ok(result)
+}
+
+#[cfg(not(feature = "sftp"))]
+fn modify_remote<T, F>(_: &str, _: bool, _: F) -> Result<T>
+    where F: FnOnce(&mut File) -> Result<T>
+{
+    fail("this fat12 was built without SFTP support; rebuild it with `--features sftp`")
+}
+
+/// Whether the image named is an object in an S3 bucket, which is read and
+/// written with the `s3` feature.
+fn is_s3(disk_path: &str) -> bool {
+    disk_path.starts_with("s3://")
+}
+
+/// The object an `s3://` URL names, reached as the `AWS_*` variables say.
+#[cfg(feature = "s3")]
+fn s3_object(url: &str) -> s3::Object {
+    let config = s3::Config::from_env().unwrap_or_else(|e| fail(&format!("{}: {}", url, e)));
+    s3::Object::parse(url, config).unwrap_or_else(|| fail(&format!("{}: expected s3://BUCKET/KEY", url)))
+}
+
+#[cfg(feature = "s3")]
+fn open_s3(url: &str, dir: Option<PathBuf>, max_memory: Option<u64>) -> http::HttpImage {
+    s3_object(url).open(&http::Cache { dir, max_memory }).unwrap_or_else(|e| fail(&e.to_string()))
+}
+
+#[cfg(not(feature = "s3"))]
+fn open_s3(_: &str, _: Option<PathBuf>, _: Option<u64>) -> File {
+    fail("this fat12 was built without S3 support; rebuild it with `--features s3`")
+}
+
+/// The part of `modify_image` for an object in S3, which is modified in a
+/// temporary copy and stored again only if it changed, and only if nothing
+/// else has stored it since.
+#[cfg(feature = "s3")]
+fn modify_s3<T, F>(disk_path: &str, create: bool, mutate: F) -> Result<T>
+    where F: FnOnce(&mut File) -> Result<T>
+{
+    let object = s3_object(disk_path);
+    let (before, etag) = object.read(create)?;
+    let mut disk_file = spool(&mut &before[..])?;
+    let result = mutate(&mut disk_file)?;
+    let after = read_image(&mut disk_file)?;
+    if after != before || etag.is_none() {
+        object.store(&after, etag.as_deref())?;
+    }
+    Ok(result)
+}
+
+#[cfg(not(feature = "s3"))]
+fn modify_s3<T, F>(_: &str, _: bool, _: F) -> Result<T>
+    where F: FnOnce(&mut File) -> Result<T>
+{
+    fail("this fat12 was built without S3 support; rebuild it with `--features s3`")
+}
+
+/// Decodes the ImageDisk or HFE image at `disk_path`, or fails.
+fn decode_image(disk_path: &str) -> ingest::Decoded {
+    ingest::read(Path::new(disk_path)).unwrap_or_else(|e| fail(&e.to_string()))
+}
+
+/// Whether `image` starts with a FAT boot sector that gives its layout.
+fn has_boot_sector(image: &[u8]) -> bool {
+    image.len() >= 512 && boot_sector_problems(&image[..512]).is_empty()
+}
+
+/// Guesses the geometry of an image with a damaged boot sector and prints the
+/// evidence. With `write`, a boot sector for that geometry is written.
+fn recover_bpb(disk_file: &mut File,
+               write: bool,
+               out: &mut dyn Write)
+               -> Result<()> {
+    let mut image = read_image(disk_file)?;
+    let recovery = match geometry::recover(&image) {
+        Some(recovery) => recovery,
+        None => {
+            writeln!(out, "image size {} bytes matches no standard floppy geometry", image.len())?;
+            return Ok(());
+        }
+    };
+    let geometry = recovery.geometry;
+    writeln!(out, "image size {} bytes matches {} geometry", image.len(), geometry.name)?;
+    writeln!(out,
+             "FAT signatures: {} of {} found (media 0x{:02X})",
+             recovery.fat_signatures,
+             geometry::FAT_COUNT,
+             geometry.media)?;
+    writeln!(out,
+             "root directory: {}",
+             if recovery.root_dir_plausible { "looks valid" } else { "does not look valid" })?;
+    if !write {
+        return Ok(());
+    }
+    // Keep the original boot code when there is any, since only the BPB is
+    // known to be damaged.
+    let boot_sector = &mut image[..512];
+    if boot_sector[510..512] == [0x55, 0xAA] && boot_sector[0x3E..510].iter().any(|&b| b != 0) {
+        geometry::write_bpb(boot_sector, geometry);
+    } else {
+        boot_sector.copy_from_slice(&geometry::boot_sector(geometry));
+    }
+    disk_file.seek(SeekFrom::Start(0))?;
+    disk_file.write_all(boot_sector)?;
+    writeln!(out, "boot sector rewritten for {} geometry", geometry.name)?;
+    Ok(())
+}
+
+/// The times given with `--created`, `--modified` and `--accessed`, over
+/// those of the host file `reference` if there is one.
+fn flag_times(args: &[String], reference: Option<&Path>) -> Times {
+    let mut times = reference.map_or(Times::default(), |path| Times::of_host_file(path).or_exit());
+    let flag = |name: &str| {
+        flag_value(args, name).map(|text| dates::parse(text, false).unwrap_or_else(|e| fail(&e)))
+    };
+    times.created = flag("--created").or(times.created);
+    times.modified = flag("--modified").or(times.modified);
+    times.accessed = flag("--accessed").or(times.accessed);
+    times
+}
+
+/// Reads ATTRIB-style changes such as `+r -a` as the bits to set and the bits
+/// to clear.
+fn attribute_changes(changes: &[String]) -> (u8, u8) {
+    let (mut set, mut clear) = (0, 0);
+    for change in changes {
+        let bit = match change.get(1..).map(|letter| letter.to_ascii_lowercase()).as_deref() {
+            Some("r") => DirEntryAttributes::ReadOnly as u8,
+            Some("h") => DirEntryAttributes::Hidden as u8,
+            Some("s") => DirEntryAttributes::System as u8,
+            Some("a") => DirEntryAttributes::Archive as u8,
+            _ => fail(&format!("unknown attribute change: {} (try +r, -h, +s or -a)", change)),
+        };
+        if change.starts_with('+') {
+            set |= bit;
+        } else if change.starts_with('-') {
+            clear |= bit;
+        } else {
+            fail(&format!("unknown attribute change: {} (try +r, -h, +s or -a)", change));
+        }
+    }
+    (set, clear)
 }
 
 fn main() {
-    let mut args = env::args();
+    run();
+    print_warnings();
+}
+
+fn run() {
+    let mut args: Vec<String> = env::args().collect();
+    // `--max-memory SIZE` applies to every command, wherever it is given, so
+    // it is taken out before the command's own arguments are looked at.
+    let mut max_memory = None;
+    if let Some(i) = args.iter().position(|a| a == "--max-memory") {
+        let size = args.get(i + 1).cloned().unwrap_or_else(|| fail("--max-memory needs a size"));
+        let bytes = memory::parse_size(&size).unwrap_or_else(|| fail(&format!("invalid size: {}", size)));
+        max_memory = Some(bytes);
+        args.drain(i..i + 2);
+    }
+    // So are `--max-entries N`, `--max-chain N` and `--max-output SIZE`,
+    // which bound the work a hostile image can make.
+    let mut limit = |flag: &str, parse: fn(&str) -> Option<u64>| {
+        let i = args.iter().position(|a| a == flag)?;
+        let value = args.get(i + 1).cloned().unwrap_or_else(|| fail(&format!("{} needs a value", flag)));
+        let limit = parse(&value).unwrap_or_else(|| fail(&format!("invalid {}: {}", flag, value)));
+        args.drain(i..i + 2);
+        Some(limit)
+    };
+    let max_entries = limit("--max-entries", |n| n.parse().ok());
+    let max_chain = limit("--max-chain", |n| n.parse().ok()).map(|n| n.min(u32::MAX as u64) as u32);
+    let max_output = limit("--max-output", memory::parse_size);
+    // `--dir-end marker|sector|all` says how much of a directory to zero after
+    // an entry added at its end, for every command that adds one.
+    let mut directory_end = DirectoryEnd::default();
+    if let Some(i) = args.iter().position(|a| a == "--dir-end") {
+        let end = args.get(i + 1).cloned().unwrap_or_else(|| fail("--dir-end needs marker, sector or all"));
+        directory_end = DirectoryEnd::parse(&end)
+            .unwrap_or_else(|| fail(&format!("unknown --dir-end: {} (marker, sector or all)", end)));
+        args.drain(i..i + 2);
+    }
+    // `--geometry TRACKS,HEADS,SPT,BPS` is for images with no BPB, such as
+    // 8-inch disks, and is kept in a sidecar so later commands don't need it.
+    // Without commas it names a geometry for `format` and `fits` instead.
+    let mut given = None;
+    if let Some(i) = args.iter().position(|a| a == "--geometry") {
+        if args.get(i + 1).is_some_and(|value| value.contains(',')) {
+            let value = args[i + 1].clone();
+            let physical = physical::Physical::parse(&value).unwrap_or_else(|| {
+                fail(&format!("invalid geometry: {} (expected tracks,heads,spt,bps)", value))
+            });
+            args.drain(i..i + 2);
+            if let Some(disk_path) = args.get(2).filter(|path| *path != STREAM) {
+                // Only a geometry that some layout fits is worth remembering.
+                if ingest::format_of(disk_path).is_some() {
+                    physical::disk_info(&mut std::io::Cursor::new(decode_image(disk_path).image), &physical)
+                        .or_exit();
+                } else {
+                    let mut disk_file = File::open(disk_path)
+                        .unwrap_or_else(|e| fail(&format!("{}: {}", disk_path, e)));
+                    physical::disk_info(&mut disk_file, &physical).or_exit();
+                }
+                physical::save(disk_path, &physical).or_exit();
+            }
+            given = Some(physical);
+        }
+    }
+    // The geometry only ever applies to the image argument; any other image
+    // a command reads goes by its own sidecar.
+    let geometry = match given {
+        Some(physical) => Some(physical),
+        None if args.len() >= 3 => physical::load(&args[2]).or_exit(),
+        None => None,
+    };
+    let volume_options = VolumeOptions {
+        geometry,
+        max_memory,
+        warnings: warnings().clone(),
+        policy: None,
+        directory_end,
+        name_mapping: None,
+        max_entries,
+        max_chain,
+        max_output,
+    };
     if args.len() < 3 {
-        println!("usage: fat12 command");
-        return;
+        usage();
+    }
+    let (command, disk_path) = (&args[1], &args[2]);
+    if command != "complete" && !completion::COMMANDS.contains(&command.as_str()) {
+        eprintln!("fat12: unknown command: {}", command);
+        usage();
+    }
+    let done = match command.as_str() {
+        "completions" => cmd_completions(&args),
+        "cat" if disk_path == "--spanned" => cmd_cat_spanned(&args, &volume_options),
+        "fits" => cmd_fits(&args),
+        "gen-fixture" => cmd_gen_fixture(&args),
+        "build" => cmd_build(&args),
+        "overlay" => cmd_overlay(&args),
+        "scrub" => cmd_scrub(&args),
+        "catalog" => cmd_catalog(&args),
+        "watch" => cmd_watch(&args, &volume_options),
+        #[cfg(feature = "tui")]
+        "browse" => cmd_browse(&args, &volume_options),
+        #[cfg(not(feature = "tui"))]
+        "browse" => fail("this fat12 was built without the file manager; rebuild it with `--features tui`"),
+        "search" => cmd_search(&args),
+        "ingest" => cmd_ingest(&args),
+        "rescue" => cmd_rescue(&args),
+        "put" => cmd_put(&args, &volume_options),
+        "touch" => cmd_touch(&args, &volume_options),
+        "cp-image" => cmd_cp_image(&args, &volume_options),
+        "mkdir" => cmd_mkdir(&args, &volume_options),
+        "undelete" => cmd_undelete(&args, &volume_options),
+        "reformat" => cmd_reformat(&args, &volume_options),
+        "format" => cmd_format(&args),
+        "fill" => cmd_fill(&args, &volume_options),
+        "corrupt" => cmd_corrupt(&args, &volume_options),
+        "rm" => cmd_rm(&args, &volume_options),
+        "undo" => cmd_undo(&args, &volume_options),
+        "attrib" if args.len() > 4 => cmd_attrib(&args, &volume_options),
+        "redact" => cmd_redact(&args, &volume_options),
+        "restore" => cmd_restore(&args),
+        "compact-dir" => cmd_compact_dir(&args, &volume_options),
+        "grow-root" | "recluster" if !args[3..].iter().any(|a| a == "--dry-run") => {
+            cmd_relayout(&args, &volume_options)
+        }
+        "check" if args[3..].iter().any(|a| a == "--repair") => cmd_check_repair(&args, &volume_options),
+        "recover-bpb" if args[3..].iter().any(|a| a == "--write") => cmd_recover_bpb_write(&args),
+        _ => cmd_read(&args, volume_options),
+    };
+    done.or_exit();
+}
+
+/// `completions SHELL`: prints the completion script for `SHELL`.
+fn cmd_completions(args: &[String]) -> Result<()> {
+    let disk_path = &args[2];
+    match completion::script(disk_path) {
+        Some(script) => print!("{}", script),
+        None => println!("unsupported shell: {}", disk_path),
+    }
+    Ok(())
+}
+
+/// `cat --spanned MANIFEST [NAME]`: prints a file split across a set of disks.
+fn cmd_cat_spanned(args: &[String], volume_options: &VolumeOptions) -> Result<()> {
+    let manifest = args.get(3).unwrap_or_else(|| fail("cat --spanned needs a manifest"));
+    let files = spanned::load(Path::new(manifest))
+        .unwrap_or_else(|e| fail(&format!("{}: {}", manifest, e)));
+    let file = match args.get(4) {
+        Some(name) => files.iter().find(|file| file.name.eq_ignore_ascii_case(name)),
+        None if files.len() == 1 => files.first(),
+        None => {
+            let names: Vec<&str> = files.iter().map(|file| file.name.as_str()).collect();
+            fail(&format!("{} spans several files; name one of: {}", manifest, names.join(", ")))
+        }
+    };
+    let file = file.unwrap_or_else(|| fail(&format!("{}: no such file in {}", args[4], manifest)));
+    let stdout = std::io::stdout();
+    spanned::cat(file, volume_options, &mut stdout.lock())?;
+    Ok(())
+}
+
+/// `fits HOST_DIR`: reports whether a host directory fits on a floppy.
+fn cmd_fits(args: &[String]) -> Result<()> {
+    let disk_path = &args[2];
+    let geometry = flag_value(args, "--geometry").unwrap_or("1.44M");
+    if !print_fits(disk_path, geometry) {
+        exit(1);
+    }
+    Ok(())
+}
+
+/// `gen-fixture --profile NAME -o PATH`: writes one of the test images.
+fn cmd_gen_fixture(args: &[String]) -> Result<()> {
+    let name = flag_value(args, "--profile").unwrap_or_else(|| fail("gen-fixture needs --profile"));
+    let profile = fixture::Profile::parse(name).unwrap_or_else(|| {
+        fail(&format!("unknown profile: {} (try lfn-heavy, deep-tree, fragmented or dos1x)", name))
+    });
+    let out_path = flag_value(args, "-o").unwrap_or_else(|| fail("gen-fixture needs -o PATH"));
+    let image = fixture::build(profile)?;
+    let written = if out_path == "-" {
+        std::io::stdout().write_all(&image)
+    } else {
+        fs::write(out_path, &image)
+    };
+    written.unwrap_or_else(|e| fail(&format!("{}: {}", out_path, e)));
+    Ok(())
+}
+
+/// `build MANIFEST -o OUT`: makes an image from a JSON manifest of its tree.
+fn cmd_build(args: &[String]) -> Result<()> {
+    let manifest_path = Path::new(&args[2]);
+    let out_path = flag_value(args, "-o").unwrap_or_else(|| fail("build needs -o OUT"));
+    let text = fs::read_to_string(manifest_path).unwrap_or_else(|e| fail(&format!("{}: {}", args[2], e)));
+    let manifest: serde_json::Value = serde_json::from_str(&text)
+        .unwrap_or_else(|e| fail(&format!("{}: {}", args[2], e)));
+    if !args.iter().any(|a| a == "--force") && fs::metadata(out_path).is_ok_and(|m| m.len() > 0) {
+        fail(&format!("{}: already exists; use --force to overwrite it", out_path));
+    }
+    // Sources are found next to the manifest, wherever fat12 is run from.
+    let base = manifest_path.parent().unwrap_or(Path::new(""));
+    let mut image = std::io::Cursor::new(Vec::new());
+    let built = build::build(&manifest, base, &mut image)?;
+    fs::write(out_path, image.get_ref()).unwrap_or_else(|e| fail(&format!("{}: {}", out_path, e)));
+    println!("built {}: {} files of {} bytes in all, {} directories",
+             out_path,
+             built.files,
+             built.bytes,
+             built.directories);
+    Ok(())
+}
+
+/// `overlay BASE --cow OVERLAY [--commit|--discard]`: starts an overlay on
+/// an image, which commands then change in its place, or ends one.
+fn cmd_overlay(args: &[String]) -> Result<()> {
+    let base = Path::new(&args[2]);
+    let path = flag_value(args, "--cow").unwrap_or_else(|| fail("overlay needs --cow OVERLAY"));
+    let (commit, discard) = (args.iter().any(|a| a == "--commit"), args.iter().any(|a| a == "--discard"));
+    let exists = overlay::is_overlay(path);
+    if (commit || discard) && !exists {
+        fail(&format!("{}: not an overlay", path));
+    }
+    if !exists && Path::new(path).exists() {
+        fail(&format!("{}: already exists and isn't an overlay", path));
+    }
+    let overlay = if exists {
+        overlay::Overlay::load(Path::new(path))?
+    } else {
+        overlay::Overlay::create(base, Path::new(path))?
+    };
+    if overlay.base != fs::canonicalize(base)? {
+        fail(&format!("{} is an overlay on {}, not {}", path, overlay.base.display(), base.display()));
+    }
+    if commit {
+        let written = overlay.commit(Path::new(path))?;
+        println!("committed {} sectors to {}", written, base.display());
+    } else if discard {
+        fs::remove_file(path)?;
+        println!("discarded {} changed sectors", overlay.sectors.len());
+    } else {
+        println!("{} on {}: {} sectors changed{}",
+                 path,
+                 base.display(),
+                 overlay.sectors.len(),
+                 if exists { "" } else { " (new overlay)" });
+    }
+    Ok(())
+}
+
+/// How many images the batch commands read at once: `--jobs N`, or one for
+/// each CPU.
+fn jobs(args: &[String], default: usize) -> usize {
+    match flag_value(args, "--jobs").map(|n| (n, n.parse())) {
+        None => default,
+        Some((_, Ok(jobs))) if jobs > 0 => jobs,
+        Some((n, _)) => fail(&format!("invalid job count: {}", n)),
+    }
+}
+
+/// `scrub DIR`: checks an archive of images against its manifest of hashes,
+/// reading `--jobs N` images at once.
+fn cmd_scrub(args: &[String]) -> Result<()> {
+    let dir = Path::new(&args[2]);
+    let manifest = flag_value(args, "--manifest").map_or(dir.join("hashes.json"), |m| m.into());
+    let json = progress::enabled(args);
+    let mut meter = progress::Meter::new("scrub", Box::new(std::io::stdout()));
+    let mut options = scrub::Options::default();
+    options.jobs = jobs(args, options.jobs);
+    let report = scrub::scrub_with(dir, &manifest, &options, |done, total| {
+        if json {
+            meter.update(done, total);
+        }
+    }).unwrap_or_else(|e| fail(&e.to_string()));
+    if json {
+        for warning in &report.warnings {
+            println!("{}", progress::warning_event("scrub", warning, None));
+        }
+        println!("{}",
+                 progress::done_event("scrub",
+                                      json!({
+                                          "checked": report.checked,
+                                          "added": report.added,
+                                          "problems": report.problems,
+                                      })));
+        if !report.problems.is_empty() {
+            exit(1);
+        }
+        return Ok(());
+    }
+    for name in &report.added {
+        println!("added: {}", name);
+    }
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+    for warning in &report.warnings {
+        eprintln!("fat12: warning: {}", warning);
+    }
+    println!("{} images checked, {} added to the manifest, {} problems",
+             report.checked,
+             report.added.len(),
+             report.problems.len());
+    if !report.problems.is_empty() {
+        exit(1);
+    }
+    Ok(())
+}
+
+/// `catalog DIR -o CATALOG`: records what is in every image under DIR. The
+/// catalog so far is kept as CATALOG's `.partial` every hundred images, and
+/// `--resume` carries on from it after an interruption. `--jobs N` images are
+/// read at once.
+fn cmd_catalog(args: &[String]) -> Result<()> {
+    let catalog_path = flag_value(args, "-o").unwrap_or_else(|| fail("catalog needs -o CATALOG"));
+    let resume = args[3..].iter().any(|a| a == "--resume");
+    let mut options = catalog::Options { resume, ..Default::default() };
+    options.jobs = jobs(args, options.jobs);
+    let report = catalog::catalog_with(Path::new(&args[2]), Path::new(catalog_path), &options)
+        .unwrap_or_else(|e| fail(&e.to_string()));
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+    println!("{} images cataloged, {} entries in all", report.cataloged, report.files);
+    if !report.problems.is_empty() {
+        exit(1);
+    }
+    Ok(())
+}
+
+/// `search CATALOG [NAME] [--grep TEXT] [-i]`: finds the cataloged images
+/// holding a file named NAME, a DOS wildcard, or with `--grep` the lines of
+/// their files holding TEXT, read as code page 437.
+fn cmd_search(args: &[String]) -> Result<()> {
+    let catalog = catalog::load(Path::new(&args[2])).unwrap_or_else(|e| fail(&e.to_string()));
+    let text = flag_value(args, "--grep");
+    let name = args.get(3).filter(|arg| !arg.starts_with('-')).map(|name| name.as_str());
+    let ignore_case = args[3..].iter().any(|a| a == "-i" || a == "--ignore-case");
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    let mut found = false;
+    match (name, text) {
+        (None, None) => fail("search needs a name or --grep TEXT"),
+        (Some(name), None) => {
+            for hit in catalog::search(&catalog, name) {
+                found = true;
+                writeln!(out,
+                         "{}:{}{} {} {}",
+                         hit.image,
+                         hit.path,
+                         if hit.directory { "/" } else { "" },
+                         hit.size,
+                         hit.modified.as_deref().unwrap_or("-"))?;
+            }
+        }
+        (name, Some(text)) => {
+            catalog::grep_images(&catalog,
+                                 text,
+                                 name,
+                                 ignore_case,
+                                 &mut |image, line| {
+                                     found = true;
+                                     writeln!(out, "{}:{}:{}:{}", image, line.path, line.line, line.text)
+                                         .or_exit();
+                                 },
+                                 &mut |problem| eprintln!("fat12: {}", problem))?;
+        }
+    }
+    if !found {
+        exit(1);
+    }
+    Ok(())
+}
+
+/// `ingest TRACK_IMAGE OUT`: decodes a track-level image to a sector image.
+fn cmd_ingest(args: &[String]) -> Result<()> {
+    let disk_path = &args[2];
+    let out_path = args.get(3).unwrap_or_else(|| fail("ingest needs an output image"));
+    let decoded = decode_image(disk_path);
+    for chs in &decoded.bad {
+        eprintln!("unreadable: sector {}", chs);
+    }
+    fs::write(out_path, &decoded.image).unwrap_or_else(|e| fail(&format!("{}: {}", out_path, e)));
+    // A disk with no BPB keeps its geometry in the sidecar, as with
+    // `--geometry`, if some layout fits it.
+    let mut image = std::io::Cursor::new(&decoded.image[..]);
+    if !has_boot_sector(&decoded.image) && physical::disk_info(&mut image, &decoded.physical).is_ok() {
+        physical::save(out_path, &decoded.physical)?;
+    }
+    println!("wrote {} bytes ({} tracks, {} heads, {} sectors of {} bytes) to {}; {} sectors unreadable",
+             decoded.image.len(),
+             decoded.physical.tracks,
+             decoded.physical.heads,
+             decoded.physical.sectors_per_track,
+             decoded.physical.bytes_per_sector,
+             out_path,
+             decoded.bad.len());
+    if !decoded.bad.is_empty() {
+        exit(1);
+    }
+    Ok(())
+}
+
+/// `watch SRC --target IMAGE[:/DIR] [--mkfs GEOMETRY]`: keeps DIR of the
+/// image, the root by default, in step with the host directory SRC, looking
+/// for changes twice a second until interrupted. With `--mkfs` the image is
+/// made again from nothing with that geometry on every change; otherwise the
+/// files that changed are written again and those gone deleted.
+fn cmd_watch(args: &[String], volume_options: &VolumeOptions) -> Result<()> {
+    let src = Path::new(&args[2]);
+    let target = flag_value(args, "--target").unwrap_or_else(|| fail("watch needs --target IMAGE[:/DIR]"));
+    let (image_path, dir) = match target.rfind(":/") {
+        Some(colon) => (&target[..colon], &target[colon + 1..]),
+        None => (target, "/"),
+    };
+    let geometry = flag_value(args, "--mkfs").map(|name| {
+        geometry::by_name(name).unwrap_or_else(|| fail(&format!("unknown geometry: {}", name)))
+    });
+    let mut before: Option<watch::Snapshot> = None;
+    loop {
+        let after = watch::snapshot(src).unwrap_or_else(|e| fail(&format!("{}: {}", src.display(), e)));
+        if before.as_ref() != Some(&after) {
+            let last = before.unwrap_or_default();
+            match update_image(image_path, dir, src, geometry, volume_options, &last, &after) {
+                Ok(update) => {
+                    println!("{} {}: {} written, {} directories made, {} removed",
+                             Local::now().format("%H:%M:%S"),
+                             image_path,
+                             update.written,
+                             update.made,
+                             update.removed)
+                }
+                Err(e) => eprintln!("fat12: {}: {}", image_path, e),
+            }
+            before = Some(after);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(500));
+    }
+}
+
+/// One round of `watch`: brings DIR of the image in step with the host tree
+/// as it is now, `after`, from `before`, or makes the image again from
+/// nothing with `geometry`.
+fn update_image(image_path: &str,
+                dir: &str,
+                src: &Path,
+                geometry: Option<&geometry::Geometry>,
+                volume_options: &VolumeOptions,
+                before: &watch::Snapshot,
+                after: &watch::Snapshot)
+                -> Result<watch::Update> {
+    let mut image_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(geometry.is_some())
+        .truncate(false)
+        .open(image_path)?;
+    let _lock = lock::exclusive(&image_file, image_path, "watch", warnings())?;
+    let geometry = match geometry {
+        Some(geometry) => geometry,
+        None => {
+            let info = read_disk_info_with(&mut image_file, volume_options)?;
+            return watch::update(&info, &mut image_file, src, dir, before, after);
+        }
+    };
+    // Made in memory and written over the old image in place, so an
+    // emulator with it open sees the new one.
+    let mut image = std::io::Cursor::new(Vec::new());
+    format(&mut image, geometry)?;
+    let info = read_disk_info_with(&mut image, volume_options)?;
+    let update = watch::update(&info, &mut image, src, dir, &watch::Snapshot::new(), after)?;
+    image_file.seek(SeekFrom::Start(0))?;
+    image_file.write_all(image.get_ref())?;
+    image_file.set_len(image.get_ref().len() as u64)?;
+    Ok(update)
+}
+
+/// `rescue SOURCE OUT`: copies what can be read of a failing disk, with a map.
+/// The map is written as the rescue goes, every megabyte, and `--resume`
+/// carries on from it: only what it has as bad or untried is read again.
+fn cmd_rescue(args: &[String]) -> Result<()> {
+    let disk_path = &args[2];
+    let out_path = args.get(3).unwrap_or_else(|| fail("rescue needs an output image"));
+    let map_path = flag_value(args, "--map").map_or(format!("{}.map", out_path), |m| m.to_string());
+    let resume = args[3..].iter().any(|a| a == "--resume");
+    let options = rescue::Options {
+        sector_size: 512,
+        retries: flag_value(args, "--retries").map_or(3, |n| {
+            n.parse().unwrap_or_else(|_| fail(&format!("invalid retry count: {}", n)))
+        }),
+        fill: flag_value(args, "--fill").unwrap_or("BADSECTOR!").as_bytes().to_vec(),
+        resume: if resume {
+            let map = fs::read_to_string(&map_path)
+                .and_then(|text| rescue::read_map(&text))
+                .unwrap_or_else(|e| fail(&format!("{}: {}", map_path, e)));
+            Some(map)
+        } else {
+            None
+        },
+        checkpoint_every: 1 << 20,
+    };
+    if options.fill.is_empty() {
+        fail("the fill pattern can't be empty");
+    }
+    let mut source = File::open(disk_path).unwrap_or_else(|e| fail(&format!("{}: {}", disk_path, e)));
+    let mut out = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(!resume)
+        .open(out_path)
+        .unwrap_or_else(|e| fail(&format!("{}: {}", out_path, e)));
+    let write_map = |map: &rescue::Rescue| -> std::io::Result<()> {
+        let partial = format!("{}.partial", map_path);
+        map.write_map(&mut File::create(&partial)?)?;
+        fs::rename(&partial, &map_path)
+    };
+    let json = progress::enabled(args);
+    let result = rescue::rescue(&mut source, &mut out, &options, |done, total, error| {
+        match error {
+            Some((start, error)) if json => {
+                progress::warning("rescue", &error.to_string(), Some((start, done)))
+            }
+            Some((start, error)) => eprintln!("unreadable: bytes {}-{}: {}", start, done - 1, error),
+            None if json => progress::progress("rescue", done, total),
+            None => (),
+        }
+    }, write_map).unwrap_or_else(|e| fail(&e.to_string()));
+    write_map(&result)?;
+    if json {
+        println!("{}",
+                 progress::done_event("rescue",
+                                      json!({
+                                          "size": result.size,
+                                          "unreadable": result.bad_bytes(),
+                                          "ranges": result.bad.len(),
+                                          "map": map_path,
+                                      })));
+    } else {
+        println!("rescued {} of {} bytes; {} bytes unreadable in {} ranges; map written to {}",
+                 result.size - result.bad_bytes(),
+                 result.size,
+                 result.bad_bytes(),
+                 result.bad.len(),
+                 map_path);
+    }
+    if !result.bad.is_empty() {
+        exit(1);
+    }
+    Ok(())
+}
+
+/// `put HOST_FILE... PATH`: copies host files into the image.
+fn cmd_put(args: &[String], volume_options: &VolumeOptions) -> Result<()> {
+    // Host files, then the path in the image: a directory if there are
+    // several files.
+    let operands: Vec<&String> = args[3..].iter().take_while(|a| !a.starts_with("--")).collect();
+    let (path, host_paths) = match operands.split_last() {
+        Some((path, host_paths)) if !host_paths.is_empty() => (path, host_paths),
+        _ => fail("put needs a host file and a path in the image"),
+    };
+    // `--preserve-times` takes the creation and access times from the
+    // host file as well as the modification time.
+    let preserve = args[3..].iter().any(|a| a == "--preserve-times");
+    // `--short-names` stores 8.3 names only, cut down as DOS would.
+    let mut volume_options = volume_options.clone();
+    if args[3..].iter().any(|a| a == "--short-names") {
+        volume_options.name_mapping = Some(names::shared(names::Truncate));
+    }
+    let mut files: Vec<(&Path, Times)> = host_paths.iter()
+        .map(|host_path| {
+            let host_path = Path::new(host_path.as_str());
+            (host_path, flag_times(args, Some(host_path).filter(|_| preserve)))
+        })
+        .collect();
+    // `--order-file LIST` names the files that go first, one to a line, for
+    // boot disks whose system files have to lead the directory and the data.
+    if let Some(list) = flag_value(args, "--order-file") {
+        let text = fs::read_to_string(list).unwrap_or_else(|e| fail(&format!("{}: {}", list, e)));
+        let order: Vec<String> = text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(String::from)
+            .collect();
+        order_files(&mut files, &order);
+    }
+    modify_image(args, false, |disk_file| {
+        let info = read_disk_info_with(disk_file, &volume_options)?;
+        match files[..] {
+            [(host_path, ref times)] => put_with_times(&info, disk_file, host_path, path, times),
+            _ => put_files(&info, disk_file, &files, path),
+        }
+    });
+    Ok(())
+}
+
+/// `touch PATH`: sets the times of an entry.
+fn cmd_touch(args: &[String], volume_options: &VolumeOptions) -> Result<()> {
+    let path = args.get(3).unwrap_or_else(|| fail("touch needs a path in the image"));
+    let mut times = flag_times(args, flag_value(args, "--reference").map(Path::new));
+    if times == Times::default() {
+        let now = Local::now().naive_local();
+        times = Times { created: None, modified: Some(now), accessed: Some(now) };
+    }
+    modify_image(args, false, |disk_file| {
+        let info = read_disk_info_with(disk_file, volume_options)?;
+        touch(&info, disk_file, path, &times)
+    });
+    Ok(())
+}
+
+/// `cp-image PATH DST_IMAGE DST_PATH`: copies a file from one image to another.
+fn cmd_cp_image(args: &[String], volume_options: &VolumeOptions) -> Result<()> {
+    let disk_path = &args[2];
+    let (src_path, dst_image, dst_path) = match (args.get(3), args.get(4), args.get(5)) {
+        (Some(src_path), Some(dst_image), Some(dst_path)) => (src_path, dst_image, dst_path),
+        _ => fail("cp-image needs a source image and path, and a destination image and path"),
+    };
+    // The destination is the image being changed, so it stands in for
+    // the image argument when it is locked, audited and written back.
+    let dst_args: Vec<String> = [&args[..2], &args[4..]].concat();
+    if disk_path == dst_image {
+        if disk_path == STREAM {
+            fail("only one of the images can be on stdin");
+        }
+        modify_image(&dst_args, false, |dst| {
+            let info = read_disk_info_with(dst, volume_options)?;
+            copy_between(&info, &mut dst.try_clone()?, src_path, &info, dst, dst_path)
+        });
+        return Ok(());
+    }
+    let (mut src, volume_options, _lock) = open_image(args, disk_path, volume_options.clone());
+    let src_info = read_disk_info_with(&mut src, &volume_options)?;
+    // The destination goes by its own sidecar, not the source's geometry.
+    let dst_geometry = physical::load(dst_image)?;
+    let dst_options = VolumeOptions { geometry: dst_geometry, ..volume_options.clone() };
+    modify_image(&dst_args, false, |dst| {
+        let dst_info = read_disk_info_with(dst, &dst_options)?;
+        copy_between(&src_info, &mut src, src_path, &dst_info, dst, dst_path)
+    });
+    Ok(())
+}
+
+/// `browse [DIR]`: the two-pane file manager, with the image on the left and
+/// DIR, or the current directory, on the right.
+#[cfg(feature = "tui")]
+fn cmd_browse(args: &[String], volume_options: &VolumeOptions) -> Result<()> {
+    let disk_path = &args[2];
+    if disk_path == STREAM || !std::io::stdout().is_terminal() {
+        fail("browse needs a terminal, and an image file rather than stdin");
     }
-    let (command, disk_path) = (args.nth(1).unwrap(), args.next().unwrap());
-    let mut disk_file = File::open(disk_path).unwrap();
+    let host_dir = match args.get(3) {
+        Some(dir) => PathBuf::from(dir),
+        None => env::current_dir()?,
+    };
+    let image_name = Path::new(disk_path).file_name().map_or(disk_path.clone(), |name| {
+        name.to_string_lossy().into_owned()
+    });
+    modify_image(args, false, |disk_file| {
+        let volume = Fat12Volume::open_writable_with(disk_file, volume_options)?;
+        tui::run(volume, &image_name, &host_dir)
+    });
+    Ok(())
+}
+
+/// `mkdir PATH`: creates a directory.
+fn cmd_mkdir(args: &[String], volume_options: &VolumeOptions) -> Result<()> {
+    let path = args.get(3).unwrap_or_else(|| fail("mkdir needs a path in the image"));
+    modify_image(args, false, |disk_file| {
+        let info = read_disk_info_with(disk_file, volume_options)?;
+        mkdir(&info, disk_file, path)
+    });
+    Ok(())
+}
 
+/// `undelete [PATH]`: lists deleted entries, or restores the one at `PATH`.
+fn cmd_undelete(args: &[String], volume_options: &VolumeOptions) -> Result<()> {
+    let disk_path = &args[2];
+    let path = match args.get(3).filter(|a| !a.starts_with("--")) {
+        Some(path) => path,
+        None => {
+            let (mut disk_file, volume_options, _lock) = open_image(args, disk_path, volume_options.clone());
+            let info = read_disk_info_with(&mut disk_file, &volume_options)?;
+            list_deleted(&info, &mut disk_file)?;
+            return Ok(());
+        }
+    };
+    // The first character comes from `--first-char`, or from the path
+    // when it isn't the `_` the listing shows.
+    let first_char = match flag_value(args, "--first-char") {
+        Some(c) if c.len() == 1 => Some(c.as_bytes()[0]),
+        Some(c) => fail(&format!("--first-char needs one character, not {}", c)),
+        None => path.rsplit('/').next().and_then(|name| name.bytes().next())
+            .filter(|&c| c != undelete::UNKNOWN_FIRST_CHAR),
+    };
+    let restored = modify_image(args, false, |disk_file| {
+        let info = read_disk_info_with(disk_file, volume_options)?;
+        let deleted = undelete::find(&info, disk_file)?;
+        let mut matching = deleted.iter().filter(|deleted| deleted.matches(path)).collect::<Vec<_>>();
+        // Of several entries deleted under the same name, the one that
+        // can still be restored.
+        matching.sort_by_key(|deleted| !deleted.recoverable());
+        match matching.first() {
+            Some(deleted) => undelete::restore(&info, disk_file, deleted, first_char),
+            None => Err(Error::NotFound(path.to_string())),
+        }
+    });
+    writeln!(report(args), "restored {}", restored)?;
+    Ok(())
+}
+
+/// `reformat --quick|--full`: formats the image again in its own layout.
+fn cmd_reformat(args: &[String], volume_options: &VolumeOptions) -> Result<()> {
+    let flags = &args[3..];
+    let options = ReformatOptions {
+        full: flags.iter().any(|f| f == "--full"),
+        scan: flags.iter().any(|f| f == "--scan"),
+        keep_label: flags.iter().any(|f| f == "--keep-label"),
+        keep_serial: flags.iter().any(|f| f == "--keep-serial"),
+        keep_bootcode: flags.iter().any(|f| f == "--keep-bootcode"),
+    };
+    let (full, scan) = (options.full, options.scan);
+    if full == flags.iter().any(|f| f == "--quick") {
+        fail("reformat needs either --quick or --full");
+    }
+    if scan && !full {
+        fail("--scan needs --full");
+    }
+    let json = progress::enabled(args);
+    let mut meter = progress::Meter::new("reformat", report(args));
+    let result = modify_image(args, false, |disk_file| {
+        let info = read_disk_info_with(disk_file, volume_options)?;
+        reformat_with_progress(&info, disk_file, &options, |done, total| {
+            if json {
+                meter.update(done, total);
+            }
+        })
+    });
+    let mut out = report(args);
+    if json {
+        let fields = json!({
+            "zeroed": result.zeroed,
+            "bad_clusters": result.bad_clusters,
+            "volume_id": result.volume_id,
+        });
+        writeln!(out, "{}", progress::done_event("reformat", fields))?;
+    } else if full {
+        writeln!(out,
+                 "full format: {} clusters zeroed, {} bad clusters {}",
+                 result.zeroed,
+                 result.bad_clusters,
+                 if scan { "found by the surface scan" } else { "kept" })?;
+    } else {
+        writeln!(out,
+                 "quick format: FATs and root directory reset, {} bad clusters kept",
+                 result.bad_clusters)?;
+    }
+    if let Some(id) = result.volume_id.filter(|_| !json) {
+        writeln!(out, "volume serial number is {:04X}-{:04X}", id >> 16, id & 0xFFFF)?;
+    }
+    Ok(())
+}
+
+/// `format --geometry NAME|--size SIZE`: creates an empty image.
+fn cmd_format(args: &[String]) -> Result<()> {
+    let disk_path = &args[2];
+    let geometry = match (flag_value(args, "--geometry"), flag_value(args, "--size")) {
+        (Some(name), None) => {
+            let geometry = geometry::by_name(name);
+            geometry.cloned().unwrap_or_else(|| fail(&format!("unknown geometry: {}", name)))
+        }
+        (None, Some(size)) => {
+            // Floppies also go by the names they're sold under, `1.44M` and so on.
+            let bytes = geometry::by_name(size).map(|g| g.size()).or_else(|| memory::parse_size(size));
+            let bytes = bytes.unwrap_or_else(|| {
+                fail(&format!("invalid size: {}; give bytes, K, M or G, or a floppy such as 1.44M", size))
+            });
+            geometry::for_size(bytes)
+                .unwrap_or_else(|| fail(&format!("can't format {} bytes as FAT12 or FAT16", bytes)))
+        }
+        _ => fail("format needs either --geometry or --size"),
+    };
+    let force = args.iter().any(|a| a == "--force");
+    if disk_path != STREAM && !force && fs::metadata(disk_path).is_ok_and(|m| m.len() > 0) {
+        fail(&format!("{}: already exists; use --force to overwrite it, or reformat", disk_path));
+    }
+    let volume_id = modify_image(args, true, |disk_file| {
+        disk_file.set_len(0)?;
+        format(disk_file, &geometry)
+    });
+    let mut out = report(args);
+    writeln!(out,
+             "formatted {}: {} {}, {} clusters of {} bytes",
+             disk_path,
+             geometry.name,
+             geometry.fat_type().name(),
+             geometry.clusters(),
+             geometry.sectors_per_cluster as u32 * geometry::SECTOR_SIZE as u32)?;
+    writeln!(out, "volume serial number is {:04X}-{:04X}", volume_id >> 16, volume_id & 0xFFFF)?;
+    Ok(())
+}
+
+/// `fill`: stamps every free cluster with its number.
+fn cmd_fill(args: &[String], volume_options: &VolumeOptions) -> Result<()> {
+    let json = progress::enabled(args);
+    let mut meter = progress::Meter::new("fill", report(args));
+    let stamped = modify_image(args, false, |disk_file| {
+        let info = read_disk_info_with(disk_file, volume_options)?;
+        fill_free_with_progress(&info, disk_file, |done, total| {
+            if json {
+                meter.update(done, total);
+            }
+        })
+    });
+    if json {
+        writeln!(report(args), "{}", progress::done_event("fill", json!({"stamped": stamped})))?;
+    } else {
+        writeln!(report(args), "stamped {} free clusters with their numbers", stamped)?;
+    }
+    Ok(())
+}
+
+/// `corrupt --kind KIND`: damages the image, for testing repairs.
+fn cmd_corrupt(args: &[String], volume_options: &VolumeOptions) -> Result<()> {
+    let kind = flag_value(args, "--kind").unwrap_or_else(|| fail("corrupt needs --kind"));
+    let kind = corrupt::Kind::parse(kind).unwrap_or_else(|| {
+        fail(&format!("unknown kind: {} (crosslink, loop, bad-bpb or orphan-chain)", kind))
+    });
+    let seed = flag_value(args, "--seed").map_or(0, |n| {
+        n.parse().unwrap_or_else(|_| fail(&format!("invalid seed: {}", n)))
+    });
+    let done = modify_image(args, false, |disk_file| {
+        let info = read_disk_info_with(disk_file, volume_options)?;
+        corrupt::corrupt(&info, disk_file, kind, seed)
+    });
+    match done {
+        Some(description) => writeln!(report(args), "{}", description)?,
+        None => fail("the volume has nothing to damage that way"),
+    }
+    Ok(())
+}
+
+/// `rm PATH`: deletes a file.
+fn cmd_rm(args: &[String], volume_options: &VolumeOptions) -> Result<()> {
+    let path = args.get(3).unwrap_or_else(|| fail("rm needs a path in the image"));
+    // Hidden and system files are what a boot disk boots from; deleting
+    // one takes --include-system.
+    let include_system = args.iter().any(|a| a == "--include-system");
+    let mut volume_options = volume_options.clone();
+    if !include_system {
+        volume_options.policy = Some(policy::protect_system());
+    }
+    let disk_path = &args[2];
+    modify_image(args, false, |disk_file| {
+        let info = read_disk_info_with(disk_file, &volume_options)?;
+        let deletion = rm(&info, disk_file, path).map_err(|e| match e {
+            Error::Denied(why) => Error::Denied(format!("{} (--include-system deletes it anyway)", why)),
+            e => e,
+        })?;
+        if disk_path != STREAM && undo::enabled(disk_path, args) {
+            undo::record(disk_path, &deletion)?;
+        }
+        Ok(())
+    });
+    Ok(())
+}
+
+/// `undo [N]`: puts back the last `N` files deleted with `--undoable`.
+fn cmd_undo(args: &[String], volume_options: &VolumeOptions) -> Result<()> {
+    // `fat12 undo IMAGE [N]` puts back the last N files deleted with
+    // --undoable, newest first.
+    let disk_path = &args[2];
+    let count = args.get(3).filter(|a| !a.starts_with('-')).map_or(1, |n| {
+        n.parse().unwrap_or_else(|_| fail(&format!("invalid count: {}", n)))
+    });
+    if disk_path == STREAM {
+        fail("an image read from stdin has no undo log");
+    }
+    let mut deletions = undo::load(disk_path)?;
+    if deletions.is_empty() {
+        fail(&format!("{}: nothing to undo", disk_path));
+    }
+    let undone = deletions.split_off(deletions.len().saturating_sub(count));
+    modify_image(args, false, |disk_file| {
+        let info = read_disk_info_with(disk_file, volume_options)?;
+        undo::undo(&info, disk_file, &undone)?;
+        undo::save(disk_path, &deletions)?;
+        Ok(())
+    });
+    let mut out = report(args);
+    for deletion in undone.iter().rev() {
+        writeln!(out, "restored {}", deletion.path)?;
+    }
+    Ok(())
+}
+
+/// `attrib PATH +R -H ...`: changes the attributes of an entry.
+fn cmd_attrib(args: &[String], volume_options: &VolumeOptions) -> Result<()> {
+    let path = &args[3];
+    let (set, clear) = attribute_changes(&args[4..]);
+    let attributes = modify_image(args, false, |disk_file| {
+        let info = read_disk_info_with(disk_file, volume_options)?;
+        set_attributes(&info, disk_file, path, set, clear)
+    });
+    writeln!(report(args), "{} {}", attribute_string(attributes), path)?;
+    Ok(())
+}
+
+/// `redact`: clears labels, timestamps, deleted entries or slack.
+fn cmd_redact(args: &[String], volume_options: &VolumeOptions) -> Result<()> {
+    let flags = &args[3..];
+    let options = RedactOptions {
+        wipe_labels: flags.iter().any(|f| f == "--wipe-labels"),
+        zero_timestamps: flags.iter().any(|f| f == "--zero-timestamps"),
+        strip_deleted: flags.iter().any(|f| f == "--strip-deleted"),
+        zero_slack: flags.iter().any(|f| f == "--zero-slack"),
+    };
+    let lines = modify_image(args, false, |disk_file| {
+        let info = read_disk_info_with(disk_file, volume_options)?;
+        redact(&info, disk_file, &options)
+    });
+    let mut out = report(args);
+    if lines.is_empty() {
+        writeln!(out, "nothing to redact")?;
+    }
+    for line in lines {
+        writeln!(out, "{}", line)?;
+    }
+    Ok(())
+}
+
+/// `restore --from DIR`: writes a backup back to the image.
+fn cmd_restore(args: &[String]) -> Result<()> {
+    let dir = flag_value(args, "--from").unwrap_or_else(|| fail("restore needs --from DIR"));
+    let at = flag_value(args, "--at").map(|at| dates::parse(at, true).unwrap_or_else(|e| fail(&e)));
+    let (backup, image) = backup::restore(Path::new(dir), at)
+        .unwrap_or_else(|e| fail(&e.to_string()));
+    modify_image(args, true, |disk_file| {
+        disk_file.set_len(0)?;
+        disk_file.seek(SeekFrom::Start(0))?;
+        Ok(disk_file.write_all(&image)?)
+    });
+    writeln!(report(args), "restored backup {} taken {}", backup.seq, backup.time)?;
+    Ok(())
+}
+
+/// `compact-dir [PATH]`: squeezes deleted slots out of a directory.
+fn cmd_compact_dir(args: &[String], volume_options: &VolumeOptions) -> Result<()> {
+    let path = args.get(3).map_or("/", |p| p.as_str());
+    let compaction = modify_image(args, false, |disk_file| {
+        let info = read_disk_info_with(disk_file, volume_options)?;
+        compact_dir(&info, disk_file, path)
+    });
+    writeln!(report(args),
+             "{} slots removed, {} clusters freed",
+             compaction.slots,
+             compaction.clusters)?;
+    Ok(())
+}
+
+/// The layout `grow-root --entries N` or `recluster --sectors-per-cluster N`
+/// asks for, the rest as the volume has it.
+fn layout_arg(args: &[String], info: &DiskInfo) -> relayout::Layout {
+    let mut layout = relayout::Layout::of(info);
+    if args[1] == "recluster" {
+        let sectors = flag_value(args, "--sectors-per-cluster")
+            .unwrap_or_else(|| fail("recluster needs --sectors-per-cluster N"));
+        layout.sectors_per_cluster = sectors.parse()
+            .unwrap_or_else(|_| fail(&format!("invalid sectors per cluster: {}", sectors)));
+        return layout;
+    }
+    let entries = flag_value(args, "--entries").unwrap_or_else(|| fail("grow-root needs --entries N"));
+    layout.root_dir_entries = entries.parse()
+        .unwrap_or_else(|_| fail(&format!("invalid entry count: {}", entries)));
+    if layout.root_dir_entries < info.root_dir_entries {
+        fail(&format!("the root directory has {} entries already", info.root_dir_entries));
+    }
+    layout
+}
+
+/// Prints what laying the volume out anew takes. Returns whether it fits.
+fn print_plan(plan: &relayout::Plan, bytes_per_sector: u16, out: &mut dyn Write) -> Result<bool> {
+    // A layout that can't be had at all has no clusters to count.
+    if plan.clusters == 0 {
+        writeln!(out, "doesn't fit: {}", plan.problem.as_deref().unwrap_or("no clusters"))?;
+        return Ok(false);
+    }
+    writeln!(out,
+             "root directory: {} entries, {} in use",
+             plan.root_dir_entries,
+             plan.root_slots_needed)?;
+    writeln!(out,
+             "clusters: {} of {} bytes, {} needed",
+             plan.clusters,
+             plan.sectors_per_cluster as u32 * bytes_per_sector as u32,
+             plan.clusters_needed)?;
+    writeln!(out, "FATs: {} sectors each", plan.sectors_per_fat)?;
+    if let Some(ref problem) = plan.problem {
+        writeln!(out, "doesn't fit: {}", problem)?;
+    }
+    Ok(plan.problem.is_none())
+}
+
+/// `grow-root --entries N`: makes the root directory bigger, moving the data
+/// along; `recluster --sectors-per-cluster N` changes the cluster size. With
+/// `--dry-run` they only say what that would take.
+fn cmd_relayout(args: &[String], volume_options: &VolumeOptions) -> Result<()> {
+    let (plan, bytes_per_sector) = modify_image(args, false, |disk_file| {
+        let info = read_disk_info_with(disk_file, volume_options)?;
+        Ok((relayout::relayout(&info, disk_file, layout_arg(args, &info))?, info.bytes_per_sector))
+    });
+    print_plan(&plan, bytes_per_sector, &mut *report(args))?;
+    Ok(())
+}
+
+/// `check --repair`: checks the volume and repairs what it can.
+fn cmd_check_repair(args: &[String], volume_options: &VolumeOptions) -> Result<()> {
+    let json = args.iter().any(|a| a == "--json");
+    let check = modify_image(args, false, |disk_file| {
+        let info = read_disk_info_with(disk_file, volume_options)?;
+        let check = check::check(&info, disk_file)?;
+        check.repair(&info, disk_file)?;
+        Ok(check)
+    });
+    if json {
+        writeln!(report(args), "{}", json::check(&check, true))?;
+        if check.findings.iter().any(|finding| !finding.repairable) {
+            exit(1);
+        }
+    } else if !print_check(&check, true, &mut *report(args)) {
+        exit(1);
+    }
+    Ok(())
+}
+
+/// `recover-bpb --write`: rebuilds a damaged boot sector in place.
+fn cmd_recover_bpb_write(args: &[String]) -> Result<()> {
+    modify_image(args, false, |disk_file| recover_bpb(disk_file, true, &mut *report(args)));
+    Ok(())
+}
+
+/// Runs the commands that only read the image, wherever it is.
+fn cmd_read(args: &[String], volume_options: VolumeOptions) -> Result<()> {
+    let (command, disk_path) = (&args[1], &args[2]);
+    if is_url(disk_path) || is_s3(disk_path) {
+        let mut args = args.to_vec();
+        let dir = cache_dir(&mut args);
+        let max_memory = volume_options.max_memory;
+        if is_url(disk_path) {
+            browse(&args, open_url(disk_path, dir, max_memory), &volume_options);
+        } else {
+            browse(&args, open_s3(disk_path, dir, max_memory), &volume_options);
+        }
+        return Ok(());
+    }
+    if is_sftp(disk_path) {
+        browse(args, open_sftp(disk_path), &volume_options);
+        return Ok(());
+    }
+    let (mut disk_file, volume_options, _lock) = open_image(args, disk_path, volume_options);
+    match command.as_ref() {
+        "recover-bpb" => recover_bpb(&mut disk_file, false, &mut std::io::stdout())?,
+        #[cfg(feature = "fuse")]
+        "mount" => {
+            let mountpoint = args.get(3).unwrap_or_else(|| fail("mount needs a mount point"));
+            mount::mount(disk_file, &volume_options, Path::new(mountpoint), disk_path)?;
+        }
+        #[cfg(not(feature = "fuse"))]
+        "mount" => fail("this fat12 was built without FUSE support; rebuild it with `--features fuse`"),
+        _ => browse(args, disk_file, &volume_options),
+    }
+    Ok(())
+}
+
+/// Runs the commands that only read the image, from a file or a URL alike.
+fn browse<S: Source>(args: &[String], mut disk_file: S, volume_options: &VolumeOptions) {
+    let (command, disk_path) = (&args[1], &args[2]);
+    // `--json` makes `info`, `list`, `tree`, `df`, `check` and `stat` print
+    // JSON with every field, for scripts.
+    let json = args.iter().any(|a| a == "--json");
     match command.as_ref() {
+        "info" if json => {
+            let info = read_disk_info_with(&mut disk_file, volume_options).or_exit();
+            println!("{}", json::disk_info(&info));
+        }
         "info" => {
-            let info = read_disk_info(&mut disk_file).unwrap();
-            println!("{}", std::str::from_utf8(&info.os_name).unwrap());
+            let info = read_disk_info_with(&mut disk_file, volume_options).or_exit();
+            println!("{}", String::from_utf8_lossy(&info.os_name));
             println!("0x{:X}", info.bytes_per_sector);
+            if bpb_looks_valid(&info) {
+                println!("{}", info.fat_type().name());
+                if info.fat_type() == FatType::Fat32 {
+                    println!("root cluster {}, backup boot sector {}",
+                             info.root_cluster,
+                             info.backup_boot_sector);
+                    match read_fs_info(&info, &mut disk_file).or_exit() {
+                        Some(FsInfo { free_clusters: Some(free), .. }) => println!("{} free clusters (FSInfo)", free),
+                        Some(_) => println!("free cluster count unknown (FSInfo)"),
+                        None => println!("no valid FSInfo sector"),
+                    }
+                }
+            } else {
+                println!("warning: the BPB looks damaged; try `fat12 recover-bpb`");
+            }
+        }
+        "test" => {
+            let info = read_disk_info_with(&mut disk_file, volume_options).or_exit();
+            match check_predicates(&info, &mut disk_file, &args[3..]) {
+                Ok(true) => (),
+                Ok(false) => exit(1),
+                Err(message) => {
+                    eprintln!("fat12: {}", message);
+                    exit(2);
+                }
+            }
+        }
+        "df" => {
+            let info = read_disk_info_with(&mut disk_file, volume_options).or_exit();
+            if !bpb_looks_valid(&info) {
+                fail("the BPB looks damaged; try `fat12 recover-bpb`");
+            }
+            let space = free_space(&info, &*fat_of(&info, &disk_file).or_exit());
+            if json {
+                println!("{}", json::free_space(&space));
+            } else {
+                print_df(&space, args.iter().any(|a| a == "--human"));
+            }
+        }
+        "grow-root" | "recluster" => {
+            let info = read_disk_info_with(&mut disk_file, volume_options).or_exit();
+            let plan = relayout::plan(&info, &mut disk_file, layout_arg(args, &info)).or_exit();
+            if !print_plan(&plan, info.bytes_per_sector, &mut std::io::stdout()).or_exit() {
+                exit(1);
+            }
+        }
+        "du" => {
+            let info = read_disk_info_with(&mut disk_file, volume_options).or_exit();
+            let path = args.get(3).map_or("/", |p| p.as_str());
+            if !du(&info, &mut disk_file, path).or_exit() {
+                exit(1);
+            }
+        }
+        "cat" => {
+            let info = read_disk_info_with(&mut disk_file, volume_options).or_exit();
+            let name = args.get(3).unwrap_or_else(|| fail("cat needs a file name"));
+            if !cat(&info, &mut disk_file, name).or_exit() {
+                exit(1);
+            }
+        }
+        "grep" => {
+            let info = read_disk_info_with(&mut disk_file, volume_options).or_exit();
+            let pattern = args.get(3).unwrap_or_else(|| fail("grep needs a pattern"));
+            // `--include` takes wildcards separated by `;`, as in `*.SYS;*.BAT`.
+            let options = grep::GrepOptions {
+                ignore_case: args[4..].iter().any(|a| a == "-i" || a == "--ignore-case"),
+                include: flag_value(args, "--include")
+                    .map_or(Vec::new(), |include| include.split(';').map(String::from).collect()),
+            };
+            let fat = fat_of(&info, &disk_file).or_exit();
+            let stdout = std::io::stdout();
+            let mut out = stdout.lock();
+            let mut matched = false;
+            grep::grep(&info, &mut disk_file, &*fat, pattern, &options, &mut |found| {
+                    matched = true;
+                    writeln!(out, "{}:{}:{}", found.path, found.line, found.text).or_exit();
+                })
+                .or_exit();
+            if !matched {
+                exit(1);
+            }
+        }
+        "view" => {
+            let info = read_disk_info_with(&mut disk_file, volume_options).or_exit();
+            let name = args.get(3).unwrap_or_else(|| fail("view needs a file name"));
+            let flags = &args[4..];
+            let hex = if flags.iter().any(|f| f == "--hex") {
+                Some(true)
+            } else if flags.iter().any(|f| f == "--text") {
+                Some(false)
+            } else {
+                None
+            };
+            let page = !flags.iter().any(|f| f == "--no-pager");
+            if !view(&info, &mut disk_file, name, hex, page).or_exit() {
+                exit(1);
+            }
+        }
+        "extract" => {
+            let info = read_disk_info_with(&mut disk_file, volume_options).or_exit();
+            let (path, host_path) = match (args.get(3), args.get(4)) {
+                (Some(path), Some(host_path)) => (path, host_path),
+                _ => fail("extract needs a path in the image and a host path"),
+            };
+            let policy = match flag_value(args, "--on-collision") {
+                Some(policy) => {
+                    extract::Collision::parse(policy)
+                        .unwrap_or_else(|| fail(&format!("unknown collision policy: {}", policy)))
+                }
+                None if args[5..].iter().any(|a| a == "--force") => extract::Collision::Overwrite,
+                None => extract::Collision::Error,
+            };
+            let mut placer = extract::Placer::new(policy);
+            // Host files only get modification and access times set.
+            let times = flag_times(args, None);
+            let ok = extract(&info, &mut disk_file, path, Path::new(host_path), &mut placer, &times)
+                .unwrap_or_else(|e| match e {
+                    Error::AlreadyExists(_) => {
+                        fail(&format!("{}; use --force to replace it or --on-collision skip|suffix", e))
+                    }
+                    e => exit_with(e),
+                });
+            if let Some(map) = load_map(args, &info) {
+                warn_unrecovered(&info, &mut disk_file, path, &map).or_exit();
+            }
+            let mut ok = ok;
+            if args[5..].iter().any(|a| a == "--unpack") {
+                let placed = placer.placed().to_vec();
+                let (unpacked, all) = unpack::unpack_all(&placed, &mut placer).or_exit();
+                if unpacked > 0 {
+                    eprintln!("fat12: {} archives unpacked", unpacked);
+                }
+                ok &= all;
+            }
+            if !ok {
+                exit(1);
+            }
+        }
+        "bodyfile" => {
+            let info = read_disk_info_with(&mut disk_file, volume_options).or_exit();
+            let mount = flag_value(args, "--mount").unwrap_or("");
+            let stdout = std::io::stdout();
+            let fat = fat_of(&info, &disk_file).or_exit();
+            bodyfile::write(&info, &mut disk_file, &*fat, mount, &mut stdout.lock()).or_exit();
+        }
+        "check" => {
+            let info = read_disk_info_with(&mut disk_file, volume_options).or_exit();
+            let check = check::check(&info, &mut disk_file).or_exit();
+            if json {
+                println!("{}", json::check(&check, false));
+                if !check.is_clean() {
+                    exit(1);
+                }
+            } else if !print_check(&check, false, &mut std::io::stdout()) {
+                exit(1);
+            }
+        }
+        "health" => {
+            let info = read_disk_info_with(&mut disk_file, volume_options).or_exit();
+            if !bpb_looks_valid(&info) {
+                fail("the BPB looks damaged; try `fat12 recover-bpb`");
+            }
+            let fat = fat_of(&info, &disk_file).or_exit();
+            let health = health::check(&info, &mut disk_file, &*fat).or_exit();
+            println!("{}: {} / 100 (grade {})", disk_path, health.score(), health.grade());
+            println!("  FAT copies:    {}",
+                     if health.fat_mismatches == 0 {
+                         "agree".to_string()
+                     } else {
+                         format!("{} of {} differ from the first", health.fat_mismatches, info.fats - 1)
+                     });
+            println!("  bad clusters:  {}", health.bad_clusters);
+            println!("  fragmentation: {} of {} files", health.fragmented_files, health.files);
+            println!("  problems:      {}", health.problems.len());
+            for problem in &health.problems {
+                println!("    {}", problem);
+            }
+        }
+        "dfxml" => {
+            let info = read_disk_info_with(&mut disk_file, volume_options).or_exit();
+            let size = disk_file.seek(SeekFrom::End(0)).or_exit();
+            let stdout = std::io::stdout();
+            let fat = fat_of(&info, &disk_file).or_exit();
+            dfxml::export(&info, &mut disk_file, &*fat, disk_path, size, &mut stdout.lock()).or_exit();
+        }
+        "tree" => {
+            let info = read_disk_info_with(&mut disk_file, volume_options).or_exit();
+            let flags = &args[3..];
+            print_tree(&info,
+                       &mut disk_file,
+                       flags.iter().any(|f| f == "--deleted"),
+                       flags.iter().any(|f| f == "--human"),
+                       json).or_exit();
+        }
+        "locate" => {
+            let info = read_disk_info_with(&mut disk_file, volume_options).or_exit();
+            let path = args.get(3).unwrap_or_else(|| fail("locate needs a path in the image"));
+            if !locate(&info, &mut disk_file, path).or_exit() {
+                exit(1);
+            }
+        }
+        "export-tracks" => {
+            let info = read_disk_info_with(&mut disk_file, volume_options).or_exit();
+            let dir = args.get(3).unwrap_or_else(|| fail("export-tracks needs an output directory"));
+            let mut layout = tracks::TrackLayout::of(&info)
+                .unwrap_or_else(|| fail("the BPB gives no sectors per track or heads; try --geometry"));
+            // Sectors in order, as the BPB implies, unless told otherwise.
+            layout.interleave = flag_value(args, "--interleave").map_or(layout.interleave, |n| {
+                n.parse().ok().filter(|&n| n > 0 && n < layout.sectors_per_track.max(2))
+                    .unwrap_or_else(|| fail(&format!("invalid --interleave: {}", n)))
+            });
+            layout.first_sector = flag_value(args, "--first-sector").map_or(layout.first_sector, |n| {
+                n.parse().ok().filter(|&n: &u16| n.checked_add(layout.sectors_per_track).is_some())
+                    .unwrap_or_else(|| fail(&format!("invalid --first-sector: {}", n)))
+            });
+            let written = tracks::export(&mut disk_file, &layout, Path::new(dir)).or_exit();
+            println!("wrote {} tracks of {} sectors to {}, described in {}",
+                     written,
+                     layout.sectors_per_track,
+                     dir,
+                     Path::new(dir).join("diskdefs.cfg").display());
+        }
+        "stat" => {
+            let info = read_disk_info_with(&mut disk_file, volume_options).or_exit();
+            let path = args.get(3).unwrap_or_else(|| fail("stat needs a path"));
+            let offsets = args[4..].iter().any(|a| a == "--offsets");
+            if !stat(&info, &mut disk_file, path, json, offsets).or_exit() {
+                exit(1);
+            }
         }
         "list" => {
-            let info = read_disk_info(&mut disk_file).unwrap();
-            list_rootdir(&info, &mut disk_file).unwrap();
+            let info = read_disk_info_with(&mut disk_file, volume_options).or_exit();
+            let flags = &args[3..];
+            let date_format = flag_value(args, "--date-format").unwrap_or("%Y-%m-%d %H:%M:%S");
+            if format::StrftimeItems::new(date_format).any(|item| item == format::Item::Error) {
+                fail(&format!("invalid date format: {}", date_format));
+            }
+            let options = ListOptions {
+                identify: flags.iter().any(|f| f == "--identify"),
+                human: flags.iter().any(|f| f == "--human"),
+                bare: flags.iter().any(|f| f == "--bare"),
+                all: flags.iter().any(|f| f == "--all"),
+                offsets: flags.iter().any(|f| f == "--offsets"),
+                color: flag_value(args, "--color").map_or(color::When::Auto, |when| {
+                    color::When::parse(when).unwrap_or_else(|| fail(&format!("unknown color setting: {}", when)))
+                }).enabled(),
+                date_format: date_format.to_string(),
+                annotations: flag_value(args, "--annotations").map(|db| {
+                    let db = annotations::Annotations::load(Path::new(db)).unwrap_or_else(|e| fail(&e.to_string()));
+                    db.for_image(&hash_image(&mut disk_file).or_exit())
+                }),
+                map: load_map(args, &info),
+                json,
+                recursive: flags.iter().any(|f| f == "--recursive" || f == "-r"),
+                full_paths: flags.iter().any(|f| f == "--full-paths"),
+            };
+            let path = args.get(3).filter(|a| !a.starts_with('-')).map_or("/", |p| p.as_str());
+            let directory = Directory::open(&info, &mut disk_file, path)
+                .or_exit()
+                .unwrap_or_else(|| fail(&format!("{}: no such directory", path)));
+            let path = path.trim_end_matches('/');
+            let mut directories = vec![(path.to_string(), directory.clone())];
+            if options.recursive {
+                let fat = fat_of(&info, &disk_file).or_exit();
+                walk_tree(&info,
+                          &mut disk_file,
+                          &*fat,
+                          &directory,
+                          path,
+                          false,
+                          &mut HashSet::new(),
+                          &mut |_, found| {
+                              if found.entry.attributes & DirEntryAttributes::SubDir as u8 != 0 {
+                                  let directory = Directory::chain(&info, &*fat, found.entry.flc);
+                                  directories.push((found.path, directory));
+                              }
+                              Ok(())
+                          })
+                    .or_exit();
+            }
+            if options.full_paths || options.json || !options.recursive {
+                list_dir(&info, &mut disk_file, &directories, &options).or_exit();
+            } else {
+                // One listing for each directory, as `ls -R` prints them.
+                for (i, directory) in directories.iter().enumerate() {
+                    if i > 0 {
+                        println!();
+                    }
+                    println!("{}:", if directory.0.is_empty() { "/" } else { &directory.0 });
+                    list_dir(&info, &mut disk_file, std::slice::from_ref(directory), &options).or_exit();
+                }
+            }
+        }
+        "annotate" => {
+            let info = read_disk_info_with(&mut disk_file, volume_options).or_exit();
+            let path = args.get(3)
+                .filter(|a| !a.starts_with("--"))
+                .unwrap_or_else(|| fail("annotate needs a path in the image"));
+            let db_path = Path::new(flag_value(args, "--annotations")
+                .unwrap_or_else(|| fail("annotate needs --annotations FILE")));
+            if find_path(&info, &mut disk_file, path).or_exit().is_none() {
+                fail(&format!("{}: no such file", path));
+            }
+            let image = hash_image(&mut disk_file).or_exit();
+            let mut db = annotations::Annotations::load(db_path).unwrap_or_else(|e| fail(&e.to_string()));
+            let annotation = annotations::Annotation {
+                note: flag_value(args, "--note").map(|s| s.to_string()),
+                software: flag_value(args, "--software").map(|s| s.to_string()),
+                license: flag_value(args, "--license").map(|s| s.to_string()),
+            };
+            if annotation.note.is_none() && annotation.software.is_none() && annotation.license.is_none() {
+                if let Some(annotation) = db.get(&image, path) {
+                    println!("{}", annotation.summary());
+                }
+            } else {
+                db.set(&image, path, &annotation);
+                db.save(db_path).unwrap_or_else(|e| fail(&e.to_string()));
+            }
+        }
+        "complete" => {
+            let info = read_disk_info_with(&mut disk_file, volume_options).or_exit();
+            let prefix = args.get(3).map_or("", |p| p.as_str());
+            complete_rootdir(&info, &mut disk_file, prefix).or_exit();
+        }
+        "attrib" => {
+            let info = read_disk_info_with(&mut disk_file, volume_options).or_exit();
+            let path = args.get(3).unwrap_or_else(|| fail("attrib needs a path in the image"));
+            let entry = find_path(&info, &mut disk_file, path).or_exit();
+            let entry = entry.ok_or_else(|| Error::NotFound(path.to_string())).or_exit();
+            println!("{} {}", attribute_string(entry.attributes), path);
+        }
+        "eas" => {
+            let info = read_disk_info_with(&mut disk_file, volume_options).or_exit();
+            if !print_eas(&info, &mut disk_file, args.get(3).map(|p| p.as_str())).or_exit() {
+                exit(1);
+            }
+        }
+        "exeinfo" => {
+            let info = read_disk_info_with(&mut disk_file, volume_options).or_exit();
+            let name = args.get(3).map(|n| n.as_str());
+            print_exeinfo(&info, &mut disk_file, name).or_exit();
+        }
+        "backup" => {
+            let dir = flag_value(args, "--to").unwrap_or_else(|| fail("backup needs --to DIR"));
+            let incremental = args[3..].iter().any(|a| a == "--incremental");
+            let image = read_image(&mut disk_file).or_exit();
+            let json = progress::enabled(args);
+            let mut meter = progress::Meter::new("backup", Box::new(std::io::stdout()));
+            let backup = backup::backup_with_progress(&image, Path::new(dir), incremental, |done, total| {
+                if json {
+                    meter.update(done, total);
+                }
+            }).unwrap_or_else(|e| fail(&e.to_string()));
+            if json {
+                let fields = json!({
+                    "seq": backup.seq,
+                    "full": backup.full,
+                    "changed_sectors": backup.changed_sectors,
+                    "sectors": image.len().div_ceil(backup::SECTOR_SIZE),
+                });
+                println!("{}", progress::done_event("backup", fields));
+            } else {
+                println!("backup {} ({}): {} of {} sectors stored",
+                         backup.seq,
+                         if backup.full { "full" } else { "incremental" },
+                         backup.changed_sectors,
+                         image.len().div_ceil(backup::SECTOR_SIZE));
+            }
+        }
+        "pack" => {
+            let manifest = args.get(3).unwrap_or_else(|| fail("pack needs a manifest path"));
+            let image = read_image(&mut disk_file).or_exit();
+            let (chunks, new) = chunked::write(&image, Path::new(manifest))
+                .unwrap_or_else(|e| fail(&e.to_string()));
+            println!("{} chunks, {} new, {} already stored", chunks, new, chunks - new);
+        }
+        "unpack" => {
+            let out_path = args.get(3).unwrap_or_else(|| fail("unpack needs an output image"));
+            let image = read_image(&mut disk_file).or_exit();
+            File::create(out_path)
+                .and_then(|mut out| out.write_all(&image))
+                .unwrap_or_else(|e| fail(&format!("{}: {}", out_path, e)));
+        }
+        "log" => {
+            let image = read_image(&mut disk_file).or_exit();
+            if !audit::verify(disk_path, &image).or_exit() {
+                exit(1);
+            }
         }
-        _ => (),
+        // Only a local image gets here without being handled already.
+        "mount" | "recover-bpb" => fail(&format!("{} needs an image on this machine", command)),
+        _ => usage(),
     }
 }
@@ -0,0 +1,30 @@
+/// How much is read at once when there is no limit.
+const DEFAULT_BUFFER: usize = 1 << 20;
+
+/// Whether holding `bytes` more in memory is within `limit`. Anything that
+/// might take a large part of it, such as a whole FAT, has to fit in a
+/// quarter, leaving the rest for everything else.
+pub fn allows(limit: Option<u64>, bytes: u64) -> bool {
+    limit.is_none_or(|limit| bytes <= limit / 4)
+}
+
+/// The size to read or copy in, at most `wanted`: smaller under a tight
+/// `limit`, but never below a sector.
+pub fn buffer_size(limit: Option<u64>, wanted: usize) -> usize {
+    let cap = limit.map_or(DEFAULT_BUFFER, |limit| (limit / 16).min(DEFAULT_BUFFER as u64) as usize);
+    wanted.min(cap).max(512)
+}
+
+/// Parses a size such as `4096`, `512K`, `64M` or `2G` (powers of 1024; a
+/// trailing `B` or `iB` is allowed).
+pub fn parse_size(text: &str) -> Option<u64> {
+    let text = text.trim().to_uppercase();
+    let text = text.strip_suffix("IB").or_else(|| text.strip_suffix('B')).unwrap_or(&text);
+    let (digits, shift) = match text.chars().last()? {
+        'K' => (&text[..text.len() - 1], 10),
+        'M' => (&text[..text.len() - 1], 20),
+        'G' => (&text[..text.len() - 1], 30),
+        _ => (text, 0),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift).filter(|&bytes| bytes > 0)
+}
@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use chrono::{Local, NaiveDateTime, TimeZone};
+use fuser::{Config, Errno, FileAttr, FileHandle, FileType, Filesystem, Generation, INodeNo, LockOwner,
+            MountOption, OpenFlags, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, ReplyStatfs, Request};
+use {cluster_chain, cluster_size, cluster_start, dos_date, dos_datetime, free_space, read_disk_info_with,
+     read_fat, tree, DirEntry, DirEntryAttributes, DiskInfo, FreeSpace, Result, VolumeOptions};
+
+/// How long the kernel may cache what it is told: the volume can't change
+/// under a read-only mount.
+const TTL: Duration = Duration::from_secs(3600);
+
+/// A file or directory, numbered by its place in `ImageFs::nodes` plus 1, so
+/// the root is inode 1 as FUSE expects.
+struct Node {
+    /// The long name, or the 8.3 one if there is none.
+    name: String,
+    parent: u64,
+    /// `None` for the root, which has no entry.
+    entry: Option<DirEntry>,
+    chain: Vec<u32>,
+    children: Vec<u64>,
+}
+impl Node {
+    fn kind(&self) -> FileType {
+        match self.entry {
+            Some(ref entry) if (entry.attributes & DirEntryAttributes::SubDir as u8) == 0 => {
+                FileType::RegularFile
+            }
+            _ => FileType::Directory,
+        }
+    }
+}
+
+/// A volume served read-only over FUSE. The tree and every chain are read
+/// once, when it is made; file reads go to the image, a cluster at a time.
+pub struct ImageFs<R> {
+    info: DiskInfo,
+    disk_file: Mutex<R>,
+    nodes: Vec<Node>,
+    space: FreeSpace,
+}
+
+impl<R: Read + Seek> ImageFs<R> {
+    /// Reads the volume in `disk_file` with `options`, as
+    /// `read_disk_info_with` does.
+    pub fn new(mut disk_file: R, options: &VolumeOptions) -> Result<Self> {
+        let info = read_disk_info_with(&mut disk_file, options)?;
+        let fat = read_fat(&info, &mut disk_file)?;
+        let root = Node { name: String::new(), parent: 1, entry: None, chain: vec![], children: vec![] };
+        let mut nodes = vec![root];
+        let mut dirs: HashMap<String, u64> = HashMap::new();
+        dirs.insert(String::new(), 1);
+        for found in tree(&info, &mut disk_file, &fat[..], false)? {
+            let (parent_path, short_name) = found.path.rsplit_once('/').unwrap_or(("", &found.path));
+            // `tree` gives a directory before what is in it.
+            let parent = dirs[parent_path];
+            let ino = nodes.len() as u64 + 1;
+            let entry = found.entry;
+            if (entry.attributes & DirEntryAttributes::SubDir as u8) != 0 {
+                dirs.insert(found.path.clone(), ino);
+            }
+            let chain = if entry.flc >= 2 { cluster_chain(&info, &fat[..], entry.flc) } else { Vec::new() };
+            nodes[parent as usize - 1].children.push(ino);
+            nodes.push(Node {
+                name: entry.long_name.clone().unwrap_or_else(|| short_name.to_string()),
+                parent,
+                entry: Some(entry),
+                chain,
+                children: Vec::new(),
+            });
+        }
+        let space = free_space(&info, &fat[..]);
+        Ok(ImageFs { info, disk_file: Mutex::new(disk_file), nodes, space })
+    }
+
+    fn node(&self, ino: INodeNo) -> std::result::Result<&Node, Errno> {
+        self.nodes.get((ino.0 as usize).wrapping_sub(1)).ok_or(Errno::ENOENT)
+    }
+
+    fn attr(&self, req: &Request, ino: u64, node: &Node) -> FileAttr {
+        let time = |datetime: Option<NaiveDateTime>| {
+            let local = datetime.and_then(|t| Local.from_local_datetime(&t).earliest());
+            local.map_or(UNIX_EPOCH, SystemTime::from)
+        };
+        let kind = node.kind();
+        let (size, mtime, atime, crtime) = match node.entry {
+            Some(ref entry) if kind == FileType::RegularFile => {
+                let mtime = time(dos_datetime(entry.last_write_date, entry.last_write_time));
+                (entry.file_size as u64,
+                 mtime,
+                 dos_date(entry.last_access_date).map_or(mtime, |date| time(date.and_hms_opt(0, 0, 0))),
+                 entry.created().map_or(mtime, |t| time(Some(t))))
+            }
+            Some(ref entry) => {
+                let mtime = time(dos_datetime(entry.last_write_date, entry.last_write_time));
+                (0, mtime, mtime, mtime)
+            }
+            None => (0, UNIX_EPOCH, UNIX_EPOCH, UNIX_EPOCH),
+        };
+        let cluster_bytes = cluster_size(&self.info);
+        FileAttr {
+            ino: INodeNo(ino),
+            size,
+            blocks: node.chain.len() as u64 * cluster_bytes / 512,
+            atime,
+            mtime,
+            ctime: mtime,
+            crtime,
+            kind,
+            perm: if kind == FileType::Directory { 0o555 } else { 0o444 },
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            // DOS has no owners, so everything belongs to whoever asks.
+            uid: req.uid(),
+            gid: req.gid(),
+            rdev: 0,
+            blksize: cluster_bytes as u32,
+            flags: 0,
+        }
+    }
+
+    /// Reads up to `size` bytes from `offset`, following the file's chain.
+    fn read_at(&self, node: &Node, offset: u64, size: u32) -> std::result::Result<Vec<u8>, Errno> {
+        let file_size = node.entry.as_ref().map_or(0, |entry| entry.file_size as u64);
+        let end = file_size.min(offset + size as u64);
+        let cluster_bytes = cluster_size(&self.info);
+        let mut disk_file = self.disk_file.lock().unwrap_or_else(|e| e.into_inner());
+        let mut data = Vec::with_capacity(end.saturating_sub(offset) as usize);
+        let mut pos = offset;
+        while pos < end {
+            // A chain too short for the file's size ends the file early.
+            let cluster = *node.chain.get((pos / cluster_bytes) as usize).ok_or(Errno::EIO)?;
+            let start = pos % cluster_bytes;
+            let len = (cluster_bytes - start).min(end - pos);
+            let mut buf = vec![0; len as usize];
+            disk_file.seek(SeekFrom::Start(cluster_start(&self.info, cluster) + start))
+                .and_then(|_| disk_file.read_exact(&mut buf))
+                .map_err(|_| Errno::EIO)?;
+            data.extend_from_slice(&buf);
+            pos += len;
+        }
+        Ok(data)
+    }
+}
+
+impl<R: Read + Seek + Send + 'static> Filesystem for ImageFs<R> {
+    fn lookup(&self, req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEntry) {
+        let name = name.to_string_lossy();
+        let found = self.node(parent).and_then(|dir| {
+            // FAT names are case-insensitive, and the 8.3 name works as well
+            // as the long one.
+            dir.children
+                .iter()
+                .cloned()
+                .find(|&ino| {
+                    let child = &self.nodes[ino as usize - 1];
+                    child.name.eq_ignore_ascii_case(&name) ||
+                    child.entry.as_ref().is_some_and(|entry| entry.name().eq_ignore_ascii_case(&name))
+                })
+                .ok_or(Errno::ENOENT)
+        });
+        match found {
+            Ok(ino) => reply.entry(&TTL, &self.attr(req, ino, &self.nodes[ino as usize - 1]), Generation(0)),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn getattr(&self, req: &Request, ino: INodeNo, _fh: Option<FileHandle>, reply: ReplyAttr) {
+        match self.node(ino) {
+            Ok(node) => reply.attr(&TTL, &self.attr(req, ino.0, node)),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn read(&self,
+            _req: &Request,
+            ino: INodeNo,
+            _fh: FileHandle,
+            offset: u64,
+            size: u32,
+            _flags: OpenFlags,
+            _lock_owner: Option<LockOwner>,
+            reply: ReplyData) {
+        match self.node(ino).and_then(|node| self.read_at(node, offset, size)) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn readdir(&self, _req: &Request, ino: INodeNo, _fh: FileHandle, offset: u64, mut reply: ReplyDirectory) {
+        let dir = match self.node(ino) {
+            Ok(dir) if dir.kind() == FileType::Directory => dir,
+            Ok(_) => return reply.error(Errno::ENOTDIR),
+            Err(e) => return reply.error(e),
+        };
+        let dots = [(ino.0, FileType::Directory, "."), (dir.parent, FileType::Directory, "..")];
+        let children = dir.children.iter().map(|&child| {
+            let node = &self.nodes[child as usize - 1];
+            (child, node.kind(), &node.name[..])
+        });
+        let entries = dots.iter().cloned().chain(children).enumerate().skip(offset as usize);
+        for (i, (child, kind, name)) in entries {
+            // The offset given is that of the entry after this one.
+            if reply.add(INodeNo(child), i as u64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn statfs(&self, _req: &Request, _ino: INodeNo, reply: ReplyStatfs) {
+        let space = &self.space;
+        reply.statfs(space.clusters as u64,
+                     space.free_clusters as u64,
+                     space.free_clusters as u64,
+                     self.nodes.len() as u64,
+                     0,
+                     space.cluster_size as u32,
+                     255,
+                     space.cluster_size as u32);
+    }
+}
+
+/// Mounts the volume in `disk_file` read-only at `mountpoint`, with `name`
+/// as its source in the mount table, and serves it until it is unmounted.
+/// It is read with `options`, as for `ImageFs::new`.
+pub fn mount<R>(disk_file: R, options: &VolumeOptions, mountpoint: &Path, name: &str) -> Result<()>
+    where R: Read + Seek + Send + 'static
+{
+    let fs = ImageFs::new(disk_file, options)?;
+    let mut config = Config::default();
+    config.mount_options = vec![MountOption::RO,
+                                MountOption::FSName(name.to_string()),
+                                MountOption::Subtype("fat12".to_string())];
+    fuser::mount(fs, mountpoint, &config)?;
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use std::sync::Arc;
+use {alias_name, case_flags, fits, short_name, DiskInfo};
+
+/// How the names of new entries are chosen: what a host file is called in
+/// the volume, the 8.3 name each entry gets, and whether a long name is kept
+/// beside it. A volume goes by the mapping in its
+/// `VolumeOptions::name_mapping`, or by `Windows` if there is none. Every
+/// method has the Windows behaviour as its default, so a mapping only needs
+/// the ones it changes:
+///
+/// ```
+/// use fat12::names::NameMapping;
+///
+/// /// Stores every name in upper case, so no long names are made for case.
+/// struct Upper;
+/// impl NameMapping for Upper {
+///     fn image_name(&self, host_name: &str) -> String {
+///         host_name.to_uppercase()
+///     }
+/// }
+/// assert_eq!(Upper.image_name("readme.txt"), "README.TXT");
+/// ```
+pub trait NameMapping: Send + Sync {
+    /// The name a file from the host named `host_name` is stored under when
+    /// it isn't given one.
+    fn image_name(&self, host_name: &str) -> String {
+        host_name.to_string()
+    }
+
+    /// The 8.3 name, as the 11 bytes of a directory entry, for an entry named
+    /// `name`; `taken` says whether one is used in the directory already.
+    /// `None` if there is none to give it. By default a name that fits 8.3
+    /// is its own short name, and any other gets an alias as `alias_name`
+    /// makes them.
+    fn short_name(&self, name: &str, taken: &dyn Fn(&[u8; 11]) -> bool) -> Option<[u8; 11]> {
+        short_name(name).or_else(|| alias_name(name, taken))
+    }
+
+    /// Whether `name` is stored as a long name in LFN slots before the short
+    /// entry `short`. By default it is whenever the short name isn't `name`
+    /// itself, in the case `case_flags` can give it.
+    fn long_name(&self, name: &str, short: &[u8; 11]) -> bool {
+        short_name(name).as_ref() != Some(short) || case_flags(name).is_none()
+    }
+}
+
+/// Names as Windows gives them: long names kept, with `~N` aliases.
+pub struct Windows;
+impl NameMapping for Windows {}
+
+/// Names as DOS before long names had them: cut down to 8.3, with the
+/// characters DOS doesn't allow made `_`, and no long name kept. A cut name
+/// that is taken gets a `~N` alias instead.
+pub struct Truncate;
+impl NameMapping for Truncate {
+    fn short_name(&self, name: &str, taken: &dyn Fn(&[u8; 11]) -> bool) -> Option<[u8; 11]> {
+        if let Some(packed) = short_name(name) {
+            return Some(packed);
+        }
+        let name = name.trim_start_matches('.');
+        let (base, ext) = match name.rfind('.') {
+            Some(dot) => (&name[..dot], &name[dot + 1..]),
+            None => (name, ""),
+        };
+        let clean = |part: &str, len: usize| -> String {
+            part.chars()
+                .filter(|&c| c != ' ' && c != '.')
+                .map(|c| if fits::is_short_name_char(c) { c } else { '_' })
+                .take(len)
+                .collect()
+        };
+        let (base, ext) = (clean(base, 8), clean(ext, 3));
+        let cut = if ext.is_empty() { base } else { format!("{}.{}", base, ext) };
+        match short_name(&cut) {
+            Some(packed) if !taken(&packed) => Some(packed),
+            _ => alias_name(name, taken),
+        }
+    }
+
+    fn long_name(&self, _: &str, _: &[u8; 11]) -> bool {
+        false
+    }
+}
+
+/// The mapping new entries in the volume `info` describes are named by.
+pub(crate) fn mapping(info: &DiskInfo) -> &dyn NameMapping {
+    match info.options.name_mapping {
+        Some(ref mapping) => &**mapping,
+        None => &Windows,
+    }
+}
+
+/// A mapping to keep in `VolumeOptions::name_mapping`.
+pub fn shared<M: NameMapping + 'static>(mapping: M) -> Arc<dyn NameMapping> {
+    Arc::new(mapping)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::{blank, host_file, names};
+    use {put, put_with_times, Times};
+
+    #[test]
+    fn volumes_name_entries_by_their_mapping() {
+        assert_eq!(Truncate.short_name("Annual Report.text", &|_| false), Some(*b"ANNUALRETEX"));
+        let taken = |name: &[u8; 11]| name == b"ANNUALRETEX";
+        assert_eq!(Truncate.short_name("Annual Report.text", &taken), Some(*b"ANNUAL~1TEX"));
+        assert_eq!(Windows.short_name("Annual Report.text", &|_| false), Some(*b"ANNUAL~1TEX"));
+
+        let (mut info, mut image) = blank();
+        info.options.name_mapping = Some(shared(Truncate));
+        put(&info, &mut image, &host_file("names", "Long file name.txt", b"a"), "/").unwrap();
+        put(&info, &mut image, &host_file("names", "Long file names.txt", b"b"), "/").unwrap();
+        assert_eq!(names(&mut image, "/"),
+                   vec![("LONGFILE.TXT".to_string(), None), ("LONGFI~1.TXT".to_string(), None)]);
+
+        struct Underscores;
+        impl NameMapping for Underscores {
+            fn image_name(&self, host_name: &str) -> String {
+                host_name.replace(' ', "_")
+            }
+        }
+        info.options.name_mapping = Some(shared(Underscores));
+        let notes = host_file("names", "My notes.txt", b"c");
+        put_with_times(&info, &mut image, &notes, "/", &Times::default()).unwrap();
+        assert_eq!(names(&mut image, "/")[2], ("MY_NOTES.TXT".to_string(), Some("My_notes.txt".to_string())));
+    }
+}
@@ -0,0 +1,203 @@
+use backup::sha256_hex;
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// An overlay keeps the changes made to an image apart from it, so the
+/// image itself is never written until the overlay is committed. It is a
+/// text header naming the base image, its hash when the overlay was made
+/// and the length the image now has, then after a blank line each changed
+/// sector as its number in four bytes and its contents.
+const MAGIC: &str = "fat12-overlay 1";
+pub const SECTOR_SIZE: usize = 512;
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Whether `path` is an overlay rather than an image.
+pub fn is_overlay(path: &str) -> bool {
+    let mut head = [0; MAGIC.len()];
+    File::open(path).and_then(|mut file| file.read_exact(&mut head)).is_ok() &&
+    &head[..] == MAGIC.as_bytes()
+}
+
+fn next_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    Ok(line.trim_end_matches('\n').to_string())
+}
+
+pub struct Overlay {
+    pub base: PathBuf,
+    pub base_hash: String,
+    pub length: u64,
+    /// The sectors that differ from the base or lie past its end.
+    pub sectors: BTreeMap<u32, Vec<u8>>,
+}
+
+impl Overlay {
+    /// Starts an empty overlay on `base`, stored at `path`.
+    pub fn create(base: &Path, path: &Path) -> io::Result<Overlay> {
+        let image = fs::read(base)?;
+        let overlay = Overlay {
+            base: fs::canonicalize(base)?,
+            base_hash: sha256_hex(&image),
+            length: image.len() as u64,
+            sectors: BTreeMap::new(),
+        };
+        overlay.save(path)?;
+        Ok(overlay)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Overlay> {
+        let mut reader = BufReader::new(File::open(path)?);
+        if next_line(&mut reader)? != MAGIC {
+            return Err(invalid(format!("{} is not an overlay", path.display())));
+        }
+        let mut field = |name: &str| -> io::Result<String> {
+            let line = next_line(&mut reader)?;
+            line.strip_prefix(name)
+                .and_then(|value| value.strip_prefix(' '))
+                .map(String::from)
+                .ok_or_else(|| invalid(format!("{}: missing {}", path.display(), name)))
+        };
+        let base = PathBuf::from(field("base")?);
+        let base_hash = field("base-hash")?;
+        let length = field("length")?
+            .parse()
+            .map_err(|_| invalid(format!("{}: bad length", path.display())))?;
+        if !next_line(&mut reader)?.is_empty() {
+            return Err(invalid(format!("{}: no blank line after the header", path.display())));
+        }
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        if data.len() % (4 + SECTOR_SIZE) != 0 {
+            return Err(invalid(format!("{}: the last sector is cut short", path.display())));
+        }
+        let sectors = data.chunks(4 + SECTOR_SIZE)
+            .map(|record| (LittleEndian::read_u32(record), record[4..].to_vec()))
+            .collect();
+        Ok(Overlay { base, base_hash, length, sectors })
+    }
+
+    /// Writes the overlay to `path`, under a temporary name first so that an
+    /// interrupted write leaves the old one.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut data = format!("{}\nbase {}\nbase-hash {}\nlength {}\n\n",
+                               MAGIC,
+                               self.base.display(),
+                               self.base_hash,
+                               self.length)
+            .into_bytes();
+        for (&sector, contents) in &self.sectors {
+            let mut index = [0; 4];
+            LittleEndian::write_u32(&mut index, sector);
+            data.extend_from_slice(&index);
+            data.extend_from_slice(contents);
+        }
+        let partial = path.with_extension("partial");
+        File::create(&partial)?.write_all(&data)?;
+        fs::rename(&partial, path)
+    }
+
+    /// The base image as it was when the overlay was made, refused if it has
+    /// changed since, as the overlay's sectors would no longer fit it.
+    pub fn read_base(&self) -> io::Result<Vec<u8>> {
+        let base = fs::read(&self.base)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", self.base.display(), e)))?;
+        if sha256_hex(&base) != self.base_hash {
+            return Err(invalid(format!("{} has changed since the overlay on it was made",
+                                       self.base.display())));
+        }
+        Ok(base)
+    }
+
+    /// The image as it is with the overlay's changes.
+    pub fn image(&self) -> io::Result<Vec<u8>> {
+        let mut image = self.read_base()?;
+        image.resize(self.length as usize, 0);
+        for (&sector, contents) in &self.sectors {
+            let start = sector as usize * SECTOR_SIZE;
+            let end = image.len().min(start + SECTOR_SIZE);
+            if start >= end {
+                return Err(invalid(format!("sector {} of the overlay is past the end of the image", sector)));
+            }
+            image[start..end].copy_from_slice(&contents[..end - start]);
+        }
+        Ok(image)
+    }
+
+    /// Makes the overlay hold `image` as the changes to `base`, which is what
+    /// `read_base` returned.
+    pub fn update(&mut self, base: &[u8], image: &[u8]) {
+        self.length = image.len() as u64;
+        self.sectors = image.chunks(SECTOR_SIZE)
+            .enumerate()
+            .filter(|&(i, sector)| base.get(i * SECTOR_SIZE..i * SECTOR_SIZE + sector.len()) != Some(sector))
+            .map(|(i, sector)| {
+                let mut contents = sector.to_vec();
+                contents.resize(SECTOR_SIZE, 0);
+                (i as u32, contents)
+            })
+            .collect();
+    }
+
+    /// Writes the changes into the base image: only the changed sectors, and
+    /// the new length if it changed. The overlay at `path` is removed once
+    /// they are written. Returns how many sectors were written.
+    pub fn commit(&self, path: &Path) -> io::Result<usize> {
+        let image = self.image()?;
+        let mut base = OpenOptions::new().write(true).open(&self.base)?;
+        for &sector in self.sectors.keys() {
+            let start = sector as usize * SECTOR_SIZE;
+            let end = image.len().min(start + SECTOR_SIZE);
+            base.seek(SeekFrom::Start(start as u64))?;
+            base.write_all(&image[start..end])?;
+        }
+        base.set_len(self.length)?;
+        base.sync_all()?;
+        fs::remove_file(path)?;
+        Ok(self.sectors.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn overlays_leave_the_base_alone_until_committed() {
+        let dir = env::temp_dir().join(format!("fat12-overlay-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (base_path, path) = (dir.join("base.img"), dir.join("session.ovl"));
+        let base: Vec<u8> = (0..4 * SECTOR_SIZE).map(|i| (i / SECTOR_SIZE) as u8).collect();
+        fs::write(&base_path, &base).unwrap();
+
+        let mut overlay = Overlay::create(&base_path, &path).unwrap();
+        assert!(is_overlay(path.to_str().unwrap()) && !is_overlay(base_path.to_str().unwrap()));
+        let mut image = overlay.image().unwrap();
+        assert_eq!(image, base);
+        image[SECTOR_SIZE + 10] = 0xAA;
+        image.extend_from_slice(b"past the end");
+        overlay.update(&base, &image);
+        overlay.save(&path).unwrap();
+
+        let overlay = Overlay::load(&path).unwrap();
+        assert_eq!(overlay.sectors.keys().collect::<Vec<_>>(), vec![&1, &4]);
+        assert_eq!(overlay.image().unwrap(), image);
+        assert_eq!(fs::read(&base_path).unwrap(), base);
+        assert_eq!(overlay.commit(&path).unwrap(), 2);
+        assert_eq!(fs::read(&base_path).unwrap(), image);
+        assert!(!path.exists());
+
+        // Once the base changes, an overlay made on it no longer applies.
+        let stale = Overlay::create(&base_path, &path).unwrap();
+        fs::write(&base_path, &base).unwrap();
+        assert_eq!(stale.image().err().unwrap().kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,274 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use byteorder::{LittleEndian, ByteOrder};
+use geometry::{has_fat_signature, plausible_dir_entry};
+use {BYTES_PER_SECTOR, SECTORS_PER_CLUSTER, RESERVED_SECTORS, FATS, ROOT_DIR_ENTRIES, TOTAL_SECTORS,
+     MEDIA_DESCRIPTOR, SECTORS_PER_FAT, SECTORS_PER_TRACK, HEADS, FAT32_TOTAL_SECTORS, OS_NAME,
+     DiskInfo, Error, Result};
+
+/// The shape of a disk as the drive sees it, for images whose boot sector
+/// has no BPB to say so: the 8-inch disks of the earliest DOS releases, and
+/// other odd single-density media.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Physical {
+    pub tracks: u16,
+    pub heads: u16,
+    pub sectors_per_track: u16,
+    pub bytes_per_sector: u16,
+}
+impl Physical {
+    /// Parses `tracks,heads,spt,bps`, e.g. `77,1,26,128` for a 250 KB
+    /// 8-inch disk.
+    pub fn parse(text: &str) -> Option<Physical> {
+        let fields = text.split(',').map(|f| f.trim().parse::<u16>().ok()).collect::<Option<Vec<_>>>()?;
+        match fields[..] {
+            [tracks, heads, sectors_per_track, bytes_per_sector]
+                if tracks > 0 && heads > 0 && sectors_per_track > 0 &&
+                   bytes_per_sector.is_power_of_two() && bytes_per_sector >= 128 => {
+                Some(Physical { tracks, heads, sectors_per_track, bytes_per_sector })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn total_sectors(&self) -> u32 {
+        self.tracks as u32 * self.heads as u32 * self.sectors_per_track as u32
+    }
+}
+impl fmt::Display for Physical {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},{},{},{}", self.tracks, self.heads, self.sectors_per_track, self.bytes_per_sector)
+    }
+}
+
+/// Where a BPB would put the FAT, root directory and data.
+#[derive(Clone, Copy)]
+struct Layout {
+    media: u8,
+    sectors_per_cluster: u8,
+    reserved_sectors: u16,
+    root_dir_entries: u16,
+    sectors_per_fat: u16,
+}
+
+/// The 8-inch formats of MS-DOS 1.x and 2.x, which are tried before any
+/// search: single-sided and double-sided single density, then double
+/// density. All have two FATs.
+const EIGHT_INCH: &[(Physical, Layout)] = &[
+    (Physical { tracks: 77, heads: 1, sectors_per_track: 26, bytes_per_sector: 128 },
+     Layout { media: 0xFE, sectors_per_cluster: 4, reserved_sectors: 1, root_dir_entries: 68,
+              sectors_per_fat: 6 }),
+    (Physical { tracks: 77, heads: 2, sectors_per_track: 26, bytes_per_sector: 128 },
+     Layout { media: 0xFD, sectors_per_cluster: 4, reserved_sectors: 4, root_dir_entries: 68,
+              sectors_per_fat: 6 }),
+    (Physical { tracks: 77, heads: 2, sectors_per_track: 8, bytes_per_sector: 1024 },
+     Layout { media: 0xFE, sectors_per_cluster: 1, reserved_sectors: 1, root_dir_entries: 192,
+              sectors_per_fat: 2 }),
+];
+
+/// Root directory sizes to try for other geometries, most common first.
+const ROOT_DIR_SIZES: &[u16] = &[64, 68, 112, 128, 192, 224, 256, 512];
+
+/// At most this much of the image is read to find the layout: more than the
+/// whole of any floppy.
+const SEARCH_LIMIT: u64 = 4 << 20;
+
+/// Works out where the FAT, root directory and data of an image with the
+/// geometry `physical` are, and describes them as the BPB would have. The
+/// FAT has to be where the layout puts it, with its copy right after it,
+/// and a layout whose subdirectories start with their `.` entry wins over
+/// one that can't be told apart by them.
+pub fn disk_info<R: Read + Seek>(disk_file: &mut R, physical: &Physical) -> Result<DiskInfo> {
+    let mut image = Vec::new();
+    disk_file.seek(SeekFrom::Start(0))?;
+    disk_file.by_ref().take(SEARCH_LIMIT).read_to_end(&mut image)?;
+    let known = EIGHT_INCH.iter().filter(|&&(p, _)| p == *physical).map(|&(_, layout)| layout);
+    let layout = known.filter(|layout| fat_copies_at(&image, physical, layout))
+        .find(|layout| fits(&image, physical, layout).is_some())
+        .or_else(|| search(&image, physical))
+        .ok_or_else(|| Error::InvalidBootSector(format!("no FAT layout fits the geometry {}", physical)))?;
+    Ok(describe(physical, &layout))
+}
+
+/// Whether `layout`'s FAT starts at its place with the signature for its
+/// media byte, and its second copy starts right after it the same way.
+fn fat_copies_at(image: &[u8], physical: &Physical, layout: &Layout) -> bool {
+    let sector = physical.bytes_per_sector as usize;
+    let first = layout.reserved_sectors as usize * sector;
+    let second = first + layout.sectors_per_fat as usize * sector;
+    has_fat_signature(image, first, layout.media) && has_fat_signature(image, second, layout.media)
+}
+
+/// Whether the root directory `layout` implies looks like one: `None` if
+/// not, `Some(true)` if its subdirectories confirm where the data starts,
+/// and `Some(false)` if there are none to check.
+fn fits(image: &[u8], physical: &Physical, layout: &Layout) -> Option<bool> {
+    let sector = physical.bytes_per_sector as u64;
+    let root_start = (layout.reserved_sectors as u64 + 2 * layout.sectors_per_fat as u64) * sector;
+    let root_sectors = (layout.root_dir_entries as u64 * 32).div_ceil(sector);
+    let data_start = root_start + root_sectors * sector;
+    let root = image.get(root_start as usize..data_start as usize)?;
+    let slots = root.chunks(32).take_while(|slot| slot[0] != 0x00);
+    if !slots.clone().all(plausible_dir_entry) {
+        return None;
+    }
+    let cluster_bytes = layout.sectors_per_cluster as u64 * sector;
+    let mut confirmed = false;
+    for slot in slots.filter(|slot| slot[0] != 0xE5 && slot[11] & 0x3F != 0x0F && slot[11] & 0x10 != 0) {
+        let cluster = LittleEndian::read_u16(&slot[26..]) as u64;
+        let offset = (data_start + cluster.wrapping_sub(2) * cluster_bytes) as usize;
+        match image.get(offset..offset + 12) {
+            Some(dot) if cluster >= 2 && &dot[..11] == b".          " && dot[11] & 0x10 != 0 => {
+                confirmed = true
+            }
+            _ => return None,
+        }
+    }
+    Some(confirmed)
+}
+
+/// Finds a layout from the image itself: the FAT signature after the
+/// reserved sectors, its copy for the FAT's size, then the cluster size and
+/// root directory that need a FAT of just that size.
+fn search(image: &[u8], physical: &Physical) -> Option<Layout> {
+    let sector = physical.bytes_per_sector as usize;
+    let total = physical.total_sectors();
+    let mut unconfirmed = None;
+    for reserved_sectors in 1..=8u16 {
+        let first = reserved_sectors as usize * sector;
+        let media = match image.get(first) {
+            Some(&media) if media >= 0xF0 && has_fat_signature(image, first, media) => media,
+            _ => continue,
+        };
+        // Before its copy, a FAT's sectors can't hold the signature again.
+        let copy_at = |n: u16| has_fat_signature(image, first + n as usize * sector, media);
+        let sectors_per_fat = match (1..=64u16).find(|&n| copy_at(n)) {
+            Some(n) => n,
+            None => continue,
+        };
+        for &root_dir_entries in ROOT_DIR_SIZES {
+            if !(root_dir_entries as usize * 32).is_multiple_of(sector) {
+                continue;
+            }
+            let root_sectors = root_dir_entries as u32 * 32 / sector as u32;
+            for &sectors_per_cluster in &[1u8, 2, 4, 8, 16] {
+                let metadata = reserved_sectors as u32 + 2 * sectors_per_fat as u32 + root_sectors;
+                let clusters = match total.checked_sub(metadata) {
+                    Some(data) => data / sectors_per_cluster as u32,
+                    None => continue,
+                };
+                let fat_bytes = ((clusters as usize + 2) * 3).div_ceil(2);
+                if clusters == 0 || fat_bytes.div_ceil(sector) != sectors_per_fat as usize {
+                    continue;
+                }
+                let layout = Layout {
+                    media, sectors_per_cluster, reserved_sectors, root_dir_entries, sectors_per_fat
+                };
+                match fits(image, physical, &layout) {
+                    Some(true) => return Some(layout),
+                    Some(false) if unconfirmed.is_none() => unconfirmed = Some(layout),
+                    _ => {}
+                }
+            }
+        }
+    }
+    unconfirmed
+}
+
+/// The `DiskInfo` a BPB describing `layout` would give.
+fn describe(physical: &Physical, layout: &Layout) -> DiskInfo {
+    let mut sector = [0u8; 512];
+    sector[OS_NAME..OS_NAME + 8].copy_from_slice(b"        ");
+    LittleEndian::write_u16(&mut sector[BYTES_PER_SECTOR..], physical.bytes_per_sector);
+    sector[SECTORS_PER_CLUSTER] = layout.sectors_per_cluster;
+    LittleEndian::write_u16(&mut sector[RESERVED_SECTORS..], layout.reserved_sectors);
+    sector[FATS] = 2;
+    LittleEndian::write_u16(&mut sector[ROOT_DIR_ENTRIES..], layout.root_dir_entries);
+    let total = physical.total_sectors();
+    if total <= 0xFFFF {
+        LittleEndian::write_u16(&mut sector[TOTAL_SECTORS..], total as u16);
+    } else {
+        LittleEndian::write_u32(&mut sector[FAT32_TOTAL_SECTORS..], total);
+    }
+    sector[MEDIA_DESCRIPTOR] = layout.media;
+    LittleEndian::write_u16(&mut sector[SECTORS_PER_FAT..], layout.sectors_per_fat);
+    LittleEndian::write_u16(&mut sector[SECTORS_PER_TRACK..], physical.sectors_per_track);
+    LittleEndian::write_u16(&mut sector[HEADS..], physical.heads);
+    DiskInfo::new(&sector)
+}
+
+/// A sidecar holding the geometry given for an image with `--geometry`, so
+/// later commands on it don't need it again.
+fn sidecar_path(image_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.f12geo", image_path))
+}
+
+/// The geometry recorded for the image, if there is one.
+pub fn load(image_path: &str) -> Result<Option<Physical>> {
+    let text = match fs::read_to_string(sidecar_path(image_path)) {
+        Ok(text) => text,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    match Physical::parse(text.trim()) {
+        Some(physical) => Ok(Some(physical)),
+        None => Err(Error::InvalidBootSector(format!("{} holds no geometry",
+                                                     sidecar_path(image_path).display()))),
+    }
+}
+
+pub fn save(image_path: &str, physical: &Physical) -> Result<()> {
+    fs::write(sidecar_path(image_path), format!("{}\n", physical))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A blank image for `physical` holding nothing but the signatures of two
+    /// FATs: no boot sector, and an empty root directory.
+    fn blank(physical: &Physical,
+             media: u8,
+             reserved_sectors: usize,
+             sectors_per_fat: usize)
+             -> Cursor<Vec<u8>> {
+        let sector = physical.bytes_per_sector as usize;
+        let mut image = vec![0; physical.total_sectors() as usize * sector];
+        for n in 0..2 {
+            let offset = (reserved_sectors + n * sectors_per_fat) * sector;
+            image[offset..offset + 3].copy_from_slice(&[media, 0xFF, 0xFF]);
+        }
+        Cursor::new(image)
+    }
+
+    #[test]
+    fn parses_and_prints_geometries() {
+        let physical = Physical::parse("77, 2,26,128").unwrap();
+        assert_eq!(physical.total_sectors(), 4004);
+        assert_eq!(physical.to_string(), "77,2,26,128");
+        assert!(Physical::parse("77,1,26").is_none());
+        assert!(Physical::parse("77,1,26,100").is_none());
+        assert!(Physical::parse("0,1,26,128").is_none());
+    }
+
+    #[test]
+    fn eight_inch_disks_get_the_dos_layout() {
+        let physical = Physical::parse("77,2,26,128").unwrap();
+        let info = disk_info(&mut blank(&physical, 0xFD, 4, 6), &physical).unwrap();
+        assert_eq!((info.bytes_per_sector, info.reserved_sectors, info.sectors_per_fat), (128, 4, 6));
+        assert_eq!((info.root_dir_entries, info.sectors_per_cluster, info.total_sectors), (68, 4, 4004));
+    }
+
+    #[test]
+    fn other_layouts_are_found_from_the_fat() {
+        let physical = Physical::parse("40,1,18,128").unwrap();
+        let info = disk_info(&mut blank(&physical, 0xFE, 2, 5), &physical).unwrap();
+        assert_eq!((info.reserved_sectors, info.sectors_per_fat, info.sectors_per_cluster), (2, 5, 2));
+        let physical = Physical::parse("77,1,26,128").unwrap();
+        assert!(disk_info(&mut Cursor::new(vec![0; 256_256]), &physical).is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use std::sync::Arc;
+use {DirEntryAttributes, DiskInfo, Error, Result, CHANGEABLE_ATTRIBUTES};
+
+/// What a mutation does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// A file or directory is about to be created, by `put`, `mkdir` or
+    /// `copy_between`, or brought back by `undelete::restore`.
+    Create,
+    /// A file is about to be deleted by `rm`, or a directory by `rmdir`.
+    Delete,
+    /// An entry is about to be renamed in its directory by `rename`.
+    Rename,
+    /// An entry's attributes are about to change, by `set_attributes`.
+    SetAttributes,
+}
+
+/// A change about to be made to a volume, as the policy sees it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mutation {
+    pub action: Action,
+    /// The entry's path: where it will be created, where it is, or for
+    /// `Rename` the name it will have.
+    pub path: String,
+    /// The attributes the entry will have: for `Delete` and `Rename`, the
+    /// ones it has.
+    pub attributes: u8,
+}
+
+/// What the policy decides about a mutation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    /// Refuse it, for the reason given; the operation fails with `Denied`
+    /// before anything is written.
+    Deny(String),
+    /// Go ahead with this instead. The action can't change, and neither can
+    /// the path except for `Create`, as the others act on an entry that is
+    /// already there. Only the ReadOnly, Hidden, System and Archive bits
+    /// of the attributes can be changed, and not those of a `Delete` or a
+    /// `Rename`.
+    Modify(Mutation),
+}
+
+/// Decides every create, delete, rename and attribute change made to a volume whose
+/// `VolumeOptions::policy` it is. Other writes, such as `redact` or
+/// `reformat`, aren't asked about.
+#[derive(Clone)]
+pub struct Policy(Arc<dyn Fn(&Mutation) -> Decision + Send + Sync>);
+impl Policy {
+    pub fn new<F>(decide: F) -> Policy
+        where F: Fn(&Mutation) -> Decision + Send + Sync + 'static
+    {
+        Policy(Arc::new(decide))
+    }
+}
+
+/// A policy that refuses to delete or rename hidden and system files, such
+/// as IO.SYS and MSDOS.SYS, without which a boot disk no longer boots.
+/// Everything else is allowed.
+pub fn protect_system() -> Policy {
+    let protected = DirEntryAttributes::Hidden as u8 | DirEntryAttributes::System as u8;
+    Policy::new(move |mutation| match mutation.action {
+        Action::Delete | Action::Rename if mutation.attributes & protected != 0 => {
+            Decision::Deny("hidden or system file".to_string())
+        }
+        _ => Decision::Allow,
+    })
+}
+
+/// Asks the policy of the volume `info` describes about `mutation`, and
+/// returns the mutation to make: the same one if it has none.
+pub(crate) fn check(info: &DiskInfo, mutation: Mutation) -> Result<Mutation> {
+    let policy = match info.options.policy {
+        Some(ref policy) => policy,
+        None => return Ok(mutation),
+    };
+    match (policy.0)(&mutation) {
+        Decision::Allow => Ok(mutation),
+        Decision::Deny(reason) => Err(Error::Denied(format!("{}: {}", mutation.path, reason))),
+        Decision::Modify(modified) => {
+            let refuse = |why: &str| Err(Error::Denied(format!("{}: {}", mutation.path, why)));
+            if modified.action != mutation.action {
+                return refuse("the policy changed what is being done");
+            }
+            if modified.path != mutation.path && mutation.action != Action::Create {
+                return refuse("the policy moved an existing entry");
+            }
+            let attributes = if matches!(mutation.action, Action::Delete | Action::Rename) {
+                mutation.attributes
+            } else {
+                (mutation.attributes & !CHANGEABLE_ATTRIBUTES) | (modified.attributes & CHANGEABLE_ATTRIBUTES)
+            };
+            Ok(Mutation { attributes, ..modified })
+        }
+    }
+}
+
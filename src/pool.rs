@@ -0,0 +1,85 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+/// How many images the batch commands read at once unless told otherwise:
+/// one for each CPU.
+pub fn default_jobs() -> usize {
+    thread::available_parallelism().map_or(1, |jobs| jobs.get())
+}
+
+/// Runs `work` on each of `items` with up to `jobs` threads at once, and
+/// hands each item and what came of it to `done` on the calling thread, in
+/// the order of `items`, so what `done` puts together comes out the same as
+/// one item at a time would have it. Results that finish early wait for
+/// those before them. The first error from `done` stops the rest.
+pub fn map_ordered<T, R, E, W, D>(items: &[T], jobs: usize, work: W, mut done: D) -> Result<(), E>
+    where T: Sync,
+          R: Send,
+          W: Fn(&T) -> R + Sync,
+          D: FnMut(&T, R) -> Result<(), E>
+{
+    let next = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel();
+        for _ in 0..jobs.clamp(1, items.len().max(1)) {
+            let sender = sender.clone();
+            let (next, stop, work) = (&next, &stop, &work);
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= items.len() || stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                // The receiver is gone once `done` has failed.
+                if sender.send((i, work(&items[i]))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+        let mut ready = BTreeMap::new();
+        let mut handed = 0;
+        for (i, result) in receiver {
+            ready.insert(i, result);
+            while let Some(result) = ready.remove(&handed) {
+                if let Err(e) = done(&items[handed], result) {
+                    stop.store(true, Ordering::SeqCst);
+                    return Err(e);
+                }
+                handed += 1;
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn results_come_in_order() {
+        let items: Vec<u64> = (0..20).collect();
+        // The early items take longest, so they finish last.
+        let work = |&n: &u64| {
+            thread::sleep(Duration::from_millis(20 - n));
+            n * n
+        };
+        let mut results = Vec::new();
+        map_ordered(&items, 4, work, |&n, square| -> Result<(), ()> {
+            results.push((n, square));
+            Ok(())
+        }).unwrap();
+        assert_eq!(results, items.iter().map(|&n| (n, n * n)).collect::<Vec<_>>());
+
+        let mut handed = 0;
+        let stopped = map_ordered(&items, 4, work, |&n, _| {
+            handed += 1;
+            if n == 5 { Err(n) } else { Ok(()) }
+        });
+        assert_eq!((stopped, handed), (Err(5), 6));
+    }
+}
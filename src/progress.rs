@@ -0,0 +1,110 @@
+use serde_json::Value;
+use std::io::Write;
+
+/// With `--progress-json`, long operations report on stdout as
+/// newline-delimited JSON events instead of human-oriented text, one object
+/// per line with an `event` field.
+pub fn enabled(args: &[String]) -> bool {
+    args.iter().any(|a| a == "--progress-json")
+}
+
+pub fn progress_event(operation: &str, done: u64, total: u64) -> Value {
+    json!({"event": "progress", "operation": operation, "done": done, "total": total})
+}
+
+/// A problem that doesn't stop the operation, optionally tied to the byte
+/// range `start..end` of the image.
+pub fn warning_event(operation: &str, message: &str, range: Option<(u64, u64)>) -> Value {
+    let mut event = json!({"event": "warning", "operation": operation, "message": message});
+    if let Some((start, end)) = range {
+        event["start"] = json!(start);
+        event["end"] = json!(end);
+    }
+    event
+}
+
+/// The end of the operation, with what it did in `fields`, an object.
+pub fn done_event(operation: &str, fields: Value) -> Value {
+    let mut event = json!({"event": "done", "operation": operation});
+    if let Value::Object(fields) = fields {
+        event.as_object_mut().unwrap().extend(fields);
+    }
+    event
+}
+
+pub fn progress(operation: &str, done: u64, total: u64) {
+    println!("{}", progress_event(operation, done, total));
+}
+
+pub fn warning(operation: &str, message: &str, range: Option<(u64, u64)>) {
+    println!("{}", warning_event(operation, message, range));
+}
+
+/// Progress events for an operation that reports after every cluster or
+/// sector, cut down to one per whole percent so a big volume doesn't print
+/// a line for each.
+pub struct Meter {
+    operation: String,
+    out: Box<dyn Write>,
+    percent: Option<u64>,
+}
+
+impl Meter {
+    pub fn new(operation: &str, out: Box<dyn Write>) -> Meter {
+        Meter { operation: operation.to_string(), out, percent: None }
+    }
+
+    pub fn update(&mut self, done: u64, total: u64) {
+        let percent = (done * 100).checked_div(total).unwrap_or(100);
+        if self.percent != Some(percent) {
+            self.percent = Some(percent);
+            // A closed pipe shows up in the output that follows.
+            let _ = writeln!(self.out, "{}", progress_event(&self.operation, done, total));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    /// Output that can be looked at after the `Meter` that owns it writes it.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn events_are_one_json_object_a_line() {
+        let out = Shared::default();
+        let mut meter = Meter::new("fill", Box::new(out.clone()));
+        for done in 0..=1000 {
+            meter.update(done, 1000);
+        }
+        let text = String::from_utf8(out.0.borrow().clone()).unwrap();
+        let events: Vec<Value> = text.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        // 0% to 100%, once each.
+        assert_eq!(events.len(), 101);
+        assert_eq!(events[0], json!({"event": "progress", "operation": "fill", "done": 0, "total": 1000}));
+        assert_eq!(events[100]["done"], 1000);
+        assert!(events.windows(2).all(|pair| pair[0]["done"].as_u64() < pair[1]["done"].as_u64()));
+
+        let warning = warning_event("rescue", "bad sector", Some((512, 1024))).to_string();
+        assert_eq!(serde_json::from_str::<Value>(&warning).unwrap(),
+                   json!({"event": "warning", "operation": "rescue", "message": "bad sector",
+                          "start": 512, "end": 1024}));
+        assert_eq!(done_event("scrub", json!({"checked": 2})),
+                   json!({"event": "done", "operation": "scrub", "checked": 2}));
+    }
+}
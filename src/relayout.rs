@@ -0,0 +1,417 @@
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::HashSet;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use {allocate_clusters, cluster_limit, cluster_size, cluster_start, copy_file, ea, fat_entry, read_disk_info,
+     read_fat, set_fat_entry, write_fat, DirEntry, DirEntryAttributes, Directory, DiskInfo, Error, FatType,
+     Result, DIR_ENTRY_ATTRS, DIR_ENTRY_FILESIZE, DIR_ENTRY_FLC, DIR_ENTRY_SIZE, LFN_ATTRIBUTES,
+     ROOT_DIR_ENTRIES, SECTORS_PER_CLUSTER, SECTORS_PER_FAT};
+
+/// The parts of a FAT12 or FAT16 layout that `relayout` can change. The
+/// volume keeps its size, boot record, FAT type and number of FATs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Layout {
+    pub root_dir_entries: u16,
+    pub sectors_per_cluster: u8,
+}
+
+impl Layout {
+    /// The layout the volume has now.
+    pub fn of(info: &DiskInfo) -> Layout {
+        Layout { root_dir_entries: info.root_dir_entries, sectors_per_cluster: info.sectors_per_cluster }
+    }
+}
+
+/// What a new layout would be, and whether what is on the volume fits it.
+#[derive(Debug, PartialEq)]
+pub struct Plan {
+    /// The root entry count, rounded up to whole sectors as FORMAT does.
+    pub root_dir_entries: u16,
+    pub sectors_per_cluster: u8,
+    pub sectors_per_fat: u16,
+    /// Data clusters in the new layout, less any that bad sectors fall in.
+    pub clusters: u32,
+    /// Clusters the files and subdirectories would take in it.
+    pub clusters_needed: u64,
+    /// Slots the root directory's entries take, long names and label included.
+    pub root_slots_needed: usize,
+    /// Why the volume can't be laid out so, if it can't.
+    pub problem: Option<String>,
+}
+
+/// The live slots of a directory, in order, up to its end marker.
+fn live_slots<R: Read + Seek>(directory: &Directory, disk_file: &mut R) -> Result<Vec<Vec<u8>>> {
+    Ok(directory.read_slots(disk_file)?
+        .chunks(DIR_ENTRY_SIZE)
+        .take_while(|slot| slot[0] != 0x00)
+        .filter(|slot| slot[0] != 0xE5)
+        .map(|slot| slot.to_vec())
+        .collect())
+}
+
+/// The entry a slot holds, if it is a file or subdirectory rather than a
+/// long name, a label, `.` or `..`.
+fn entry_of(slot: &[u8], fat_type: FatType) -> Option<DirEntry> {
+    let attributes = slot[DIR_ENTRY_ATTRS];
+    if attributes & 0x3F == LFN_ATTRIBUTES || attributes & DirEntryAttributes::VolumeLabel as u8 != 0 ||
+       slot[0] == b'.' {
+        return None;
+    }
+    Some(DirEntry::read(slot, fat_type))
+}
+
+fn is_dir(entry: &DirEntry) -> bool {
+    entry.attributes & DirEntryAttributes::SubDir as u8 != 0
+}
+
+/// The contents of OS/2's `EA DATA. SF`, if the root slot `slot` is its
+/// entry, laid out for clusters of `cluster` bytes. Each of its sets starts
+/// a cluster, so copied as it is to clusters of another size, OS/2 could
+/// find none of them. `None` for any other entry, or if the size stays.
+fn relaid_ea_file<R: Read + Seek>(info: &DiskInfo,
+                                  disk_file: &mut R,
+                                  fat: &[u8],
+                                  slot: &[u8],
+                                  cluster: u64)
+                                  -> Result<Option<Vec<u8>>> {
+    if slot[..11] != ea::EA_FILE[..] || cluster == cluster_size(info) {
+        return Ok(None);
+    }
+    let mut data = Vec::new();
+    copy_file(info, disk_file, fat, &DirEntry::read(slot, info.fat_type()), &mut data)?;
+    ea::relaid(data, cluster_size(info) as usize, cluster as usize).map(Some)
+}
+
+/// The clusters the entries of `slots` and everything under them take with
+/// clusters of `cluster` bytes. `seen` guards against directories that lead
+/// back to one another.
+fn tree_clusters<R: Read + Seek>(info: &DiskInfo,
+                                 disk_file: &mut R,
+                                 fat: &[u8],
+                                 slots: &[Vec<u8>],
+                                 cluster: u64,
+                                 seen: &mut HashSet<u32>)
+                                 -> Result<u64> {
+    let mut clusters = 0;
+    for entry in slots.iter().filter_map(|slot| entry_of(slot, info.fat_type())) {
+        if is_dir(&entry) {
+            if !seen.insert(entry.flc) {
+                return Err(Error::CorruptFatChain(format!("{}: a directory loop", entry.name())));
+            }
+            let children = live_slots(&Directory::chain(info, fat, entry.flc), disk_file)?;
+            clusters += (children.len() as u64 * DIR_ENTRY_SIZE as u64).div_ceil(cluster).max(1);
+            clusters += tree_clusters(info, disk_file, fat, &children, cluster, seen)?;
+        } else {
+            clusters += (entry.file_size as u64).div_ceil(cluster);
+        }
+    }
+    Ok(clusters)
+}
+
+/// The sectors, counted from the start of the volume, that the bad clusters
+/// of the FAT cover.
+fn bad_sectors(info: &DiskInfo, fat: &[u8]) -> Vec<u64> {
+    let bad = info.fat_type().bad_cluster();
+    (2..cluster_limit(info))
+        .filter(|&cluster| fat_entry(info, fat, cluster) == Some(bad))
+        .flat_map(|cluster| {
+            let first = cluster_start(info, cluster) / info.bytes_per_sector as u64;
+            first..first + info.sectors_per_cluster as u64
+        })
+        .collect()
+}
+
+/// The boot sector of the new layout: `boot` with its BPB changed.
+fn new_boot_sector(boot: &[u8], plan: &Plan) -> Vec<u8> {
+    let mut boot = boot.to_vec();
+    boot[SECTORS_PER_CLUSTER] = plan.sectors_per_cluster;
+    LittleEndian::write_u16(&mut boot[ROOT_DIR_ENTRIES..], plan.root_dir_entries);
+    LittleEndian::write_u16(&mut boot[SECTORS_PER_FAT..], plan.sectors_per_fat);
+    boot
+}
+
+/// Works out the volume laid out as `layout`: how big its FATs have to be, how
+/// many clusters it has, and whether the files, subdirectories and root
+/// entries fit. Nothing is written.
+pub fn plan<R: Read + Seek>(info: &DiskInfo, disk_file: &mut R, layout: Layout) -> Result<Plan> {
+    let bytes_per_sector = info.bytes_per_sector as u32;
+    let per_sector = (bytes_per_sector / DIR_ENTRY_SIZE as u32) as u16;
+    let spc = layout.sectors_per_cluster;
+    let mut plan = Plan {
+        root_dir_entries: layout.root_dir_entries.max(1).div_ceil(per_sector).saturating_mul(per_sector),
+        sectors_per_cluster: spc,
+        sectors_per_fat: 1,
+        clusters: 0,
+        clusters_needed: 0,
+        root_slots_needed: 0,
+        problem: None,
+    };
+    let fat_type = info.fat_type();
+    if fat_type == FatType::Fat32 {
+        plan.problem = Some("FAT32 volumes have no fixed root directory to lay out".to_string());
+        return Ok(plan);
+    }
+    if !spc.is_power_of_two() {
+        plan.problem = Some(format!("{} sectors per cluster isn't a power of two", spc));
+        return Ok(plan);
+    }
+
+    // The FATs have to cover the clusters they leave room for, so grow them
+    // until they do, as FORMAT does.
+    let total = info.sector_count();
+    let root_sectors = plan.root_dir_entries as u32 * DIR_ENTRY_SIZE as u32 / bytes_per_sector;
+    let bits = if fat_type == FatType::Fat12 { 12 } else { 16 };
+    let mut data_start;
+    loop {
+        let fat_sectors = info.fats as u32 * plan.sectors_per_fat as u32;
+        data_start = info.reserved_sectors as u32 + fat_sectors + root_sectors;
+        if data_start + spc as u32 > total {
+            plan.problem = Some("the FATs and root directory leave no room for data".to_string());
+            return Ok(plan);
+        }
+        plan.clusters = (total - data_start) / spc as u32;
+        let fat_bytes = (plan.clusters as u64 + 2) * bits / 8 + 1;
+        let needed = fat_bytes.div_ceil(bytes_per_sector as u64) as u16;
+        if needed <= plan.sectors_per_fat {
+            break;
+        }
+        plan.sectors_per_fat = needed;
+    }
+    let new_type = if plan.clusters < 4085 { FatType::Fat12 } else { FatType::Fat16 };
+    if new_type != fat_type || plan.clusters >= 65525 {
+        plan.problem = Some(format!("{} clusters would need a FAT other than the volume's {}",
+                                    plan.clusters,
+                                    fat_type.name()));
+        return Ok(plan);
+    }
+
+    let fat = read_fat(info, disk_file)?;
+    let cluster = spc as u64 * bytes_per_sector as u64;
+    let mut bad_clusters = HashSet::new();
+    for sector in bad_sectors(info, &fat) {
+        if sector < data_start as u64 {
+            plan.problem = Some(format!("sector {} is bad, and would be under the new FATs or root directory",
+                                        sector));
+            return Ok(plan);
+        }
+        // Sectors past the last whole cluster are in none.
+        let index = (sector - data_start as u64) / spc as u64;
+        if index < plan.clusters as u64 {
+            bad_clusters.insert(index);
+        }
+    }
+    plan.clusters -= bad_clusters.len() as u32;
+
+    let root = live_slots(&Directory::root(info, &fat), disk_file)?;
+    plan.root_slots_needed = root.len();
+    plan.clusters_needed = tree_clusters(info, disk_file, &fat, &root, cluster, &mut HashSet::new())?;
+    for slot in &root {
+        if let Some(data) = relaid_ea_file(info, disk_file, &fat, slot, cluster)? {
+            let size = LittleEndian::read_u32(&slot[DIR_ENTRY_FILESIZE..]) as u64;
+            plan.clusters_needed += (data.len() as u64).div_ceil(cluster);
+            plan.clusters_needed -= size.div_ceil(cluster);
+        }
+    }
+    if plan.root_slots_needed > plan.root_dir_entries as usize {
+        plan.problem = Some(format!("the root directory's entries take {} slots, and the new one has {}",
+                                    plan.root_slots_needed,
+                                    plan.root_dir_entries));
+    } else if plan.clusters_needed > plan.clusters as u64 {
+        plan.problem = Some(format!("the files and directories need {} clusters, and the new layout has {}",
+                                    plan.clusters_needed,
+                                    plan.clusters));
+    }
+    Ok(plan)
+}
+
+/// Copies the tree to a new layout, directory by directory.
+struct Copy<'a> {
+    old_info: &'a DiskInfo,
+    old: Cursor<Vec<u8>>,
+    old_fat: Vec<u8>,
+    info: DiskInfo,
+    new: Cursor<Vec<u8>>,
+    fat: Vec<u8>,
+}
+
+impl<'a> Copy<'a> {
+    /// The slots of a directory with each entry's contents copied to new
+    /// clusters. `own` and `parent` are the first clusters of the new
+    /// directory and its parent, for `.` and `..`, 0 for the root.
+    fn directory(&mut self, slots: &[Vec<u8>], own: u32, parent: u32) -> Result<Vec<u8>> {
+        let mut copied = Vec::with_capacity(slots.len() * DIR_ENTRY_SIZE);
+        let cluster = cluster_size(&self.info);
+        for slot in slots {
+            let mut slot = slot.clone();
+            if &slot[..11] == b".          " {
+                LittleEndian::write_u16(&mut slot[DIR_ENTRY_FLC..], own as u16);
+            } else if &slot[..11] == b"..         " {
+                LittleEndian::write_u16(&mut slot[DIR_ENTRY_FLC..], parent as u16);
+            } else if let Some(entry) = entry_of(&slot, self.old_info.fat_type()) {
+                let first = if is_dir(&entry) {
+                    let children = live_slots(&Directory::chain(self.old_info, &self.old_fat, entry.flc),
+                                              &mut self.old)?;
+                    let count = (children.len() as u64 * DIR_ENTRY_SIZE as u64).div_ceil(cluster).max(1);
+                    let clusters = self.allocate(count as usize)?;
+                    let mut data = self.directory(&children, clusters[0], own)?;
+                    data.resize(clusters.len() * cluster as usize, 0);
+                    self.write(&clusters, &data)?;
+                    clusters[0]
+                } else {
+                    let mut data = Vec::with_capacity(entry.file_size as usize);
+                    if copy_file(self.old_info, &mut self.old, &self.old_fat, &entry, &mut data)? <
+                       entry.file_size as u64 {
+                        return Err(Error::CorruptFatChain(entry.name()));
+                    }
+                    let relaid = match own {
+                        0 => relaid_ea_file(self.old_info, &mut self.old, &self.old_fat, &slot, cluster)?,
+                        _ => None,
+                    };
+                    if let Some(relaid) = relaid {
+                        data = relaid;
+                        LittleEndian::write_u32(&mut slot[DIR_ENTRY_FILESIZE..], data.len() as u32);
+                    }
+                    let clusters = self.allocate((data.len() as u64).div_ceil(cluster) as usize)?;
+                    self.write(&clusters, &data)?;
+                    clusters.first().map_or(0, |&c| c)
+                };
+                LittleEndian::write_u16(&mut slot[DIR_ENTRY_FLC..], first as u16);
+            }
+            copied.extend_from_slice(&slot);
+        }
+        Ok(copied)
+    }
+
+    fn allocate(&mut self, count: usize) -> Result<Vec<u32>> {
+        allocate_clusters(&self.info, &mut self.fat, count)
+            .ok_or_else(|| Error::NoSpace("the new layout ran out of clusters".to_string()))
+    }
+
+    fn write(&mut self, clusters: &[u32], data: &[u8]) -> Result<()> {
+        for (&cluster, chunk) in clusters.iter().zip(data.chunks(cluster_size(&self.info) as usize)) {
+            self.new.seek(SeekFrom::Start(cluster_start(&self.info, cluster)))?;
+            self.new.write_all(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+/// Lays the volume out again as `layout`, as `plan` works it out, moving every
+/// file and subdirectory to the new data area. The boot sector and any other
+/// reserved sectors are kept but for the BPB fields that change, and so are
+/// the entries' names, attributes and times; deleted entries and lost
+/// clusters are left behind, and clusters that bad sectors fall in are marked
+/// bad again. Fails with `NoSpace`, writing nothing, if the plan has a
+/// problem, and with `CorruptFatChain` if a file's chain is broken, as it
+/// could only be copied short.
+pub fn relayout<R: Read + Write + Seek>(info: &DiskInfo, disk_file: &mut R, layout: Layout) -> Result<Plan> {
+    let plan = plan(info, disk_file, layout)?;
+    if let Some(ref problem) = plan.problem {
+        return Err(Error::NoSpace(problem.clone()));
+    }
+    let mut image = Vec::new();
+    disk_file.seek(SeekFrom::Start(0))?;
+    disk_file.read_to_end(&mut image)?;
+    let mut old = Cursor::new(image);
+    let old_fat = read_fat(info, &mut old)?;
+
+    let reserved = info.reserved_sectors as usize * info.bytes_per_sector as usize;
+    let mut new = vec![0; old.get_ref().len()];
+    new[..reserved].copy_from_slice(&new_boot_sector(&old.get_ref()[..reserved], &plan));
+    let mut new = Cursor::new(new);
+    let mut new_info = read_disk_info(&mut new)?;
+    new_info.options = info.options.clone();
+
+    let mut fat = vec![0; plan.sectors_per_fat as usize * info.bytes_per_sector as usize];
+    for n in 0..2 {
+        set_fat_entry(&new_info, &mut fat, n, fat_entry(info, &old_fat, n).unwrap_or(0));
+    }
+    let bad = new_info.fat_type().bad_cluster();
+    let data_start = cluster_start(&new_info, 2) / info.bytes_per_sector as u64;
+    for sector in bad_sectors(info, &old_fat) {
+        let cluster = (sector - data_start) / plan.sectors_per_cluster as u64 + 2;
+        if cluster < cluster_limit(&new_info) as u64 {
+            set_fat_entry(&new_info, &mut fat, cluster as u32, bad);
+        }
+    }
+
+    let root = live_slots(&Directory::root(info, &old_fat), &mut old)?;
+    let mut copy = Copy { old_info: info, old, old_fat, info: new_info, new, fat };
+    let mut slots = copy.directory(&root, 0, 0)?;
+    slots.resize(plan.root_dir_entries as usize * DIR_ENTRY_SIZE, 0);
+    let Copy { info: new_info, mut new, fat, .. } = copy;
+    Directory::root(&new_info, &fat).write_slots(&mut new, &slots)?;
+    write_fat(&new_info, &mut new, &fat)?;
+
+    disk_file.seek(SeekFrom::Start(0))?;
+    disk_file.write_all(new.get_ref())?;
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::{blank, host_file, names};
+    use {find_path, mkdir, put, Fat12Volume};
+
+    #[test]
+    fn volumes_are_laid_out_again_with_their_files() {
+        let (info, mut image) = blank();
+        mkdir(&info, &mut image, "/DIR").unwrap();
+        put(&info, &mut image, &host_file("relayout", "Long name.txt", &[7; 3000]), "/DIR").unwrap();
+        put(&info, &mut image, &host_file("relayout", "A.TXT", b"a"), "/").unwrap();
+        let before = names(&mut image, "/DIR");
+
+        let layout = Layout { root_dir_entries: 500, sectors_per_cluster: 2 };
+        let done = relayout(&info, &mut image, layout).unwrap();
+        // 500 entries round up to 512, 32 sectors of them.
+        assert_eq!((done.root_dir_entries, done.sectors_per_fat, done.problem), (512, 5, None));
+        assert_eq!(done.clusters_needed, 1 + 3 + 1);
+        let info = read_disk_info(&mut image).unwrap();
+        assert_eq!(Layout::of(&info), Layout { root_dir_entries: 512, sectors_per_cluster: 2 });
+        assert_eq!(names(&mut image, "/DIR"), before);
+        let mut volume = Fat12Volume::open(image.clone()).unwrap();
+        assert_eq!(volume.read_file("/DIR/Long name.txt").unwrap(), vec![7; 3000]);
+        assert_eq!(volume.read_file("/A.TXT").unwrap(), b"a");
+        let dir = find_path(&info, &mut image, "/DIR").unwrap().unwrap();
+        let fat = read_fat(&info, &mut image).unwrap();
+        let dots = live_slots(&Directory::chain(&info, &fat, dir.flc), &mut image).unwrap();
+        assert_eq!(LittleEndian::read_u16(&dots[0][DIR_ENTRY_FLC..]) as u32, dir.flc);
+        assert_eq!(LittleEndian::read_u16(&dots[1][DIR_ENTRY_FLC..]), 0);
+
+        // Sixteen root entries are one sector of them, too few for eighteen
+        // files, and clusters have to be a power of two sectors.
+        for n in 0..16 {
+            put(&info, &mut image, &host_file("relayout", &format!("{}.TXT", n), b"n"), "/").unwrap();
+        }
+        let small = plan(&info, &mut image, Layout { root_dir_entries: 2, sectors_per_cluster: 2 }).unwrap();
+        assert_eq!((small.root_dir_entries, small.root_slots_needed), (16, 18));
+        assert!(small.problem.unwrap().contains("take 18 slots"));
+        let odd = Layout { root_dir_entries: 512, sectors_per_cluster: 3 };
+        assert!(plan(&info, &mut image, odd).unwrap().problem.unwrap().contains("power of two"));
+        let before = image.get_ref().clone();
+        assert!(matches!(relayout(&info, &mut image, odd), Err(Error::NoSpace(_))));
+        assert_eq!(image.get_ref(), &before);
+    }
+
+    #[test]
+    fn bigger_clusters_have_to_hold_the_files() {
+        let (info, mut image) = blank();
+        for n in 0..50 {
+            let host = host_file("recluster", &format!("{}.TXT", n), &[n as u8; 700]);
+            put(&info, &mut image, &host, "/").unwrap();
+        }
+        // 32 KB clusters leave 44 of them for 50 files of a cluster each;
+        // 1 KB ones leave 1427.
+        let layout = |sectors_per_cluster| Layout { root_dir_entries: 224, sectors_per_cluster };
+        let huge = plan(&info, &mut image, layout(64)).unwrap();
+        assert_eq!((huge.clusters, huge.clusters_needed), (44, 50));
+        assert!(huge.problem.unwrap().contains("need 50 clusters"));
+
+        let done = relayout(&info, &mut image, layout(2)).unwrap();
+        assert_eq!((done.clusters, done.clusters_needed), (1427, 50));
+        let mut volume = Fat12Volume::open(image).unwrap();
+        for n in 0..50 {
+            assert_eq!(volume.read_file(&format!("/{}.TXT", n)).unwrap(), vec![n as u8; 700]);
+        }
+    }
+}
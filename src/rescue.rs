@@ -0,0 +1,379 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use {cluster_chain, cluster_start, root_dir_start, DirEntry, DirEntryAttributes, DiskInfo, FatTable, FatType};
+
+/// Sectors read at once while the source behaves. On an error the chunk is
+/// read again one sector at a time to isolate the bad ones.
+const CHUNK_SECTORS: u64 = 64;
+
+pub struct Options {
+    pub sector_size: usize,
+    pub retries: u32,
+    /// Repeated to fill the output wherever the source couldn't be read.
+    pub fill: Vec<u8>,
+    /// The map an earlier run left of the same source and output, to carry
+    /// on from: only what it has as bad or untried is read again, and the
+    /// rest of the output is left as that run wrote it.
+    pub resume: Option<Rescue>,
+    /// How many bytes are read between checkpoints.
+    pub checkpoint_every: u64,
+}
+
+/// The outcome of a rescue, or how far one has got: the size of the source
+/// and the byte ranges that couldn't be read and that haven't been read yet,
+/// each in order.
+pub struct Rescue {
+    pub size: u64,
+    pub bad: Vec<(u64, u64)>,
+    pub untried: Vec<(u64, u64)>,
+}
+impl Rescue {
+    pub fn bad_bytes(&self) -> u64 {
+        self.bad.iter().map(|&(_, len)| len).sum()
+    }
+
+    /// Writes the result as a GNU ddrescue mapfile: good ranges are finished
+    /// (`+`), unreadable ones are bad sectors (`-`) and those not read yet
+    /// are non-tried (`?`), with the position to carry on from.
+    pub fn write_map<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let (current, status) = match self.untried.first() {
+            Some(&(start, _)) => (start, '?'),
+            None => (self.size, '+'),
+        };
+        writeln!(out, "# Mapfile. Created by fat12 rescue")?;
+        writeln!(out, "# current_pos  current_status  current_pass")?;
+        writeln!(out, "0x{:08X}     {}               1", current, status)?;
+        writeln!(out, "#      pos        size  status")?;
+        let mut ranges: Vec<(u64, u64, char)> = Vec::new();
+        ranges.extend(self.bad.iter().map(|&(start, len)| (start, len, '-')));
+        ranges.extend(self.untried.iter().map(|&(start, len)| (start, len, '?')));
+        ranges.sort_unstable();
+        let mut pos = 0;
+        for (start, len, status) in ranges {
+            if start > pos {
+                writeln!(out, "0x{:08X}  0x{:08X}  +", pos, start - pos)?;
+            }
+            writeln!(out, "0x{:08X}  0x{:08X}  {}", start, len, status)?;
+            pos = start + len;
+        }
+        if self.size > pos {
+            writeln!(out, "0x{:08X}  0x{:08X}  +", pos, self.size - pos)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads a GNU ddrescue mapfile, as `write_map` writes them or ddrescue
+/// leaves them. Ranges not tried (`?`) are untried, and every other range
+/// not finished (`+`), whether bad (`-`), not trimmed (`*`) or not scraped
+/// (`/`), counts as bad.
+pub fn read_map(text: &str) -> io::Result<Rescue> {
+    let invalid = |n: usize| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("map line {}: malformed", n + 1))
+    };
+    let number = |text: &str| match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    };
+    let mut rescue = Rescue { size: 0, bad: Vec::new(), untried: Vec::new() };
+    // The first line that isn't a comment is ddrescue's position and pass.
+    let mut status_line = true;
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if status_line {
+            status_line = false;
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let field = |i: usize| fields.get(i).and_then(|f| number(f));
+        let (pos, size) = match (field(0), field(1)) {
+            (Some(pos), Some(size)) if fields.len() >= 3 => (pos, size),
+            _ => return Err(invalid(n)),
+        };
+        rescue.size = rescue.size.max(pos + size);
+        if fields[2] == "+" || size == 0 {
+            continue;
+        }
+        add_range(if fields[2] == "?" { &mut rescue.untried } else { &mut rescue.bad }, pos, size);
+    }
+    Ok(rescue)
+}
+
+/// Adds a range to the end of `ranges`, joining it to the last if they meet.
+fn add_range(ranges: &mut Vec<(u64, u64)>, pos: u64, len: u64) {
+    match ranges.last_mut() {
+        Some(last) if last.0 + last.1 == pos => last.1 += len,
+        _ => ranges.push((pos, len)),
+    }
+}
+
+impl Rescue {
+    /// Which of `count` sectors from `first` the map has as bad or untried.
+    fn bad_sectors(&self, info: &DiskInfo, first: u64, count: u64) -> Vec<u64> {
+        let sector_size = info.bytes_per_sector as u64;
+        (first..first + count)
+            .filter(|&sector| {
+                let (start, end) = (sector * sector_size, (sector + 1) * sector_size);
+                self.bad.iter().chain(&self.untried).any(|&(pos, len)| pos < end && start < pos + len)
+            })
+            .collect()
+    }
+
+    /// The sectors of `entry`'s clusters that the map has as bad, in chain
+    /// order. Clusters past the file's size aren't counted.
+    pub fn unrecovered<F: FatTable + ?Sized>(&self, info: &DiskInfo, fat: &F, entry: &DirEntry) -> Vec<u64> {
+        let sector_size = info.bytes_per_sector as u64;
+        let cluster_sectors = info.sectors_per_cluster as u64;
+        let mut chain = cluster_chain(info, fat, entry.flc);
+        if entry.attributes & DirEntryAttributes::SubDir as u8 == 0 {
+            let used = (entry.file_size as u64).div_ceil(sector_size * cluster_sectors);
+            chain.truncate(used as usize);
+        }
+        chain.iter()
+            .flat_map(|&cluster| {
+                self.bad_sectors(info, cluster_start(info, cluster) / sector_size, cluster_sectors)
+            })
+            .collect()
+    }
+
+    /// The parts of the volume's metadata the map has bad sectors in: the
+    /// boot sector, the FATs and a FAT12 or FAT16 root directory, each with
+    /// its bad sectors.
+    pub fn unrecovered_metadata(&self, info: &DiskInfo) -> Vec<(&'static str, Vec<u64>)> {
+        let sector_size = info.bytes_per_sector as u64;
+        let fats = info.fats as u64 * info.fat_sectors() as u64;
+        let mut regions = vec![("boot sector", 0, info.reserved_sectors as u64),
+                               ("FAT", info.reserved_sectors as u64, fats)];
+        if info.fat_type() != FatType::Fat32 {
+            let root_sectors = (info.root_dir_entries as u64 * 32).div_ceil(sector_size);
+            regions.push(("root directory", root_dir_start(info) / sector_size, root_sectors));
+        }
+        regions.into_iter()
+            .map(|(name, first, count)| (name, self.bad_sectors(info, first, count)))
+            .filter(|(_, bad)| !bad.is_empty())
+            .collect()
+    }
+}
+
+/// Sectors as ranges, e.g. `40-41,97`.
+pub fn sector_ranges(sectors: &[u64]) -> String {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for &sector in sectors {
+        match ranges.last_mut() {
+            Some(range) if range.1 + 1 == sector => range.1 = sector,
+            _ => ranges.push((sector, sector)),
+        }
+    }
+    ranges.iter()
+        .map(|&(a, b)| if a == b { a.to_string() } else { format!("{}-{}", a, b) })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Reads as much of `buf` as the source has at `offset`, stopping early only
+/// at the end of the source.
+fn read_at<R: Read + Seek>(source: &mut R, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    source.seek(SeekFrom::Start(offset))?;
+    let mut done = 0;
+    while done < buf.len() {
+        match source.read(&mut buf[done..]) {
+            Ok(0) => break,
+            Ok(n) => done += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(done)
+}
+
+/// Takes `len` bytes off the front of the first of `ranges`.
+fn take_front(ranges: &mut Vec<(u64, u64)>, len: u64) {
+    ranges[0].0 += len;
+    ranges[0].1 -= len;
+    if ranges[0].1 == 0 {
+        ranges.remove(0);
+    }
+}
+
+/// Copies `source` to `out`, retrying unreadable sectors and filling the ones
+/// that never read with the marker pattern. `progress` is called with the
+/// bytes done so far, the total, and the start of and error for each sector
+/// given up on. `checkpoint` is given how far the rescue has got every
+/// `checkpoint_every` bytes, to be written as a map for `resume` should the
+/// rescue not finish; an error from it stops the rescue.
+pub fn rescue<R, W, F, C>(source: &mut R,
+                          out: &mut W,
+                          options: &Options,
+                          mut progress: F,
+                          mut checkpoint: C)
+                          -> io::Result<Rescue>
+    where R: Read + Seek,
+          W: Write + Seek,
+          F: FnMut(u64, u64, Option<(u64, &io::Error)>),
+          C: FnMut(&Rescue) -> io::Result<()>
+{
+    // Block devices report a zero length in their metadata; seeking works.
+    let size = source.seek(SeekFrom::End(0))?;
+    let sector_size = options.sector_size as u64;
+    let mut filler = vec![0; options.sector_size];
+    for (i, byte) in filler.iter_mut().enumerate() {
+        *byte = options.fill[i % options.fill.len()];
+    }
+
+    let mut rescue = Rescue { size, bad: Vec::new(), untried: Vec::new() };
+    match options.resume {
+        Some(ref map) if map.size != size => {
+            let why = format!("the map is of {} bytes, but the source has {}", map.size, size);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, why));
+        }
+        Some(ref map) => {
+            let mut todo: Vec<(u64, u64)> = map.bad.iter().chain(&map.untried).cloned().collect();
+            todo.sort_unstable();
+            for (pos, len) in todo {
+                add_range(&mut rescue.untried, pos, len);
+            }
+        }
+        None if size > 0 => rescue.untried.push((0, size)),
+        None => (),
+    }
+    let remaining = |rescue: &Rescue| rescue.untried.iter().map(|&(_, len)| len).sum::<u64>();
+    let mut buf = vec![0; (CHUNK_SECTORS * sector_size) as usize];
+    let mut since_checkpoint = 0;
+    while let Some(&(offset, untried)) = rescue.untried.first() {
+        let len = untried.min(buf.len() as u64) as usize;
+        if read_at(source, offset, &mut buf[..len]).is_ok() {
+            out.seek(SeekFrom::Start(offset))?;
+            out.write_all(&buf[..len])?;
+            take_front(&mut rescue.untried, len as u64);
+        } else {
+            let chunk_end = offset + len as u64;
+            let mut offset = offset;
+            while offset < chunk_end {
+                let len = (chunk_end - offset).min(sector_size) as usize;
+                let mut result = read_at(source, offset, &mut buf[..len]);
+                for _ in 0..options.retries {
+                    if result.is_ok() {
+                        break;
+                    }
+                    result = read_at(source, offset, &mut buf[..len]);
+                }
+                out.seek(SeekFrom::Start(offset))?;
+                take_front(&mut rescue.untried, len as u64);
+                match result {
+                    Ok(_) => out.write_all(&buf[..len])?,
+                    Err(e) => {
+                        out.write_all(&filler[..len])?;
+                        add_range(&mut rescue.bad, offset, len as u64);
+                        progress(size - remaining(&rescue), size, Some((offset, &e)));
+                    }
+                }
+                offset += len as u64;
+            }
+        }
+        progress(size - remaining(&rescue), size, None);
+        since_checkpoint += len as u64;
+        if since_checkpoint >= options.checkpoint_every && !rescue.untried.is_empty() {
+            checkpoint(&rescue)?;
+            since_checkpoint = 0;
+        }
+    }
+    Ok(rescue)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::ops::Range;
+    use tests::{blank, host_file};
+    use {find_path, put, read_fat};
+
+    /// A source whose reads fail where they touch `bad`, counting the bytes
+    /// it was asked for.
+    struct Failing {
+        data: Cursor<Vec<u8>>,
+        bad: Range<u64>,
+        asked: u64,
+    }
+    impl Read for Failing {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let pos = self.data.position();
+            self.asked += buf.len() as u64;
+            if pos < self.bad.end && self.bad.start < pos + buf.len() as u64 {
+                return Err(io::Error::other("unreadable"));
+            }
+            self.data.read(buf)
+        }
+    }
+    impl Seek for Failing {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.data.seek(pos)
+        }
+    }
+
+    #[test]
+    fn interrupted_rescues_carry_on_from_their_map() {
+        let data: Vec<u8> = (0..65536).map(|i| (i / 512) as u8).collect();
+        let mut source = Failing { data: Cursor::new(data.clone()), bad: 40000..40001, asked: 0 };
+        let mut out = Cursor::new(Vec::new());
+        let mut options = Options {
+            sector_size: 512,
+            retries: 0,
+            fill: b"X".to_vec(),
+            resume: None,
+            checkpoint_every: 32768,
+        };
+        // The run stops at its first checkpoint, with half the source read.
+        let mut map = Vec::new();
+        let stopped = rescue(&mut source, &mut out, &options, |_, _, _| (), |rescue| {
+            rescue.write_map(&mut map)?;
+            Err(io::Error::from(io::ErrorKind::Interrupted))
+        });
+        assert_eq!(stopped.err().unwrap().kind(), io::ErrorKind::Interrupted);
+        let map = String::from_utf8(map).unwrap();
+        assert!(map.contains("0x00008000     ?") && map.contains("0x00008000  0x00008000  ?"), "{}", map);
+
+        options.resume = Some(read_map(&map).unwrap());
+        source.asked = 0;
+        let done = rescue(&mut source, &mut out, &options, |_, _, _| (), |_| Ok(())).unwrap();
+        assert_eq!((done.bad, done.untried), (vec![(39936, 512)], vec![]));
+        // Only the untried half was read again: a chunk, then its sectors
+        // one by one when the chunk failed.
+        assert_eq!(source.asked, 2 * 32768);
+        let out = out.into_inner();
+        assert_eq!(&out[..39936], &data[..39936]);
+        assert_eq!(&out[39936..40448], &[b'X'; 512][..]);
+        assert_eq!(&out[40448..], &data[40448..]);
+    }
+
+    #[test]
+    fn maps_name_the_files_with_unrecovered_sectors() {
+        let (info, mut image) = blank();
+        put(&info, &mut image, &host_file("map", "GOOD.TXT", &[1; 1024]), "/").unwrap();
+        put(&info, &mut image, &host_file("map", "BAD.TXT", &[2; 1024]), "/").unwrap();
+        let bad = find_path(&info, &mut image, "/BAD.TXT").unwrap().unwrap();
+        let start = cluster_start(&info, bad.flc);
+        let size = image.get_ref().len() as u64;
+        // ddrescue's own layout: a status line, then ranges, some not tried.
+        let map = format!("# Mapfile. Created by GNU ddrescue\n0x00000000 ? 1\n\
+                           0x00000000 0x{:08X} +\n0x{:08X} 0x00000200 -\n0x{:08X} 0x00000400 +\n\
+                           0x{:08X} 0x{:08X} ?\n",
+                          start + 512,
+                          start + 512,
+                          start + 1024,
+                          start + 2048,
+                          size - start - 2048);
+        let rescue = read_map(&map).unwrap();
+        assert_eq!(rescue.size, size);
+        let fat = read_fat(&info, &mut image).unwrap();
+        let good = find_path(&info, &mut image, "/GOOD.TXT").unwrap().unwrap();
+        assert!(rescue.unrecovered(&info, &fat[..], &good).is_empty());
+        let sectors = rescue.unrecovered(&info, &fat[..], &bad);
+        assert_eq!(sectors, vec![start / 512 + 1]);
+        assert_eq!(sector_ranges(&[3, 4, 5, 9]), "3-5,9");
+        assert!(rescue.unrecovered_metadata(&info).is_empty());
+        assert!(read_map("0 ? 1\n0x0 0x200\n").is_err());
+    }
+}
@@ -0,0 +1,244 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use serde_json::{self, Map, Value};
+use backup::{sha256_reader, Sha256Writer};
+use {lock, pool, bpb_looks_valid, copy_file, for_each_entry, load_fat, read_disk_info, DirEntryAttributes,
+     Error, Warning};
+
+/// The hashes recorded for one image: the whole image, and each file in it
+/// by path. The warnings aren't recorded, only reported.
+struct Hashes {
+    image: String,
+    files: BTreeMap<String, String>,
+    warnings: Vec<Warning>,
+}
+
+/// What a scrub found: the images newly added to the manifest, a line for
+/// each change or error, and one for each warning reading the images.
+pub struct Report {
+    pub checked: usize,
+    pub added: Vec<String>,
+    pub problems: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+/// How a scrub goes about it.
+pub struct Options {
+    /// How many images are read at once, each on a thread of its own. The
+    /// report comes out the same however many there are.
+    pub jobs: usize,
+}
+impl Default for Options {
+    fn default() -> Self {
+        Options { jobs: pool::default_jobs() }
+    }
+}
+
+/// Hashes an image and every file in it. Files that don't start with a
+/// plausible BPB aren't images and give `None`.
+fn hash_image(path: &Path) -> io::Result<Option<Hashes>> {
+    let mut disk_file = File::open(path)?;
+    let _lock = lock::shared(&disk_file, &path.to_string_lossy())?;
+    if disk_file.metadata()?.len() < 512 {
+        return Ok(None);
+    }
+    let info = match read_disk_info(&mut disk_file) {
+        Ok(info) => info,
+        Err(Error::InvalidBootSector(_)) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if !bpb_looks_valid(&info) {
+        return Ok(None);
+    }
+    disk_file.seek(SeekFrom::Start(0))?;
+    let image = sha256_reader(&mut disk_file)?;
+    let fat = load_fat(&info, disk_file.try_clone()?)?;
+    let mut files = BTreeMap::new();
+    for_each_entry(&info, &mut disk_file, &*fat, false, |disk_file, found| {
+        if (found.entry.attributes & DirEntryAttributes::SubDir as u8) == 0 {
+            let mut hasher = Sha256Writer::new();
+            copy_file(&info, disk_file, &*fat, &found.entry, &mut hasher)?;
+            files.insert(found.path, hasher.finish());
+        }
+        Ok(())
+    })?;
+    Ok(Some(Hashes { image, files, warnings: info.options.warnings.take() }))
+}
+
+/// Collects the regular files under `dir`, sorted, leaving out `skip`.
+pub(crate) fn find_files(dir: &Path, skip: &Path, out: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            find_files(&path, skip, out)?;
+        } else if path != skip {
+            out.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn to_json(hashes: &Hashes) -> Value {
+    let files = hashes.files
+        .iter()
+        .map(|(path, hash)| (path.clone(), Value::String(hash.clone())))
+        .collect::<Map<_, _>>();
+    let mut image = Map::new();
+    image.insert("sha256".to_string(), Value::String(hashes.image.clone()));
+    image.insert("files".to_string(), Value::Object(files));
+    Value::Object(image)
+}
+
+/// Compares an image's current hashes with the recorded ones, adding a line
+/// to `problems` for each difference.
+fn compare(name: &str, recorded: &Value, current: &Hashes, problems: &mut Vec<String>) {
+    if recorded["sha256"].as_str() == Some(current.image.as_str()) {
+        return;
+    }
+    problems.push(format!("{}: image hash changed", name));
+    let empty = Map::new();
+    let files = recorded["files"].as_object().unwrap_or(&empty);
+    for (path, hash) in files {
+        match current.files.get(path) {
+            None => problems.push(format!("{}:{}: missing", name, path)),
+            Some(current) if hash.as_str() != Some(current.as_str()) => {
+                problems.push(format!("{}:{}: contents changed", name, path))
+            }
+            Some(_) => (),
+        }
+    }
+    for path in current.files.keys().filter(|path| !files.contains_key(*path)) {
+        problems.push(format!("{}:{}: not in the manifest", name, path));
+    }
+}
+
+/// Checks every image under `dir` against the hashes in `manifest_path`, and
+/// records images the manifest doesn't know yet. Known images are never
+/// rewritten in the manifest, so a change keeps being reported until the
+/// curator deals with it.
+pub fn scrub(dir: &Path, manifest_path: &Path) -> io::Result<Report> {
+    scrub_with_progress(dir, manifest_path, |_, _| ())
+}
+
+/// Scrubs as `scrub` does, calling `progress` with the files looked at so far
+/// and the files under `dir` in all after each one.
+pub fn scrub_with_progress<F>(dir: &Path, manifest_path: &Path, progress: F) -> io::Result<Report>
+    where F: FnMut(u64, u64)
+{
+    scrub_with(dir, manifest_path, &Options::default(), progress)
+}
+
+/// Scrubs as `scrub_with_progress` does, as `options` has it.
+pub fn scrub_with<F>(dir: &Path,
+                     manifest_path: &Path,
+                     options: &Options,
+                     mut progress: F)
+                     -> io::Result<Report>
+    where F: FnMut(u64, u64)
+{
+    let mut manifest = match File::open(manifest_path) {
+        Ok(mut file) => {
+            let mut text = String::new();
+            file.read_to_string(&mut text)?;
+            serde_json::from_str(&text).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData,
+                               format!("{}: {}", manifest_path.display(), e))
+            })?
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Value::Object(Map::new()),
+        Err(e) => return Err(e),
+    };
+    let invalid = |why: &str| io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("{}: {}", manifest_path.display(), why));
+    match manifest.get("images") {
+        _ if !manifest.is_object() => return Err(invalid("not a JSON object")),
+        None => manifest["images"] = Value::Object(Map::new()),
+        Some(images) if !images.is_object() => return Err(invalid("\"images\" is not an object")),
+        Some(_) => (),
+    }
+
+    // Compare absolute paths, so the manifest is skipped however it's named.
+    let dir = fs::canonicalize(dir)?;
+    let manifest_dir = match manifest_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let skip = fs::canonicalize(manifest_dir)?.join(manifest_path.file_name().unwrap_or_default());
+
+    let mut report = Report { checked: 0, added: Vec::new(), problems: Vec::new(), warnings: Vec::new() };
+    let mut present = HashSet::new();
+    let mut paths = Vec::new();
+    find_files(&dir, &skip, &mut paths)?;
+    let total = paths.len() as u64;
+    let mut done = 0;
+    progress(done, total);
+    pool::map_ordered(&paths, options.jobs, |path| hash_image(path), |path, hashes| -> io::Result<()> {
+        done += 1;
+        progress(done, total);
+        let name = path.strip_prefix(&dir).unwrap().to_string_lossy().replace('\\', "/");
+        let hashes = match hashes {
+            Ok(Some(hashes)) => hashes,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                report.problems.push(format!("{}: {}", name, e));
+                present.insert(name);
+                return Ok(());
+            }
+        };
+        report.checked += 1;
+        report.warnings.extend(hashes.warnings.iter().map(|warning| format!("{}: {}", name, warning)));
+        match manifest["images"].get(&name) {
+            Some(recorded) => compare(&name, recorded, &hashes, &mut report.problems),
+            None => {
+                manifest["images"][&name] = to_json(&hashes);
+                report.added.push(name.clone());
+            }
+        }
+        present.insert(name);
+        Ok(())
+    })?;
+    for name in manifest["images"].as_object().into_iter().flat_map(|images| images.keys()) {
+        if !present.contains(name) {
+            report.problems.push(format!("{}: missing from the archive", name));
+        }
+    }
+
+    if !report.added.is_empty() {
+        let partial = manifest_path.with_extension("partial");
+        let mut file = File::create(&partial)?;
+        serde_json::to_writer_pretty(&mut file, &manifest)?;
+        writeln!(file)?;
+        fs::rename(&partial, manifest_path)?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn manifests_of_the_wrong_shape_are_refused() {
+        let dir = env::temp_dir().join(format!("fat12-scrub-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let manifest = dir.join("manifest.json");
+        for (text, why) in [("[]", "not a JSON object"),
+                            ("{\"images\": []}", "\"images\" is not an object"),
+                            ("{\"images\": 3}", "\"images\" is not an object")] {
+            fs::write(&manifest, text).unwrap();
+            let e = scrub(&dir, &manifest).err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            assert!(e.to_string().ends_with(why), "{}", e);
+            // Nor is the manifest rewritten.
+            assert_eq!(fs::read_to_string(&manifest).unwrap(), text);
+        }
+        fs::write(&manifest, "{}").unwrap();
+        assert_eq!(scrub(&dir, &manifest).unwrap().checked, 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
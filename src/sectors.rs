@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::io::{Read, Seek};
+use std::ops::Range;
+use {cluster_chain, cluster_start, find_slot, first_long_slot, for_each_entry, read_fat, root_dir_start,
+     DirEntryAttributes, DiskInfo, FatType, Result};
+
+/// What a sector of the volume holds, so that a sector written from outside,
+/// as an emulator's guest writes one, can be traced to what it changed.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Region {
+    /// The boot sector and any other reserved sectors.
+    Reserved,
+    /// A sector of the FAT copy with this number, from 0.
+    Fat(u8),
+    /// The fixed root directory of FAT12 and FAT16.
+    RootDirectory,
+    /// A cluster of the subdirectory at this path, or on FAT32 of the root,
+    /// which is `/`.
+    Directory(String),
+    /// A cluster of the file at this path.
+    File(String),
+    /// A data sector no file or directory has: free, bad, lost, or past the
+    /// last whole cluster.
+    Unowned,
+}
+
+/// The `i`th sector of an image, cut short at its end.
+fn sector(image: &[u8], i: usize, bytes_per_sector: usize) -> Option<&[u8]> {
+    image.get(i * bytes_per_sector..).map(|rest| &rest[..rest.len().min(bytes_per_sector)])
+}
+
+/// The sectors that differ between two images of a volume, in order: the
+/// sectors a change dirtied, for signaling it to whatever has the volume
+/// mounted.
+pub fn dirty_sectors(before: &[u8], after: &[u8], bytes_per_sector: usize) -> Vec<u64> {
+    let count = before.len().max(after.len()).div_ceil(bytes_per_sector);
+    (0..count)
+        .filter(|&i| sector(before, i, bytes_per_sector) != sector(after, i, bytes_per_sector))
+        .map(|i| i as u64)
+        .collect()
+}
+
+fn cluster_sectors(info: &DiskInfo, cluster: u32) -> Range<u64> {
+    let first = cluster_start(info, cluster) / info.bytes_per_sector as u64;
+    first..first + info.sectors_per_cluster as u64
+}
+
+/// The sectors of every FAT copy that hold the entries of `chain`. An entry
+/// of FAT12 can straddle two sectors.
+fn fat_sectors(info: &DiskInfo, chain: &[u32]) -> Vec<u64> {
+    let bytes = info.bytes_per_sector as u64;
+    let mut sectors = Vec::new();
+    for &cluster in chain {
+        let (offset, len) = match info.fat_type() {
+            FatType::Fat12 => (cluster as u64 * 3 / 2, 2),
+            FatType::Fat16 => (cluster as u64 * 2, 2),
+            FatType::Fat32 => (cluster as u64 * 4, 4),
+        };
+        for copy in 0..info.fats as u64 {
+            let fat = info.reserved_sectors as u64 + copy * info.fat_sectors() as u64;
+            sectors.push(fat + offset / bytes);
+            sectors.push(fat + (offset + len - 1) / bytes);
+        }
+    }
+    sectors
+}
+
+/// The sectors the file or directory at `path` takes as it is now: those of
+/// its directory slot and the long-name slots before it, those of each FAT
+/// copy that hold its chain, and its clusters', in order. Writing the entry
+/// dirties no others, so `sectors_of` before a change and after it together
+/// cover everything the change can touch but FAT32's FSInfo sector. `None` if
+/// there is no such entry, as for the root.
+pub fn sectors_of<R: Read + Seek>(info: &DiskInfo,
+                                  disk_file: &mut R,
+                                  path: &str)
+                                  -> Result<Option<Vec<u64>>> {
+    let (directory, slot, entry) = match find_slot(info, disk_file, path)? {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let slots = directory.read_slots(disk_file)?;
+    let bytes = info.bytes_per_sector as u64;
+    let mut sectors: Vec<u64> = (first_long_slot(&slots, slot)..slot + 1)
+        .filter_map(|slot| directory.slot_offset(slot))
+        .map(|offset| offset / bytes)
+        .collect();
+    let chain = cluster_chain(info, &read_fat(info, disk_file)?, entry.flc);
+    sectors.extend(fat_sectors(info, &chain));
+    for &cluster in &chain {
+        sectors.extend(cluster_sectors(info, cluster));
+    }
+    sectors.sort_unstable();
+    sectors.dedup();
+    Ok(Some(sectors))
+}
+
+/// Where everything on a volume is, by sector: the other way from
+/// `sectors_of`.
+pub struct SectorMap {
+    fats: Range<u64>,
+    fat_sectors: u64,
+    root: Range<u64>,
+    data_start: u64,
+    sectors_per_cluster: u64,
+    /// The paths of the files and directories, and whether each is a
+    /// directory.
+    owners: Vec<(String, bool)>,
+    /// Which of `owners` each cluster in use belongs to.
+    clusters: HashMap<u32, usize>,
+}
+
+impl SectorMap {
+    /// Maps the volume as it is now, walking its whole tree.
+    pub fn new<R: Read + Seek>(info: &DiskInfo, disk_file: &mut R) -> Result<SectorMap> {
+        let bytes = info.bytes_per_sector as u64;
+        let fat_start = info.reserved_sectors as u64;
+        let fat_sectors = info.fat_sectors() as u64;
+        let data_start = cluster_start(info, 2) / bytes;
+        let mut map = SectorMap {
+            fats: fat_start..fat_start + info.fats as u64 * fat_sectors,
+            fat_sectors,
+            root: root_dir_start(info) / bytes..data_start,
+            data_start,
+            sectors_per_cluster: info.sectors_per_cluster as u64,
+            owners: Vec::new(),
+            clusters: HashMap::new(),
+        };
+        let fat = read_fat(info, disk_file)?;
+        if info.fat_type() == FatType::Fat32 {
+            map.owners.push(("/".to_string(), true));
+            for cluster in cluster_chain(info, &fat, info.root_cluster) {
+                map.clusters.insert(cluster, 0);
+            }
+        }
+        for_each_entry(info, disk_file, &fat, false, |_, found| {
+            let owner = map.owners.len();
+            let is_dir = found.entry.attributes & DirEntryAttributes::SubDir as u8 != 0;
+            map.owners.push((found.path, is_dir));
+            for cluster in cluster_chain(info, &fat, found.entry.flc) {
+                map.clusters.entry(cluster).or_insert(owner);
+            }
+            Ok(())
+        })?;
+        Ok(map)
+    }
+
+    /// What `sector` holds.
+    pub fn region(&self, sector: u64) -> Region {
+        if sector < self.fats.start {
+            Region::Reserved
+        } else if self.fats.contains(&sector) {
+            Region::Fat(((sector - self.fats.start) / self.fat_sectors) as u8)
+        } else if self.root.contains(&sector) {
+            Region::RootDirectory
+        } else {
+            let cluster = ((sector - self.data_start) / self.sectors_per_cluster) as u32 + 2;
+            match self.clusters.get(&cluster).map(|&owner| &self.owners[owner]) {
+                Some(&(ref path, true)) => Region::Directory(path.clone()),
+                Some(&(ref path, false)) => Region::File(path.clone()),
+                None => Region::Unowned,
+            }
+        }
+    }
+
+    /// What the sectors hold, each region once and in order: the files and
+    /// directories a list of dirty sectors changed.
+    pub fn regions(&self, sectors: &[u64]) -> Vec<Region> {
+        let mut regions: Vec<Region> = sectors.iter().map(|&sector| self.region(sector)).collect();
+        regions.sort();
+        regions.dedup();
+        regions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::{blank, host_file};
+    use {mkdir, put};
+
+    #[test]
+    fn sectors_are_traced_to_files_and_back() {
+        let (info, mut image) = blank();
+        mkdir(&info, &mut image, "/DIR").unwrap();
+        let before = image.get_ref().clone();
+        put(&info, &mut image, &host_file("sectors", "Long name.txt", &[1; 1500]), "/DIR").unwrap();
+
+        // Putting the file wrote its slots in DIR's one cluster, its three
+        // clusters' entries in both FATs and the clusters themselves, and
+        // nothing else.
+        let dirty = dirty_sectors(&before, image.get_ref(), 512);
+        let sectors = sectors_of(&info, &mut image, "/DIR/Long name.txt").unwrap().unwrap();
+        assert_eq!(sectors, vec![1, 10, 33, 34, 35, 36]);
+        assert_eq!(dirty, sectors);
+        assert_eq!(sectors_of(&info, &mut image, "/NONE").unwrap(), None);
+
+        let map = SectorMap::new(&info, &mut image).unwrap();
+        assert_eq!(map.regions(&dirty),
+                   vec![Region::Fat(0),
+                        Region::Fat(1),
+                        Region::Directory("/DIR".to_string()),
+                        Region::File("/DIR/LONGNA~1.TXT".to_string())]);
+        assert_eq!(map.regions(&[0, 19, 32, 2879]),
+                   vec![Region::Reserved, Region::RootDirectory, Region::Unowned]);
+    }
+}
@@ -0,0 +1,149 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use {copy_file, find_path, read_disk_info_with, read_fat, DirEntryAttributes, Error, Result, VolumeOptions};
+
+/// One piece of a spanned file: a file on one of the disks of a set.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Piece {
+    pub image: PathBuf,
+    pub path: String,
+}
+
+/// A file split across several disks, as install sets split their archives
+/// over DISK1, DISK2 and on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpannedFile {
+    pub name: String,
+    pub pieces: Vec<Piece>,
+}
+
+/// Reads a manifest: for each spanned file a `[NAME]` line, then a line for
+/// each piece in order, giving the image and the path in it:
+///
+/// ```text
+/// # The game's archive, in three parts.
+/// [GAME.ARJ]
+/// disk1.img /GAME.ARJ
+/// disk2.img /GAME.A01
+/// disk3.img /GAME.A02
+/// ```
+///
+/// Images are found relative to `base`, the manifest's directory. Blank lines
+/// and lines starting with `#` are skipped. Fails with the number of the
+/// first line that isn't understood.
+pub fn parse(text: &str, base: &Path) -> Result<Vec<SpannedFile>> {
+    let mut files: Vec<SpannedFile> = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') && line.len() > 2 {
+            files.push(SpannedFile { name: line[1..line.len() - 1].trim().to_string(), pieces: Vec::new() });
+            continue;
+        }
+        let piece = line.split_once(char::is_whitespace)
+            .map(|(image, path)| Piece { image: base.join(image), path: path.trim().to_string() });
+        match (files.last_mut(), piece) {
+            (Some(file), Some(piece)) => file.pieces.push(piece),
+            (None, Some(_)) => return Err(invalid(number + 1, "a piece before any [NAME] line")),
+            (_, None) => return Err(invalid(number + 1, "expected IMAGE PATH or [NAME]")),
+        }
+    }
+    if let Some(file) = files.iter().find(|file| file.pieces.is_empty()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: no pieces", file.name)).into());
+    }
+    Ok(files)
+}
+
+fn invalid(line: usize, why: &str) -> Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line, why)).into()
+}
+
+/// Reads the manifest at `path`.
+pub fn load(path: &Path) -> Result<Vec<SpannedFile>> {
+    let text = fs::read_to_string(path)?;
+    parse(&text, path.parent().unwrap_or_else(|| Path::new("")))
+}
+
+/// Writes the pieces of `file` to `out` one after the other, reading each
+/// image with `options`. Fails on the first piece that is missing, a
+/// directory, or cut short by a broken chain, naming its image, so the
+/// right disk can be looked at. Returns the bytes written.
+pub fn cat<W: Write>(file: &SpannedFile, options: &VolumeOptions, out: &mut W) -> Result<u64> {
+    let mut written = 0;
+    for piece in &file.pieces {
+        let location = format!("{}:{}", piece.image.display(), piece.path);
+        let mut disk_file = File::open(&piece.image)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", piece.image.display(), e)))?;
+        let info = read_disk_info_with(&mut disk_file, options)?;
+        let entry = find_path(&info, &mut disk_file, &piece.path)?
+            .ok_or_else(|| Error::NotFound(location.clone()))?;
+        if entry.attributes & DirEntryAttributes::SubDir as u8 != 0 {
+            return Err(Error::IsADirectory(location));
+        }
+        let fat = read_fat(&info, &mut disk_file)?;
+        let copied = copy_file(&info, &mut disk_file, &fat[..], &entry, out)?;
+        if copied < entry.file_size as u64 {
+            return Err(Error::CorruptFatChain(location));
+        }
+        written += copied;
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use tests::{blank, host_file};
+    use put;
+
+    #[test]
+    fn parses_manifests() {
+        let text = "# An install set\n[GAME.ARJ]\ndisk1.img /GAME.ARJ\n\ndisk2.img  /SUB/GAME PART.A01\n\
+                    [X]\nd.img /X\n";
+        let files = parse(text, Path::new("sets")).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].name, "GAME.ARJ");
+        let piece = |image: &str, path: &str| Piece { image: PathBuf::from(image), path: path.to_string() };
+        assert_eq!(files[0].pieces,
+                   vec![piece("sets/disk1.img", "/GAME.ARJ"), piece("sets/disk2.img", "/SUB/GAME PART.A01")]);
+        assert_eq!(parse("disk1.img /A\n", Path::new("")).unwrap_err().to_string(),
+                   "line 1: a piece before any [NAME] line");
+        assert_eq!(parse("[A]\ndisk1.img\n", Path::new("")).unwrap_err().to_string(),
+                   "line 2: expected IMAGE PATH or [NAME]");
+        assert_eq!(parse("[A]\n[B]\nd.img /B\n", Path::new("")).unwrap_err().to_string(), "A: no pieces");
+    }
+
+    #[test]
+    fn cats_the_pieces_in_order() {
+        let dir = env::temp_dir().join(format!("fat12-{}-spanned", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (n, data) in [&b"first part, "[..], &[b'-'; 2000][..], &b"and the end"[..]].iter().enumerate() {
+            let (info, mut image) = blank();
+            put(&info, &mut image, &host_file("spanned", "PART.BIN", data), "/").unwrap();
+            fs::write(dir.join(format!("disk{}.img", n + 1)), image.into_inner()).unwrap();
+        }
+        let manifest = dir.join("set.txt");
+        fs::write(&manifest, "[ALL]\ndisk1.img /PART.BIN\ndisk2.img /PART.BIN\ndisk3.img /PART.BIN\n")
+            .unwrap();
+        let files = load(&manifest).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(cat(&files[0], &VolumeOptions::default(), &mut out).unwrap(), 2023);
+        assert_eq!(&out[..12], b"first part, ");
+        assert!(out.ends_with(b"-and the end"));
+
+        let missing = SpannedFile {
+            name: "ALL".to_string(),
+            pieces: vec![Piece { image: dir.join("disk2.img"), path: "/PART.A01".to_string() }],
+        };
+        match cat(&missing, &VolumeOptions::default(), &mut Vec::new()) {
+            Err(Error::NotFound(piece)) => assert!(piece.ends_with("disk2.img:/PART.A01"), "{}", piece),
+            other => panic!("expected NotFound, got {:?}", other),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,137 @@
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use {DiskInfo, Result};
+
+/// How the sectors of every track are numbered and ordered on the disk, for
+/// writing an image back to a real floppy track by track.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TrackLayout {
+    pub cylinders: u32,
+    pub heads: u16,
+    pub sectors_per_track: u16,
+    pub bytes_per_sector: u16,
+    /// How many sector positions on from one sector the next one is: 1 for
+    /// sectors in order, as PC formats write them.
+    pub interleave: u16,
+    /// The ID of the first sector of a track, 1 on every IBM format.
+    pub first_sector: u16,
+}
+impl TrackLayout {
+    /// The layout the BPB gives, with sectors in order from 1. The last
+    /// cylinder is counted even if the volume doesn't fill it. `None` if the
+    /// BPB has no sectors per track or heads.
+    pub fn of(info: &DiskInfo) -> Option<TrackLayout> {
+        if info.sectors_per_track == 0 || info.heads == 0 || info.bytes_per_sector == 0 {
+            return None;
+        }
+        let per_cylinder = info.sectors_per_track as u32 * info.heads as u32;
+        Some(TrackLayout {
+            cylinders: info.sector_count().div_ceil(per_cylinder),
+            heads: info.heads,
+            sectors_per_track: info.sectors_per_track,
+            bytes_per_sector: info.bytes_per_sector,
+            interleave: 1,
+            first_sector: 1,
+        })
+    }
+
+    /// The sector IDs of a track in the order they pass the head. Each
+    /// sector goes `interleave` positions on from the one before, or to the
+    /// next free position if that is taken.
+    pub fn sector_order(&self) -> Vec<u16> {
+        let count = self.sectors_per_track as usize;
+        let mut order: Vec<Option<u16>> = vec![None; count];
+        let mut position = 0;
+        for n in 0..self.sectors_per_track {
+            while order[position].is_some() {
+                position = (position + 1) % count;
+            }
+            order[position] = Some(self.first_sector + n);
+            position = (position + self.interleave as usize) % count;
+        }
+        order.into_iter().map(|id| id.unwrap()).collect()
+    }
+
+    /// The file the data of cylinder `cylinder`, head `head` goes in.
+    pub fn track_file(&self, dir: &Path, cylinder: u32, head: u16) -> PathBuf {
+        dir.join(format!("track{:02}.{}.bin", cylinder, head))
+    }
+
+    /// A Greaseweazle `diskdefs.cfg` entry named `name` for the layout.
+    /// Sectors of 128 bytes are taken to be single density (FM), as on the
+    /// 8-inch disks, and anything larger double density (MFM).
+    pub fn diskdef(&self, name: &str) -> String {
+        let encoding = if self.bytes_per_sector == 128 { "ibm.fm" } else { "ibm.mfm" };
+        [format!("disk {}", name),
+         format!("    cyls = {}", self.cylinders),
+         format!("    heads = {}", self.heads),
+         format!("    tracks * {}", encoding),
+         format!("        secs = {}", self.sectors_per_track),
+         format!("        bps = {}", self.bytes_per_sector),
+         format!("        interleave = {}", self.interleave),
+         format!("        id = {}", self.first_sector),
+         "    end".to_string(),
+         "end\n".to_string()]
+            .join("\n")
+    }
+}
+
+/// Writes every track of the image to its own file in `dir`, named by
+/// `TrackLayout::track_file`, with the sectors in the order `sector_order`
+/// gives, and the layout to `diskdefs.cfg` there. Sectors past the end of a
+/// truncated image are written as zeroes. Returns the number of tracks.
+pub fn export<R: Read + Seek>(disk_file: &mut R, layout: &TrackLayout, dir: &Path) -> Result<u32> {
+    fs::create_dir_all(dir)?;
+    let sector_size = layout.bytes_per_sector as usize;
+    let track_size = sector_size * layout.sectors_per_track as usize;
+    let order = layout.sector_order();
+    let mut track = Vec::with_capacity(track_size);
+    let mut tracks = 0;
+    for cylinder in 0..layout.cylinders {
+        for head in 0..layout.heads {
+            let start = (cylinder as u64 * layout.heads as u64 + head as u64) * track_size as u64;
+            disk_file.seek(SeekFrom::Start(start))?;
+            track.clear();
+            disk_file.by_ref().take(track_size as u64).read_to_end(&mut track)?;
+            track.resize(track_size, 0);
+            let mut out = Vec::with_capacity(track_size);
+            for &id in &order {
+                let n = (id - layout.first_sector) as usize;
+                out.extend_from_slice(&track[n * sector_size..(n + 1) * sector_size]);
+            }
+            File::create(layout.track_file(dir, cylinder, head))?.write_all(&out)?;
+            tracks += 1;
+        }
+    }
+    let order: Vec<String> = order.iter().map(|id| id.to_string()).collect();
+    let config = format!("# Track files hold their sectors in this order: {}\n{}",
+                         order.join(" "),
+                         layout.diskdef("fat12.export"));
+    fs::write(dir.join("diskdefs.cfg"), config)?;
+    Ok(tracks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(sectors_per_track: u16, interleave: u16) -> TrackLayout {
+        TrackLayout {
+            cylinders: 1,
+            heads: 1,
+            sectors_per_track,
+            bytes_per_sector: 512,
+            interleave,
+            first_sector: 1,
+        }
+    }
+
+    #[test]
+    fn orders_sectors_by_interleave() {
+        assert_eq!(layout(9, 1).sector_order(), vec![1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(layout(9, 2).sector_order(), vec![1, 6, 2, 7, 3, 8, 4, 9, 5]);
+        // Every third position comes round to a taken one on 9 sectors.
+        assert_eq!(layout(9, 3).sector_order(), vec![1, 4, 7, 2, 5, 8, 3, 6, 9]);
+    }
+}
@@ -0,0 +1,582 @@
+use fat12::view::{decode_text, hex_dump, is_text};
+use fat12::extract::host_name;
+use fat12::{split_path, DirEntryAttributes, Fat12Volume, ReadWrite, Result};
+use human_size;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Modifier, Style};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::Frame;
+use std::fs;
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+/// What a pane shows: a directory of the image, by its path there, or one
+/// of the host.
+#[derive(Clone, Debug, PartialEq)]
+enum Location {
+    Image(String),
+    Host(PathBuf),
+}
+
+/// An entry of a pane's directory. `..` comes first everywhere but at the
+/// top.
+struct Item {
+    name: String,
+    directory: bool,
+    size: u64,
+}
+
+struct Pane {
+    location: Location,
+    items: Vec<Item>,
+    state: ListState,
+}
+
+/// What the keys do at the moment: move around the panes, or answer a
+/// prompt or read a file in the viewer.
+enum Mode {
+    Normal,
+    /// A name being typed into the prompt, for a rename or a new directory.
+    Prompt(Prompt, String),
+    /// Waiting for `y` to delete the selected entry.
+    ConfirmDelete,
+    /// A file's contents, as text or as a hex dump, scrolled down so far.
+    View(String, Vec<String>, u16),
+}
+
+#[derive(Clone, Copy)]
+enum Prompt {
+    Rename,
+    Mkdir,
+}
+
+/// The file manager: the image in the left pane, the host in the right,
+/// and the keys Norton Commander had. Tab switches panes, Enter opens a
+/// directory, F3 views, F5 copies to the other pane's directory, F6
+/// renames, F7 makes a directory, F8 deletes and F10 or `q` quits. What
+/// fails is shown on the status line, and the panes carry on.
+pub struct App<R> {
+    volume: Fat12Volume<R, ReadWrite>,
+    /// What the image pane's title calls the image.
+    image_name: String,
+    panes: [Pane; 2],
+    active: usize,
+    mode: Mode,
+    status: String,
+    quit: bool,
+}
+
+const HELP: &str = "Tab switch  F3 view  F5 copy  F6 rename  F7 mkdir  F8 delete  F10 quit";
+
+fn image_path(dir: &str, name: &str) -> String {
+    format!("{}/{}", dir.trim_end_matches('/'), name)
+}
+
+impl<R: Read + Write + Seek> App<R> {
+    pub fn new(volume: Fat12Volume<R, ReadWrite>, image_name: &str, host_dir: &Path) -> Result<App<R>> {
+        let pane = |location| Pane { location, items: Vec::new(), state: ListState::default() };
+        let mut app = App {
+            volume,
+            image_name: image_name.to_string(),
+            panes: [pane(Location::Image("/".to_string())), pane(Location::Host(host_dir.to_path_buf()))],
+            active: 0,
+            mode: Mode::Normal,
+            status: String::new(),
+            quit: false,
+        };
+        for side in 0..2 {
+            app.load(side)?;
+        }
+        Ok(app)
+    }
+
+    /// Reads a pane's directory again, keeping the selection where it was
+    /// as far as the entries still reach.
+    fn load(&mut self, side: usize) -> Result<()> {
+        let mut items = Vec::new();
+        match self.panes[side].location {
+            Location::Image(ref dir) => {
+                if dir != "/" {
+                    items.push(Item { name: "..".to_string(), directory: true, size: 0 });
+                }
+                let skip = DirEntryAttributes::VolumeLabel as u8;
+                for entry in self.volume.read_dir(dir)? {
+                    let name = entry.long_name.clone().unwrap_or_else(|| entry.name());
+                    if entry.is_lfn() || entry.attributes & skip != 0 || name == "." || name == ".." {
+                        continue;
+                    }
+                    let directory = entry.attributes & DirEntryAttributes::SubDir as u8 != 0;
+                    items.push(Item { name, directory, size: entry.file_size as u64 });
+                }
+            }
+            Location::Host(ref dir) => {
+                if dir.parent().is_some() {
+                    items.push(Item { name: "..".to_string(), directory: true, size: 0 });
+                }
+                let mut entries = Vec::new();
+                for entry in fs::read_dir(dir)? {
+                    let entry = entry?;
+                    let metadata = entry.metadata()?;
+                    entries.push(Item {
+                        name: entry.file_name().to_string_lossy().into_owned(),
+                        directory: metadata.is_dir(),
+                        size: metadata.len(),
+                    });
+                }
+                entries.sort_by(|a, b| (!a.directory, &a.name).cmp(&(!b.directory, &b.name)));
+                items.extend(entries);
+            }
+        }
+        let pane = &mut self.panes[side];
+        let selected = pane.state.selected().unwrap_or(0).min(items.len().saturating_sub(1));
+        pane.state.select(if items.is_empty() { None } else { Some(selected) });
+        pane.items = items;
+        Ok(())
+    }
+
+    fn reload(&mut self) {
+        for side in 0..2 {
+            if let Err(e) = self.load(side) {
+                self.status = e.to_string();
+            }
+        }
+    }
+
+    fn selected(&self) -> Option<&Item> {
+        let pane = &self.panes[self.active];
+        pane.state.selected().and_then(|i| pane.items.get(i)).filter(|item| item.name != "..")
+    }
+
+    pub fn quit(&self) -> bool {
+        self.quit
+    }
+
+    /// Does what `key` does in the current mode.
+    pub fn key(&mut self, key: KeyCode) {
+        match self.mode {
+            Mode::Normal => self.normal_key(key),
+            Mode::ConfirmDelete => {
+                self.mode = Mode::Normal;
+                if key == KeyCode::Char('y') || key == KeyCode::Char('Y') {
+                    let done = self.delete();
+                    self.finish(done);
+                }
+            }
+            Mode::Prompt(prompt, ref mut text) => match key {
+                KeyCode::Char(c) => text.push(c),
+                KeyCode::Backspace => {
+                    text.pop();
+                }
+                KeyCode::Esc => self.mode = Mode::Normal,
+                KeyCode::Enter => {
+                    let name = text.trim().to_string();
+                    self.mode = Mode::Normal;
+                    if !name.is_empty() {
+                        let done = match prompt {
+                            Prompt::Rename => self.rename(&name),
+                            Prompt::Mkdir => self.mkdir(&name),
+                        };
+                        self.finish(done);
+                    }
+                }
+                _ => (),
+            },
+            Mode::View(_, ref lines, ref mut scroll) => match key {
+                KeyCode::Down => *scroll = (*scroll + 1).min(lines.len().saturating_sub(1) as u16),
+                KeyCode::Up => *scroll = scroll.saturating_sub(1),
+                KeyCode::PageDown => *scroll = (*scroll + 20).min(lines.len().saturating_sub(1) as u16),
+                KeyCode::PageUp => *scroll = scroll.saturating_sub(20),
+                KeyCode::Esc | KeyCode::F(3) | KeyCode::F(10) | KeyCode::Char('q') => {
+                    self.mode = Mode::Normal
+                }
+                _ => (),
+            },
+        }
+    }
+
+    fn normal_key(&mut self, key: KeyCode) {
+        self.status.clear();
+        let pane = &mut self.panes[self.active];
+        let last = pane.items.len().saturating_sub(1);
+        let selected = pane.state.selected().unwrap_or(0);
+        match key {
+            KeyCode::Up => pane.state.select(Some(selected.saturating_sub(1))),
+            KeyCode::Down => pane.state.select(Some((selected + 1).min(last))),
+            KeyCode::PageUp => pane.state.select(Some(selected.saturating_sub(20))),
+            KeyCode::PageDown => pane.state.select(Some((selected + 20).min(last))),
+            KeyCode::Home => pane.state.select(Some(0)),
+            KeyCode::End => pane.state.select(Some(last)),
+            KeyCode::Tab => self.active = 1 - self.active,
+            KeyCode::Enter => {
+                let done = self.open();
+                self.finish(done);
+            }
+            KeyCode::Backspace => {
+                let done = self.enter("..");
+                self.finish(done);
+            }
+            KeyCode::F(3) | KeyCode::Char('v') => {
+                if let Err(e) = self.view() {
+                    self.status = e.to_string();
+                }
+            }
+            KeyCode::F(5) | KeyCode::Char('c') => {
+                let done = self.copy();
+                self.finish(done);
+            }
+            KeyCode::F(6) | KeyCode::Char('r') => {
+                if let Some(item) = self.selected() {
+                    self.mode = Mode::Prompt(Prompt::Rename, item.name.clone());
+                }
+            }
+            KeyCode::F(7) | KeyCode::Char('m') => self.mode = Mode::Prompt(Prompt::Mkdir, String::new()),
+            KeyCode::F(8) | KeyCode::Delete if self.selected().is_some() => self.mode = Mode::ConfirmDelete,
+            KeyCode::F(10) | KeyCode::Char('q') => self.quit = true,
+            _ => (),
+        }
+    }
+
+    /// Shows how a change went and reads the panes again, as it may have
+    /// changed either.
+    fn finish(&mut self, done: Result<String>) {
+        self.reload();
+        self.status = match done {
+            Ok(status) => status,
+            Err(e) => e.to_string(),
+        };
+    }
+
+    fn open(&mut self) -> Result<String> {
+        match self.panes[self.active].state.selected().and_then(|i| self.panes[self.active].items.get(i)) {
+            Some(item) if item.directory => {
+                let name = item.name.clone();
+                self.enter(&name)
+            }
+            Some(_) => {
+                self.view()?;
+                Ok(String::new())
+            }
+            None => Ok(String::new()),
+        }
+    }
+
+    /// Goes into the directory `name` of the active pane, or up for `..`.
+    fn enter(&mut self, name: &str) -> Result<String> {
+        let pane = &mut self.panes[self.active];
+        let from = match pane.location {
+            Location::Image(ref dir) => split_path(dir).1.to_string(),
+            Location::Host(ref dir) => dir.file_name().map_or(String::new(), |n| n.to_string_lossy().into()),
+        };
+        let location = match (&pane.location, name) {
+            (Location::Image(dir), "..") if dir != "/" => {
+                Location::Image(format!("/{}", split_path(dir).0.trim_start_matches('/')))
+            }
+            (Location::Host(dir), "..") => match dir.parent() {
+                Some(parent) => Location::Host(parent.to_path_buf()),
+                None => return Ok(String::new()),
+            },
+            (_, "..") => return Ok(String::new()),
+            (Location::Image(dir), name) => Location::Image(image_path(dir, name)),
+            (Location::Host(dir), name) => Location::Host(dir.join(name)),
+        };
+        let old = std::mem::replace(&mut pane.location, location);
+        pane.state.select(Some(0));
+        if let Err(e) = self.load(self.active) {
+            self.panes[self.active].location = old;
+            return Err(e);
+        }
+        // Going up lands on the directory just left.
+        if name == ".." {
+            let pane = &mut self.panes[self.active];
+            let index = pane.items.iter().position(|item| item.name == from);
+            pane.state.select(index.or(Some(0)));
+        }
+        Ok(String::new())
+    }
+
+    fn read_selected(&mut self) -> Result<Option<(String, Vec<u8>)>> {
+        let name = match self.selected() {
+            Some(item) if !item.directory => item.name.clone(),
+            _ => return Ok(None),
+        };
+        let data = match self.panes[self.active].location {
+            Location::Image(ref dir) => self.volume.read_file(&image_path(dir, &name))?,
+            Location::Host(ref dir) => fs::read(dir.join(&name))?,
+        };
+        Ok(Some((name, data)))
+    }
+
+    /// Opens the selected file in the viewer: as code page 437 text if it
+    /// looks like text, and as a hex dump otherwise.
+    fn view(&mut self) -> Result<()> {
+        if let Some((name, data)) = self.read_selected()? {
+            let lines = if is_text(&data) {
+                decode_text(&data).lines().map(String::from).collect()
+            } else {
+                let mut dump = Vec::new();
+                hex_dump(&data, &mut dump)?;
+                String::from_utf8_lossy(&dump).lines().map(String::from).collect()
+            };
+            self.mode = Mode::View(name, lines, 0);
+        }
+        Ok(())
+    }
+
+    /// Copies the selected file or directory into the other pane's
+    /// directory, whole trees included. Nothing already there is replaced.
+    fn copy(&mut self) -> Result<String> {
+        let name = match self.selected() {
+            Some(item) => item.name.clone(),
+            None => return Ok(String::new()),
+        };
+        let to = self.panes[1 - self.active].location.clone();
+        match (self.panes[self.active].location.clone(), to) {
+            (Location::Image(from), Location::Host(to)) => self.extract(&image_path(&from, &name), &to)?,
+            (Location::Host(from), Location::Image(to)) => self.put(&from.join(&name), &to)?,
+            _ => unreachable!("one pane shows the image and the other the host"),
+        }
+        Ok(format!("copied {}", name))
+    }
+
+    /// Copies the file or tree at `path` in the image into the host
+    /// directory `to`, under a name safe there.
+    fn extract(&mut self, path: &str, to: &Path) -> Result<()> {
+        let entry = self.volume.entry(path)?.ok_or_else(|| fat12::Error::NotFound(path.to_string()))?;
+        let target = to.join(host_name(split_path(path).1));
+        if target.exists() {
+            return Err(fat12::Error::AlreadyExists(target.display().to_string()));
+        }
+        if entry.attributes & DirEntryAttributes::SubDir as u8 == 0 {
+            let data = self.volume.read_file(path)?;
+            fs::write(&target, data)?;
+            return Ok(());
+        }
+        fs::create_dir(&target)?;
+        let skip = DirEntryAttributes::VolumeLabel as u8;
+        for entry in self.volume.read_dir(path)? {
+            let name = entry.long_name.clone().unwrap_or_else(|| entry.name());
+            if !entry.is_lfn() && entry.attributes & skip == 0 && name != "." && name != ".." {
+                self.extract(&image_path(path, &name), &target)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the host file or tree at `host_path` into the image directory
+    /// `to`.
+    fn put(&mut self, host_path: &Path, to: &str) -> Result<()> {
+        if !host_path.is_dir() {
+            return self.volume.put(host_path, to);
+        }
+        let name = host_path.file_name().map_or(String::new(), |n| n.to_string_lossy().into_owned());
+        let path = image_path(to, &name);
+        self.volume.mkdir(&path)?;
+        for entry in fs::read_dir(host_path)? {
+            self.put(&entry?.path(), &path)?;
+        }
+        Ok(())
+    }
+
+    fn rename(&mut self, new_name: &str) -> Result<String> {
+        let name = match self.selected() {
+            Some(item) => item.name.clone(),
+            None => return Ok(String::new()),
+        };
+        match self.panes[self.active].location {
+            Location::Image(ref dir) => self.volume.rename(&image_path(dir, &name), new_name)?,
+            Location::Host(ref dir) => fs::rename(dir.join(&name), dir.join(new_name))?,
+        }
+        Ok(format!("renamed {} to {}", name, new_name))
+    }
+
+    fn mkdir(&mut self, name: &str) -> Result<String> {
+        match self.panes[self.active].location {
+            Location::Image(ref dir) => self.volume.mkdir(&image_path(dir, name))?,
+            Location::Host(ref dir) => fs::create_dir(dir.join(name))?,
+        }
+        Ok(format!("made {}", name))
+    }
+
+    /// Deletes the selected file, or directory if it is empty.
+    fn delete(&mut self) -> Result<String> {
+        let (name, directory) = match self.selected() {
+            Some(item) => (item.name.clone(), item.directory),
+            None => return Ok(String::new()),
+        };
+        match self.panes[self.active].location {
+            Location::Image(ref dir) if directory => {
+                self.volume.rmdir(&image_path(dir, &name))?;
+            }
+            Location::Image(ref dir) => {
+                self.volume.rm(&image_path(dir, &name))?;
+            }
+            Location::Host(ref dir) if directory => fs::remove_dir(dir.join(&name))?,
+            Location::Host(ref dir) => fs::remove_file(dir.join(&name))?,
+        }
+        Ok(format!("deleted {}", name))
+    }
+
+    pub fn draw(&mut self, frame: &mut Frame) {
+        let rows = Layout::vertical([Constraint::Min(1), Constraint::Length(1)]);
+        let [main, status] = rows.areas(frame.area());
+        let status_line = match self.mode {
+            Mode::Prompt(Prompt::Rename, ref text) => format!("Rename to: {}", text),
+            Mode::Prompt(Prompt::Mkdir, ref text) => format!("New directory: {}", text),
+            Mode::ConfirmDelete => format!("Delete {}? (y/n)", self.selected().map_or("", |item| &item.name)),
+            Mode::View(..) => "Esc close  Up/Down scroll".to_string(),
+            Mode::Normal if !self.status.is_empty() => self.status.clone(),
+            Mode::Normal => HELP.to_string(),
+        };
+        frame.render_widget(Paragraph::new(status_line), status);
+        if let Mode::View(ref name, ref lines, scroll) = self.mode {
+            let text = lines.join("\n");
+            let view = Paragraph::new(text).block(Block::bordered().title(name.as_str())).scroll((scroll, 0));
+            frame.render_widget(view, main);
+            return;
+        }
+        let [left, right] = Layout::horizontal([Constraint::Percentage(50); 2]).areas(main);
+        for (side, area) in [(0, left), (1, right)] {
+            self.draw_pane(frame, side, area);
+        }
+    }
+
+    fn draw_pane(&mut self, frame: &mut Frame, side: usize, area: Rect) {
+        let pane = &mut self.panes[side];
+        let title = match pane.location {
+            Location::Image(ref dir) => format!(" {}:{} ", self.image_name, dir),
+            Location::Host(ref dir) => format!(" {} ", dir.display()),
+        };
+        let width = area.width.saturating_sub(2) as usize;
+        let items: Vec<ListItem> = pane.items
+            .iter()
+            .map(|item| {
+                let size = if item.directory { "<DIR>".to_string() } else { human_size(item.size) };
+                let name_width = width.saturating_sub(size.len() + 1);
+                ListItem::new(format!("{:<name_width$.name_width$} {}", item.name, size))
+            })
+            .collect();
+        let highlight = if side == self.active {
+            Style::new().add_modifier(Modifier::REVERSED)
+        } else {
+            Style::new().add_modifier(Modifier::UNDERLINED)
+        };
+        let list = List::new(items).block(Block::bordered().title(title)).highlight_style(highlight);
+        frame.render_stateful_widget(list, area, &mut pane.state);
+    }
+}
+
+/// Runs the file manager on `volume` in the terminal until it is quit.
+pub fn run<R>(volume: Fat12Volume<R, ReadWrite>, image_name: &str, host_dir: &Path) -> Result<()>
+    where R: Read + Write + Seek
+{
+    let mut app = App::new(volume, image_name, host_dir)?;
+    let mut terminal = ratatui::init();
+    let result = (|| -> io::Result<()> {
+        while !app.quit() {
+            terminal.draw(|frame| app.draw(frame))?;
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press {
+                    app.key(key.code);
+                }
+            }
+        }
+        Ok(())
+    })();
+    ratatui::restore();
+    Ok(result?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fat12::{format, geometry};
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+    use std::env;
+    use std::io::Cursor;
+
+    /// What the app shows on a 100 by 12 terminal, a line a row.
+    fn screen<R: Read + Write + Seek>(app: &mut App<R>) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(100, 12)).unwrap();
+        terminal.draw(|frame| app.draw(frame)).unwrap();
+        let rows: Vec<String> = terminal.backend()
+            .buffer()
+            .content()
+            .chunks(100)
+            .map(|row| row.iter().map(|cell| cell.symbol()).collect())
+            .collect();
+        rows.join("\n")
+    }
+
+    fn select<R>(app: &mut App<R>, name: &str) {
+        let pane = &mut app.panes[app.active];
+        let index = pane.items.iter().position(|item| item.name == name);
+        assert!(index.is_some(), "no {} in {:?}", name, pane.location);
+        pane.state.select(index);
+    }
+
+    fn type_text<R: Read + Write + Seek>(app: &mut App<R>, text: &str) {
+        for c in text.chars() {
+            app.key(KeyCode::Char(c));
+        }
+        app.key(KeyCode::Enter);
+    }
+
+    #[test]
+    fn files_are_copied_renamed_and_deleted_across_the_panes() {
+        let dir = env::temp_dir().join(format!("fat12-browse-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("Readme.txt"), "hello\r\n").unwrap();
+        let mut image = Cursor::new(Vec::new());
+        format(&mut image, geometry::by_name("1.44M").unwrap()).unwrap();
+        let mut app = App::new(Fat12Volume::open_writable(image).unwrap(), "a.img", &dir).unwrap();
+        assert!(screen(&mut app).contains(" a.img:/ "));
+
+        // From the host into the image, then renamed there.
+        app.key(KeyCode::Tab);
+        select(&mut app, "Readme.txt");
+        app.key(KeyCode::F(5));
+        assert!(screen(&mut app).contains("copied Readme.txt"));
+        app.key(KeyCode::F(5));
+        assert!(screen(&mut app).contains("already exists"));
+        app.key(KeyCode::Tab);
+        select(&mut app, "Readme.txt");
+        app.key(KeyCode::F(6));
+        for _ in 0.."Readme.txt".len() {
+            app.key(KeyCode::Backspace);
+        }
+        type_text(&mut app, "NOTES.TXT");
+        assert!(app.volume.entry("/NOTES.TXT").unwrap().is_some());
+        assert!(app.volume.entry("/Readme.txt").unwrap().is_none());
+
+        select(&mut app, "NOTES.TXT");
+        app.key(KeyCode::F(3));
+        assert!(screen(&mut app).contains("hello"));
+        app.key(KeyCode::Esc);
+
+        // Back out to the host, then deleted from the image.
+        app.key(KeyCode::F(5));
+        assert_eq!(fs::read(dir.join("NOTES.TXT")).unwrap(), b"hello\r\n");
+        app.key(KeyCode::F(8));
+        assert!(screen(&mut app).contains("Delete NOTES.TXT? (y/n)"));
+        app.key(KeyCode::Char('y'));
+        assert!(app.volume.entry("/NOTES.TXT").unwrap().is_none());
+
+        // Into a new directory and out again, landing on it.
+        app.key(KeyCode::F(7));
+        type_text(&mut app, "DOCS");
+        select(&mut app, "DOCS");
+        app.key(KeyCode::Enter);
+        assert!(screen(&mut app).contains(" a.img:/DOCS "));
+        app.key(KeyCode::Backspace);
+        assert_eq!(app.selected().map(|item| item.name.as_str()), Some("DOCS"));
+        app.key(KeyCode::F(8));
+        app.key(KeyCode::Char('y'));
+        assert!(app.volume.entry("/DOCS").unwrap().is_none());
+
+        app.key(KeyCode::Char('q'));
+        assert!(app.quit());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,322 @@
+use std::collections::HashSet;
+use std::io::{Read, Seek, SeekFrom, Write};
+use byteorder::{ByteOrder, LittleEndian};
+use policy::{self, Action, Mutation};
+use {cluster_chain, cluster_limit, cluster_size, cluster_start, fat_entry, fits, lfn_checksum, read_fat,
+     set_fat_entry, tree, write_fat, DirEntry, DirEntryAttributes, Directory, DiskInfo, Error, FatType,
+     Result, DIR_ENTRY_ATTRS, DIR_ENTRY_SIZE, LFN_ATTRIBUTES, LFN_CHARS, LFN_CHECKSUM, LFN_LAST};
+
+/// Where the character DOS overwrote with 0xE5 goes back when none is given
+/// and no long name tells what it was.
+pub const UNKNOWN_FIRST_CHAR: u8 = b'_';
+
+/// A deleted entry in one of the volume's live directories, and what
+/// restoring it would take.
+pub struct Deleted {
+    /// Its path as `walk_tree` gives deleted entries, with `_` in place of
+    /// the lost first character.
+    pub path: String,
+    /// The entry, with the long name from the deleted LFN slots before it if
+    /// they are still there and belong to it.
+    pub entry: DirEntry,
+    /// The first character of the short name, worked out from the checksum
+    /// the long name's slots carry. `None` when there are none.
+    pub first_char: Option<u8>,
+    /// The clusters the entry would get back, first to last, or why it
+    /// can't be restored.
+    pub chain: std::result::Result<Vec<u32>, &'static str>,
+    directory: Directory,
+    slot: usize,
+    /// How many deleted LFN slots before the entry hold its long name.
+    long_slots: usize,
+}
+impl Deleted {
+    pub fn recoverable(&self) -> bool {
+        self.chain.is_ok()
+    }
+
+    /// Whether `path` names this entry: its own path, or the same path with
+    /// any character first in the name.
+    pub fn matches(&self, path: &str) -> bool {
+        let path = format!("/{}", path.trim_matches('/')).to_uppercase();
+        let own = self.path.to_uppercase();
+        match (path.rsplit_once('/'), own.rsplit_once('/')) {
+            (Some((parent, name)), Some((own_parent, own_name))) => {
+                parent == own_parent && !name.is_empty() && name.get(1..) == own_name.get(1..)
+            }
+            _ => false,
+        }
+    }
+
+    /// The 8.3 name as `DirEntry::name` gives it, with `first_char` first.
+    pub fn name_with(&self, first_char: u8) -> String {
+        let short_name = self.short_name(first_char);
+        let name = String::from_utf8_lossy(&short_name[..8]);
+        let ext = String::from_utf8_lossy(&short_name[8..]);
+        if ext.trim().is_empty() {
+            name.trim().to_string()
+        } else {
+            format!("{}.{}", name.trim(), ext.trim())
+        }
+    }
+
+    /// The short name it gets back with `first_char` first.
+    fn short_name(&self, first_char: u8) -> [u8; 11] {
+        let mut name = [0u8; 11];
+        name[..8].copy_from_slice(&self.entry.file_name);
+        name[8..].copy_from_slice(&self.entry.file_ext);
+        name[0] = first_char;
+        name
+    }
+}
+
+/// Every deleted file and directory in the volume's live directories, in
+/// the order `tree` walks them. Deleted directories aren't looked into.
+pub fn find<R: Read + Seek>(info: &DiskInfo, disk_file: &mut R) -> Result<Vec<Deleted>> {
+    let fat = read_fat(info, disk_file)?;
+    let mut live = HashSet::new();
+    if info.fat_type() == FatType::Fat32 {
+        live.extend(cluster_chain(info, &fat[..], info.root_cluster));
+    }
+    let mut directories = vec![(String::new(), Directory::root(info, &fat[..]))];
+    for found in tree(info, disk_file, &fat[..], false)? {
+        if found.entry.flc >= 2 {
+            live.extend(cluster_chain(info, &fat[..], found.entry.flc));
+        }
+        if (found.entry.attributes & DirEntryAttributes::SubDir as u8) != 0 && found.entry.flc >= 2 {
+            directories.push((found.path, Directory::chain(info, &fat[..], found.entry.flc)));
+        }
+    }
+    let mut deleted = Vec::new();
+    for (path, directory) in directories {
+        let slots = directory.read_slots(disk_file)?;
+        // The deleted LFN slots since the last entry, nearest the entry last.
+        let mut long_slots: Vec<&[u8]> = Vec::new();
+        for (slot, data) in slots.chunks(DIR_ENTRY_SIZE).enumerate() {
+            if data[0] == 0x00 {
+                break;
+            }
+            let lfn = data[DIR_ENTRY_ATTRS] & 0x3F == LFN_ATTRIBUTES;
+            let label = data[DIR_ENTRY_ATTRS] & DirEntryAttributes::VolumeLabel as u8 != 0 && !lfn;
+            if data[0] != 0xE5 || label {
+                long_slots.clear();
+                continue;
+            }
+            if lfn {
+                long_slots.push(data);
+                continue;
+            }
+            let mut entry = DirEntry::read(data, info.fat_type());
+            let (first_char, long_name, owned) = long_name(data, &long_slots);
+            entry.long_name = long_name;
+            long_slots.clear();
+            deleted.push(Deleted {
+                path: String::new(),
+                chain: plan(info, disk_file, &fat, &live, &entry)?,
+                entry,
+                first_char,
+                directory: directory.clone(),
+                slot,
+                long_slots: owned,
+            });
+            let last = deleted.last_mut().unwrap();
+            last.path = format!("{}/{}", path, last.name_with(UNKNOWN_FIRST_CHAR));
+        }
+    }
+    Ok(deleted)
+}
+
+/// Decodes the long name in the deleted LFN slots `slots` before the short
+/// entry `entry`. Their checksum covers the short name's first character,
+/// so only one character gives it; without LFN slots, or if none of them
+/// agree, there is no name. Returns the character, the name and how many of
+/// the slots, counting back from the entry, make it.
+fn long_name(entry: &[u8], slots: &[&[u8]]) -> (Option<u8>, Option<String>, usize) {
+    let checksum = match slots.last() {
+        Some(slot) => slot[LFN_CHECKSUM],
+        None => return (None, None, 0),
+    };
+    let owned = slots.iter().rev().take_while(|slot| slot[LFN_CHECKSUM] == checksum).count();
+    let mut name = [0u8; 11];
+    name.copy_from_slice(&entry[..11]);
+    let first_char = (0x20..=0xFF).map(|c| c as u8).find(|&c| {
+        name[0] = c;
+        lfn_checksum(&name) == checksum
+    });
+    let first_char = match first_char {
+        Some(c) if fits::is_short_name_char(c as char) => c,
+        _ => return (None, None, 0),
+    };
+    let units: Vec<u16> = slots[slots.len() - owned..]
+        .iter()
+        .rev()
+        .flat_map(|slot| LFN_CHARS.iter().map(move |&offset| LittleEndian::read_u16(&slot[offset..])))
+        .take_while(|&unit| unit != 0)
+        .collect();
+    let long_name = std::char::decode_utf16(units)
+        .map(|c| c.unwrap_or(std::char::REPLACEMENT_CHARACTER))
+        .collect();
+    (Some(first_char), Some(long_name), owned)
+}
+
+/// Works out the clusters a deleted entry would get back. If its chain is
+/// still in the FAT, that chain; otherwise, as DOS UNDELETE assumes, the
+/// free clusters from its first cluster on, as many as its size needs. A
+/// directory gets its first cluster, if that still starts with `.`.
+fn plan<R: Read + Seek>(info: &DiskInfo,
+                        disk_file: &mut R,
+                        fat: &[u8],
+                        live: &HashSet<u32>,
+                        entry: &DirEntry)
+                        -> Result<std::result::Result<Vec<u32>, &'static str>> {
+    let is_dir = (entry.attributes & DirEntryAttributes::SubDir as u8) != 0;
+    if entry.file_size == 0 && !is_dir {
+        return Ok(Ok(Vec::new()));
+    }
+    if entry.flc < 2 || entry.flc >= cluster_limit(info) {
+        return Ok(Err("its first cluster is outside the data area"));
+    }
+    if live.contains(&entry.flc) {
+        return Ok(Err("its first cluster belongs to another file now"));
+    }
+    let needed = if is_dir { 1 } else { (entry.file_size as u64).div_ceil(cluster_size(info)) as usize };
+    let chain = if fat_entry(info, fat, entry.flc) == Some(0) {
+        let chain: Vec<u32> = (entry.flc..cluster_limit(info))
+            .filter(|&cluster| fat_entry(info, fat, cluster) == Some(0))
+            .take(needed)
+            .collect();
+        if chain.len() < needed {
+            return Ok(Err("there aren't enough free clusters after its first one"));
+        }
+        chain
+    } else {
+        let chain = cluster_chain(info, fat, entry.flc);
+        if chain.iter().any(|cluster| live.contains(cluster)) {
+            return Ok(Err("its chain runs into another file's clusters"));
+        }
+        if !is_dir && chain.len() != needed {
+            return Ok(Err("the chain left in the FAT doesn't match its size"));
+        }
+        chain
+    };
+    if is_dir {
+        let mut dot = [0u8; 12];
+        disk_file.seek(SeekFrom::Start(cluster_start(info, chain[0])))?;
+        disk_file.read_exact(&mut dot)?;
+        if &dot[..11] != b".          " || dot[11] & DirEntryAttributes::SubDir as u8 == 0 {
+            return Ok(Err("its first cluster no longer holds a directory"));
+        }
+    }
+    Ok(Ok(chain))
+}
+
+/// Restores `deleted`: links its chain back into the FAT and gives its entry
+/// back its first character, `first_char` if given, or the one its long name
+/// gives, or `UNKNOWN_FIRST_CHAR`. The long name comes back too if the
+/// character is the one it was made for. Returns the restored path. Fails
+/// with `Unrecoverable` if `find` found no way to restore it, or with
+/// `AlreadyExists` if a live entry has the name it would get.
+pub fn restore<R: Read + Write + Seek>(info: &DiskInfo,
+                                       disk_file: &mut R,
+                                       deleted: &Deleted,
+                                       first_char: Option<u8>)
+                                       -> Result<String> {
+    let chain = deleted.chain
+        .as_ref()
+        .map_err(|why| Error::Unrecoverable(format!("{}: {}", deleted.path, why)))?;
+    let first_char = first_char.or(deleted.first_char).unwrap_or(UNKNOWN_FIRST_CHAR).to_ascii_uppercase();
+    let short_name = deleted.short_name(first_char);
+    let parent = &deleted.path[..deleted.path.rfind('/').unwrap()];
+    let path = format!("{}/{}", parent, deleted.name_with(first_char));
+    if !fits::is_short_name_char(first_char as char) {
+        return Err(Error::InvalidName(path));
+    }
+    let taken = deleted.directory
+        .entries(disk_file)?
+        .into_iter()
+        .any(|(_, entry)| {
+            !entry.is_lfn() && entry.file_name == short_name[..8] && entry.file_ext == short_name[8..]
+        });
+    if taken {
+        return Err(Error::AlreadyExists(path));
+    }
+    let attributes = deleted.entry.attributes;
+    let change = policy::check(info, Mutation { action: Action::Create, path: path.clone(), attributes })?;
+    if change.path != path {
+        return Err(Error::Denied(format!("{}: an undeleted entry can't be moved", path)));
+    }
+
+    let mut fat = read_fat(info, disk_file)?;
+    for pair in chain.windows(2) {
+        set_fat_entry(info, &mut fat, pair[0], pair[1]);
+    }
+    if let Some(&last) = chain.last() {
+        set_fat_entry(info, &mut fat, last, info.fat_type().end_of_chain());
+    }
+    // The FAT goes first, as in `put`: an interrupted undelete leaves lost
+    // clusters rather than an entry whose chain is still free.
+    write_fat(info, disk_file, &fat)?;
+    if Some(first_char) == deleted.first_char {
+        // The slot nearest the entry is the name's first part.
+        for part in 1..=deleted.long_slots {
+            let order = if part == deleted.long_slots { part as u8 | LFN_LAST } else { part as u8 };
+            deleted.directory.write_slot(disk_file, deleted.slot - part, &[order])?;
+        }
+    }
+    deleted.directory.write_slot(disk_file, deleted.slot, &[first_char])?;
+    if change.attributes != attributes {
+        let offset = deleted.directory.slot_offset(deleted.slot).unwrap() + DIR_ENTRY_ATTRS as u64;
+        disk_file.seek(SeekFrom::Start(offset))?;
+        disk_file.write_all(&[change.attributes])?;
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::{blank, host_file};
+    use {mkdir, put, rm, Fat12Volume};
+
+    #[test]
+    fn restores_files_with_their_long_names() {
+        let (info, mut image) = blank();
+        let data = vec![7; 3 * cluster_size(&info) as usize - 10];
+        put(&info, &mut image, &host_file("undelete", "Annual Report.txt", &data), "/").unwrap();
+        put(&info, &mut image, &host_file("undelete", "NOTES.TXT", b"notes"), "/").unwrap();
+        rm(&info, &mut image, "/Annual Report.txt").unwrap();
+        rm(&info, &mut image, "/NOTES.TXT").unwrap();
+
+        let deleted = find(&info, &mut image).unwrap();
+        let paths: Vec<&str> = deleted.iter().map(|deleted| &deleted.path[..]).collect();
+        assert_eq!(paths, vec!["/_NNUAL~1.TXT", "/_OTES.TXT"]);
+        assert_eq!(deleted[0].first_char, Some(b'A'));
+        assert_eq!(deleted[0].entry.long_name.as_deref(), Some("Annual Report.txt"));
+        assert_eq!(deleted[1].first_char, None);
+        assert!(deleted[1].matches("/notes.txt") && !deleted[1].matches("/NOTES.BAK"));
+
+        assert_eq!(restore(&info, &mut image, &deleted[0], None).unwrap(), "/ANNUAL~1.TXT");
+        assert_eq!(restore(&info, &mut image, &deleted[1], Some(b'n')).unwrap(), "/NOTES.TXT");
+        let mut volume = Fat12Volume::open(image).unwrap();
+        assert_eq!(volume.read_file("/Annual Report.txt").unwrap(), data);
+        assert_eq!(volume.read_file("/NOTES.TXT").unwrap(), b"notes");
+    }
+
+    #[test]
+    fn reused_clusters_are_not_restored() {
+        let (info, mut image) = blank();
+        mkdir(&info, &mut image, "/SUB").unwrap();
+        put(&info, &mut image, &host_file("reused", "LOST.TXT", b"lost"), "/").unwrap();
+        rm(&info, &mut image, "/LOST.TXT").unwrap();
+        // The freed cluster is the first free one, so the next file gets it.
+        put(&info, &mut image, &host_file("reused", "NEW.TXT", b"new"), "/SUB").unwrap();
+
+        let deleted = find(&info, &mut image).unwrap();
+        assert_eq!(deleted.len(), 1);
+        assert_eq!(deleted[0].chain, Err("its first cluster belongs to another file now"));
+        match restore(&info, &mut image, &deleted[0], Some(b'L')) {
+            Err(Error::Unrecoverable(_)) => {}
+            other => panic!("expected Unrecoverable, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use {fat_entry, read_fat, set_fat_entry, write_fat, DiskInfo, Error, Result, DIR_ENTRY_SIZE};
+
+/// What `rm` changed, exactly enough to change it back: the slots of the
+/// entry and its LFN entries as they were, and the FAT entries of the chain
+/// it freed with the values they had.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Deletion {
+    pub path: String,
+    /// Each slot's byte offset in the image and its bytes before the delete.
+    pub slots: Vec<(u64, [u8; DIR_ENTRY_SIZE])>,
+    /// Each freed cluster and what its FAT entry said.
+    pub chain: Vec<(u32, u32)>,
+}
+
+pub fn log_path(image_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.f12undo", image_path))
+}
+
+/// Deletions are recorded when asked to with `--undoable`, and always once an
+/// image has an undo log, as with the audit log.
+pub fn enabled(image_path: &str, args: &[String]) -> bool {
+    args.iter().any(|a| a == "--undoable") || log_path(image_path).exists()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<[u8; DIR_ENTRY_SIZE]> {
+    let mut bytes = [0; DIR_ENTRY_SIZE];
+    if text.len() != 2 * DIR_ENTRY_SIZE {
+        return None;
+    }
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(text.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// A deletion as a line of the log:
+/// `path=/A.TXT\tslots=9728:4120...\tchain=2:3,3:4095`.
+fn format(deletion: &Deletion) -> String {
+    let slots: Vec<String> = deletion.slots.iter().map(|&(offset, ref bytes)| {
+        format!("{}:{}", offset, hex(bytes))
+    }).collect();
+    let chain: Vec<String> = deletion.chain.iter()
+        .map(|&(cluster, value)| format!("{}:{}", cluster, value))
+        .collect();
+    format!("path={}\tslots={}\tchain={}",
+            deletion.path.replace(['\t', '\n'], " "),
+            slots.join(","),
+            chain.join(","))
+}
+
+fn pairs(text: &str) -> impl Iterator<Item = Option<(&str, &str)>> {
+    text.split(',').filter(|pair| !pair.is_empty()).map(|pair| pair.split_once(':'))
+}
+
+fn parse(line: &str) -> Option<Deletion> {
+    let mut fields = line.split('\t').filter_map(|f| f.split_once('='));
+    let (path, slots, chain) = match (fields.next(), fields.next(), fields.next()) {
+        (Some(("path", path)), Some(("slots", slots)), Some(("chain", chain))) => (path, slots, chain),
+        _ => return None,
+    };
+    let slots = pairs(slots)
+        .map(|pair| pair.and_then(|(offset, bytes)| Some((offset.parse().ok()?, unhex(bytes)?))))
+        .collect::<Option<Vec<_>>>()?;
+    let chain = pairs(chain)
+        .map(|pair| pair.and_then(|(cluster, value)| Some((cluster.parse().ok()?, value.parse().ok()?))))
+        .collect::<Option<Vec<_>>>()?;
+    Some(Deletion { path: path.to_string(), slots, chain })
+}
+
+/// The deletions recorded for `image_path`, oldest first. Fails on a line that
+/// can't be read back, naming it.
+pub fn load(image_path: &str) -> Result<Vec<Deletion>> {
+    let mut text = String::new();
+    match File::open(log_path(image_path)) {
+        Ok(mut file) => file.read_to_string(&mut text)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    text.lines().enumerate().map(|(n, line)| {
+        parse(line).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("undo log line {}: malformed", n + 1)).into()
+        })
+    }).collect()
+}
+
+/// Appends `deletion` to the undo log of `image_path`.
+pub fn record(image_path: &str, deletion: &Deletion) -> io::Result<()> {
+    let mut log = OpenOptions::new().create(true).append(true).open(log_path(image_path))?;
+    writeln!(log, "{}", format(deletion))
+}
+
+/// Replaces the undo log of `image_path` with `deletions`, removing it if
+/// there are none left.
+pub fn save(image_path: &str, deletions: &[Deletion]) -> io::Result<()> {
+    if deletions.is_empty() {
+        return match fs::remove_file(log_path(image_path)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            done => done,
+        };
+    }
+    let text: String = deletions.iter().map(|deletion| format(deletion) + "\n").collect();
+    fs::write(log_path(image_path), text)
+}
+
+/// Puts back the files of `deletions`, newest first, writing their slots and
+/// FAT entries as they were. Each is checked before anything is written: its
+/// slots must still be deleted and otherwise unchanged, and its clusters
+/// still free, or it fails with `Unrecoverable` and nothing is restored, as
+/// something written since may own them now.
+pub fn undo<R: Read + Write + Seek>(info: &DiskInfo,
+                                    disk_file: &mut R,
+                                    deletions: &[Deletion])
+                                    -> Result<()> {
+    let mut fat = read_fat(info, disk_file)?;
+    for deletion in deletions.iter().rev() {
+        for &(offset, ref bytes) in &deletion.slots {
+            let mut slot = [0; DIR_ENTRY_SIZE];
+            disk_file.seek(SeekFrom::Start(offset))?;
+            disk_file.read_exact(&mut slot)?;
+            if slot[0] != 0xE5 || slot[1..] != bytes[1..] {
+                return Err(Error::Unrecoverable(format!("{}: its directory entry has been reused",
+                                                        deletion.path)));
+            }
+        }
+        for &(cluster, value) in &deletion.chain {
+            if fat_entry(info, &fat, cluster) != Some(0) {
+                let why = format!("{}: cluster {} is in use again", deletion.path, cluster);
+                return Err(Error::Unrecoverable(why));
+            }
+            set_fat_entry(info, &mut fat, cluster, value);
+        }
+    }
+    write_fat(info, disk_file, &fat)?;
+    for deletion in deletions.iter().rev() {
+        for &(offset, ref bytes) in &deletion.slots {
+            disk_file.seek(SeekFrom::Start(offset))?;
+            disk_file.write_all(bytes)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::{blank, host_file, names};
+    use {put, rm};
+
+    #[test]
+    fn undoes_deletions_exactly() {
+        let (info, mut image) = blank();
+        put(&info, &mut image, &host_file("undo", "A long name.txt", &[b'a'; 1500]), "/").unwrap();
+        put(&info, &mut image, &host_file("undo", "B.TXT", b"bee"), "/").unwrap();
+        let before = image.get_ref().clone();
+        let first = rm(&info, &mut image, "/A long name.txt").unwrap();
+        let second = rm(&info, &mut image, "/B.TXT").unwrap();
+        assert_eq!((first.slots.len(), first.chain.len()), (3, 3));
+        let deletions = vec![first, second];
+        for deletion in &deletions {
+            assert_eq!(parse(&format(deletion)).as_ref(), Some(deletion));
+        }
+        undo(&info, &mut image, &deletions).unwrap();
+        assert_eq!(image.get_ref(), &before);
+
+        // Once something else takes a cluster back, the file stays deleted.
+        let deletion = rm(&info, &mut image, "/A long name.txt").unwrap();
+        put(&info, &mut image, &host_file("undo", "C.TXT", b"sea"), "/").unwrap();
+        match undo(&info, &mut image, &[deletion]) {
+            Err(Error::Unrecoverable(why)) => assert!(why.starts_with("/A long name.txt: "), "{}", why),
+            other => panic!("expected Unrecoverable, got {:?}", other),
+        }
+        assert_eq!(names(&mut image, "/").len(), 2);
+    }
+}
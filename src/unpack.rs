@@ -0,0 +1,355 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use byteorder::{LittleEndian, ByteOrder};
+use flate2::read::DeflateDecoder;
+use flate2::Crc;
+use extract::{self, Placer};
+use {Error, Result};
+
+/// Archive formats common on DOS disks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Zip,
+    Arj,
+    Lzh,
+    Arc,
+}
+impl Format {
+    /// The format a file's extension names, if any.
+    pub fn of(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_string_lossy().to_uppercase();
+        match extension.as_str() {
+            "ZIP" => Some(Format::Zip),
+            "ARJ" => Some(Format::Arj),
+            "LZH" | "LHA" => Some(Format::Lzh),
+            "ARC" => Some(Format::Arc),
+            _ => None,
+        }
+    }
+
+    /// The environment variable that can name a command to unpack the format
+    /// with, e.g. `FAT12_UNPACK_ARJ="7z x -y"`.
+    pub fn hook_variable(self) -> &'static str {
+        match self {
+            Format::Zip => "FAT12_UNPACK_ZIP",
+            Format::Arj => "FAT12_UNPACK_ARJ",
+            Format::Lzh => "FAT12_UNPACK_LZH",
+            Format::Arc => "FAT12_UNPACK_ARC",
+        }
+    }
+
+    /// The command run when the variable isn't set. ZIP has none, since it
+    /// is unpacked here.
+    fn default_hook(self) -> Option<&'static str> {
+        match self {
+            Format::Zip => None,
+            Format::Arj => Some("arj x -y"),
+            Format::Lzh => Some("lha xf"),
+            Format::Arc => Some("arc x"),
+        }
+    }
+}
+
+/// How deep archives inside archives are unpacked.
+const MAX_DEPTH: u32 = 4;
+
+/// Unpacks every archive among `paths` into a directory named after it, with
+/// its extension dropped, and removes the archive once that has worked, so an
+/// extraction ends up with the files themselves. Archives that come out of an
+/// archive are unpacked in turn, a few levels deep. ZIP files are unpacked
+/// here unless their format's hook variable is set; the others are handed to
+/// the command in it, or to `arj`, `lha` or `arc`, with the archive's path
+/// after its arguments and the new directory as working directory. An
+/// archive that can't be unpacked is reported on stderr and left as it is,
+/// with whatever had come out of it removed.
+/// Returns the number unpacked and whether all of them were.
+pub fn unpack_all(paths: &[PathBuf], placer: &mut Placer) -> Result<(u32, bool)> {
+    let mut pending: Vec<(PathBuf, u32)> = paths.iter().map(|path| (path.clone(), 0)).collect();
+    let mut unpacked = 0;
+    let mut ok = true;
+    while let Some((path, depth)) = pending.pop() {
+        let format = match Format::of(&path) {
+            Some(format) if path.is_file() && depth < MAX_DEPTH => format,
+            _ => continue,
+        };
+        let dir = match placer.place(&path.with_extension(""))? {
+            Some(dir) => dir,
+            None => {
+                eprintln!("fat12: {}: not unpacked, {} already exists",
+                          path.display(),
+                          path.with_extension("").display());
+                continue;
+            }
+        };
+        extract::create_dir(&dir)?;
+        match unpack(format, &path, &dir, placer) {
+            Ok(files) => {
+                fs::remove_file(&path)?;
+                unpacked += 1;
+                pending.extend(files.into_iter().map(|file| (file, depth + 1)));
+            }
+            Err(e) => {
+                // Half-unpacked output would look like the real content.
+                let _ = fs::remove_dir_all(&dir);
+                eprintln!("fat12: {}: can't unpack: {}", path.display(), e);
+                ok = false;
+            }
+        }
+    }
+    Ok((unpacked, ok))
+}
+
+/// Unpacks one archive into `dir`. Returns the files it held.
+fn unpack(format: Format, archive: &Path, dir: &Path, placer: &mut Placer) -> Result<Vec<PathBuf>> {
+    let hook = env::var(format.hook_variable()).ok().or_else(|| format.default_hook().map(String::from));
+    match hook {
+        Some(hook) => {
+            run_hook(&hook, archive, dir)?;
+            let mut files = Vec::new();
+            list_files(dir, &mut files)?;
+            Ok(files)
+        }
+        None => unzip(&mut File::open(archive)?, dir, placer),
+    }
+}
+
+/// Runs `hook` in `dir` with the archive's absolute path appended.
+fn run_hook(hook: &str, archive: &Path, dir: &Path) -> Result<()> {
+    let mut words = hook.split_whitespace();
+    let program = words.next().ok_or_else(|| invalid("the unpack command is empty"))?;
+    let status = Command::new(program)
+        .args(words)
+        .arg(fs::canonicalize(archive)?)
+        .current_dir(dir)
+        .status()
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", program, e)))?;
+    if !status.success() {
+        return Err(invalid(&format!("{} failed with {}", program, status)));
+    }
+    Ok(())
+}
+
+/// Every file below `dir`.
+fn list_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            list_files(&entry.path(), files)?;
+        } else {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+fn invalid(why: &str) -> Error {
+    io::Error::new(io::ErrorKind::InvalidData, why.to_string()).into()
+}
+
+const END_OF_DIRECTORY: u32 = 0x0605_4b50;
+const END_OF_DIRECTORY_SIZE: usize = 22;
+const DIRECTORY_HEADER: u32 = 0x0201_4b50;
+const DIRECTORY_HEADER_SIZE: usize = 46;
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const LOCAL_HEADER_SIZE: usize = 30;
+
+/// One file or directory in a ZIP's central directory.
+struct ZipEntry {
+    name: String,
+    flags: u16,
+    method: u16,
+    crc: u32,
+    compressed_size: u32,
+    size: u32,
+    local_header: u32,
+}
+
+/// Reads the central directory at the end of a ZIP file.
+fn zip_entries<R: Read + Seek>(zip: &mut R) -> Result<Vec<ZipEntry>> {
+    let length = zip.seek(SeekFrom::End(0))?;
+    // The end record is followed by a comment of at most 64 KB.
+    let tail_size = length.min(END_OF_DIRECTORY_SIZE as u64 + 0xFFFF);
+    zip.seek(SeekFrom::Start(length - tail_size))?;
+    let mut tail = Vec::with_capacity(tail_size as usize);
+    zip.by_ref().take(tail_size).read_to_end(&mut tail)?;
+    let end = (0..tail.len().saturating_sub(END_OF_DIRECTORY_SIZE - 1))
+        .rev()
+        .find(|&i| LittleEndian::read_u32(&tail[i..]) == END_OF_DIRECTORY)
+        .ok_or_else(|| invalid("not a ZIP file"))?;
+    let end = &tail[end..];
+    let count = LittleEndian::read_u16(&end[10..]);
+    let size = LittleEndian::read_u32(&end[12..]);
+    zip.seek(SeekFrom::Start(LittleEndian::read_u32(&end[16..]) as u64))?;
+    let mut directory = Vec::new();
+    zip.by_ref().take(size as u64).read_to_end(&mut directory)?;
+    let mut entries = Vec::with_capacity(count as usize);
+    let mut header = &directory[..];
+    for _ in 0..count {
+        if header.len() < DIRECTORY_HEADER_SIZE || LittleEndian::read_u32(header) != DIRECTORY_HEADER {
+            return Err(invalid("the ZIP's directory is cut short"));
+        }
+        let name_length = LittleEndian::read_u16(&header[28..]) as usize;
+        let extra_length = LittleEndian::read_u16(&header[30..]) as usize;
+        let comment_length = LittleEndian::read_u16(&header[32..]) as usize;
+        let record_size = DIRECTORY_HEADER_SIZE + name_length + extra_length + comment_length;
+        if header.len() < record_size {
+            return Err(invalid("the ZIP's directory is cut short"));
+        }
+        let flags = LittleEndian::read_u16(&header[8..]);
+        entries.push(ZipEntry {
+            name: zip_name(flags, &header[DIRECTORY_HEADER_SIZE..DIRECTORY_HEADER_SIZE + name_length]),
+            flags,
+            method: LittleEndian::read_u16(&header[10..]),
+            crc: LittleEndian::read_u32(&header[16..]),
+            compressed_size: LittleEndian::read_u32(&header[20..]),
+            size: LittleEndian::read_u32(&header[24..]),
+            local_header: LittleEndian::read_u32(&header[42..]),
+        });
+        header = &header[record_size..];
+    }
+    Ok(entries)
+}
+
+/// A member's name: UTF-8 if its flags say so, else code page 437, as DOS
+/// PKZIP wrote them.
+fn zip_name(flags: u16, name: &[u8]) -> String {
+    if flags & 0x800 != 0 {
+        String::from_utf8_lossy(name).into_owned()
+    } else {
+        name.iter().map(|&byte| ::view::cp437(byte)).collect()
+    }
+}
+
+/// Unpacks a ZIP file into `dir`, taking host paths from `placer`. Stored and
+/// deflated members are supported, which covers PKZIP 2 on; the shrunk and
+/// imploded ones of PKZIP 1 need `FAT12_UNPACK_ZIP="unzip -o"` or similar.
+/// Members' names are made host-safe a component at a time, so none can land
+/// outside `dir`. Returns the files written.
+pub fn unzip<R: Read + Seek>(zip: &mut R, dir: &Path, placer: &mut Placer) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in zip_entries(zip)? {
+        let mut path = dir.to_path_buf();
+        let components: Vec<&str> = entry.name
+            .split(['/', '\\'])
+            .filter(|component| !component.is_empty() && *component != ".")
+            .collect();
+        for (i, component) in components.iter().enumerate() {
+            path.push(extract::host_name(component));
+            if i + 1 < components.len() || entry.name.ends_with('/') {
+                extract::create_dir(&path)?;
+            }
+        }
+        if components.is_empty() || entry.name.ends_with('/') {
+            continue;
+        }
+        if entry.flags & 1 != 0 {
+            return Err(invalid(&format!("{}: encrypted", entry.name)));
+        }
+        let path = match placer.place(&path)? {
+            Some(path) => path,
+            None => continue,
+        };
+        zip.seek(SeekFrom::Start(entry.local_header as u64))?;
+        let mut local = [0; LOCAL_HEADER_SIZE];
+        zip.read_exact(&mut local)?;
+        if LittleEndian::read_u32(&local) != LOCAL_HEADER {
+            return Err(invalid(&format!("{}: no local header", entry.name)));
+        }
+        let skip = LittleEndian::read_u16(&local[26..]) as i64 + LittleEndian::read_u16(&local[28..]) as i64;
+        zip.seek(SeekFrom::Current(skip))?;
+        let data = zip.by_ref().take(entry.compressed_size as u64);
+        let mut reader: Box<dyn Read> = match entry.method {
+            0 => Box::new(data),
+            8 => Box::new(DeflateDecoder::new(data)),
+            method => {
+                return Err(invalid(&format!("{}: compression method {} isn't supported", entry.name, method)));
+            }
+        };
+        let mut contents = Vec::new();
+        reader.by_ref().take(entry.size as u64).read_to_end(&mut contents)?;
+        let mut crc = Crc::new();
+        crc.update(&contents);
+        if contents.len() as u64 != entry.size as u64 || crc.sum() != entry.crc {
+            return Err(invalid(&format!("{}: damaged, its CRC doesn't match", entry.name)));
+        }
+        io::copy(&mut &contents[..], &mut extract::create_file(&path)?)?;
+        files.push(path);
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use std::process;
+    use extract::Collision;
+    use flate2::write::DeflateEncoder;
+    use flate2::Compression;
+
+    /// A ZIP holding `members`, each deflated.
+    fn zip(members: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = Vec::new();
+        let mut directory = Vec::new();
+        for &(name, data) in members {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data).unwrap();
+            let packed = encoder.finish().unwrap();
+            let mut crc = Crc::new();
+            crc.update(data);
+            let mut fields = [0; 16];
+            LittleEndian::write_u16(&mut fields[2..], 8);
+            LittleEndian::write_u32(&mut fields[8..], crc.sum());
+            LittleEndian::write_u32(&mut fields[12..], packed.len() as u32);
+            let mut sizes = [0; 8];
+            LittleEndian::write_u32(&mut sizes, data.len() as u32);
+            LittleEndian::write_u16(&mut sizes[4..], name.len() as u16);
+            let offset = zip.len() as u32;
+            zip.extend_from_slice(&LOCAL_HEADER.to_le_bytes());
+            zip.extend_from_slice(&[20, 0]);
+            zip.extend_from_slice(&fields);
+            zip.extend_from_slice(&sizes);
+            zip.extend_from_slice(name.as_bytes());
+            zip.extend_from_slice(&packed);
+            directory.extend_from_slice(&DIRECTORY_HEADER.to_le_bytes());
+            directory.extend_from_slice(&[20, 0, 20, 0]);
+            directory.extend_from_slice(&fields);
+            directory.extend_from_slice(&sizes);
+            directory.extend_from_slice(&[0; 10]);
+            directory.extend_from_slice(&offset.to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+        }
+        let start = zip.len() as u32;
+        zip.extend_from_slice(&directory);
+        zip.extend_from_slice(&END_OF_DIRECTORY.to_le_bytes());
+        zip.extend_from_slice(&[0; 4]);
+        zip.extend_from_slice(&(members.len() as u16).to_le_bytes());
+        zip.extend_from_slice(&(members.len() as u16).to_le_bytes());
+        zip.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        zip.extend_from_slice(&start.to_le_bytes());
+        zip.extend_from_slice(&[0; 2]);
+        zip
+    }
+
+    #[test]
+    fn unzips_inside_the_directory() {
+        let dir = env::temp_dir().join(format!("fat12-unpack-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let data = zip(&[("README.TXT", b"hello"), ("SUB/../../X.TXT", b"x"), ("GAME/DATA.BIN", &[7; 3000])]);
+        let mut placer = Placer::new(Collision::Error);
+        let files = unzip(&mut Cursor::new(data), &dir, &mut placer).unwrap();
+        assert_eq!(files.len(), 3);
+        assert_eq!(fs::read(dir.join("README.TXT")).unwrap(), b"hello");
+        assert_eq!(fs::read(dir.join("SUB/___/___/X.TXT")).unwrap(), b"x");
+        assert_eq!(fs::read(dir.join("GAME/DATA.BIN")).unwrap(), vec![7; 3000]);
+
+        let mut damaged = zip(&[("A.TXT", b"some text")]);
+        damaged[LOCAL_HEADER_SIZE + 5] ^= 0xFF;
+        assert!(unzip(&mut Cursor::new(damaged), &dir, &mut placer).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
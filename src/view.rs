@@ -0,0 +1,87 @@
+use std::io::{self, Write};
+
+/// The glyphs code page 437 draws for bytes 0x00 to 0x1F, which are control
+/// codes in ASCII.
+const LOW_GLYPHS: &str = " ☺☻♥♦♣♠•◘○◙♂♀♪♫☼►◄↕‼¶§▬↨↑↓→←∟↔▲▼";
+
+/// Code page 437 from 0x80 on: accented letters, box drawing, Greek and
+/// mathematical symbols.
+const HIGH_GLYPHS: &str = "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»░▒▓│┤╡╢╖╕╣║╗╝╜╛┐\
+                           └┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{A0}";
+
+/// The character code page 437, the IBM PC's, shows for `byte`, control
+/// codes included.
+pub fn cp437(byte: u8) -> char {
+    match byte {
+        0x00..=0x1F => LOW_GLYPHS.chars().nth(byte as usize).unwrap(),
+        0x7F => '⌂',
+        0x20..=0x7E => byte as char,
+        _ => HIGH_GLYPHS.chars().nth(byte as usize - 0x80).unwrap(),
+    }
+}
+
+/// DOS text as a string: bytes decoded as code page 437, CR LF line ends
+/// made LF, and everything from a Ctrl-Z, which DOS ended text files with,
+/// left out. Tabs, line feeds and form feeds are kept as they are.
+pub fn decode_text(data: &[u8]) -> String {
+    let end = data.iter().position(|&byte| byte == 0x1A).unwrap_or(data.len());
+    let data = &data[..end];
+    let mut text = String::with_capacity(data.len());
+    for (i, &byte) in data.iter().enumerate() {
+        match byte {
+            b'\r' if data.get(i + 1) == Some(&b'\n') => (),
+            b'\t' | b'\n' | 0x0C => text.push(byte as char),
+            _ => text.push(cp437(byte)),
+        }
+    }
+    text
+}
+
+/// How much of a file `is_text` looks at.
+pub const SNIFF_SIZE: usize = 4096;
+
+/// Whether `data` looks like text: no NUL bytes in its first 4 KB, and
+/// hardly any control codes besides those text files have.
+pub fn is_text(data: &[u8]) -> bool {
+    let head = &data[..data.len().min(SNIFF_SIZE)];
+    let control = head.iter()
+        .filter(|&&byte| byte < 0x20 && !b"\t\n\r\x0c\x1a".contains(&byte))
+        .count();
+    !head.contains(&0) && control * 100 <= head.len()
+}
+
+/// Writes a hex dump of `data`, 16 bytes a line, each line with its offset,
+/// the bytes in hex and the characters code page 437 shows for them, or `.`
+/// for control codes.
+pub fn hex_dump(data: &[u8], out: &mut dyn Write) -> io::Result<()> {
+    for (line, row) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = row.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let (left, right) = hex.split_at(hex.len().min(8));
+        let chars: String = row.iter()
+            .map(|&byte| if byte < 0x20 || byte == 0x7F { '.' } else { cp437(byte) })
+            .collect();
+        writeln!(out, "{:08x}  {:<23}  {:<23}  |{}|", line * 16, left.join(" "), right.join(" "), chars)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_dos_text() {
+        assert_eq!(decode_text(b"\xC9\xCD\xBB\r\n\xBA\x01\xBA\r\n\xC8\xCD\xBC\x1A\0\0"), "╔═╗\n║☺║\n╚═╝");
+        assert!(is_text(b"DEVICE=HIMEM.SYS\r\n\x1A"));
+        assert!(!is_text(b"MZ\x90\0\x03\0"));
+    }
+
+    #[test]
+    fn dumps_sixteen_bytes_a_line() {
+        let mut out = Vec::new();
+        hex_dump(b"0123456789abcdef\x00\xDB", &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "00000000  30 31 32 33 34 35 36 37  38 39 61 62 63 64 65 66  |0123456789abcdef|\n\
+                    00000010  00 db                                             |.█|\n");
+    }
+}
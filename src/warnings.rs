@@ -0,0 +1,89 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Something odd about an image that didn't stop the operation: the library
+/// worked around it and carried on. Entries are named by their 8.3 name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Warning {
+    /// A timestamp that is set but isn't a valid date and time, such as one
+    /// in month 13. It reads as missing.
+    BadTimestamp { name: String, field: &'static str },
+    /// LFN slots that don't make a long name for the entry after them: out of
+    /// order, incomplete, or checksummed for a different short name. The
+    /// short name is used. `None` for slots left before a deleted entry, as
+    /// DOS leaves them when it deletes a file with a long name.
+    OrphanedLongName(Option<String>),
+    /// FAT copy `n`, counting from 0, differs from the one read.
+    FatCopyMismatch(u8),
+    /// A subdirectory whose cluster was already visited walking the tree,
+    /// so the tree loops back on itself there. It isn't entered again.
+    DirectoryCycle(String),
+    /// The image's metadata no longer matches the fingerprint left by the
+    /// last write, so another program has written to the image since.
+    ChangedElsewhere(String),
+    /// A lock sidecar whose holder is gone, left by the process named. It is
+    /// replaced.
+    StaleLock(String),
+    /// A file, or part of the volume's metadata, with sectors the ddrescue map
+    /// read alongside the image has as not recovered, given as ranges.
+    Unrecovered { what: String, sectors: String },
+    /// Sectors of a track-level image, such as a Teledisk or IMD file, that
+    /// it has no data for or marks as bad. They read as zeroes.
+    UnreadableSectors { image: String, count: usize },
+    /// A file copied to a FAT32 volume without its OS/2 extended
+    /// attributes, which FAT32 has nowhere to keep.
+    EasNotCopied(String),
+    /// A file copied without its DR-DOS password, to a volume that has
+    /// nowhere to keep it.
+    PasswordNotCopied(String),
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Warning::BadTimestamp { ref name, field } => write!(f, "{}: invalid {}, ignored", name, field),
+            Warning::OrphanedLongName(Some(ref name)) => {
+                write!(f, "{}: the long name slots before it don't belong to it", name)
+            }
+            Warning::OrphanedLongName(None) => write!(f, "long name slots left behind by a deleted entry"),
+            Warning::FatCopyMismatch(n) => write!(f, "FAT copy {} differs from the one in use", n + 1),
+            Warning::DirectoryCycle(ref path) => {
+                write!(f, "{}: leads back to a directory already visited; not entered", path)
+            }
+            Warning::ChangedElsewhere(ref path) => {
+                write!(f, "{} was changed by another program since fat12 last wrote it", path)
+            }
+            Warning::StaleLock(ref holder) => write!(f, "removing stale lock left by {}", holder),
+            Warning::Unrecovered { ref what, ref sectors } => {
+                let noun = if sectors.contains([',', '-']) { "sectors" } else { "sector" };
+                write!(f, "{}: {} {} weren't recovered, per the map", what, noun, sectors)
+            }
+            Warning::UnreadableSectors { ref image, count } => {
+                write!(f, "{}: {} sectors missing or unreadable, read as zeroes", image, count)
+            }
+            Warning::EasNotCopied(ref name) => write!(f, "{}: extended attributes not copied to FAT32", name),
+            Warning::PasswordNotCopied(ref name) => write!(f, "{}: DR-DOS password not copied", name),
+        }
+    }
+}
+
+/// Where the warnings of a volume go, as `VolumeOptions::warnings`. Clones
+/// share one list, so the `DiskInfo`, the directories read through it and
+/// the caller all see the same warnings.
+#[derive(Clone, Debug, Default)]
+pub struct Warnings(Arc<Mutex<Vec<Warning>>>);
+impl Warnings {
+    /// Records a warning, once: the same directory is often read several
+    /// times in one operation.
+    pub fn warn(&self, warning: Warning) {
+        let mut warnings = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if !warnings.contains(&warning) {
+            warnings.push(warning);
+        }
+    }
+
+    /// The warnings recorded since the last call, oldest first.
+    pub fn take(&self) -> Vec<Warning> {
+        std::mem::take(&mut *self.0.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+use {find_path, mkdir, names, put, rm, rmdir, DirEntryAttributes, DiskInfo, Error, Result};
+
+/// A host tree as `update` compares it: every file and directory below the
+/// root by its path from there, a file with its size and modification time
+/// and a directory with `None`.
+pub type Snapshot = BTreeMap<PathBuf, Option<(u64, SystemTime)>>;
+
+/// What `update` changed.
+#[derive(Debug, Default, PartialEq)]
+pub struct Update {
+    pub written: usize,
+    pub made: usize,
+    pub removed: usize,
+}
+
+/// The tree at `root` as it is now.
+pub fn snapshot(root: &Path) -> io::Result<Snapshot> {
+    let mut snapshot = Snapshot::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(root.join(&dir))? {
+            let entry = entry?;
+            let path = dir.join(entry.file_name());
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                pending.push(path.clone());
+                snapshot.insert(path, None);
+            } else {
+                snapshot.insert(path, Some((metadata.len(), metadata.modified()?)));
+            }
+        }
+    }
+    Ok(snapshot)
+}
+
+/// The path in the image of `path` from the root of a host tree copied into
+/// `target`, each name as the volume's name mapping stores it.
+fn image_path(info: &DiskInfo, target: &str, path: &Path) -> String {
+    let mut image_path = target.trim_end_matches('/').to_string();
+    for part in path.components() {
+        if let Component::Normal(name) = part {
+            image_path.push('/');
+            image_path.push_str(&names::mapping(info).image_name(&name.to_string_lossy()));
+        }
+    }
+    image_path
+}
+
+/// Whether the entry at `path` is there, and whether it is a directory.
+fn kind_of<R: Read + Seek>(info: &DiskInfo, disk_file: &mut R, path: &str) -> Result<Option<bool>> {
+    let entry = find_path(info, disk_file, path)?;
+    Ok(entry.map(|entry| entry.attributes & DirEntryAttributes::SubDir as u8 != 0))
+}
+
+/// Brings the directory `target` of the image in step with the host tree at
+/// `root`, which was as `before` has it when they were last in step and is
+/// now as `after` has it. Files new or changed since are written again, over
+/// what the image has there, and files and directories gone are deleted, so
+/// with an empty `before` every file is written but nothing is deleted.
+/// `target` and the directories on the way to it are made if need be.
+pub fn update<R: Read + Write + Seek>(info: &DiskInfo,
+                                      disk_file: &mut R,
+                                      root: &Path,
+                                      target: &str,
+                                      before: &Snapshot,
+                                      after: &Snapshot)
+                                      -> Result<Update> {
+    let mut update = Update::default();
+    let mut dir = String::new();
+    for part in target.split('/').filter(|part| !part.is_empty()) {
+        dir = format!("{}/{}", dir, part);
+        if kind_of(info, disk_file, &dir)?.is_none() {
+            mkdir(info, disk_file, &dir)?;
+            update.made += 1;
+        }
+    }
+
+    // Deepest first, so a directory is empty by the time it goes.
+    for (path, was) in before.iter().rev() {
+        let now = after.get(path);
+        if now.is_some_and(|now| now.is_none() == was.is_none()) {
+            continue;
+        }
+        let at = image_path(info, target, path);
+        let removed = if was.is_none() { rmdir(info, disk_file, &at) } else { rm(info, disk_file, &at) };
+        match removed {
+            Ok(_) => update.removed += 1,
+            Err(Error::NotFound(_)) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    // Parents first, as the paths sort.
+    for (path, now) in after {
+        if before.get(path) == Some(now) {
+            continue;
+        }
+        let at = image_path(info, target, path);
+        let existing = kind_of(info, disk_file, &at)?;
+        match *now {
+            None if existing == Some(true) => (),
+            None => {
+                mkdir(info, disk_file, &at)?;
+                update.made += 1;
+            }
+            Some(_) => {
+                if existing == Some(false) {
+                    rm(info, disk_file, &at)?;
+                }
+                let parent = image_path(info, target, path.parent().unwrap_or(Path::new("")));
+                put(info, disk_file, &root.join(path), if parent.is_empty() { "/" } else { &parent })?;
+                update.written += 1;
+            }
+        }
+    }
+    Ok(update)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use tests::{blank, names};
+
+    #[test]
+    fn images_follow_the_host_tree() {
+        let root = env::temp_dir().join(format!("fat12-watch-{}", std::process::id()));
+        fs::create_dir_all(root.join("src/old")).unwrap();
+        fs::write(root.join("src/Kernel.bin"), [0x90; 700]).unwrap();
+        fs::write(root.join("src/old/A.TXT"), "a").unwrap();
+        let (info, mut image) = blank();
+
+        let first = snapshot(&root).unwrap();
+        let update1 = update(&info, &mut image, &root, "/BOOT", &Snapshot::new(), &first).unwrap();
+        assert_eq!(update1, Update { written: 2, made: 3, removed: 0 });
+        assert_eq!(names(&mut image, "/BOOT/SRC/OLD")[2..], [("A.TXT".to_string(), None)]);
+
+        // A changed file is written again, and a directory gone is deleted
+        // with what it held.
+        fs::write(root.join("src/Kernel.bin"), [0x90; 900]).unwrap();
+        fs::remove_dir_all(root.join("src/old")).unwrap();
+        fs::write(root.join("src/NEW.TXT"), "new").unwrap();
+        let second = snapshot(&root).unwrap();
+        let update2 = update(&info, &mut image, &root, "/BOOT", &first, &second).unwrap();
+        assert_eq!(update2, Update { written: 2, made: 0, removed: 2 });
+        let kernel = find_path(&info, &mut image, "/BOOT/SRC/Kernel.bin").unwrap().unwrap();
+        assert_eq!(kernel.file_size, 900);
+        assert_eq!(names(&mut image, "/BOOT/SRC").len(), 4);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,243 @@
+//! The command line as scripts see it: fat12 run on images made with its own
+//! `format` and `put`, and what it prints and exits with.
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+/// Runs fat12 with `args`.
+fn fat12(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_fat12")).args(args).stdin(Stdio::null()).output().unwrap()
+}
+
+/// What a run of fat12 that has to succeed prints.
+fn stdout(args: &[&str]) -> String {
+    let output = fat12(args);
+    assert!(output.status.success(), "{:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// An empty directory of the test's own.
+fn scratch(test: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("fat12-cli-{}-{}", std::process::id(), test));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A host file in `dir` named `name` holding `data`.
+fn host(dir: &Path, name: &str, data: &[u8]) -> String {
+    let path = dir.join(name);
+    fs::write(&path, data).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+/// A freshly formatted 1.44M image in `dir`.
+fn blank(dir: &Path) -> String {
+    let image = dir.join("disk.img").to_str().unwrap().to_string();
+    stdout(&["format", &image, "--size", "1.44M"]);
+    image
+}
+
+#[test]
+fn lists_line_up_with_human_sizes_and_any_date_format() {
+    let dir = scratch("list");
+    let image = blank(&dir);
+    stdout(&["put", &image, &host(&dir, "big.bin", &[0; 3000]), &host(&dir, "A.TXT", b"a"), "/"]);
+    for path in ["/BIG.BIN", "/A.TXT"] {
+        stdout(&["touch", &image, path, "--created", "2001-02-03 04:05:06"]);
+    }
+
+    assert_eq!(stdout(&["list", &image]),
+               "f 3000 BIG.BIN 2001-02-03 04:05:06 big.bin\n\
+                f    1 A.TXT   2001-02-03 04:05:06\n");
+    assert_eq!(stdout(&["list", &image, "--human", "--date-format", "%d/%m/%Y"]),
+               "f 2.9K BIG.BIN 03/02/2001 big.bin\n\
+                f    1 A.TXT   03/02/2001\n");
+    assert_eq!(stdout(&["list", &image, "--bare"]), "BIG.BIN\nA.TXT\n");
+    assert!(!fat12(&["list", &image, "--date-format", "%Q"]).status.success());
+}
+
+#[test]
+fn stat_follows_the_chain_of_an_entry_in_any_directory() {
+    let dir = scratch("stat");
+    let image = blank(&dir);
+    stdout(&["mkdir", &image, "/DOCS"]);
+    stdout(&["put", &image, &host(&dir, "A.TXT", b"a"), &host(&dir, "B.TXT", b"b"), "/DOCS"]);
+    // C.BIN takes A.TXT's slot and cluster, and two more after B.TXT's.
+    stdout(&["rm", &image, "/DOCS/A.TXT"]);
+    stdout(&["put", &image, &host(&dir, "C.BIN", &[0; 1500]), "/DOCS"]);
+
+    let stat = stdout(&["stat", &image, "/DOCS/C.BIN"]);
+    // The times are the clock's.
+    let times = ["created:", "accessed:", "modified:"];
+    let lines: Vec<&str> = stat.lines().filter(|line| !times.iter().any(|time| line.starts_with(time))).collect();
+    assert_eq!(lines,
+               ["name:           C.BIN",
+                "attributes:     -----A (0x20)",
+                "size:           1500 bytes",
+                "first cluster:  3",
+                "allocated:      1536 bytes in 3 clusters",
+                "slack:          36 bytes",
+                "chain:          3 clusters in 2 fragments",
+                "directory slot: /DOCS #2 at byte 0x4240"]);
+    assert!(!fat12(&["stat", &image, "/DOCS/NONE"]).status.success());
+}
+
+#[test]
+fn test_exits_by_whether_its_predicates_hold() {
+    let dir = scratch("test");
+    let image = blank(&dir);
+    stdout(&["mkdir", &image, "/SYSTEM"]);
+    stdout(&["put", &image, &host(&dir, "KERNEL.SYS", &[0x90; 100]), "/SYSTEM"]);
+    let status = |args: &[&str]| {
+        let mut all = vec!["test", &image];
+        all.extend(args);
+        fat12(&all).status.code()
+    };
+
+    assert_eq!(status(&["--is-dir", "/SYSTEM", "--is-file", "/SYSTEM/KERNEL.SYS"]), Some(0));
+    assert_eq!(status(&["--exists", "/", "--min-free", "1M"]), Some(0));
+    assert_eq!(status(&["--is-file", "/SYSTEM"]), Some(1));
+    assert_eq!(status(&["--exists", "/SYSTEM/KERNEL.SYS", "--exists", "/SYSTEM/MISSING"]), Some(1));
+    assert_eq!(status(&["--exists", "/SYSTEM/KERNEL.SYS/X"]), Some(1));
+    assert_eq!(status(&["--min-free", "2M"]), Some(1));
+    // Predicates that can't be evaluated are told apart from false ones.
+    assert_eq!(status(&["--min-free", "lots"]), Some(2));
+    assert_eq!(status(&["--exists"]), Some(2));
+}
+
+/// Runs fat12 with `args` and `input` as its standard input.
+fn piped(args: &[&str], input: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_fat12"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn images_stream_through_stdin_and_stdout() {
+    let dir = scratch("stream");
+    let image = blank(&dir);
+    let blank_image = fs::read(&image).unwrap();
+
+    // A change to a streamed image comes out on stdout, with the report on
+    // stderr, and the image read from stdin lists as any other.
+    let put = piped(&["put", "-", &host(&dir, "A.TXT", b"streamed"), "/"], &blank_image);
+    assert!(put.status.success());
+    assert_eq!(put.stdout.len(), blank_image.len());
+    let listed = piped(&["list", "-", "--bare"], &put.stdout);
+    assert_eq!(String::from_utf8(listed.stdout).unwrap(), "A.TXT\n");
+    assert_eq!(piped(&["cat", "-", "/A.TXT"], &put.stdout).stdout, b"streamed");
+    assert!(!piped(&["list", "-"], b"not an image").status.success());
+}
+
+#[test]
+fn output_cut_short_ends_quietly() {
+    let dir = scratch("pipe");
+    let image = blank(&dir);
+    stdout(&["put", &image, &host(&dir, "BIG.BIN", &[0x55; 300_000]), "/"]);
+
+    // As `fat12 cat ... | head -c 16` would: more than a pipe holds, read
+    // from only a little.
+    let mut child = Command::new(env!("CARGO_BIN_EXE_fat12"))
+        .args(["cat", &image, "/BIG.BIN"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut head = [0; 16];
+    child.stdout.take().unwrap().read_exact(&mut head).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(head, [0x55; 16]);
+    assert_eq!(output.status.code(), Some(141));
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+}
+
+#[test]
+fn offsets_are_where_entries_and_clusters_sit_in_the_image() {
+    let dir = scratch("offsets");
+    let image = blank(&dir);
+    stdout(&["put", &image, &host(&dir, "A.TXT", b"a"), &host(&dir, "B.TXT", b"b"), "/"]);
+    stdout(&["rm", &image, "/A.TXT"]);
+    stdout(&["put", &image, &host(&dir, "C.BIN", &[0; 1500]), "/"]);
+
+    // The root starts at 0x2600 and cluster 2 at 0x4200 on a 1.44M disk.
+    let list = stdout(&["list", &image, "--offsets"]);
+    let lines: Vec<&str> = list.lines().collect();
+    assert!(lines[0].ends_with(" @0x2600 0x4200-0x43FF,0x4600-0x49FF"), "{}", list);
+    assert!(lines[1].ends_with(" @0x2620 0x4400-0x45FF"), "{}", list);
+    let stat = stdout(&["stat", &image, "/C.BIN", "--offsets"]);
+    assert!(stat.contains("\ndirectory slot: root #0 at byte 0x2600\n"), "{}", stat);
+    assert!(stat.ends_with("\nclusters:       2 at 0x4200, 4 at 0x4600, 5 at 0x4800\n"), "{}", stat);
+    let json = stdout(&["stat", &image, "/C.BIN", "--offsets", "--json"]);
+    assert!(json.contains("\"cluster_offsets\":[16896,17920,18432]"), "{}", json);
+    assert!(json.contains("\"slot_offset\":9728"), "{}", json);
+}
+
+#[test]
+fn attrib_changes_only_the_bits_it_is_given() {
+    let dir = scratch("attrib");
+    let image = blank(&dir);
+    stdout(&["mkdir", &image, "/D"]);
+    stdout(&["put", &image, &host(&dir, "A.TXT", b"a"), "/D"]);
+
+    assert_eq!(stdout(&["attrib", &image, "/D/A.TXT", "+R", "+h", "-A"]), "RH---- /D/A.TXT\n");
+    assert_eq!(stdout(&["attrib", &image, "/D/A.TXT"]), "RH---- /D/A.TXT\n");
+    // Hidden entries are left out of listings unless all are asked for.
+    assert_eq!(stdout(&["list", &image, "/D", "--bare"]), ".\n..\n");
+    assert_eq!(stdout(&["list", &image, "/D", "--bare", "--all"]), ".\n..\nA.TXT\n");
+    assert_eq!(stdout(&["attrib", &image, "/D", "+S"]), "--S-D- /D\n");
+
+    // What an entry is can't be changed, and nothing is written for a bad
+    // change or a missing entry.
+    let before = fs::read(&image).unwrap();
+    assert!(!fat12(&["attrib", &image, "/D/A.TXT", "+D"]).status.success());
+    assert_eq!(fat12(&["attrib", &image, "/D/NONE", "+R"]).status.code(), Some(2));
+    assert_eq!(fs::read(&image).unwrap(), before);
+}
+
+#[test]
+fn locate_gives_each_cluster_its_track() {
+    let dir = scratch("locate");
+    let image = blank(&dir);
+    stdout(&["put", &image, &host(&dir, "A.TXT", b"a"), &host(&dir, "B.TXT", b"b"), "/"]);
+    stdout(&["rm", &image, "/A.TXT"]);
+    stdout(&["put", &image, &host(&dir, "C.BIN", &[0; 1500]), "/"]);
+
+    // Cluster 5 starts the second track.
+    assert_eq!(stdout(&["locate", &image, "/C.BIN"]),
+               " cluster        lba cylinder head   sectors\n       \
+                2         33        0    1     16-16\n       \
+                4         35        0    1     18-18\n       \
+                5         36        1    0       1-1\n");
+    assert!(!fat12(&["locate", &image, "/NONE"]).status.success());
+}
+
+#[test]
+fn compact_dir_squeezes_deleted_slots_out_of_the_root() {
+    let dir = scratch("compact");
+    let image = blank(&dir);
+    for n in 1..5 {
+        stdout(&["put", &image, &host(&dir, &format!("Long name {}.txt", n), b"x"), "/"]);
+    }
+    stdout(&["rm", &image, "/Long name 1.txt"]);
+    stdout(&["rm", &image, "/Long name 3.txt"]);
+
+    // Each file took an entry and two slots of its long name.
+    assert_eq!(stdout(&["compact-dir", &image, "/"]), "6 slots removed, 0 clusters freed\n");
+    let list = stdout(&["list", &image]);
+    let long_names: Vec<&str> = list.lines().map(|line| &line[line.len() - 15..]).collect();
+    assert_eq!(long_names, ["Long name 2.txt", "Long name 4.txt"]);
+    // The last entry moved up from slot 11 to slot 5.
+    assert!(stdout(&["list", &image, "--offsets"]).contains(" @0x26A0 "));
+    assert_eq!(stdout(&["compact-dir", &image]), "0 slots removed, 0 clusters freed\n");
+    assert_eq!(fat12(&["compact-dir", &image, "/NONE"]).status.code(), Some(2));
+}